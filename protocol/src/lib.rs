@@ -2,10 +2,12 @@ use std::fmt;
 use std::io::{self, Write};
 use std::ops::Deref;
 
-use byteorder::{BigEndian, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 
 pub const DHT_EXT: (usize, u8) = (7, 1);
 pub const EXT_PROTO: (usize, u8) = (5, 0x10);
+pub const FAST_EXT: (usize, u8) = (7, 0x04);
+pub const V2_EXT: (usize, u8) = (7, 0x10);
 pub const UT_META_ID: u8 = 9;
 pub const UT_PEX_ID: u8 = 11;
 
@@ -16,6 +18,16 @@ pub trait Bitfield: Clone + From<Vec<u8>> {
 
 pub trait Buffer: Clone + Deref<Target = [u8]> {}
 
+/// Identifies a range of hashes within a file's BEP 52 merkle tree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
 pub enum Message<BF: Bitfield, Buf: Clone + Deref<Target = [u8]>> {
     // TODO: Consider moving this to the heap,
     // reduces the enum size from 48 bytes to 24,
@@ -50,10 +62,25 @@ pub enum Message<BF: Bitfield, Buf: Clone + Deref<Target = [u8]>> {
         length: u32,
     },
     Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
     Extension {
         id: u8,
         payload: Vec<u8>,
     },
+    HashRequest(Box<HashRequest>),
+    Hashes {
+        req: Box<HashRequest>,
+        hashes: Vec<[u8; 32]>,
+    },
+    HashReject(Box<HashRequest>),
 }
 
 impl<BF: Bitfield, Buf: Buffer> fmt::Debug for Message<BF, Buf> {
@@ -91,7 +118,23 @@ impl<BF: Bitfield, Buf: Buffer> fmt::Debug for Message<BF, Buf> {
                 index, begin, length
             ),
             Message::Port(port) => write!(f, "Message::Port({:?})", port),
+            Message::SuggestPiece(p) => write!(f, "Message::SuggestPiece({})", p),
+            Message::HaveAll => write!(f, "Message::HaveAll"),
+            Message::HaveNone => write!(f, "Message::HaveNone"),
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => write!(
+                f,
+                "Message::RejectRequest {{ idx: {}, begin: {}, len: {} }}",
+                index, begin, length
+            ),
+            Message::AllowedFast(p) => write!(f, "Message::AllowedFast({})", p),
             Message::Extension { id, .. } => write!(f, "Message::Extension {{ id: {} }}", id),
+            Message::HashRequest(ref r) => write!(f, "Message::HashRequest({:?})", r),
+            Message::Hashes { ref req, .. } => write!(f, "Message::Hashes({:?})", req),
+            Message::HashReject(ref r) => write!(f, "Message::HashReject({:?})", r),
         }
     }
 }
@@ -137,10 +180,32 @@ impl<BF: Bitfield, Buf: Buffer> Clone for Message<BF, Buf> {
                 length,
             },
             Message::Port(port) => Message::Port(port),
+            Message::SuggestPiece(p) => Message::SuggestPiece(p),
+            Message::HaveAll => Message::HaveAll,
+            Message::HaveNone => Message::HaveNone,
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => Message::RejectRequest {
+                index,
+                begin,
+                length,
+            },
+            Message::AllowedFast(p) => Message::AllowedFast(p),
             Message::Extension { id, ref payload } => Message::Extension {
                 id,
                 payload: payload.clone(),
             },
+            Message::HashRequest(ref r) => Message::HashRequest(r.clone()),
+            Message::Hashes {
+                ref req,
+                ref hashes,
+            } => Message::Hashes {
+                req: req.clone(),
+                hashes: hashes.clone(),
+            },
+            Message::HashReject(ref r) => Message::HashReject(r.clone()),
         }
     }
 }
@@ -160,8 +225,12 @@ impl<BF: Bitfield, Buf: Buffer> PartialEq for Message<BF, Buf> {
             | (&Message::Choke, &Message::Choke)
             | (&Message::Unchoke, &Message::Unchoke)
             | (&Message::Interested, &Message::Interested)
-            | (&Message::Uninterested, &Message::Uninterested) => true,
-            (&Message::Have(p), &Message::Have(p_)) => p == p_,
+            | (&Message::Uninterested, &Message::Uninterested)
+            | (&Message::HaveAll, &Message::HaveAll)
+            | (&Message::HaveNone, &Message::HaveNone) => true,
+            (&Message::Have(p), &Message::Have(p_))
+            | (&Message::SuggestPiece(p), &Message::SuggestPiece(p_))
+            | (&Message::AllowedFast(p), &Message::AllowedFast(p_)) => p == p_,
             (&Message::Port(p), &Message::Port(p_)) => p == p_,
             (
                 &Message::Request {
@@ -200,6 +269,18 @@ impl<BF: Bitfield, Buf: Buffer> PartialEq for Message<BF, Buf> {
                     begin: b,
                     length: l,
                 },
+            )
+            | (
+                &Message::RejectRequest {
+                    index,
+                    begin,
+                    length,
+                },
+                &Message::RejectRequest {
+                    index: i,
+                    begin: b,
+                    length: l,
+                },
             ) => index == i && begin == b && length == l,
             (
                 &Message::Extension { id, ref payload },
@@ -208,6 +289,18 @@ impl<BF: Bitfield, Buf: Buffer> PartialEq for Message<BF, Buf> {
                     payload: ref p,
                 },
             ) => id == i && payload == p,
+            (&Message::HashRequest(ref r), &Message::HashRequest(ref r_))
+            | (&Message::HashReject(ref r), &Message::HashReject(ref r_)) => r == r_,
+            (
+                &Message::Hashes {
+                    ref req,
+                    ref hashes,
+                },
+                &Message::Hashes {
+                    req: ref r,
+                    hashes: ref h,
+                },
+            ) => req == r && hashes == h,
             _ => false,
         }
     }
@@ -218,6 +311,8 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
         let mut rsv = [0u8; 8];
         rsv[DHT_EXT.0] |= DHT_EXT.1;
         rsv[EXT_PROTO.0] |= EXT_PROTO.1;
+        rsv[FAST_EXT.0] |= FAST_EXT.1;
+        rsv[V2_EXT.0] |= V2_EXT.1;
         Message::Handshake {
             rsv,
             hash: *hash,
@@ -251,7 +346,12 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
 
     pub fn is_special(&self) -> bool {
         match *self {
-            Message::Handshake { .. } | Message::Bitfield(_) | Message::Extension { .. } => true,
+            Message::Handshake { .. }
+            | Message::Bitfield(_)
+            | Message::Extension { .. }
+            | Message::HashRequest(_)
+            | Message::Hashes { .. }
+            | Message::HashReject(_) => true,
             _ => false,
         }
     }
@@ -260,13 +360,20 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
         match *self {
             Message::Handshake { .. } => 68,
             Message::KeepAlive => 4,
-            Message::Choke | Message::Unchoke | Message::Interested | Message::Uninterested => 5,
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::Uninterested
+            | Message::HaveAll
+            | Message::HaveNone => 5,
             Message::Port(_) => 7,
-            Message::Have(_) => 9,
+            Message::Have(_) | Message::SuggestPiece(_) | Message::AllowedFast(_) => 9,
             Message::Bitfield(ref pf) => 5 + pf.bytes(),
-            Message::Request { .. } | Message::Cancel { .. } => 17,
+            Message::Request { .. } | Message::Cancel { .. } | Message::RejectRequest { .. } => 17,
             Message::Piece { ref data, .. } => 13 + data.len(),
             Message::Extension { ref payload, .. } => 6 + payload.len(),
+            Message::HashRequest(_) | Message::HashReject(_) => 53,
+            Message::Hashes { ref hashes, .. } => 53 + hashes.len() * 32,
         }
     }
 
@@ -354,17 +461,89 @@ impl<BF: Bitfield, Buf: Buffer> Message<BF, Buf> {
                 buf.write_u32::<BigEndian>(begin)?;
                 buf.write_u32::<BigEndian>(length)?;
             }
+            Message::SuggestPiece(piece) => {
+                buf.write_u32::<BigEndian>(5)?;
+                buf.write_u8(0x0D)?;
+                buf.write_u32::<BigEndian>(piece)?;
+            }
+            Message::HaveAll => {
+                buf.write_u32::<BigEndian>(1)?;
+                buf.write_u8(0x0E)?;
+            }
+            Message::HaveNone => {
+                buf.write_u32::<BigEndian>(1)?;
+                buf.write_u8(0x0F)?;
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                buf.write_u32::<BigEndian>(13)?;
+                buf.write_u8(0x10)?;
+                buf.write_u32::<BigEndian>(index)?;
+                buf.write_u32::<BigEndian>(begin)?;
+                buf.write_u32::<BigEndian>(length)?;
+            }
+            Message::AllowedFast(piece) => {
+                buf.write_u32::<BigEndian>(5)?;
+                buf.write_u8(0x11)?;
+                buf.write_u32::<BigEndian>(piece)?;
+            }
             Message::Extension { id, ref payload } => {
                 buf.write_u32::<BigEndian>(2 + payload.len() as u32)?;
                 buf.write_u8(20)?;
                 buf.write_u8(id)?;
                 buf.write_all(payload)?;
             }
+            Message::HashRequest(ref req) => {
+                buf.write_u32::<BigEndian>(49)?;
+                buf.write_u8(21)?;
+                req.encode(&mut buf)?;
+            }
+            Message::Hashes {
+                ref req,
+                ref hashes,
+            } => {
+                buf.write_u32::<BigEndian>(49 + hashes.len() as u32 * 32)?;
+                buf.write_u8(22)?;
+                req.encode(&mut buf)?;
+                for h in hashes {
+                    buf.write_all(h)?;
+                }
+            }
+            Message::HashReject(ref req) => {
+                buf.write_u32::<BigEndian>(49)?;
+                buf.write_u8(23)?;
+                req.encode(&mut buf)?;
+            }
         };
         Ok(())
     }
 }
 
+impl HashRequest {
+    pub fn decode(data: &[u8]) -> HashRequest {
+        let mut pieces_root = [0u8; 32];
+        pieces_root.copy_from_slice(&data[..32]);
+        HashRequest {
+            pieces_root,
+            base_layer: BigEndian::read_u32(&data[32..36]),
+            index: BigEndian::read_u32(&data[36..40]),
+            length: BigEndian::read_u32(&data[40..44]),
+            proof_layers: BigEndian::read_u32(&data[44..48]),
+        }
+    }
+
+    fn encode<W: Write>(&self, buf: &mut W) -> io::Result<()> {
+        buf.write_all(&self.pieces_root)?;
+        buf.write_u32::<BigEndian>(self.base_layer)?;
+        buf.write_u32::<BigEndian>(self.index)?;
+        buf.write_u32::<BigEndian>(self.length)?;
+        buf.write_u32::<BigEndian>(self.proof_layers)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -100,9 +100,45 @@ pub enum CMessage {
         serial: u64,
         ids: Vec<String>,
     },
+    SetDeadline {
+        serial: u64,
+        id: String,
+        offset: u64,
+        #[serde(default)]
+        length: Option<u64>,
+        #[serde(default)]
+        deadline: u64,
+    },
     PurgeDns {
         serial: u64,
     },
+    ReloadBlocklist {
+        serial: u64,
+    },
+    BanPeer {
+        serial: u64,
+        ip: String,
+        #[serde(default)]
+        torrent_id: Option<String>,
+        #[serde(default)]
+        expires: Option<DateTime<Utc>>,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    UnbanPeer {
+        serial: u64,
+        ip: String,
+        #[serde(default)]
+        torrent_id: Option<String>,
+    },
+    AddThrottleGroup {
+        serial: u64,
+        name: String,
+        #[serde(default)]
+        throttle_up: Option<i64>,
+        #[serde(default)]
+        throttle_down: Option<i64>,
+    },
 }
 
 /// Server -> client message
@@ -2,7 +2,7 @@ use std::borrow::Cow;
 use std::fmt;
 use std::mem;
 
-use chrono::prelude::{DateTime, Utc};
+use chrono::prelude::{DateTime, NaiveTime, Utc, Weekday};
 use serde;
 use serde_json as json;
 use url::Url;
@@ -21,6 +21,8 @@ pub enum Resource {
     File(File),
     Peer(Peer),
     Tracker(Tracker),
+    #[serde(rename = "throttle_group")]
+    ThrottleGroup(ThrottleGroup),
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -33,6 +35,8 @@ pub enum ResourceKind {
     File,
     Piece,
     Tracker,
+    #[serde(rename = "throttle_group")]
+    ThrottleGroup,
 }
 
 /// To increase server->client update efficiency, we
@@ -63,6 +67,21 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         user_data: json::Value,
     },
+    Bans {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        bans: Vec<Ban>,
+    },
+    SeedLimits {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        ratio_limit: Option<f32>,
+        seed_time_limit: Option<i64>,
+        idle_limit: Option<i64>,
+        limit_action: Option<SeedAction>,
+    },
 
     ServerTransfer {
         id: String,
@@ -75,6 +94,15 @@ pub enum SResourceUpdate<'a> {
         ses_transferred_up: u64,
         ses_transferred_down: u64,
     },
+    ServerLanTransfer {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        lan_rate_up: u64,
+        lan_rate_down: u64,
+        lan_transferred_up: u64,
+        lan_transferred_down: u64,
+    },
     ServerSpace {
         id: String,
         #[serde(rename = "type")]
@@ -87,6 +115,34 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         download_token: String,
     },
+    ServerPortMapping {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        port_mapping: PortMapping,
+        external_ip: Option<String>,
+    },
+    ServerBlocklist {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        blocklist: u64,
+        blocked_incoming: u64,
+        blocked_outgoing: u64,
+    },
+    ServerSchedule {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        schedule: Vec<RateProfile>,
+    },
+    ServerRateProfile {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        rate_profile: Option<String>,
+        turtle: bool,
+    },
 
     TorrentStatus {
         id: String,
@@ -118,6 +174,31 @@ pub enum SResourceUpdate<'a> {
         kind: ResourceKind,
         strategy: Strategy,
     },
+    TorrentEndgame {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        endgame: bool,
+        wasted: u64,
+    },
+    TorrentSuperSeed {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        super_seed: bool,
+    },
+    TorrentSeedTime {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        seed_time: u64,
+    },
+    TorrentThrottleGroup {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        throttle_group: Option<String>,
+    },
     TorrentPriority {
         id: String,
         #[serde(rename = "type")]
@@ -144,6 +225,14 @@ pub enum SResourceUpdate<'a> {
         last_report: DateTime<Utc>,
         error: Option<String>,
     },
+    TrackerScrape {
+        id: String,
+        #[serde(rename = "type")]
+        kind: ResourceKind,
+        complete: Option<u32>,
+        incomplete: Option<u32>,
+        downloaded: Option<u32>,
+    },
 
     FilePriority {
         id: String,
@@ -188,12 +277,30 @@ pub struct CResourceUpdate {
     pub path: Option<String>,
     pub priority: Option<u8>,
     pub strategy: Option<Strategy>,
+    pub super_seed: Option<bool>,
     #[serde(deserialize_with = "deserialize_throttle")]
     #[serde(default)]
     pub throttle_up: Option<Option<i64>>,
     #[serde(deserialize_with = "deserialize_throttle")]
     #[serde(default)]
     pub throttle_down: Option<Option<i64>>,
+    #[serde(deserialize_with = "deserialize_limit")]
+    #[serde(default)]
+    pub ratio_limit: Option<Option<f32>>,
+    #[serde(deserialize_with = "deserialize_limit")]
+    #[serde(default)]
+    pub seed_time_limit: Option<Option<i64>>,
+    #[serde(deserialize_with = "deserialize_limit")]
+    #[serde(default)]
+    pub idle_limit: Option<Option<i64>>,
+    #[serde(deserialize_with = "deserialize_limit")]
+    #[serde(default)]
+    pub limit_action: Option<Option<SeedAction>>,
+    pub schedule: Option<Vec<RateProfile>>,
+    pub turtle: Option<bool>,
+    #[serde(deserialize_with = "deserialize_limit")]
+    #[serde(default)]
+    pub throttle_group: Option<Option<String>>,
     pub user_data: Option<json::Value>,
 }
 
@@ -210,7 +317,26 @@ pub struct Server {
     pub transferred_down: u64,
     pub ses_transferred_up: u64,
     pub ses_transferred_down: u64,
+    /// Transfer with peers on the local network, which is included
+    /// in the totals
+    pub lan_rate_up: u64,
+    pub lan_rate_down: u64,
+    pub lan_transferred_up: u64,
+    pub lan_transferred_down: u64,
     pub free_space: u64,
+    pub port_mapping: PortMapping,
+    pub external_ip: Option<String>,
+    pub bans: Vec<Ban>,
+    pub blocklist: u64,
+    pub blocked_incoming: u64,
+    pub blocked_outgoing: u64,
+    pub ratio_limit: Option<f32>,
+    pub seed_time_limit: Option<i64>,
+    pub idle_limit: Option<i64>,
+    pub limit_action: SeedAction,
+    pub schedule: Vec<RateProfile>,
+    pub rate_profile: Option<String>,
+    pub turtle: bool,
     pub started: DateTime<Utc>,
     pub user_data: json::Value,
 }
@@ -242,12 +368,68 @@ impl Server {
                 self.ses_transferred_up = ses_transferred_up;
                 self.ses_transferred_down = ses_transferred_down;
             }
+            SResourceUpdate::ServerLanTransfer {
+                lan_rate_up,
+                lan_rate_down,
+                lan_transferred_up,
+                lan_transferred_down,
+                ..
+            } => {
+                self.lan_rate_up = lan_rate_up;
+                self.lan_rate_down = lan_rate_down;
+                self.lan_transferred_up = lan_transferred_up;
+                self.lan_transferred_down = lan_transferred_down;
+            }
             SResourceUpdate::ServerToken { download_token, .. } => {
                 self.download_token = download_token;
             }
             SResourceUpdate::ServerSpace { free_space, .. } => {
                 self.free_space = free_space;
             }
+            SResourceUpdate::ServerPortMapping {
+                port_mapping,
+                external_ip,
+                ..
+            } => {
+                self.port_mapping = port_mapping;
+                self.external_ip = external_ip;
+            }
+            SResourceUpdate::Bans { bans, .. } => {
+                self.bans = bans;
+            }
+            SResourceUpdate::ServerBlocklist {
+                blocklist,
+                blocked_incoming,
+                blocked_outgoing,
+                ..
+            } => {
+                self.blocklist = blocklist;
+                self.blocked_incoming = blocked_incoming;
+                self.blocked_outgoing = blocked_outgoing;
+            }
+            SResourceUpdate::SeedLimits {
+                ratio_limit,
+                seed_time_limit,
+                idle_limit,
+                limit_action,
+                ..
+            } => {
+                self.ratio_limit = ratio_limit;
+                self.seed_time_limit = seed_time_limit;
+                self.idle_limit = idle_limit;
+                self.limit_action = limit_action.unwrap_or_default();
+            }
+            SResourceUpdate::ServerSchedule { schedule, .. } => {
+                self.schedule = schedule;
+            }
+            SResourceUpdate::ServerRateProfile {
+                rate_profile,
+                turtle,
+                ..
+            } => {
+                self.rate_profile = rate_profile;
+                self.turtle = turtle;
+            }
             SResourceUpdate::Rate {
                 rate_up, rate_down, ..
             } => {
@@ -276,15 +458,25 @@ pub struct Torrent {
     pub progress: f32,
     pub availability: f32,
     pub strategy: Strategy,
+    pub super_seed: bool,
+    pub endgame: bool,
+    pub wasted: u64,
     pub rate_up: u64,
     pub rate_down: u64,
     pub throttle_up: Option<i64>,
     pub throttle_down: Option<i64>,
     pub transferred_up: u64,
     pub transferred_down: u64,
+    pub seed_time: u64,
+    pub ratio_limit: Option<f32>,
+    pub seed_time_limit: Option<i64>,
+    pub idle_limit: Option<i64>,
+    pub limit_action: Option<SeedAction>,
+    pub throttle_group: Option<String>,
     pub peers: u16,
     pub trackers: u8,
     pub tracker_urls: Vec<String>,
+    pub bans: Vec<Ban>,
     pub size: Option<u64>,
     pub pieces: Option<u64>,
     pub piece_size: Option<u32>,
@@ -334,12 +526,42 @@ impl Torrent {
             SResourceUpdate::TorrentPicker { strategy, .. } => {
                 self.strategy = strategy;
             }
+            SResourceUpdate::TorrentEndgame {
+                endgame, wasted, ..
+            } => {
+                self.endgame = endgame;
+                self.wasted = wasted;
+            }
+            SResourceUpdate::TorrentSuperSeed { super_seed, .. } => {
+                self.super_seed = super_seed;
+            }
+            SResourceUpdate::TorrentSeedTime { seed_time, .. } => {
+                self.seed_time = seed_time;
+            }
+            SResourceUpdate::TorrentThrottleGroup { throttle_group, .. } => {
+                self.throttle_group = throttle_group;
+            }
+            SResourceUpdate::SeedLimits {
+                ratio_limit,
+                seed_time_limit,
+                idle_limit,
+                limit_action,
+                ..
+            } => {
+                self.ratio_limit = ratio_limit;
+                self.seed_time_limit = seed_time_limit;
+                self.idle_limit = idle_limit;
+                self.limit_action = limit_action;
+            }
             SResourceUpdate::TorrentPriority { priority, .. } => {
                 self.priority = priority;
             }
             SResourceUpdate::TorrentPieces { piece_field, .. } => {
                 self.piece_field = piece_field;
             }
+            SResourceUpdate::Bans { bans, .. } => {
+                self.bans = bans;
+            }
             SResourceUpdate::Resource(Cow::Borrowed(Resource::Torrent(t))) => *self = t.clone(),
             SResourceUpdate::Resource(Cow::Owned(Resource::Torrent(mut t))) => {
                 mem::swap(self, &mut t)
@@ -362,10 +584,79 @@ pub enum Status {
     Leeching,
     Idle,
     Seeding,
+    /// Selected files are complete and being seeded, but others are not
+    #[serde(rename = "partial_seed")]
+    PartialSeed,
     Hashing,
     Error,
 }
 
+/// A peer address or range which has been banned, why, and until when.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Ban {
+    pub ip: String,
+    pub reason: String,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/// Alternative throttle rates which apply during part of each week. Times
+/// are local, and a profile which ends before it starts runs overnight.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateProfile {
+    pub name: String,
+    /// Days the profile starts on
+    pub days: Vec<Weekday>,
+    #[serde(with = "hm_time")]
+    pub start: NaiveTime,
+    #[serde(with = "hm_time")]
+    pub end: NaiveTime,
+    pub throttle_up: Option<i64>,
+    pub throttle_down: Option<i64>,
+}
+
+/// Times of day as "HH:MM".
+mod hm_time {
+    use chrono::NaiveTime;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(&time.format("%H:%M"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<NaiveTime, D::Error> {
+        let s = String::deserialize(de)?;
+        NaiveTime::parse_from_str(&s, "%H:%M").map_err(de::Error::custom)
+    }
+}
+
+/// How the server's ports are being mapped on the gateway.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum PortMapping {
+    Disabled,
+    Pending,
+    Pcp,
+    NatPmp,
+    Upnp,
+    Failed,
+}
+
+impl PortMapping {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PortMapping::Disabled => "disabled",
+            PortMapping::Pending => "pending",
+            PortMapping::Pcp => "pcp",
+            PortMapping::NatPmp => "natpmp",
+            PortMapping::Upnp => "upnp",
+            PortMapping::Failed => "failed",
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 #[serde(deny_unknown_fields)]
@@ -383,6 +674,32 @@ impl Strategy {
     }
 }
 
+/// What happens to a torrent once it reaches a seeding limit.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum SeedAction {
+    Pause,
+    Remove,
+    RemoveData,
+}
+
+impl SeedAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &SeedAction::Pause => "pause",
+            &SeedAction::Remove => "remove",
+            &SeedAction::RemoveData => "remove_data",
+        }
+    }
+}
+
+impl Default for SeedAction {
+    fn default() -> Self {
+        SeedAction::Pause
+    }
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Piece {
@@ -442,6 +759,7 @@ pub struct Peer {
     pub torrent_id: String,
     pub client_id: String,
     pub ip: String,
+    pub encrypted: bool,
     pub rate_up: u64,
     pub rate_down: u64,
     pub availability: f32,
@@ -474,6 +792,10 @@ pub struct Tracker {
     pub url: Url,
     pub last_report: DateTime<Utc>,
     pub error: Option<String>,
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
+    pub downloaded: Option<u32>,
+    pub tier: usize,
     pub user_data: json::Value,
 }
 
@@ -486,11 +808,46 @@ impl Tracker {
                 self.last_report = last_report;
                 self.error = error;
             }
+            SResourceUpdate::TrackerScrape {
+                complete,
+                incomplete,
+                downloaded,
+                ..
+            } => {
+                self.complete = complete;
+                self.incomplete = incomplete;
+                self.downloaded = downloaded;
+            }
             _ => {}
         }
     }
 }
 
+/// Rates shared by a set of torrents.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ThrottleGroup {
+    pub id: String,
+    pub name: String,
+    pub throttle_up: Option<i64>,
+    pub throttle_down: Option<i64>,
+    pub user_data: json::Value,
+}
+
+impl ThrottleGroup {
+    pub fn update(&mut self, update: SResourceUpdate<'_>) {
+        if let SResourceUpdate::Throttle {
+            throttle_up,
+            throttle_down,
+            ..
+        } = update
+        {
+            self.throttle_up = throttle_up;
+            self.throttle_down = throttle_down;
+        }
+    }
+}
+
 impl<'a> SResourceUpdate<'a> {
     pub fn id(&self) -> &str {
         match self {
@@ -498,19 +855,31 @@ impl<'a> SResourceUpdate<'a> {
             &SResourceUpdate::Throttle { ref id, .. }
             | &SResourceUpdate::Rate { ref id, .. }
             | &SResourceUpdate::UserData { ref id, .. }
+            | &SResourceUpdate::Bans { ref id, .. }
+            | &SResourceUpdate::SeedLimits { ref id, .. }
             | &SResourceUpdate::ServerTransfer { ref id, .. }
+            | &SResourceUpdate::ServerLanTransfer { ref id, .. }
             | &SResourceUpdate::ServerToken { ref id, .. }
             | &SResourceUpdate::ServerSpace { ref id, .. }
+            | &SResourceUpdate::ServerPortMapping { ref id, .. }
+            | &SResourceUpdate::ServerBlocklist { ref id, .. }
+            | &SResourceUpdate::ServerSchedule { ref id, .. }
+            | &SResourceUpdate::ServerRateProfile { ref id, .. }
             | &SResourceUpdate::TorrentStatus { ref id, .. }
             | &SResourceUpdate::TorrentTransfer { ref id, .. }
             | &SResourceUpdate::TorrentPeers { ref id, .. }
             | &SResourceUpdate::TorrentPicker { ref id, .. }
+            | &SResourceUpdate::TorrentEndgame { ref id, .. }
+            | &SResourceUpdate::TorrentSuperSeed { ref id, .. }
+            | &SResourceUpdate::TorrentSeedTime { ref id, .. }
+            | &SResourceUpdate::TorrentThrottleGroup { ref id, .. }
             | &SResourceUpdate::TorrentPriority { ref id, .. }
             | &SResourceUpdate::TorrentPath { ref id, .. }
             | &SResourceUpdate::TorrentPieces { ref id, .. }
             | &SResourceUpdate::FilePriority { ref id, .. }
             | &SResourceUpdate::FileProgress { ref id, .. }
             | &SResourceUpdate::TrackerStatus { ref id, .. }
+            | &SResourceUpdate::TrackerScrape { ref id, .. }
             | &SResourceUpdate::PeerAvailability { ref id, .. }
             | &SResourceUpdate::PieceAvailable { ref id, .. }
             | &SResourceUpdate::PieceDownloaded { ref id, .. } => id,
@@ -527,6 +896,7 @@ impl Resource {
             &Resource::Piece(ref t) => &t.id,
             &Resource::Peer(ref t) => &t.id,
             &Resource::Tracker(ref t) => &t.id,
+            &Resource::ThrottleGroup(ref t) => &t.id,
         }
     }
 
@@ -548,6 +918,7 @@ impl Resource {
             &Resource::Piece(_) => ResourceKind::Piece,
             &Resource::Peer(_) => ResourceKind::Peer,
             &Resource::Tracker(_) => ResourceKind::Tracker,
+            &Resource::ThrottleGroup(_) => ResourceKind::ThrottleGroup,
         }
     }
 
@@ -559,6 +930,7 @@ impl Resource {
             &mut Resource::Piece(ref mut r) => &mut r.user_data,
             &mut Resource::Peer(ref mut r) => &mut r.user_data,
             &mut Resource::Tracker(ref mut r) => &mut r.user_data,
+            &mut Resource::ThrottleGroup(ref mut r) => &mut r.user_data,
         }
     }
 
@@ -611,6 +983,13 @@ impl Resource {
         }
     }
 
+    pub fn as_throttle_group(&self) -> &ThrottleGroup {
+        match self {
+            &Resource::ThrottleGroup(ref t) => t,
+            _ => panic!(),
+        }
+    }
+
     pub fn update(&mut self, update: SResourceUpdate<'_>) {
         match self {
             &mut Resource::Server(ref mut s) => {
@@ -631,6 +1010,9 @@ impl Resource {
             &mut Resource::Tracker(ref mut t) => {
                 t.update(update);
             }
+            &mut Resource::ThrottleGroup(ref mut t) => {
+                t.update(update);
+            }
         }
     }
 }
@@ -679,6 +1061,49 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  session download: {} B", t.ses_transferred_down)?;
                 write!(f, "\n")?;
+                write!(f, "  LAN upload: {} B/s", t.lan_rate_up)?;
+                write!(f, "\n")?;
+                write!(f, "  LAN download: {} B/s", t.lan_rate_down)?;
+                write!(f, "\n")?;
+                write!(f, "  LAN uploaded: {} B", t.lan_transferred_up)?;
+                write!(f, "\n")?;
+                write!(f, "  LAN downloaded: {} B", t.lan_transferred_down)?;
+                write!(f, "\n")?;
+                write!(f, "  bans: {}", t.bans.len())?;
+                write!(f, "\n")?;
+                write!(
+                    f,
+                    "  blocked: {} incoming, {} outgoing",
+                    t.blocked_incoming, t.blocked_outgoing
+                )?;
+                write!(f, "\n")?;
+                write!(f, "  ratio limit: {}", fmt_limit(t.ratio_limit, "none"))?;
+                write!(f, "\n")?;
+                write!(
+                    f,
+                    "  seed time limit: {}",
+                    fmt_limit(t.seed_time_limit, "none")
+                )?;
+                write!(f, "\n")?;
+                write!(f, "  idle limit: {}", fmt_limit(t.idle_limit, "none"))?;
+                write!(f, "\n")?;
+                write!(f, "  limit action: {}", t.limit_action.as_str())?;
+                write!(f, "\n")?;
+                write!(f, "  rate profiles: {}", t.schedule.len())?;
+                write!(f, "\n")?;
+                if t.turtle {
+                    write!(f, "  rate profile: turtle")?;
+                } else {
+                    write!(
+                        f,
+                        "  rate profile: {}",
+                        t.rate_profile
+                            .as_ref()
+                            .map(|p| p.as_str())
+                            .unwrap_or("none")
+                    )?;
+                }
+                write!(f, "\n")?;
                 write!(f, "  started at: {}", t.started)?;
                 write!(f, "\n")?;
                 write!(f, "}}")?;
@@ -718,6 +1143,8 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  strategy: {:?}", t.strategy)?;
                 write!(f, "\n")?;
+                write!(f, "  super seeding: {}", t.super_seed)?;
+                write!(f, "\n")?;
                 write!(f, "  upload: {} B/s", t.rate_up)?;
                 write!(f, "\n")?;
                 write!(f, "  download: {} B/s", t.rate_down)?;
@@ -750,10 +1177,34 @@ impl fmt::Display for Resource {
                 write!(f, "\n")?;
                 write!(f, "  downloaded: {} B", t.transferred_down)?;
                 write!(f, "\n")?;
+                write!(f, "  seed time: {} s", t.seed_time)?;
+                write!(f, "\n")?;
+                write!(f, "  ratio limit: {}", fmt_limit(t.ratio_limit, "server"))?;
+                write!(f, "\n")?;
+                write!(
+                    f,
+                    "  seed time limit: {}",
+                    fmt_limit(t.seed_time_limit, "server")
+                )?;
+                write!(f, "\n")?;
+                write!(f, "  idle limit: {}", fmt_limit(t.idle_limit, "server"))?;
+                write!(f, "\n")?;
+                write!(
+                    f,
+                    "  limit action: {}",
+                    t.limit_action.map(|a| a.as_str()).unwrap_or("server")
+                )?;
+                write!(f, "\n")?;
+                if let Some(ref g) = t.throttle_group {
+                    write!(f, "  throttle group: {}", g)?;
+                    write!(f, "\n")?;
+                }
                 write!(f, "  peers: {}", t.peers)?;
                 write!(f, "\n")?;
                 write!(f, "  trackers: {}", t.trackers)?;
                 write!(f, "\n")?;
+                write!(f, "  bans: {}", t.bans.len())?;
+                write!(f, "\n")?;
                 if let Some(s) = t.size {
                     write!(f, "  size: {} B", s)?;
                 } else {
@@ -792,11 +1243,33 @@ impl fmt::Display for Resource {
             &Resource::Tracker(ref t) => {
                 write!(f, "{:#?}", t)?;
             }
+            &Resource::ThrottleGroup(ref t) => {
+                write!(f, "{:#?}", t)?;
+            }
         }
         Ok(())
     }
 }
 
+/// Formats a seeding limit, where negative values disable it.
+fn fmt_limit<T: fmt::Display + PartialOrd + Default>(limit: Option<T>, unset: &str) -> String {
+    match limit {
+        Some(l) if l >= T::default() => l.to_string(),
+        Some(_) => "none".to_owned(),
+        None => unset.to_owned(),
+    }
+}
+
+/// Deserializes a field which may be null, distinguishing this from
+/// the field being absent.
+fn deserialize_limit<'de, D, T>(de: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    serde::Deserialize::deserialize(de).map(Some)
+}
+
 fn deserialize_throttle<'de, D>(de: D) -> Result<Option<Option<i64>>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -821,6 +1294,7 @@ impl Queryable for Resource {
             &Resource::Piece(ref t) => t.field(f),
             &Resource::Peer(ref t) => t.field(f),
             &Resource::Tracker(ref t) => t.field(f),
+            &Resource::ThrottleGroup(ref t) => t.field(f),
         }
     }
 }
@@ -860,7 +1334,35 @@ impl Queryable for Server {
             "transferred_down" => Some(Field::N(self.transferred_down as i64)),
             "ses_transferred_up" => Some(Field::N(self.ses_transferred_up as i64)),
             "ses_transferred_down" => Some(Field::N(self.ses_transferred_down as i64)),
+            "lan_rate_up" => Some(Field::N(self.lan_rate_up as i64)),
+            "lan_rate_down" => Some(Field::N(self.lan_rate_down as i64)),
+            "lan_transferred_up" => Some(Field::N(self.lan_transferred_up as i64)),
+            "lan_transferred_down" => Some(Field::N(self.lan_transferred_down as i64)),
             "free_space" => Some(Field::N(self.free_space as i64)),
+            "port_mapping" => Some(Field::S(self.port_mapping.as_str())),
+            "external_ip" => Some(
+                self.external_ip
+                    .as_ref()
+                    .map(|ip| Field::S(ip))
+                    .unwrap_or(FNULL),
+            ),
+            "bans" => Some(Field::V(
+                self.bans.iter().map(|b| Field::S(&b.ip)).collect(),
+            )),
+            "blocklist" => Some(Field::N(self.blocklist as i64)),
+            "blocked_incoming" => Some(Field::N(self.blocked_incoming as i64)),
+            "blocked_outgoing" => Some(Field::N(self.blocked_outgoing as i64)),
+            "ratio_limit" => Some(self.ratio_limit.map(|v| Field::F(v)).unwrap_or(FNULL)),
+            "seed_time_limit" => Some(self.seed_time_limit.map(|v| Field::N(v)).unwrap_or(FNULL)),
+            "idle_limit" => Some(self.idle_limit.map(|v| Field::N(v)).unwrap_or(FNULL)),
+            "limit_action" => Some(Field::S(self.limit_action.as_str())),
+            "rate_profile" => Some(
+                self.rate_profile
+                    .as_ref()
+                    .map(|p| Field::S(p))
+                    .unwrap_or(FNULL),
+            ),
+            "turtle" => Some(Field::B(self.turtle)),
 
             "started" => Some(Field::D(self.started)),
 
@@ -882,6 +1384,8 @@ impl Queryable for Torrent {
                     .unwrap_or(FNULL),
             ),
             "private" => Some(Field::B(self.private)),
+            "super_seed" => Some(Field::B(self.super_seed)),
+            "endgame" => Some(Field::B(self.endgame)),
             "creator" => Some(
                 self.creator
                     .as_ref()
@@ -910,11 +1414,30 @@ impl Queryable for Torrent {
             "throttle_down" => Some(self.throttle_down.map(|v| Field::N(v)).unwrap_or(FNULL)),
             "transferred_up" => Some(Field::N(self.transferred_up as i64)),
             "transferred_down" => Some(Field::N(self.transferred_down as i64)),
+            "wasted" => Some(Field::N(self.wasted as i64)),
+            "seed_time" => Some(Field::N(self.seed_time as i64)),
+            "ratio_limit" => Some(self.ratio_limit.map(|v| Field::F(v)).unwrap_or(FNULL)),
+            "seed_time_limit" => Some(self.seed_time_limit.map(|v| Field::N(v)).unwrap_or(FNULL)),
+            "idle_limit" => Some(self.idle_limit.map(|v| Field::N(v)).unwrap_or(FNULL)),
+            "limit_action" => Some(
+                self.limit_action
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
+            "throttle_group" => Some(
+                self.throttle_group
+                    .as_ref()
+                    .map(|v| Field::S(v.as_str()))
+                    .unwrap_or(FNULL),
+            ),
             "peers" => Some(Field::N(self.peers as i64)),
             "trackers" => Some(Field::N(self.trackers as i64)),
             "tracker_urls" => Some(Field::V(
                 self.tracker_urls.iter().map(|url| Field::S(url)).collect(),
             )),
+            "bans" => Some(Field::V(
+                self.bans.iter().map(|b| Field::S(&b.ip)).collect(),
+            )),
             "size" => Some(self.size.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
             "pieces" => Some(self.pieces.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
             "piece_size" => Some(self.piece_size.map(|v| Field::N(v as i64)).unwrap_or(FNULL)),
@@ -979,6 +1502,7 @@ impl Queryable for Peer {
             "id" => Some(Field::S(&self.id)),
             "torrent_id" => Some(Field::S(&self.torrent_id)),
             "ip" => Some(Field::S(&self.ip)),
+            "encrypted" => Some(Field::B(self.encrypted)),
 
             "rate_up" => Some(Field::N(self.rate_up as i64)),
             "rate_down" => Some(Field::N(self.rate_down as i64)),
@@ -1009,6 +1533,38 @@ impl Queryable for Tracker {
 
             "last_report" => Some(Field::D(self.last_report)),
 
+            "complete" => Some(
+                self.complete
+                    .map(|v| Field::N(i64::from(v)))
+                    .unwrap_or(FNULL),
+            ),
+            "incomplete" => Some(
+                self.incomplete
+                    .map(|v| Field::N(i64::from(v)))
+                    .unwrap_or(FNULL),
+            ),
+            "tier" => Some(Field::N(self.tier as i64)),
+            "downloaded" => Some(
+                self.downloaded
+                    .map(|v| Field::N(i64::from(v)))
+                    .unwrap_or(FNULL),
+            ),
+
+            _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
+
+            _ => None,
+        }
+    }
+}
+
+impl Queryable for ThrottleGroup {
+    fn field(&self, f: &str) -> Option<Field<'_>> {
+        match f {
+            "id" => Some(Field::S(&self.id)),
+            "name" => Some(Field::S(&self.name)),
+            "throttle_up" => Some(self.throttle_up.map(|v| Field::N(v)).unwrap_or(FNULL)),
+            "throttle_down" => Some(self.throttle_down.map(|v| Field::N(v)).unwrap_or(FNULL)),
+
             _ if f.starts_with("user_data") => self.user_data.field(&f[9..]),
 
             _ => None,
@@ -1024,6 +1580,7 @@ impl Status {
             Status::Leeching => "leeching",
             Status::Idle => "idle",
             Status::Seeding => "seeding",
+            Status::PartialSeed => "partial_seed",
             Status::Hashing => "hashing",
             Status::Magnet => "magnet",
             Status::Error => "error",
@@ -1069,7 +1626,24 @@ impl Default for Server {
             transferred_down: 0,
             ses_transferred_up: 0,
             ses_transferred_down: 0,
+            lan_rate_up: 0,
+            lan_rate_down: 0,
+            lan_transferred_up: 0,
+            lan_transferred_down: 0,
             free_space: 0,
+            port_mapping: PortMapping::Disabled,
+            external_ip: None,
+            bans: vec![],
+            blocklist: 0,
+            blocked_incoming: 0,
+            blocked_outgoing: 0,
+            ratio_limit: None,
+            seed_time_limit: None,
+            idle_limit: None,
+            limit_action: SeedAction::Pause,
+            schedule: vec![],
+            rate_profile: None,
+            turtle: false,
             download_token: "".to_owned(),
             started: Utc::now(),
             user_data: json::Value::Null,
@@ -1094,15 +1668,25 @@ impl Default for Torrent {
             progress: 0.,
             availability: 0.,
             strategy: Strategy::Rarest,
+            super_seed: false,
+            endgame: false,
+            wasted: 0,
             rate_up: 0,
             rate_down: 0,
             throttle_up: None,
             throttle_down: None,
             transferred_up: 0,
             transferred_down: 0,
+            seed_time: 0,
+            ratio_limit: None,
+            seed_time_limit: None,
+            idle_limit: None,
+            limit_action: None,
+            throttle_group: None,
             peers: 0,
             trackers: 0,
             tracker_urls: vec![],
+            bans: vec![],
             size: None,
             pieces: None,
             piece_size: None,
@@ -1113,6 +1697,18 @@ impl Default for Torrent {
     }
 }
 
+impl Default for ThrottleGroup {
+    fn default() -> Self {
+        ThrottleGroup {
+            id: "".to_owned(),
+            name: "".to_owned(),
+            throttle_up: None,
+            throttle_down: None,
+            user_data: json::Value::Null,
+        }
+    }
+}
+
 impl Default for Tracker {
     fn default() -> Self {
         Tracker {
@@ -1121,6 +1717,10 @@ impl Default for Tracker {
             url: Url::parse("http://my.tracker/announce").unwrap(),
             last_report: Utc::now(),
             error: None,
+            complete: None,
+            incomplete: None,
+            downloaded: None,
+            tier: 0,
             user_data: json::Value::Null,
         }
     }
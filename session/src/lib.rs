@@ -3,7 +3,7 @@ extern crate serde_derive;
 
 pub mod torrent {
     pub use self::current::Session;
-    pub use self::ver_fa1b6f as current;
+    pub use self::ver_e3a5b9 as current;
 
     #[derive(Serialize, Deserialize, Clone)]
     pub struct Bitfield {
@@ -12,8 +12,20 @@ pub mod torrent {
     }
 
     pub fn load(data: &[u8]) -> Option<Session> {
-        if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+        if let Ok(m) = bincode::deserialize::<ver_e3a5b9::Session>(data) {
             Some(m)
+        } else if let Ok(m) = bincode::deserialize::<ver_c82d17::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_4a7c3e::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_9c4f1a::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_d2e07c::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_3b52a0::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_fa1b6f::Session>(data) {
+            Some(m.migrate())
         } else if let Ok(m) = bincode::deserialize::<ver_6e27af::Session>(data) {
             Some(m.migrate())
         } else if let Ok(m) = bincode::deserialize::<ver_249b1b::Session>(data) {
@@ -33,7 +45,7 @@ pub mod torrent {
         }
     }
 
-    pub mod ver_fa1b6f {
+    pub mod ver_e3a5b9 {
         use super::Bitfield;
 
         use chrono::{DateTime, Utc};
@@ -53,7 +65,27 @@ pub mod torrent {
             pub created: DateTime<Utc>,
             pub throttle_ul: Option<i64>,
             pub throttle_dl: Option<i64>,
-            pub trackers: Vec<String>,
+            pub trackers: Vec<Tracker>,
+            pub super_seed: bool,
+            pub seed_time: u64,
+            pub ratio_limit: Option<f32>,
+            pub seed_time_limit: Option<i64>,
+            pub idle_limit: Option<i64>,
+            pub limit_action: Option<SeedAction>,
+            pub throttle_group: Option<String>,
+        }
+
+        #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+        pub enum SeedAction {
+            Pause,
+            Remove,
+            RemoveData,
+        }
+
+        #[derive(Clone, Debug, Serialize, Deserialize)]
+        pub struct Tracker {
+            pub url: String,
+            pub tier: usize,
         }
 
         #[derive(Clone, Serialize, Deserialize)]
@@ -66,16 +98,33 @@ pub mod torrent {
             pub total_len: u64,
             pub hashes: Vec<Vec<u8>>,
             pub hash: [u8; 20],
+            pub hash_v2: Option<[u8; 32]>,
+            pub hashes_v2: Vec<[u8; 32]>,
             pub files: Vec<File>,
             pub private: bool,
             pub be_name: Option<Vec<u8>>,
             pub piece_idx: Vec<(usize, u64)>,
+            pub web_seeds: Vec<WebSeed>,
+        }
+
+        #[derive(Clone, Debug, Serialize, Deserialize)]
+        pub struct WebSeed {
+            pub url: String,
+            pub kind: WebSeedKind,
+        }
+
+        #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+        pub enum WebSeedKind {
+            Url,
+            Http,
         }
 
         #[derive(Serialize, Deserialize, Clone, Debug)]
         pub struct File {
             pub path: PathBuf,
             pub length: u64,
+            pub pieces_root: Option<[u8; 32]>,
+            pub padding: bool,
         }
 
         #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -96,6 +145,381 @@ pub mod torrent {
         }
     }
 
+    pub mod ver_c82d17 {
+        pub use self::next::{
+            File, Info, SeedAction, Status, StatusState, Tracker, WebSeed, WebSeedKind,
+        };
+        pub use super::ver_e3a5b9 as next;
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<Tracker>,
+            pub super_seed: bool,
+            pub seed_time: u64,
+            pub ratio_limit: Option<f32>,
+            pub seed_time_limit: Option<i64>,
+            pub idle_limit: Option<i64>,
+            pub limit_action: Option<SeedAction>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    super_seed: self.super_seed,
+                    seed_time: self.seed_time,
+                    ratio_limit: self.ratio_limit,
+                    seed_time_limit: self.seed_time_limit,
+                    idle_limit: self.idle_limit,
+                    limit_action: self.limit_action,
+                    throttle_group: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_4a7c3e {
+        pub use self::next::{File, Info, Status, StatusState, Tracker, WebSeed, WebSeedKind};
+        pub use super::ver_c82d17 as next;
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<Tracker>,
+            pub super_seed: bool,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    super_seed: self.super_seed,
+                    seed_time: 0,
+                    ratio_limit: None,
+                    seed_time_limit: None,
+                    idle_limit: None,
+                    limit_action: None,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_9c4f1a {
+        pub use self::next::{File, Info, Status, StatusState, Tracker, WebSeed, WebSeedKind};
+        pub use super::ver_4a7c3e as next;
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<Tracker>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                    super_seed: false,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_d2e07c {
+        pub use self::next::{File, Status, StatusState, Tracker};
+        pub use super::ver_9c4f1a as next;
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<Tracker>,
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        pub struct Info {
+            pub name: String,
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub piece_len: u32,
+            pub total_len: u64,
+            pub hashes: Vec<Vec<u8>>,
+            pub hash: [u8; 20],
+            pub hash_v2: Option<[u8; 32]>,
+            pub hashes_v2: Vec<[u8; 32]>,
+            pub files: Vec<File>,
+            pub private: bool,
+            pub be_name: Option<Vec<u8>>,
+            pub piece_idx: Vec<(usize, u64)>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: next::Info {
+                        name: self.info.name,
+                        announce: self.info.announce,
+                        creator: self.info.creator,
+                        comment: self.info.comment,
+                        piece_len: self.info.piece_len,
+                        total_len: self.info.total_len,
+                        hashes: self.info.hashes,
+                        hash: self.info.hash,
+                        hash_v2: self.info.hash_v2,
+                        hashes_v2: self.info.hashes_v2,
+                        files: self.info.files,
+                        private: self.info.private,
+                        be_name: self.info.be_name,
+                        piece_idx: self.info.piece_idx,
+                        web_seeds: vec![],
+                    },
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_3b52a0 {
+        pub use self::next::{File, Info, Status, StatusState};
+        pub use super::ver_d2e07c as next;
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: self.info,
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    // Tiers weren't recorded, keep the old order as a single tier
+                    trackers: self
+                        .trackers
+                        .into_iter()
+                        .map(|url| next::Tracker { url, tier: 0 })
+                        .collect(),
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_fa1b6f {
+        pub use self::next::{Status, StatusState};
+        pub use super::ver_3b52a0 as next;
+        use super::Bitfield;
+
+        use chrono::{DateTime, Utc};
+
+        use std::path::PathBuf;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub info: Info,
+            pub pieces: Bitfield,
+            pub uploaded: u64,
+            pub downloaded: u64,
+            pub status: Status,
+            pub path: Option<String>,
+            pub priority: u8,
+            pub priorities: Vec<u8>,
+            pub created: DateTime<Utc>,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub trackers: Vec<String>,
+        }
+
+        #[derive(Clone, Serialize, Deserialize)]
+        pub struct Info {
+            pub name: String,
+            pub announce: Option<String>,
+            pub creator: Option<String>,
+            pub comment: Option<String>,
+            pub piece_len: u32,
+            pub total_len: u64,
+            pub hashes: Vec<Vec<u8>>,
+            pub hash: [u8; 20],
+            pub files: Vec<File>,
+            pub private: bool,
+            pub be_name: Option<Vec<u8>>,
+            pub piece_idx: Vec<(usize, u64)>,
+        }
+
+        #[derive(Serialize, Deserialize, Clone, Debug)]
+        pub struct File {
+            pub path: PathBuf,
+            pub length: u64,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    info: next::Info {
+                        name: self.info.name,
+                        announce: self.info.announce,
+                        creator: self.info.creator,
+                        comment: self.info.comment,
+                        piece_len: self.info.piece_len,
+                        total_len: self.info.total_len,
+                        hashes: self.info.hashes,
+                        hash: self.info.hash,
+                        hash_v2: None,
+                        hashes_v2: vec![],
+                        files: self
+                            .info
+                            .files
+                            .into_iter()
+                            .map(|f| next::File {
+                                path: f.path,
+                                length: f.length,
+                                pieces_root: None,
+                                padding: false,
+                            })
+                            .collect(),
+                        private: self.info.private,
+                        be_name: self.info.be_name,
+                        piece_idx: self.info.piece_idx,
+                    },
+                    pieces: self.pieces,
+                    uploaded: self.uploaded,
+                    downloaded: self.downloaded,
+                    status: self.status,
+                    path: self.path,
+                    priority: self.priority,
+                    priorities: self.priorities,
+                    created: self.created,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    trackers: self.trackers,
+                }
+                .migrate()
+            }
+        }
+    }
+
     pub mod ver_6e27af {
         pub use self::next::{File, Status, StatusState};
         pub use super::ver_fa1b6f as next;
@@ -388,3 +812,228 @@ pub mod torrent {
         }
     }
 }
+
+pub mod server {
+    pub use self::current::Session;
+    pub use self::ver_7d3f09 as current;
+
+    pub fn load(data: &[u8]) -> Option<Session> {
+        if let Ok(m) = bincode::deserialize::<ver_7d3f09::Session>(data) {
+            Some(m)
+        } else if let Ok(m) = bincode::deserialize::<ver_52c1ae::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_e80b44::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_1f6a2d::Session>(data) {
+            Some(m.migrate())
+        } else if let Ok(m) = bincode::deserialize::<ver_b9047c::Session>(data) {
+            Some(m.migrate())
+        } else {
+            None
+        }
+    }
+
+    impl Session {
+        pub fn migrate(self) -> Self {
+            self
+        }
+    }
+
+    pub mod ver_7d3f09 {
+        use chrono::Weekday;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub id: String,
+            pub ul: u64,
+            pub dl: u64,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub ratio_limit: Option<f32>,
+            pub seed_time_limit: Option<i64>,
+            pub idle_limit: Option<i64>,
+            pub limit_action: Option<SeedAction>,
+            pub schedule: Option<Vec<RateProfile>>,
+            pub turtle: bool,
+            pub groups: Vec<ThrottleGroup>,
+            pub lan_ul: u64,
+            pub lan_dl: u64,
+        }
+
+        #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+        pub enum SeedAction {
+            Pause,
+            Remove,
+            RemoveData,
+        }
+
+        #[derive(Serialize, Deserialize)]
+        pub struct RateProfile {
+            pub name: String,
+            pub days: Vec<Weekday>,
+            // Local times as HH:MM
+            pub start: String,
+            pub end: String,
+            pub throttle_up: Option<i64>,
+            pub throttle_down: Option<i64>,
+        }
+
+        #[derive(Serialize, Deserialize)]
+        pub struct ThrottleGroup {
+            pub id: String,
+            pub name: String,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+        }
+    }
+
+    pub mod ver_52c1ae {
+        pub use self::next::{RateProfile, SeedAction, ThrottleGroup};
+        pub use super::ver_7d3f09 as next;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub id: String,
+            pub ul: u64,
+            pub dl: u64,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub ratio_limit: Option<f32>,
+            pub seed_time_limit: Option<i64>,
+            pub idle_limit: Option<i64>,
+            pub limit_action: Option<SeedAction>,
+            pub schedule: Option<Vec<RateProfile>>,
+            pub turtle: bool,
+            pub groups: Vec<ThrottleGroup>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    id: self.id,
+                    ul: self.ul,
+                    dl: self.dl,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    ratio_limit: self.ratio_limit,
+                    seed_time_limit: self.seed_time_limit,
+                    idle_limit: self.idle_limit,
+                    limit_action: self.limit_action,
+                    schedule: self.schedule,
+                    turtle: self.turtle,
+                    groups: self.groups,
+                    lan_ul: 0,
+                    lan_dl: 0,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_e80b44 {
+        pub use self::next::{RateProfile, SeedAction};
+        pub use super::ver_52c1ae as next;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub id: String,
+            pub ul: u64,
+            pub dl: u64,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub ratio_limit: Option<f32>,
+            pub seed_time_limit: Option<i64>,
+            pub idle_limit: Option<i64>,
+            pub limit_action: Option<SeedAction>,
+            pub schedule: Option<Vec<RateProfile>>,
+            pub turtle: bool,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    id: self.id,
+                    ul: self.ul,
+                    dl: self.dl,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    ratio_limit: self.ratio_limit,
+                    seed_time_limit: self.seed_time_limit,
+                    idle_limit: self.idle_limit,
+                    limit_action: self.limit_action,
+                    schedule: self.schedule,
+                    turtle: self.turtle,
+                    groups: Vec::new(),
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_1f6a2d {
+        pub use self::next::SeedAction;
+        pub use super::ver_e80b44 as next;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub id: String,
+            pub ul: u64,
+            pub dl: u64,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+            pub ratio_limit: Option<f32>,
+            pub seed_time_limit: Option<i64>,
+            pub idle_limit: Option<i64>,
+            pub limit_action: Option<SeedAction>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    id: self.id,
+                    ul: self.ul,
+                    dl: self.dl,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    ratio_limit: self.ratio_limit,
+                    seed_time_limit: self.seed_time_limit,
+                    idle_limit: self.idle_limit,
+                    limit_action: self.limit_action,
+                    schedule: None,
+                    turtle: false,
+                }
+                .migrate()
+            }
+        }
+    }
+
+    pub mod ver_b9047c {
+        pub use super::ver_1f6a2d as next;
+
+        #[derive(Serialize, Deserialize)]
+        pub struct Session {
+            pub id: String,
+            pub ul: u64,
+            pub dl: u64,
+            pub throttle_ul: Option<i64>,
+            pub throttle_dl: Option<i64>,
+        }
+
+        impl Session {
+            pub fn migrate(self) -> super::current::Session {
+                next::Session {
+                    id: self.id,
+                    ul: self.ul,
+                    dl: self.dl,
+                    throttle_ul: self.throttle_ul,
+                    throttle_dl: self.throttle_dl,
+                    ratio_limit: None,
+                    seed_time_limit: None,
+                    idle_limit: None,
+                    limit_action: None,
+                }
+                .migrate()
+            }
+        }
+    }
+}
@@ -1,8 +1,10 @@
 use std::io::Read;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::{fs, process};
 
 use crate::args;
+use crate::rpc::resource::RateProfile;
+use crate::util::filter::{self, IpFilter};
 
 error_chain! {
     errors {
@@ -27,18 +29,24 @@ error_chain! {
 pub struct Config {
     pub port: u16,
     pub max_dl: u32,
+    pub max_ul: u32,
     pub trk: TrkConfig,
     pub dht: DhtConfig,
     pub rpc: RpcConfig,
     pub disk: DiskConfig,
     pub net: NetConfig,
     pub peer: PeerConfig,
+    pub schedule: ScheduleConfig,
+    /// Subnets of net.lan whose peers use the LAN rates
+    pub lan: IpFilter,
 }
 
 #[derive(Debug, Clone)]
 pub struct DhtConfig {
     pub port: u16,
     pub bootstrap_node: Option<SocketAddr>,
+    pub port6: u16,
+    pub bootstrap_node6: Option<SocketAddr>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -47,6 +55,9 @@ pub struct ConfigFile {
     pub port: u16,
     #[serde(default = "default_max_dl")]
     pub max_dl: u32,
+    /// Maximum number of seeding torrents, 0 for no limit
+    #[serde(default)]
+    pub max_ul: u32,
     #[serde(default)]
     pub rpc: RpcConfig,
     #[serde(default)]
@@ -59,6 +70,8 @@ pub struct ConfigFile {
     pub net: NetConfig,
     #[serde(default)]
     pub peer: PeerConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -81,6 +94,15 @@ pub struct RpcConfig {
 pub struct TrkConfig {
     #[serde(default = "default_trk_port")]
     pub port: u16,
+    /// Interval in seconds between tracker scrapes, 0 disables scraping
+    #[serde(default = "default_scrape_interval")]
+    pub scrape_interval: u64,
+    /// Announce to a tracker in every tier rather than only the first working tier
+    #[serde(default)]
+    pub announce_all_tiers: bool,
+    /// Announce non-private torrents on the local network, as per BEP 14
+    #[serde(default)]
+    pub lsd: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -89,6 +111,10 @@ pub struct DhtConfigFile {
     pub port: u16,
     #[serde(default = "default_bootstrap_node")]
     pub bootstrap_node: Option<String>,
+    #[serde(default = "default_dht_port6")]
+    pub port6: u16,
+    #[serde(default = "default_bootstrap_node")]
+    pub bootstrap_node6: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -99,6 +125,10 @@ pub struct DiskConfig {
     pub directory: String,
     #[serde(default = "default_validate")]
     pub validate: bool,
+    /// MiB of memory used to collect downloaded blocks into whole pieces
+    /// before writing them, or 0 to write blocks as they arrive
+    #[serde(default = "default_write_cache")]
+    pub write_cache: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -109,12 +139,70 @@ pub struct NetConfig {
     pub max_open_sockets: usize,
     #[serde(default = "default_max_announces")]
     pub max_open_announces: usize,
+    /// Map the peer, DHT and tracker ports on the gateway with PCP, NAT-PMP or UPnP
+    #[serde(default)]
+    pub port_mapping: bool,
+    /// Gateway used for PCP and NAT-PMP, found from the default route if unset
+    #[serde(default)]
+    pub gateway: Option<Ipv4Addr>,
+    /// eMule DAT, PeerGuardian P2P or CIDR list of peer addresses to block
+    #[serde(default)]
+    pub blocklist: Option<String>,
+    /// Local subnets, whose peers bypass the global and torrent rates
+    #[serde(default = "default_lan")]
+    pub lan: Vec<String>,
+    /// Rates shared by all peers on the local network
+    #[serde(default)]
+    pub lan_throttle_up: Option<i64>,
+    #[serde(default)]
+    pub lan_throttle_down: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerConfig {
     #[serde(default = "default_prune_timeout")]
     pub prune_timeout: u64,
+    #[serde(default = "default_encryption")]
+    pub encryption: EncryptionPolicy,
+    #[serde(default = "default_utp")]
+    pub utp: bool,
+    #[serde(default = "default_transport")]
+    pub transport: Transport,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleConfig {
+    /// Global rates used while turtle mode is on
+    #[serde(default = "default_turtle_rate")]
+    pub turtle_up: Option<i64>,
+    #[serde(default = "default_turtle_rate")]
+    pub turtle_down: Option<i64>,
+    /// Weekly profiles of global rates, the first matching profile is used
+    #[serde(default)]
+    pub profiles: Vec<RateProfile>,
+}
+
+/// Policy used for Message Stream Encryption of peer connections.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionPolicy {
+    /// Only plaintext connections are used
+    Disabled,
+    /// Outgoing connections are encrypted, falling back to plaintext
+    /// if the peer does not support it. Incoming plaintext is accepted.
+    Prefer,
+    /// Only encrypted connections are used
+    Require,
+}
+
+/// Transport used for outgoing peer connections.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    /// uTP is used when enabled, falling back to TCP if the
+    /// peer does not respond
+    Utp,
 }
 
 impl ConfigFile {
@@ -141,6 +229,10 @@ impl ConfigFile {
                         error!("Config max_dl must not be 0");
                         process::exit(1);
                     }
+                    if let Some(net) = cfg.net.lan.iter().find(|n| filter::parse_net(n).is_none()) {
+                        error!("Config net.lan contains invalid subnet {}", net);
+                        process::exit(1);
+                    }
                     if !cfg!(debug_assertions) && !cfg.disk.validate {
                         error!("validation skipping can only be used in development, overriding!");
                         cfg.disk.validate = true;
@@ -176,25 +268,33 @@ impl Config {
     }
 
     pub fn from_file(mut file: ConfigFile) -> Config {
-        let addr = file
-            .dht
-            .bootstrap_node
-            .and_then(|n| n.to_socket_addrs().ok())
-            .and_then(|mut a| a.next());
+        let resolve = |node: Option<String>, v6: bool| {
+            node.and_then(|n| n.to_socket_addrs().ok())
+                .and_then(|mut a| a.find(|a| a.is_ipv6() == v6))
+        };
         let dht = DhtConfig {
             port: file.dht.port,
-            bootstrap_node: addr,
+            bootstrap_node: resolve(file.dht.bootstrap_node, false),
+            port6: file.dht.port6,
+            bootstrap_node6: resolve(file.dht.bootstrap_node6, true),
         };
         file.disk.session = shellexpand::tilde(&file.disk.session).into();
         file.disk.directory = shellexpand::tilde(&file.disk.directory).into();
+        file.net.blocklist = file
+            .net
+            .blocklist
+            .map(|p| shellexpand::tilde(&p).into_owned());
         Config {
             port: file.port,
             max_dl: file.max_dl,
+            max_ul: file.max_ul,
+            lan: IpFilter::from_nets(&file.net.lan),
             trk: file.tracker,
             rpc: file.rpc,
             disk: file.disk,
             net: file.net,
             peer: file.peer,
+            schedule: file.schedule,
             dht,
         }
     }
@@ -209,9 +309,15 @@ fn default_max_dl() -> u32 {
 fn default_trk_port() -> u16 {
     16_362
 }
+fn default_scrape_interval() -> u64 {
+    1_800
+}
 fn default_dht_port() -> u16 {
     16_309
 }
+fn default_dht_port6() -> u16 {
+    16_310
+}
 fn default_rpc_port() -> u16 {
     8_412
 }
@@ -241,6 +347,9 @@ fn default_directory_dir() -> String {
 fn default_validate() -> bool {
     true
 }
+fn default_write_cache() -> usize {
+    32
+}
 fn default_max_files() -> usize {
     500
 }
@@ -250,21 +359,59 @@ fn default_max_sockets() -> usize {
 fn default_max_announces() -> usize {
     50
 }
+fn default_lan() -> Vec<String> {
+    [
+        "10.0.0.0/8",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "169.254.0.0/16",
+        "fe80::/10",
+        "fc00::/7",
+    ]
+    .iter()
+    .map(|n| n.to_string())
+    .collect()
+}
 fn default_prune_timeout() -> u64 {
     15
 }
+fn default_encryption() -> EncryptionPolicy {
+    EncryptionPolicy::Prefer
+}
+fn default_utp() -> bool {
+    true
+}
+fn default_transport() -> Transport {
+    Transport::Tcp
+}
+fn default_turtle_rate() -> Option<i64> {
+    Some(102_400)
+}
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             port: default_port(),
             max_dl: default_max_dl(),
+            max_ul: 0,
             trk: Default::default(),
             rpc: Default::default(),
             disk: Default::default(),
             net: Default::default(),
             dht: Default::default(),
             peer: Default::default(),
+            schedule: Default::default(),
+            lan: IpFilter::from_nets(&default_lan()),
+        }
+    }
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> ScheduleConfig {
+        ScheduleConfig {
+            turtle_up: default_turtle_rate(),
+            turtle_down: default_turtle_rate(),
+            profiles: vec![],
         }
     }
 }
@@ -286,6 +433,9 @@ impl Default for TrkConfig {
     fn default() -> TrkConfig {
         TrkConfig {
             port: default_trk_port(),
+            scrape_interval: default_scrape_interval(),
+            announce_all_tiers: false,
+            lsd: false,
         }
     }
 }
@@ -295,6 +445,8 @@ impl Default for DhtConfigFile {
         DhtConfigFile {
             port: default_dht_port(),
             bootstrap_node: default_bootstrap_node(),
+            port6: default_dht_port6(),
+            bootstrap_node6: default_bootstrap_node(),
         }
     }
 }
@@ -304,6 +456,8 @@ impl Default for DhtConfig {
         DhtConfig {
             port: default_dht_port(),
             bootstrap_node: None,
+            port6: default_dht_port6(),
+            bootstrap_node6: None,
         }
     }
 }
@@ -314,6 +468,7 @@ impl Default for DiskConfig {
             session: default_session_dir(),
             directory: default_directory_dir(),
             validate: default_validate(),
+            write_cache: default_write_cache(),
         }
     }
 }
@@ -324,6 +479,12 @@ impl Default for NetConfig {
             max_open_files: default_max_files(),
             max_open_sockets: default_max_sockets(),
             max_open_announces: default_max_announces(),
+            port_mapping: false,
+            gateway: None,
+            blocklist: None,
+            lan: default_lan(),
+            lan_throttle_up: None,
+            lan_throttle_down: None,
         }
     }
 }
@@ -332,6 +493,9 @@ impl Default for PeerConfig {
     fn default() -> PeerConfig {
         PeerConfig {
             prune_timeout: default_prune_timeout(),
+            encryption: default_encryption(),
+            utp: default_utp(),
+            transport: default_transport(),
         }
     }
 }
@@ -6,6 +6,8 @@ use std::{io, time};
 use amy::{self, ChannelError};
 
 use crate::control::cio::{self, Error, ErrorKind, Result, ResultExt};
+use crate::socket::utp::{self, Utp};
+use crate::socket::Socket;
 use crate::torrent::peer::reader::RRes;
 use crate::util::UHashMap;
 use crate::CONFIG;
@@ -13,6 +15,7 @@ use crate::{disk, rpc, torrent, tracker};
 
 const POLL_INT_MS: usize = 1000;
 const PRUNE_GOAL: usize = 50;
+const UTP_TICK_MS: usize = 100;
 
 /// Amy based CIO implementation. Currently the default one used.
 pub struct ACIO {
@@ -39,6 +42,10 @@ struct ACIOData {
     crashed: bool,
     listener: TcpListener,
     lid: usize,
+    utp: Option<Utp>,
+    /// Poller IDs of the uTP socket and its tick timer
+    uid: Option<usize>,
+    utid: Option<usize>,
 }
 
 impl ACIO {
@@ -49,12 +56,24 @@ impl ACIO {
         listener.set_nonblocking(true)?;
         let lid = reg.register(&listener, amy::Event::Both)?;
 
+        let (utp, uid, utid) = if CONFIG.peer.utp {
+            let utp = Utp::new(port)?;
+            let uid = reg.register(&utp, amy::Event::Read)?;
+            let utid = reg.set_interval(UTP_TICK_MS)?;
+            (Some(utp), Some(uid), Some(utid))
+        } else {
+            (None, None, None)
+        };
+
         let data = ACIOData {
             poll,
             reg,
             chans,
             listener,
             lid,
+            utp,
+            uid,
+            utid,
             peers: UHashMap::default(),
             events: Vec::new(),
             crashed: false,
@@ -95,10 +114,10 @@ impl ACIO {
                 match d.listener.accept() {
                     Ok((conn, ip)) => {
                         debug!("Accepted new connection from {:?}!", ip);
-                        if conn.set_nonblocking(true).is_err() {
-                            continue;
+                        match Socket::from_stream(conn) {
+                            Ok(sock) => events.push(cio::Event::Incoming(Box::new(sock))),
+                            Err(e) => error!("Failed to set up connection: {}", e),
                         }
-                        events.push(cio::Event::Incoming(conn));
                     }
                     Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                         break;
@@ -108,6 +127,37 @@ impl ACIO {
                     }
                 }
             }
+        } else if d.uid == Some(id) || d.utid == Some(id) {
+            let d = &mut *d;
+            let mut uevs = Vec::new();
+            if let Some(ref mut utp) = d.utp {
+                if d.uid == Some(id) {
+                    utp.readable(&mut uevs);
+                } else {
+                    utp.tick(&mut uevs);
+                }
+            }
+            for ev in uevs {
+                match ev {
+                    utp::Event::Incoming(s) => {
+                        debug!("Accepted new uTP connection from {:?}!", s.addr());
+                        events.push(cio::Event::Incoming(Box::new(Socket::from_utp(s))));
+                    }
+                    utp::Event::Ready(pid) => {
+                        let not = amy::Notification {
+                            id: pid,
+                            event: amy::Event::Both,
+                        };
+                        if let Err(e) = self.process_peer_ev(not, events, &mut d.peers) {
+                            d.remove_peer(pid);
+                            events.push(cio::Event::Peer {
+                                peer: pid,
+                                event: Err(e),
+                            });
+                        }
+                    }
+                }
+            }
         } else {
             // Timer event
             events.push(cio::Event::Timer(id));
@@ -156,19 +206,33 @@ impl ACIO {
                             break;
                         }
                         RRes::Err(e) => {
-                            return Err(Error::with_chain(e, ErrorKind::IO));
+                            return Err(peer_err(peer, e));
                         }
                     }
                 }
             }
             if ev.writable() {
-                peer.writable().chain_err(|| ErrorKind::IO)?;
+                if let Err(e) = peer.writable() {
+                    return Err(peer_err(peer, e));
+                }
             }
         }
         Ok(())
     }
 }
 
+/// Converts an IO error on a peer, marking connections which failed during
+/// an encrypted handshake or uTP connection so they can be retried.
+fn peer_err(peer: &torrent::PeerConn, e: io::Error) -> Error {
+    if peer.sock().tcp_fallback() {
+        Error::with_chain(e, ErrorKind::UtpFailed(peer.sock().addr()))
+    } else if peer.sock().plaintext_fallback() {
+        Error::with_chain(e, ErrorKind::EncryptionFailed(peer.sock().addr()))
+    } else {
+        Error::with_chain(e, ErrorKind::IO)
+    }
+}
+
 impl cio::CIO for ACIO {
     fn poll(&mut self, events: &mut Vec<cio::Event>) -> Result<()> {
         {
@@ -225,16 +289,26 @@ impl cio::CIO for ACIO {
                 self.remove_peer(id);
             }
         }
-        let id = self
-            .data
-            .borrow_mut()
-            .reg
-            .register(peer.sock(), amy::Event::Both)
-            .chain_err(|| ErrorKind::IO)?;
+        let id = {
+            let mut d = self.data.borrow_mut();
+            match (peer.sock().tcp(), peer.sock().utp(), d.utp.as_mut()) {
+                (Some(s), _, _) => d
+                    .reg
+                    .register(s, amy::Event::Both)
+                    .chain_err(|| ErrorKind::IO)?,
+                (None, Some(s), Some(utp)) => utp.attach(s),
+                (None, _, _) => bail!(ErrorKind::IO),
+            }
+        };
         if let Some(t) = peer.sock_mut().throttle.as_mut() {
             t.id = id
         }
+        let utp = peer.sock().utp().is_some();
         self.data.borrow_mut().peers.insert(id, peer);
+        // uTP streams may have buffered data which will not be signaled again
+        if utp {
+            self.flush_peers(vec![id]);
+        }
         Ok(id)
     }
 
@@ -339,8 +413,11 @@ impl cio::CIO for ACIO {
 impl ACIOData {
     fn remove_peer(&mut self, pid: cio::PID) {
         if let Some(p) = self.peers.remove(&pid) {
-            if let Err(e) = self.reg.deregister(p.sock()) {
-                error!("Failed to deregister sock: {:?}", e);
+            // uTP streams are closed once dropped
+            if let Some(s) = p.sock().tcp() {
+                if let Err(e) = self.reg.deregister(s) {
+                    error!("Failed to deregister sock: {:?}", e);
+                }
             }
             self.events.push(cio::Event::Peer {
                 peer: pid,
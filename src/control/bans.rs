@@ -0,0 +1,172 @@
+//! Peer bans, which cover an address or CIDR range either globally or
+//! for a single torrent, and may expire.
+
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+
+use crate::rpc::resource;
+use crate::util::filter;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Ban {
+    /// The address or range as given
+    target: String,
+    start: IpAddr,
+    end: IpAddr,
+    /// Infohash of the torrent the ban is limited to
+    torrent: Option<[u8; 20]>,
+    expires: Option<DateTime<Utc>>,
+    reason: String,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Bans {
+    bans: Vec<Ban>,
+}
+
+impl Ban {
+    /// Creates a ban, returning None if the target is not an address or range.
+    pub fn new(
+        target: &str,
+        torrent: Option<[u8; 20]>,
+        expires: Option<DateTime<Utc>>,
+        reason: String,
+    ) -> Option<Ban> {
+        let (start, end) = filter::parse_net(target)?;
+        Some(Ban {
+            target: target.trim().to_owned(),
+            start,
+            end,
+            torrent,
+            expires,
+            reason,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => filter::ipv4_mapped(v6).map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        ip.is_ipv4() == self.start.is_ipv4() && self.start <= ip && ip <= self.end
+    }
+
+    fn expired(&self) -> bool {
+        self.expires.map(|e| e <= Utc::now()).unwrap_or(false)
+    }
+
+    pub fn rpc_info(&self) -> resource::Ban {
+        resource::Ban {
+            ip: self.target.clone(),
+            reason: self.reason.clone(),
+            expires: self.expires,
+        }
+    }
+}
+
+impl Bans {
+    pub fn new() -> Bans {
+        Bans::default()
+    }
+
+    /// Adds a ban, replacing any existing ban of the same target.
+    pub fn add(&mut self, ban: Ban) {
+        self.remove(&ban.target, ban.torrent);
+        self.bans.push(ban);
+    }
+
+    /// Removes a ban, returning whether it existed.
+    pub fn remove(&mut self, target: &str, torrent: Option<[u8; 20]>) -> bool {
+        let len = self.bans.len();
+        let target = target.trim();
+        self.bans
+            .retain(|b| !(b.target == target && b.torrent == torrent));
+        self.bans.len() != len
+    }
+
+    /// Removes every ban limited to a torrent, returning whether any existed.
+    pub fn remove_torrent(&mut self, torrent: &[u8; 20]) -> bool {
+        let len = self.bans.len();
+        self.bans.retain(|b| b.torrent.as_ref() != Some(torrent));
+        self.bans.len() != len
+    }
+
+    /// Whether an address is banned globally, or from the given torrent.
+    pub fn banned(&self, ip: IpAddr, torrent: Option<&[u8; 20]>) -> bool {
+        self.bans.iter().any(|b| {
+            (b.torrent.is_none() || b.torrent.as_ref() == torrent) && !b.expired() && b.contains(ip)
+        })
+    }
+
+    /// Removes expired bans, returning the scopes of those removed.
+    pub fn expire(&mut self) -> Vec<Option<[u8; 20]>> {
+        let mut scopes = Vec::new();
+        for ban in self.bans.iter().filter(|b| b.expired()) {
+            if !scopes.contains(&ban.torrent) {
+                scopes.push(ban.torrent);
+            }
+        }
+        self.bans.retain(|b| !b.expired());
+        scopes
+    }
+
+    /// Bans in a scope, either global or of a single torrent.
+    pub fn scope<'a>(&'a self, torrent: Option<[u8; 20]>) -> impl Iterator<Item = &'a Ban> + 'a {
+        self.bans.iter().filter(move |b| b.torrent == torrent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ban, Bans};
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn test_bans() {
+        let mut bans = Bans::new();
+        let t = [1; 20];
+        bans.add(Ban::new("10.0.0.0/8", None, None, String::new()).unwrap());
+        bans.add(Ban::new("192.168.1.1", Some(t), None, String::new()).unwrap());
+        let expired = Utc::now() - Duration::seconds(1);
+        bans.add(Ban::new("172.16.0.1", None, Some(expired), String::new()).unwrap());
+        assert!(Ban::new("10.0.0.0/33", None, None, String::new()).is_none());
+
+        assert!(bans.banned("10.1.2.3".parse().unwrap(), None));
+        assert!(bans.banned("::ffff:10.1.2.3".parse().unwrap(), Some(&t)));
+        assert!(!bans.banned("192.168.1.1".parse().unwrap(), None));
+        assert!(!bans.banned("192.168.1.1".parse().unwrap(), Some(&[2; 20])));
+        assert!(bans.banned("192.168.1.1".parse().unwrap(), Some(&t)));
+        assert!(!bans.banned("172.16.0.1".parse().unwrap(), None));
+
+        assert_eq!(bans.expire(), vec![None]);
+        assert_eq!(bans.scope(None).count(), 1);
+        assert!(!bans.remove("192.168.1.1", None));
+        assert!(bans.remove("192.168.1.1", Some(t)));
+        assert!(!bans.banned("192.168.1.1".parse().unwrap(), Some(&t)));
+
+        bans.add(Ban::new("192.168.1.2", Some(t), None, String::new()).unwrap());
+        assert!(!bans.remove_torrent(&[2; 20]));
+        assert!(bans.remove_torrent(&t));
+        assert_eq!(bans.scope(Some(t)).count(), 0);
+        assert_eq!(bans.scope(None).count(), 1);
+    }
+
+    #[test]
+    fn test_serialize() {
+        let mut bans = Bans::new();
+        let t = [1; 20];
+        let expires = Utc::now() + Duration::hours(1);
+        bans.add(Ban::new("10.0.0.0/8", None, Some(expires), "a".to_owned()).unwrap());
+        bans.add(Ban::new("2001:db8::/32", Some(t), None, "b".to_owned()).unwrap());
+
+        let data = bincode::serialize(&bans).unwrap();
+        let loaded: Bans = bincode::deserialize(&data).unwrap();
+        let info = |b: &Bans, scope| b.scope(scope).map(Ban::rpc_info).collect::<Vec<_>>();
+        assert_eq!(info(&loaded, None), info(&bans, None));
+        assert_eq!(info(&loaded, Some(t)), info(&bans, Some(t)));
+        assert!(loaded.banned("10.1.2.3".parse().unwrap(), None));
+        assert!(loaded.banned("2001:db8::1".parse().unwrap(), Some(&t)));
+        assert!(!loaded.banned("2001:db8::1".parse().unwrap(), None));
+    }
+}
@@ -1,5 +1,6 @@
+use crate::socket::Socket;
 use crate::{disk, rpc, torrent, tracker};
-use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr};
 
 error_chain! {
     errors {
@@ -17,6 +18,16 @@ error_chain! {
             description("removal requested")
                 display("removal requested")
         }
+
+        EncryptionFailed(addr: SocketAddr) {
+            description("encrypted handshake failed")
+                display("encrypted handshake with {} failed", addr)
+        }
+
+        UtpFailed(addr: SocketAddr) {
+            description("uTP connection failed")
+                display("uTP connection to {} failed", addr)
+        }
     }
 }
 
@@ -32,7 +43,13 @@ pub enum Event {
     RPC(Result<rpc::Message>),
     Tracker(Result<tracker::Response>),
     Disk(Result<disk::Response>),
-    Incoming(TcpStream),
+    Incoming(Box<Socket>),
+    /// A peer was banned from a torrent for sending corrupt data
+    Ban {
+        tid: usize,
+        ip: IpAddr,
+        reason: String,
+    },
 }
 
 /// Control IO trait used as an abstraction boundary between
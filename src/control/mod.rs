@@ -1,22 +1,28 @@
 use std::io::Read;
-use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::atomic;
+use std::sync::Arc;
 use std::{fs, io, mem, process, time};
 
-use chrono::Utc;
+use chrono::{DateTime, Local, NaiveTime, Utc};
+use url::Url;
 
-use crate::throttle::Throttler;
-use crate::torrent::{self, peer, Torrent};
+use crate::socket::{mse, Socket};
+use crate::throttle::{ThrottleGroup, Throttler};
+use crate::torrent::{self, peer, SeedLimits, Torrent};
+use crate::util::filter::IpFilter;
 use crate::util::{
-    self, hash_to_id, id_to_hash, io_err, io_err_val, random_string, FHashSet, MHashMap, UHashMap,
-    UHashSet,
+    self, hash_to_id, id_to_hash, io_err, io_err_val, random_string, FHashMap, FHashSet, MHashMap,
+    UHashMap, UHashSet,
 };
-use crate::{disk, rpc, stat, tracker, CONFIG, DL_TOKEN, SHUTDOWN};
+use crate::{disk, rpc, session, stat, tracker, CONFIG, DL_TOKEN, SHUTDOWN};
 
 pub mod acio;
+mod bans;
 pub mod cio;
 mod job;
+mod schedule;
 
 /// Tracker update job interval
 const TRK_JOB_SECS: u64 = 60;
@@ -32,6 +38,15 @@ const SPACE_JOB_SECS: u64 = 10;
 const PEX_JOB_SECS: u64 = 60 * 5;
 /// Interval to enqueue new torrents
 const ENQUEUE_JOB_SECS: u64 = 5;
+/// Interval to remove expired bans
+const BAN_JOB_SECS: u64 = 60;
+/// Interval to check torrents against seeding limits
+const SEED_JOB_SECS: u64 = 10;
+/// Interval to switch global rates by the schedule
+const SCHEDULE_JOB_SECS: u64 = 15;
+
+/// Number of torrents a peer must be banned from before it's banned globally
+const GLOBAL_BAN_OFFENCES: u32 = 2;
 
 /// Interval to requery all jobs and execute if needed
 const JOB_INT_MS: usize = 500;
@@ -42,34 +57,72 @@ pub struct Control<T: cio::CIO> {
     tid_cnt: usize,
     job_timer: usize,
     stat: stat::EMA,
+    /// Transfer rates with peers on the local network
+    lan_stat: stat::EMA,
     jobs: JobManager<T>,
     torrents: UHashMap<Torrent<T>>,
     queue: Queue,
     peers: UHashMap<usize>,
     incoming: UHashSet,
     hash_idx: MHashMap<[u8; 20], usize>,
+    mse_keys: mse::Keys,
+    filter: IpFilter,
+    bans: bans::Bans,
+    /// Throttle groups by RPC ID
+    groups: FHashMap<String, ThrottleGroup>,
     data: ServerData,
     db: amy::Sender<disk::Request>,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Default)]
 struct ServerData {
     id: String,
     ul: u64,
     dl: u64,
-    #[serde(skip)]
     session_ul: u64,
-    #[serde(skip)]
     session_dl: u64,
-    #[serde(skip)]
     free_space: u64,
+    port_mapping: Option<tracker::PortMapStatus>,
+    /// Number of torrents each peer has been banned from
+    offences: FHashMap<IpAddr, u32>,
+    /// Connections refused by the blocklist this session
+    blocked_incoming: u64,
+    blocked_outgoing: u64,
+    /// Whether the blocklist counters changed since the last RPC update
+    blocked_changed: bool,
+    /// Name of the rate profile currently in use
+    rate_profile: Option<String>,
+    throttle_ul: Option<i64>,
+    throttle_dl: Option<i64>,
+    limits: SeedLimits,
+    /// Rate profiles set over RPC, replacing those of the config
+    schedule: Option<Vec<rpc::resource::RateProfile>>,
+    turtle: bool,
+    groups: Vec<GroupData>,
+    /// Bytes transferred with peers on the local network, included in ul and dl
+    lan_ul: u64,
+    lan_dl: u64,
+}
+
+struct GroupData {
+    id: String,
+    name: String,
     throttle_ul: Option<i64>,
     throttle_dl: Option<i64>,
 }
 
+/// Queues of downloading and seeding torrents, limiting how many
+/// of each are active at once.
 struct Queue {
-    active_dl: FHashSet<usize>,
-    inactive_dl: [FHashSet<usize>; 6],
+    dl: Slots,
+    ul: Slots,
+}
+
+struct Slots {
+    max: usize,
+    active: FHashSet<usize>,
+    /// Inactive torrents indexed by priority
+    inactive: [FHashSet<usize>; 6],
 }
 
 pub trait CJob<T: cio::CIO> {
@@ -117,6 +170,15 @@ impl<T: cio::CIO> Control<T> {
         jobs.add_cjob(SpaceUpdate, time::Duration::from_secs(SPACE_JOB_SECS));
         jobs.add_cjob(EnqueueUpdate, time::Duration::from_secs(ENQUEUE_JOB_SECS));
         jobs.add_cjob(SerializeUpdate, time::Duration::from_secs(SES_JOB_SECS));
+        jobs.add_cjob(BanUpdate, time::Duration::from_secs(BAN_JOB_SECS));
+        jobs.add_cjob(SeedUpdate, time::Duration::from_secs(SEED_JOB_SECS));
+        jobs.add_cjob(ScheduleUpdate, time::Duration::from_secs(SCHEDULE_JOB_SECS));
+        if CONFIG.trk.scrape_interval > 0 {
+            jobs.add_cjob(
+                ScrapeUpdate,
+                time::Duration::from_secs(CONFIG.trk.scrape_interval),
+            );
+        }
         let job_timer = cio
             .set_timer(JOB_INT_MS)
             .map_err(|_| io_err_val("timer failure!"))?;
@@ -130,7 +192,12 @@ impl<T: cio::CIO> Control<T> {
             peers,
             incoming,
             hash_idx,
+            mse_keys: mse::Keys::new(),
+            filter: IpFilter::new(),
+            bans: bans::Bans::new(),
+            groups: FHashMap::default(),
             stat: stat::EMA::new(),
+            lan_stat: stat::EMA::new(),
             data: Default::default(),
             db,
             queue: Queue::new(),
@@ -141,6 +208,10 @@ impl<T: cio::CIO> Control<T> {
         if self.deserialize().is_err() {
             error!("Session deserialization failed!");
         }
+        if let Err(e) = self.load_blocklist() {
+            error!("Failed to load blocklist: {}", e);
+        }
+        self.update_rates();
         debug!("Initialized!");
         self.send_rpc_info();
         let mut events = Vec::with_capacity(20);
@@ -166,7 +237,7 @@ impl<T: cio::CIO> Control<T> {
         debug!("Serializing server data!");
         let mut path = PathBuf::from(sd);
         path.push("syn_data");
-        match bincode::serialize(&self.data) {
+        match bincode::serialize(&self.data.session()) {
             Ok(data) => {
                 self.db.send(disk::Request::WriteFile { path, data }).ok();
             }
@@ -174,26 +245,51 @@ impl<T: cio::CIO> Control<T> {
                 error!("Failed to serialize server data");
             }
         }
+        self.serialize_bans();
         debug!("Serializing torrents!");
         for torrent in self.torrents.values_mut() {
             torrent.serialize();
         }
     }
 
+    fn serialize_bans(&mut self) {
+        let mut path = PathBuf::from(&CONFIG.disk.session);
+        path.push("ban_data");
+        match bincode::serialize(&self.bans) {
+            Ok(data) => {
+                self.db.send(disk::Request::WriteFile { path, data }).ok();
+            }
+            Err(_) => {
+                error!("Failed to serialize bans");
+            }
+        }
+    }
+
     fn deserialize(&mut self) -> io::Result<()> {
         let sd = &CONFIG.disk.session;
         debug!("Deserializing server data!");
         let mut pb = PathBuf::from(sd);
         pb.push("syn_data");
-        if let Ok(Ok(data)) = fs::File::open(pb).map(|mut f| bincode::deserialize_from(&mut f)) {
+        if let Some(data) = fs::read(pb).ok().and_then(|d| ServerData::load(&d)) {
             self.data = data;
             self.throttler.set_ul_rate(self.data.throttle_ul);
             self.throttler.set_dl_rate(self.data.throttle_dl);
+            for g in &self.data.groups {
+                let group = self.throttler.new_group(g.throttle_dl, g.throttle_ul);
+                self.groups.insert(g.id.clone(), group);
+            }
         } else {
             error!("No server data found, regenerating!");
             self.data = ServerData::new();
         }
 
+        debug!("Deserializing bans!");
+        let mut pb = PathBuf::from(sd);
+        pb.push("ban_data");
+        if let Ok(Ok(bans)) = fs::File::open(pb).map(|mut f| bincode::deserialize_from(&mut f)) {
+            self.bans = bans;
+        }
+
         debug!("Deserializing torrents!");
         for entry in fs::read_dir(sd)? {
             if self.deserialize_torrent(entry).is_err() {
@@ -204,6 +300,12 @@ impl<T: cio::CIO> Control<T> {
                 process::exit(1);
             }
         }
+        let hashes: Vec<_> = self.hash_idx.keys().cloned().collect();
+        for hash in hashes {
+            if self.bans.scope(Some(hash)).next().is_some() {
+                self.update_bans(Some(hash));
+            }
+        }
         Ok(())
     }
 
@@ -222,12 +324,15 @@ impl<T: cio::CIO> Control<T> {
 
         let tid = self.tid_cnt;
         let throttle = self.throttler.get_throttle(tid);
-        if let Some(t) = Torrent::deserialize(tid, &data, throttle, self.cio.new_handle()) {
+        if let Some(mut t) = Torrent::deserialize(tid, &data, throttle, self.cio.new_handle()) {
             trace!("Succesfully parsed torrent file {:?}", dir.path());
+            let group = t.throttle_group().and_then(|g| self.groups.get(g)).cloned();
+            t.restore_throttle_group(group.as_ref());
             self.hash_idx.insert(t.info().hash, tid);
+            self.mse_keys.insert(t.info().hash);
             self.tid_cnt += 1;
             if t.status().leeching() {
-                self.queue.add(tid, t.priority());
+                self.queue.dl.add(tid, t.priority());
             }
             self.torrents.insert(tid, t);
         } else {
@@ -261,17 +366,23 @@ impl<T: cio::CIO> Control<T> {
                 trace!("rpc error: {:?}", e.backtrace());
             }
             cio::Event::Incoming(conn) => {
-                self.handle_incoming_conn(conn);
+                self.handle_incoming_conn(*conn);
             }
             cio::Event::Timer(t) => {
                 if t == self.throttler.id() {
+                    let (lan_ul, lan_dl) = self.throttler.update_lan();
                     let (ul, dl) = self.throttler.update();
+                    let (ul, dl) = (ul + lan_ul, dl + lan_dl);
                     self.data.ul += ul;
                     self.data.dl += dl;
                     self.data.session_ul += ul;
                     self.data.session_dl += dl;
+                    self.data.lan_ul += lan_ul;
+                    self.data.lan_dl += lan_dl;
                     self.stat.add_ul(ul);
                     self.stat.add_dl(dl);
+                    self.lan_stat.add_ul(lan_ul);
+                    self.lan_stat.add_dl(lan_dl);
                 } else if t == self.throttler.fid() {
                     self.flush_blocked_peers();
                 } else if t == self.job_timer {
@@ -284,6 +395,9 @@ impl<T: cio::CIO> Control<T> {
             cio::Event::Peer { peer, event } => {
                 self.handle_peer_ev(peer, event);
             }
+            cio::Event::Ban { tid, ip, reason } => {
+                self.handle_ban(tid, ip, reason);
+            }
         }
         false
     }
@@ -303,13 +417,50 @@ impl<T: cio::CIO> Control<T> {
                     return;
                 }
             }
-            tracker::Response::DHT { tid, peers } | tracker::Response::PEX { tid, peers } => {
-                (tid, peers)
+            tracker::Response::DHT { tid, peers }
+            | tracker::Response::PEX { tid, peers }
+            | tracker::Response::LSD { tid, peers } => (tid, peers),
+            tracker::Response::Scrape { url, resp } => {
+                match resp {
+                    Ok(rs) => {
+                        for r in rs {
+                            if let Some(torrent) = self.torrents.get_mut(&r.tid) {
+                                torrent.set_scrape_response(url.as_ref(), &r);
+                            }
+                        }
+                    }
+                    Err(e) => debug!("Scrape of {} failed: {}", url, e),
+                }
+                return;
+            }
+            tracker::Response::WebSeed {
+                tid,
+                seed,
+                piece,
+                offset,
+                resp,
+            } => {
+                if let Some(torrent) = self.torrents.get_mut(&tid) {
+                    torrent.handle_webseed_resp(seed, piece, offset, resp);
+                }
+                return;
+            }
+            tracker::Response::PortMap(status) => {
+                self.data.port_mapping = Some(status);
+                self.update_rpc_port_mapping();
+                return;
             }
         };
+        let hash = match self.torrents.get(&id) {
+            Some(t) => t.info().hash,
+            None => return,
+        };
         for ip in &peers {
             trace!("Adding peer({:?})!", ip);
-            if let Ok(peer) = peer::PeerConn::new_outgoing(ip) {
+            if !self.admit(ip, Some(&hash), false) {
+                continue;
+            }
+            if let Ok(peer) = peer::PeerConn::new_outgoing(ip, &hash) {
                 trace!("Added peer({:?})!", ip);
                 self.add_peer(id, peer);
             }
@@ -335,18 +486,152 @@ impl<T: cio::CIO> Control<T> {
         }
     }
 
-    fn handle_incoming_conn(&mut self, conn: TcpStream) {
-        match peer::PeerConn::new_incoming(conn) {
-            Ok(pconn) => match self.cio.add_peer(pconn) {
-                Ok(pid) => {
-                    self.incoming.insert(pid);
+    /// Records a torrent's ban of a peer, banning it from every
+    /// torrent once it has offended repeatedly.
+    fn handle_ban(&mut self, tid: usize, ip: IpAddr, reason: String) {
+        let hash = match self.torrents.get(&tid) {
+            Some(t) => t.info().hash,
+            None => return,
+        };
+        if let Some(ban) = bans::Ban::new(&ip.to_string(), Some(hash), None, reason) {
+            self.bans.add(ban);
+            self.update_bans(Some(hash));
+        }
+        let offences = {
+            let offences = self.data.offences.entry(ip).or_insert(0);
+            *offences += 1;
+            *offences
+        };
+        if offences < GLOBAL_BAN_OFFENCES || self.bans.banned(ip, None) {
+            return;
+        }
+        info!("Banning {} from all torrents", ip);
+        let reason = format!("Sent corrupt data in {} torrents", offences);
+        if let Some(ban) = bans::Ban::new(&ip.to_string(), None, None, reason) {
+            self.bans.add(ban);
+            self.update_bans(None);
+        }
+    }
+
+    /// Bans an address or range on behalf of an RPC client.
+    fn ban_peer(
+        &mut self,
+        ip: &str,
+        torrent_id: Option<String>,
+        expires: Option<DateTime<Utc>>,
+        reason: Option<String>,
+    ) -> Result<(), String> {
+        let torrent = self.ban_scope(torrent_id)?;
+        let reason = reason.unwrap_or_else(|| "Banned by RPC".to_owned());
+        let ban = bans::Ban::new(ip, torrent, expires, reason)
+            .ok_or_else(|| format!("Invalid address or range {}", ip))?;
+        info!("Banning {}", ip);
+        self.bans.add(ban);
+        self.update_bans(torrent);
+        Ok(())
+    }
+
+    fn unban_peer(&mut self, ip: &str, torrent_id: Option<String>) -> Result<(), String> {
+        let torrent = self.ban_scope(torrent_id)?;
+        if !self.bans.remove(ip, torrent) {
+            return Err(format!("{} is not banned", ip));
+        }
+        info!("Unbanning {}", ip);
+        self.update_bans(torrent);
+        Ok(())
+    }
+
+    /// Resolves the torrent a ban applies to, None meaning every torrent.
+    fn ban_scope(&self, torrent_id: Option<String>) -> Result<Option<[u8; 20]>, String> {
+        match torrent_id {
+            Some(id) => id_to_hash(&id)
+                .filter(|hash| self.hash_idx.contains_key(hash))
+                .map(Some)
+                .ok_or_else(|| format!("Torrent {} does not exist", id)),
+            None => Ok(None),
+        }
+    }
+
+    /// Drops the bans limited to a removed torrent.
+    fn remove_bans(&mut self, torrent: &[u8; 20]) {
+        if self.bans.remove_torrent(torrent) {
+            self.serialize_bans();
+        }
+    }
+
+    /// Applies a change to the bans of a scope, disconnecting any
+    /// newly banned peers, persisting the bans and updating RPC.
+    fn update_bans(&mut self, torrent: Option<[u8; 20]>) {
+        let bans = &self.bans;
+        match torrent {
+            Some(hash) => {
+                let list = bans.scope(torrent).map(bans::Ban::rpc_info).collect();
+                let torrents = &mut self.torrents;
+                if let Some(t) = self
+                    .hash_idx
+                    .get(&hash)
+                    .and_then(|tid| torrents.get_mut(tid))
+                {
+                    t.disconnect(|addr| bans.banned(addr, Some(&hash)));
+                    t.set_bans(list);
                 }
-                Err(e) => {
-                    error!("Failed to add peer connection: {:?}", e);
+            }
+            None => {
+                for t in self.torrents.values_mut() {
+                    t.disconnect(|addr| bans.banned(addr, None));
                 }
-            },
+                self.update_rpc_bans();
+            }
+        }
+        self.serialize_bans();
+    }
+
+    /// Loads the configured blocklist, replacing the current one and
+    /// disconnecting any peers it now blocks.
+    fn load_blocklist(&mut self) -> io::Result<()> {
+        self.filter = match CONFIG.net.blocklist {
+            Some(ref path) => IpFilter::load(path)?,
+            None => IpFilter::new(),
+        };
+        info!("Loaded blocklist with {} ranges", self.filter.len());
+        let filter = &self.filter;
+        for torrent in self.torrents.values_mut() {
+            torrent.disconnect(|addr| filter.contains(addr));
+        }
+        self.data.blocked_changed = true;
+        Ok(())
+    }
+
+    /// Whether a connection to or from a peer is allowed by the
+    /// blocklist and bans, counting any refused by the blocklist.
+    fn admit(&mut self, addr: &SocketAddr, torrent: Option<&[u8; 20]>, incoming: bool) -> bool {
+        if self.bans.banned(addr.ip(), torrent) {
+            return false;
+        }
+        if self.filter.contains(addr.ip()) {
+            debug!("Blocked connection with {}", addr);
+            if incoming {
+                self.data.blocked_incoming += 1;
+            } else {
+                self.data.blocked_outgoing += 1;
+            }
+            self.data.blocked_changed = true;
+            return false;
+        }
+        true
+    }
+
+    fn handle_incoming_conn(&mut self, conn: Socket) {
+        if !self.admit(&conn.addr(), None, true) {
+            return;
+        }
+        let pconn = peer::PeerConn::new_incoming(conn, &self.mse_keys);
+        match self.cio.add_peer(pconn) {
+            Ok(pid) => {
+                self.incoming.insert(pid);
+            }
             Err(e) => {
-                error!("Failed to create peer connection: {:?}", e);
+                error!("Failed to add peer connection: {:?}", e);
             }
         }
     }
@@ -383,12 +668,30 @@ impl<T: cio::CIO> Control<T> {
 
         if let Some(&tid) = p.get(&pid) {
             let t = &mut self.torrents;
+            let (fallback, tcp_fallback) = match ev {
+                Err(cio::Error(cio::ErrorKind::EncryptionFailed(addr), _)) => (Some(addr), None),
+                Err(cio::Error(cio::ErrorKind::UtpFailed(addr), _)) => (None, Some(addr)),
+                _ => (None, None),
+            };
             if let Some(torrent) = t.get_mut(&tid) {
                 if torrent.peer_ev(pid, ev).is_err() {
                     p.remove(&pid);
                     torrent.update_rpc_peers();
                 }
             }
+            if let Some(addr) = fallback {
+                debug!("Retrying peer {} without encryption", addr);
+                if let Ok(peer) = peer::PeerConn::new_outgoing_plain(&addr) {
+                    self.add_peer(tid, peer);
+                }
+            }
+            if let Some(addr) = tcp_fallback {
+                debug!("Retrying peer {} over TCP", addr);
+                let hash = self.torrents.get(&tid).map(|t| t.info().hash);
+                if let Some(Ok(peer)) = hash.map(|h| peer::PeerConn::new_outgoing_tcp(&addr, &h)) {
+                    self.add_peer(tid, peer);
+                }
+            }
         } else if self.incoming.remove(&pid) {
             if self.inc_handshake(pid, ev).is_err() {
                 self.cio.remove_peer(pid);
@@ -412,7 +715,7 @@ impl<T: cio::CIO> Control<T> {
         serial: u64,
     ) {
         debug!("Adding {:?}, start: {}!", info, start);
-        let id = hash_to_id(&info.hash);
+        let id = info.rpc_id();
         if self.hash_idx.contains_key(&info.hash) {
             debug!("Tried to add torrent that already exists!");
             self.cio.msg_rpc(rpc::CtlMessage::Error {
@@ -434,8 +737,9 @@ impl<T: cio::CIO> Control<T> {
             import,
         );
         self.hash_idx.insert(t.info().hash, tid);
+        self.mse_keys.insert(t.info().hash);
         self.tid_cnt += 1;
-        self.queue.add(tid, t.priority());
+        self.queue.dl.add(tid, t.priority());
         self.torrents.insert(tid, t);
         self.cio
             .msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial })
@@ -444,19 +748,21 @@ impl<T: cio::CIO> Control<T> {
     fn handle_rpc_ev(&mut self, req: rpc::Message) -> bool {
         debug!("Handling rpc reqest!");
         match req {
-            rpc::Message::UpdateTorrent(u) => {
-                let hash_idx = &self.hash_idx;
-                let torrents = &mut self.torrents;
-                let res = id_to_hash(&u.id)
-                    .and_then(|d| hash_idx.get(d.as_ref()))
-                    .and_then(|i| torrents.get_mut(i));
-                if let Some(t) = res {
-                    let old_pri = t.priority();
-                    t.rpc_update(u);
-                    let new_pri = t.priority();
-                    self.queue.modify_pri(t.id(), new_pri, old_pri);
+            rpc::Message::UpdateTorrent {
+                resource,
+                client,
+                serial,
+            } => match resource.throttle_group {
+                Some(Some(ref id)) if !self.groups.contains_key(id) => {
+                    let reason = format!("Throttle group {} does not exist", id);
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason,
+                    });
                 }
-            }
+                _ => self.update_torrent(resource),
+            },
             rpc::Message::Torrent {
                 info,
                 path,
@@ -486,11 +792,15 @@ impl<T: cio::CIO> Control<T> {
                 peer,
             } => {
                 let res = id_to_hash(&id)
-                    .and_then(|d| self.hash_idx.get(d.as_ref()))
-                    .cloned();
-                let pres = peer::PeerConn::new_outgoing(&peer);
-                if let Some(tid) = res {
-                    if let Ok(pc) = pres {
+                    .and_then(|d| self.hash_idx.get(d.as_ref()).map(|tid| (d, *tid)));
+                if let Some((hash, tid)) = res {
+                    if !self.admit(&peer, Some(&hash), false) {
+                        self.cio.msg_rpc(rpc::CtlMessage::Error {
+                            client,
+                            serial,
+                            reason: format!("Peer {} is blocked", peer),
+                        });
+                    } else if let Ok(pc) = peer::PeerConn::new_outgoing(&peer, &hash) {
                         if let Some(id) = self.add_peer_rpc(tid, pc) {
                             self.cio
                                 .msg_rpc(rpc::CtlMessage::Pending { id, client, serial });
@@ -543,21 +853,42 @@ impl<T: cio::CIO> Control<T> {
                 id,
                 throttle_up,
                 throttle_down,
+                ratio_limit,
+                seed_time_limit,
+                idle_limit,
+                limit_action,
+                schedule,
+                turtle,
             } => {
-                let tu = throttle_up.unwrap_or_else(|| self.throttler.ul_rate());
-                let td = throttle_down.unwrap_or_else(|| self.throttler.dl_rate());
-                self.throttler.set_ul_rate(tu);
-                self.throttler.set_dl_rate(td);
-                self.data.throttle_ul = tu;
-                self.data.throttle_dl = td;
-                self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
-                    rpc::resource::SResourceUpdate::Throttle {
-                        id,
-                        kind: rpc::resource::ResourceKind::Server,
-                        throttle_up: tu,
-                        throttle_down: td,
-                    },
-                ]));
+                if ratio_limit.is_some()
+                    || seed_time_limit.is_some()
+                    || idle_limit.is_some()
+                    || limit_action.is_some()
+                {
+                    let limits = &mut self.data.limits;
+                    limits.ratio = ratio_limit.unwrap_or(limits.ratio);
+                    limits.seed_time = seed_time_limit.unwrap_or(limits.seed_time);
+                    limits.idle = idle_limit.unwrap_or(limits.idle);
+                    limits.action = limit_action.unwrap_or(limits.action);
+                    self.update_rpc_limits();
+                }
+                self.data.throttle_ul = throttle_up.unwrap_or(self.data.throttle_ul);
+                self.data.throttle_dl = throttle_down.unwrap_or(self.data.throttle_dl);
+                if let Some(schedule) = schedule {
+                    self.data.schedule = Some(schedule.clone());
+                    self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                        rpc::resource::SResourceUpdate::ServerSchedule {
+                            id,
+                            kind: rpc::resource::ResourceKind::Server,
+                            schedule,
+                        },
+                    ]));
+                }
+                if let Some(turtle) = turtle {
+                    self.data.turtle = turtle;
+                }
+                self.update_rates();
+                self.update_rpc_rates();
             }
             rpc::Message::RemoveTorrent {
                 id,
@@ -566,21 +897,29 @@ impl<T: cio::CIO> Control<T> {
                 artifacts,
             } => {
                 let hash_idx = &mut self.hash_idx;
+                let mse_keys = &self.mse_keys;
                 let torrents = &mut self.torrents;
-                let cio = &mut self.cio;
                 let reason = format!("Torrent {} does not exist", id);
-                id_to_hash(&id)
-                    .and_then(|d| hash_idx.remove(d.as_ref()))
-                    .and_then(|i| torrents.remove(&i))
-                    .map(|mut t| t.delete(artifacts))
-                    .map(|_| cio.msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial }))
-                    .unwrap_or_else(|| {
-                        cio.msg_rpc(rpc::CtlMessage::Error {
-                            client,
-                            serial,
-                            reason,
-                        })
-                    });
+                let removed = id_to_hash(&id).and_then(|d| {
+                    mse_keys.remove(&d);
+                    let mut t = hash_idx
+                        .remove(d.as_ref())
+                        .and_then(|i| torrents.remove(&i))?;
+                    t.delete(artifacts);
+                    Some(d)
+                });
+                match removed {
+                    Some(hash) => {
+                        self.remove_bans(&hash);
+                        self.cio
+                            .msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial });
+                    }
+                    None => self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason,
+                    }),
+                }
             }
             rpc::Message::Pause(id) => {
                 let hash_idx = &mut self.hash_idx;
@@ -614,6 +953,36 @@ impl<T: cio::CIO> Control<T> {
                     }
                 }
             }
+            rpc::Message::SetDeadline {
+                id,
+                torrent_id,
+                offset,
+                length,
+                deadline,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let res = id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i));
+                if let Some(t) = res {
+                    t.set_deadline(&id, offset, length, deadline);
+                }
+            }
+            rpc::Message::Stream {
+                id,
+                torrent_id,
+                req,
+            } => {
+                let hash_idx = &self.hash_idx;
+                let torrents = &mut self.torrents;
+                let res = id_to_hash(&torrent_id)
+                    .and_then(|d| hash_idx.get(d.as_ref()))
+                    .and_then(|i| torrents.get_mut(i));
+                if let Some(t) = res {
+                    t.stream(&id, *req);
+                }
+            }
             rpc::Message::RemovePeer {
                 id,
                 torrent_id,
@@ -637,6 +1006,55 @@ impl<T: cio::CIO> Control<T> {
                         })
                     });
             }
+            rpc::Message::AddThrottleGroup {
+                name,
+                client,
+                serial,
+                throttle_up,
+                throttle_down,
+            } => self.add_throttle_group(name, throttle_up, throttle_down, client, serial),
+            rpc::Message::UpdateThrottleGroup {
+                id,
+                throttle_up,
+                throttle_down,
+            } => {
+                if let (Some(group), Some(data)) = (
+                    self.groups.get_mut(&id),
+                    self.data.groups.iter_mut().find(|g| g.id == id),
+                ) {
+                    data.throttle_ul = throttle_up.unwrap_or(data.throttle_ul);
+                    data.throttle_dl = throttle_down.unwrap_or(data.throttle_dl);
+                    group.set_ul_rate(data.throttle_ul);
+                    group.set_dl_rate(data.throttle_dl);
+                    self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                        rpc::resource::SResourceUpdate::Throttle {
+                            id,
+                            kind: rpc::resource::ResourceKind::ThrottleGroup,
+                            throttle_up: data.throttle_ul,
+                            throttle_down: data.throttle_dl,
+                        },
+                    ]));
+                }
+            }
+            rpc::Message::RemoveThrottleGroup { id, client, serial } => {
+                if self.groups.remove(&id).is_some() {
+                    self.data.groups.retain(|g| g.id != id);
+                    for t in self.torrents.values_mut() {
+                        if t.throttle_group() == Some(id.as_str()) {
+                            t.set_throttle_group(None);
+                        }
+                    }
+                    self.cio.msg_rpc(rpc::CtlMessage::Removed(vec![id.clone()]));
+                    self.cio
+                        .msg_rpc(rpc::CtlMessage::ClientRemoved { id, client, serial });
+                } else {
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason: "Throttle group does not exist!".to_string(),
+                    });
+                }
+            }
             rpc::Message::RemoveTracker {
                 id,
                 torrent_id,
@@ -673,6 +1091,45 @@ impl<T: cio::CIO> Control<T> {
             rpc::Message::PurgeDNS => {
                 self.cio.msg_trk(tracker::Request::PurgeDNS);
             }
+            rpc::Message::ReloadBlocklist { client, serial } => {
+                if let Err(e) = self.load_blocklist() {
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason: format!("Failed to load blocklist: {}", e),
+                    });
+                }
+            }
+            rpc::Message::BanPeer {
+                ip,
+                torrent_id,
+                expires,
+                reason,
+                client,
+                serial,
+            } => {
+                if let Err(reason) = self.ban_peer(&ip, torrent_id, expires, reason) {
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason,
+                    });
+                }
+            }
+            rpc::Message::UnbanPeer {
+                ip,
+                torrent_id,
+                client,
+                serial,
+            } => {
+                if let Err(reason) = self.unban_peer(&ip, torrent_id) {
+                    self.cio.msg_rpc(rpc::CtlMessage::Error {
+                        client,
+                        serial,
+                        reason,
+                    });
+                }
+            }
         }
         false
     }
@@ -691,8 +1148,7 @@ impl<T: cio::CIO> Control<T> {
     fn add_peer(&mut self, id: usize, peer: peer::PeerConn) {
         trace!("Adding peer to torrent {:?}!", id);
         if let Some(torrent) = self.torrents.get_mut(&id) {
-            if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
-                self.queue.add(id, torrent.priority());
+            if !self.queue.admit(id, torrent) {
                 return;
             }
             if let Some(pid) = torrent.add_peer(peer) {
@@ -709,9 +1165,13 @@ impl<T: cio::CIO> Control<T> {
         rsv: [u8; 8],
     ) -> Result<(), ()> {
         trace!("Adding peer to torrent {:?}!", id);
+        let addr = self.cio.get_peer(pid, |pconn| pconn.sock().addr());
+        let bans = &self.bans;
         if let Some(torrent) = self.torrents.get_mut(&id) {
-            if !self.queue.active_dl.contains(&id) && !torrent.status().completed() {
-                self.queue.add(id, torrent.priority());
+            if addr.map_or(false, |a| bans.banned(a.ip(), Some(&torrent.info().hash))) {
+                return Err(());
+            }
+            if !self.queue.admit(id, torrent) {
                 return Err(());
             }
             if let Some(pid) = torrent.add_inc_peer(pid, cid, rsv) {
@@ -732,6 +1192,170 @@ impl<T: cio::CIO> Control<T> {
         ]));
     }
 
+    fn update_rpc_bans(&mut self) {
+        let bans = self.rpc_bans();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::Bans {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                bans,
+            },
+        ]));
+    }
+
+    fn rpc_bans(&self) -> Vec<rpc::resource::Ban> {
+        self.bans.scope(None).map(bans::Ban::rpc_info).collect()
+    }
+
+    fn update_torrent(&mut self, u: rpc::resource::CResourceUpdate) {
+        let hash_idx = &self.hash_idx;
+        let torrents = &mut self.torrents;
+        let groups = &self.groups;
+        let res = id_to_hash(&u.id)
+            .and_then(|d| hash_idx.get(d.as_ref()))
+            .and_then(|i| torrents.get_mut(i));
+        if let Some(t) = res {
+            if let Some(ref group) = u.throttle_group {
+                t.set_throttle_group(
+                    group
+                        .as_ref()
+                        .and_then(|id| groups.get(id).map(|g| (id.as_str(), g))),
+                );
+            }
+            let old_pri = t.priority();
+            t.rpc_update(u);
+            let new_pri = t.priority();
+            self.queue.dl.modify_pri(t.id(), new_pri, old_pri);
+            self.queue.ul.modify_pri(t.id(), new_pri, old_pri);
+        }
+    }
+
+    fn add_throttle_group(
+        &mut self,
+        name: String,
+        throttle_ul: Option<i64>,
+        throttle_dl: Option<i64>,
+        client: usize,
+        serial: u64,
+    ) {
+        let id = util::group_rpc_id(&name);
+        if self.groups.contains_key(&id) {
+            self.cio.msg_rpc(rpc::CtlMessage::Error {
+                client,
+                serial,
+                reason: format!("Throttle group {} already exists", name),
+            });
+            return;
+        }
+        let group = self.throttler.new_group(throttle_dl, throttle_ul);
+        self.groups.insert(id.clone(), group);
+        let data = GroupData {
+            id: id.clone(),
+            name,
+            throttle_ul,
+            throttle_dl,
+        };
+        self.cio
+            .msg_rpc(rpc::CtlMessage::Extant(vec![data.rpc_info()]));
+        self.data.groups.push(data);
+        self.cio
+            .msg_rpc(rpc::CtlMessage::Uploaded { id, client, serial });
+    }
+
+    /// Rate profiles in use, those set over RPC replacing the config's.
+    fn schedule(&self) -> &[rpc::resource::RateProfile] {
+        match self.data.schedule {
+            Some(ref schedule) => schedule,
+            None => &CONFIG.schedule.profiles,
+        }
+    }
+
+    /// Sets the global rates by turtle mode, the active rate profile or
+    /// the default rates in that order, returning whether they changed.
+    fn update_rates(&mut self) -> bool {
+        let (profile, ul, dl) = if self.data.turtle {
+            (None, CONFIG.schedule.turtle_up, CONFIG.schedule.turtle_down)
+        } else {
+            match schedule::active(self.schedule(), Local::now().naive_local()) {
+                Some(p) => (Some(p.name.clone()), p.throttle_up, p.throttle_down),
+                None => (None, self.data.throttle_ul, self.data.throttle_dl),
+            }
+        };
+        if profile != self.data.rate_profile {
+            match profile {
+                Some(ref name) => info!("Switching to rate profile {}", name),
+                None => info!("Leaving rate profile"),
+            }
+        }
+        let changed = profile != self.data.rate_profile
+            || ul != self.throttler.ul_rate()
+            || dl != self.throttler.dl_rate();
+        self.data.rate_profile = profile;
+        self.throttler.set_ul_rate(ul);
+        self.throttler.set_dl_rate(dl);
+        changed
+    }
+
+    fn update_rpc_rates(&mut self) {
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::Throttle {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                throttle_up: self.throttler.ul_rate(),
+                throttle_down: self.throttler.dl_rate(),
+            },
+            rpc::resource::SResourceUpdate::ServerRateProfile {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                rate_profile: self.data.rate_profile.clone(),
+                turtle: self.data.turtle,
+            },
+        ]));
+    }
+
+    fn update_rpc_limits(&mut self) {
+        let limits = self.data.limits;
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::SeedLimits {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                ratio_limit: limits.ratio,
+                seed_time_limit: limits.seed_time,
+                idle_limit: limits.idle,
+                limit_action: Some(limits.action.unwrap_or_default()),
+            },
+        ]));
+    }
+
+    fn update_rpc_port_mapping(&mut self) {
+        let (port_mapping, external_ip) = self.rpc_port_mapping();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            rpc::resource::SResourceUpdate::ServerPortMapping {
+                id: self.data.id.clone(),
+                kind: rpc::resource::ResourceKind::Server,
+                port_mapping,
+                external_ip,
+            },
+        ]));
+    }
+
+    fn rpc_port_mapping(&self) -> (rpc::resource::PortMapping, Option<String>) {
+        use crate::rpc::resource::PortMapping;
+
+        let status = match self.data.port_mapping {
+            _ if !CONFIG.net.port_mapping => return (PortMapping::Disabled, None),
+            None => return (PortMapping::Pending, None),
+            Some(ref s) => s,
+        };
+        let method = match status.method {
+            Some(tracker::PortMapMethod::Pcp) => PortMapping::Pcp,
+            Some(tracker::PortMapMethod::NatPmp) => PortMapping::NatPmp,
+            Some(tracker::PortMapMethod::Upnp) => PortMapping::Upnp,
+            None => PortMapping::Failed,
+        };
+        (method, status.external_ip.map(|ip| ip.to_string()))
+    }
+
     fn update_rpc_tx(&mut self) {
         self.stat.tick();
         if self.stat.active() {
@@ -749,9 +1373,35 @@ impl<T: cio::CIO> Control<T> {
                 },
             ]));
         }
+        self.lan_stat.tick();
+        if self.lan_stat.active() {
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                rpc::resource::SResourceUpdate::ServerLanTransfer {
+                    id: self.data.id.clone(),
+                    kind: rpc::resource::ResourceKind::Server,
+                    lan_rate_up: self.lan_stat.avg_ul(),
+                    lan_rate_down: self.lan_stat.avg_dl(),
+                    lan_transferred_up: self.data.lan_ul,
+                    lan_transferred_down: self.data.lan_dl,
+                },
+            ]));
+        }
+        if self.data.blocked_changed {
+            self.data.blocked_changed = false;
+            self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+                rpc::resource::SResourceUpdate::ServerBlocklist {
+                    id: self.data.id.clone(),
+                    kind: rpc::resource::ResourceKind::Server,
+                    blocklist: self.filter.len() as u64,
+                    blocked_incoming: self.data.blocked_incoming,
+                    blocked_outgoing: self.data.blocked_outgoing,
+                },
+            ]));
+        }
     }
 
     fn send_rpc_info(&mut self) {
+        let (port_mapping, external_ip) = self.rpc_port_mapping();
         let res = rpc::resource::Resource::Server(rpc::resource::Server {
             id: self.data.id.clone(),
             rate_up: 0,
@@ -762,12 +1412,29 @@ impl<T: cio::CIO> Control<T> {
             transferred_down: self.data.dl,
             ses_transferred_up: self.data.session_ul,
             ses_transferred_down: self.data.session_dl,
+            lan_transferred_up: self.data.lan_ul,
+            lan_transferred_down: self.data.lan_dl,
             free_space: self.data.free_space,
+            port_mapping,
+            external_ip,
+            bans: self.rpc_bans(),
+            blocklist: self.filter.len() as u64,
+            blocked_incoming: self.data.blocked_incoming,
+            blocked_outgoing: self.data.blocked_outgoing,
+            ratio_limit: self.data.limits.ratio,
+            seed_time_limit: self.data.limits.seed_time,
+            idle_limit: self.data.limits.idle,
+            limit_action: self.data.limits.action.unwrap_or_default(),
+            schedule: self.schedule().to_vec(),
+            rate_profile: self.data.rate_profile.clone(),
+            turtle: self.data.turtle,
             started: Utc::now(),
             download_token: DL_TOKEN.clone(),
             ..Default::default()
         });
-        self.cio.msg_rpc(rpc::CtlMessage::Extant(vec![res]));
+        let mut resources = vec![res];
+        resources.extend(self.data.groups.iter().map(GroupData::rpc_info));
+        self.cio.msg_rpc(rpc::CtlMessage::Extant(resources));
     }
 }
 
@@ -790,15 +1457,164 @@ impl ServerData {
             session_ul: 0,
             session_dl: 0,
             free_space: 0,
+            port_mapping: None,
+            offences: FHashMap::default(),
+            blocked_incoming: 0,
+            blocked_outgoing: 0,
+            blocked_changed: false,
+            rate_profile: None,
             throttle_ul: Some(-1),
             throttle_dl: Some(-1),
+            limits: SeedLimits::default(),
+            schedule: None,
+            turtle: false,
+            groups: Vec::new(),
+            lan_ul: 0,
+            lan_dl: 0,
+        }
+    }
+
+    /// Loads server data, migrating it from older formats if needed.
+    fn load(data: &[u8]) -> Option<ServerData> {
+        let d = session::server::load(data)?;
+        Some(ServerData {
+            id: d.id,
+            ul: d.ul,
+            dl: d.dl,
+            throttle_ul: d.throttle_ul,
+            throttle_dl: d.throttle_dl,
+            limits: SeedLimits {
+                ratio: d.ratio_limit,
+                seed_time: d.seed_time_limit,
+                idle: d.idle_limit,
+                action: d.limit_action.map(|a| match a {
+                    session::server::current::SeedAction::Pause => rpc::resource::SeedAction::Pause,
+                    session::server::current::SeedAction::Remove => {
+                        rpc::resource::SeedAction::Remove
+                    }
+                    session::server::current::SeedAction::RemoveData => {
+                        rpc::resource::SeedAction::RemoveData
+                    }
+                }),
+            },
+            schedule: d.schedule.map(|profiles| {
+                profiles
+                    .into_iter()
+                    .filter_map(|p| {
+                        Some(rpc::resource::RateProfile {
+                            name: p.name,
+                            days: p.days,
+                            start: NaiveTime::parse_from_str(&p.start, "%H:%M").ok()?,
+                            end: NaiveTime::parse_from_str(&p.end, "%H:%M").ok()?,
+                            throttle_up: p.throttle_up,
+                            throttle_down: p.throttle_down,
+                        })
+                    })
+                    .collect()
+            }),
+            turtle: d.turtle,
+            groups: d
+                .groups
+                .into_iter()
+                .map(|g| GroupData {
+                    id: g.id,
+                    name: g.name,
+                    throttle_ul: g.throttle_ul,
+                    throttle_dl: g.throttle_dl,
+                })
+                .collect(),
+            lan_ul: d.lan_ul,
+            lan_dl: d.lan_dl,
+            ..ServerData::new()
+        })
+    }
+
+    fn session(&self) -> session::server::Session {
+        session::server::Session {
+            id: self.id.clone(),
+            ul: self.ul,
+            dl: self.dl,
+            throttle_ul: self.throttle_ul,
+            throttle_dl: self.throttle_dl,
+            ratio_limit: self.limits.ratio,
+            seed_time_limit: self.limits.seed_time,
+            idle_limit: self.limits.idle,
+            limit_action: self.limits.action.map(|a| match a {
+                rpc::resource::SeedAction::Pause => session::server::current::SeedAction::Pause,
+                rpc::resource::SeedAction::Remove => session::server::current::SeedAction::Remove,
+                rpc::resource::SeedAction::RemoveData => {
+                    session::server::current::SeedAction::RemoveData
+                }
+            }),
+            schedule: self.schedule.as_ref().map(|profiles| {
+                profiles
+                    .iter()
+                    .map(|p| session::server::current::RateProfile {
+                        name: p.name.clone(),
+                        days: p.days.clone(),
+                        start: p.start.format("%H:%M").to_string(),
+                        end: p.end.format("%H:%M").to_string(),
+                        throttle_up: p.throttle_up,
+                        throttle_down: p.throttle_down,
+                    })
+                    .collect()
+            }),
+            turtle: self.turtle,
+            groups: self
+                .groups
+                .iter()
+                .map(|g| session::server::current::ThrottleGroup {
+                    id: g.id.clone(),
+                    name: g.name.clone(),
+                    throttle_ul: g.throttle_ul,
+                    throttle_dl: g.throttle_dl,
+                })
+                .collect(),
+            lan_ul: self.lan_ul,
+            lan_dl: self.lan_dl,
         }
     }
 }
 
+impl GroupData {
+    fn rpc_info(&self) -> rpc::resource::Resource {
+        rpc::resource::Resource::ThrottleGroup(rpc::resource::ThrottleGroup {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            throttle_up: self.throttle_ul,
+            throttle_down: self.throttle_dl,
+            ..Default::default()
+        })
+    }
+}
+
 impl Queue {
     fn new() -> Queue {
-        let inactive_dl = [
+        Queue {
+            dl: Slots::new(CONFIG.max_dl as usize),
+            ul: Slots::new(CONFIG.max_ul as usize),
+        }
+    }
+
+    /// Whether a torrent may connect to peers, queueing it if not.
+    fn admit<T: cio::CIO>(&mut self, id: usize, torrent: &Torrent<T>) -> bool {
+        let slots = if torrent.status().completed() {
+            &mut self.ul
+        } else {
+            &mut self.dl
+        };
+        if slots.active.contains(&id) {
+            return true;
+        }
+        slots.add(id, torrent.priority());
+        false
+    }
+}
+
+impl Slots {
+    /// Creates slots for up to max active torrents, 0 meaning no limit.
+    fn new(max: usize) -> Slots {
+        let inactive = [
             FHashSet::default(),
             FHashSet::default(),
             FHashSet::default(),
@@ -806,39 +1622,53 @@ impl Queue {
             FHashSet::default(),
             FHashSet::default(),
         ];
-        Queue {
-            active_dl: FHashSet::default(),
-            inactive_dl,
+        Slots {
+            max,
+            active: FHashSet::default(),
+            inactive,
         }
     }
 
-    fn dl_full(&self) -> bool {
-        self.active_dl.len() == CONFIG.max_dl as usize
+    fn full(&self) -> bool {
+        self.max != 0 && self.active.len() >= self.max
+    }
+
+    fn contains(&self, id: usize) -> bool {
+        self.active.contains(&id) || self.inactive.iter().any(|q| q.contains(&id))
     }
 
     fn modify_pri(&mut self, id: usize, pri: u8, old_pri: u8) {
-        let pri = pri as usize;
-        let old_pri = old_pri as usize;
-        self.inactive_dl[old_pri].remove(&id);
-        self.inactive_dl[pri].insert(id);
+        if self.inactive[old_pri as usize].remove(&id) {
+            self.inactive[pri as usize].insert(id);
+        }
     }
 
-    fn add(&mut self, id: usize, pri: u8) {
-        let pri = pri as usize;
-        if self.dl_full() {
-            self.inactive_dl[pri].insert(id);
+    /// Adds a torrent, returning whether it's active.
+    fn add(&mut self, id: usize, pri: u8) -> bool {
+        if self.full() {
+            self.inactive[pri as usize].insert(id);
+            false
         } else {
-            self.active_dl.insert(id);
+            self.active.insert(id);
+            true
+        }
+    }
+
+    /// Removes torrents which no longer belong in the queue.
+    fn retain<F: Fn(usize) -> bool>(&mut self, f: F) {
+        self.active.retain(|id| f(*id));
+        for q in &mut self.inactive {
+            q.retain(|id| f(*id));
         }
     }
 
     fn enqueue<F: FnMut(usize)>(&mut self, mut f: F) {
-        while !self.dl_full() && self.inactive_dl.iter().any(|q| !q.is_empty()) {
-            for i in (0..self.inactive_dl.len()).rev() {
-                if !self.inactive_dl[i].is_empty() {
-                    let next = { *self.inactive_dl[i].iter().next().unwrap() };
-                    self.inactive_dl[i].remove(&next);
-                    self.active_dl.insert(next);
+        while !self.full() && self.inactive.iter().any(|q| !q.is_empty()) {
+            for i in (0..self.inactive.len()).rev() {
+                if !self.inactive[i].is_empty() {
+                    let next = { *self.inactive[i].iter().next().unwrap() };
+                    self.inactive[i].remove(&next);
+                    self.active.insert(next);
                     f(next);
                     break;
                 }
@@ -902,14 +1732,32 @@ impl<T: cio::CIO> CJob<T> for EnqueueUpdate {
         let queue = &mut control.queue;
         let torrents = &mut control.torrents;
 
-        queue.active_dl.retain(|tid| match torrents.get(tid) {
+        queue.dl.active.retain(|tid| match torrents.get(tid) {
             Some(t) => t.status().should_dl(),
             None => false,
         });
-        for q in &mut queue.inactive_dl {
+        for q in &mut queue.dl.inactive {
             q.retain(|tid| torrents.contains_key(tid));
         }
-        queue.enqueue(|tid| torrents.get_mut(&tid).unwrap().update_tracker());
+        queue
+            .dl
+            .enqueue(|tid| torrents.get_mut(&tid).unwrap().update_tracker());
+
+        // Seeding torrents join the queue once they complete, and stop
+        // seeding if no slot is free.
+        queue.ul.retain(|tid| match torrents.get(&tid) {
+            Some(t) => t.status().should_ul(),
+            None => false,
+        });
+        for (&tid, t) in torrents.iter_mut() {
+            if t.status().should_ul() && !queue.ul.contains(tid) && !queue.ul.add(tid, t.priority())
+            {
+                t.disconnect(|_| true);
+            }
+        }
+        queue
+            .ul
+            .enqueue(|tid| torrents.get_mut(&tid).unwrap().update_tracker());
     }
 }
 
@@ -920,3 +1768,128 @@ impl<T: cio::CIO> CJob<T> for SerializeUpdate {
         control.serialize();
     }
 }
+
+pub struct BanUpdate;
+
+impl<T: cio::CIO> CJob<T> for BanUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        for torrent in control.bans.expire() {
+            control.update_bans(torrent);
+        }
+    }
+}
+
+pub struct SeedUpdate;
+
+impl<T: cio::CIO> CJob<T> for SeedUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        let limits = control.data.limits;
+        let reached: Vec<_> = control
+            .torrents
+            .iter()
+            .filter_map(|(&tid, t)| t.seed_limit(&limits).map(|a| (tid, a)))
+            .collect();
+        for (tid, action) in reached {
+            let artifacts = match action {
+                rpc::resource::SeedAction::Pause => {
+                    if let Some(t) = control.torrents.get_mut(&tid) {
+                        info!("Torrent {} reached a seeding limit, pausing", t.rpc_id());
+                        t.pause();
+                    }
+                    continue;
+                }
+                rpc::resource::SeedAction::Remove => false,
+                rpc::resource::SeedAction::RemoveData => true,
+            };
+            if let Some(mut t) = control.torrents.remove(&tid) {
+                info!("Torrent {} reached a seeding limit, removing", t.rpc_id());
+                control.hash_idx.remove(&t.info().hash);
+                control.mse_keys.remove(&t.info().hash);
+                control.remove_bans(&t.info().hash);
+                t.delete(artifacts);
+            }
+        }
+    }
+}
+
+pub struct ScheduleUpdate;
+
+impl<T: cio::CIO> CJob<T> for ScheduleUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        if control.update_rates() {
+            control.update_rpc_rates();
+        }
+    }
+}
+
+pub struct ScrapeUpdate;
+
+impl<T: cio::CIO> CJob<T> for ScrapeUpdate {
+    fn update(&mut self, control: &mut Control<T>) {
+        let mut batches: MHashMap<Arc<Url>, Vec<(usize, [u8; 20])>> = MHashMap::default();
+        for (&tid, torrent) in &control.torrents {
+            for trk in torrent.trackers() {
+                batches
+                    .entry(trk.url.clone())
+                    .or_default()
+                    .push((tid, torrent.info().hash));
+            }
+        }
+        for (url, torrents) in batches {
+            for req in tracker::Request::scrape(&url, &torrents) {
+                control.cio.msg_trk(req);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::cio::test;
+    use chrono::Duration;
+    use std::thread;
+
+    fn control(mut reg: amy::Registrar) -> Control<test::TCIO> {
+        let throttler = Throttler::new(None, None, 100, &reg).unwrap();
+        let (db, _) = reg.channel().unwrap();
+        Control::new(test::TCIO::new(), throttler, db).unwrap()
+    }
+
+    #[test]
+    fn test_admit_bans() {
+        let poll = amy::Poller::new().unwrap();
+        let mut c = control(poll.get_registrar());
+        let (t1, t2) = ([1; 20], [2; 20]);
+        let addr = "10.0.0.1:6881".parse().unwrap();
+        let ban = bans::Ban::new("10.0.0.1", Some(t1), None, String::new()).unwrap();
+        c.bans.add(ban);
+
+        assert!(!c.admit(&addr, Some(&t1), false));
+        assert!(c.admit(&addr, Some(&t2), false));
+        // Incoming connections are checked again once their torrent is known
+        assert!(c.admit(&addr, None, true));
+        assert!(c.admit(&"10.0.0.2:6881".parse().unwrap(), Some(&t1), false));
+        assert_eq!(c.data.blocked_incoming + c.data.blocked_outgoing, 0);
+    }
+
+    #[test]
+    fn test_ban_expiry() {
+        let poll = amy::Poller::new().unwrap();
+        let mut c = control(poll.get_registrar());
+        let addr = "10.0.0.1:6881".parse().unwrap();
+        let expires = Utc::now() + Duration::milliseconds(100);
+        c.bans
+            .add(bans::Ban::new("10.0.0.1", None, Some(expires), String::new()).unwrap());
+        c.bans
+            .add(bans::Ban::new("10.0.0.2", None, None, String::new()).unwrap());
+        assert!(!c.admit(&addr, None, true));
+        thread::sleep(time::Duration::from_millis(150));
+        assert!(c.admit(&addr, None, true));
+
+        BanUpdate.update(&mut c);
+        let left: Vec<_> = c.bans.scope(None).map(|b| b.rpc_info().ip).collect();
+        assert_eq!(left, vec!["10.0.0.2".to_owned()]);
+        assert!(!c.admit(&"10.0.0.2:6881".parse().unwrap(), None, true));
+    }
+}
@@ -0,0 +1,64 @@
+//! Weekly schedule of alternative global rates.
+
+use chrono::{Datelike, NaiveDateTime};
+
+use crate::rpc::resource::RateProfile;
+
+/// Finds the first profile which applies at a local time.
+pub fn active(profiles: &[RateProfile], now: NaiveDateTime) -> Option<&RateProfile> {
+    profiles.iter().find(|p| applies(p, now))
+}
+
+fn applies(profile: &RateProfile, now: NaiveDateTime) -> bool {
+    let (day, time) = (now.weekday(), now.time());
+    profile.days.iter().any(|&d| {
+        if profile.start < profile.end {
+            d == day && profile.start <= time && time < profile.end
+        } else if profile.start > profile.end {
+            // Overnight profiles run into the next day
+            (d == day && profile.start <= time) || (d.succ() == day && time < profile.end)
+        } else {
+            d == day
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::active;
+    use crate::rpc::resource::RateProfile;
+    use chrono::{NaiveDate, NaiveTime, Weekday};
+
+    fn profile(name: &str, days: Vec<Weekday>, start: (u32, u32), end: (u32, u32)) -> RateProfile {
+        RateProfile {
+            name: name.to_owned(),
+            days,
+            start: NaiveTime::from_hms(start.0, start.1, 0),
+            end: NaiveTime::from_hms(end.0, end.1, 0),
+            throttle_up: None,
+            throttle_down: None,
+        }
+    }
+
+    #[test]
+    fn test_active() {
+        let profiles = vec![
+            profile("office", vec![Weekday::Mon, Weekday::Tue], (9, 0), (17, 0)),
+            profile("night", vec![Weekday::Tue], (22, 0), (6, 0)),
+            profile("weekend", vec![Weekday::Sat], (0, 0), (0, 0)),
+        ];
+        // 2018-01-01 was a Monday
+        let at = |d, h, m| NaiveDate::from_ymd(2018, 1, d).and_hms(h, m, 0);
+        let name = |d, h, m| active(&profiles, at(d, h, m)).map(|p| p.name.as_str());
+
+        assert_eq!(name(1, 8, 59), None);
+        assert_eq!(name(1, 9, 0), Some("office"));
+        assert_eq!(name(1, 17, 0), None);
+        assert_eq!(name(2, 23, 0), Some("night"));
+        assert_eq!(name(3, 5, 59), Some("night"));
+        assert_eq!(name(3, 6, 0), None);
+        assert_eq!(name(1, 23, 0), None);
+        assert_eq!(name(6, 12, 0), Some("weekend"));
+        assert_eq!(name(7, 0, 0), None);
+    }
+}
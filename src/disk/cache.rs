@@ -1,8 +1,11 @@
+use std::collections::BTreeMap;
 use std::ffi::OsString;
-use std::{fs, io, mem, path};
+use std::{cmp, fs, io, mem, path};
 
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{IoSlice, Read, Seek, SeekFrom, Write};
 
+use super::Location;
+use crate::torrent::LocIter;
 use crate::util::{native, MHashMap};
 use crate::CONFIG;
 
@@ -18,6 +21,31 @@ pub struct FileCache {
     files: MHashMap<path::PathBuf, Entry>,
 }
 
+/// Downloaded blocks held until their piece is validated, so it can be
+/// hashed without reading it back, then written out at once.
+pub struct PieceCache {
+    pieces: MHashMap<(usize, u32), CachedPiece>,
+    /// Bytes of block data held
+    size: usize,
+    /// Bytes which may be held before pieces are written out early
+    budget: usize,
+    /// Incremented on each insertion, to find the least recently written piece
+    clock: u64,
+}
+
+pub struct CachedPiece {
+    path: Option<String>,
+    /// Blocks by their offset in the piece
+    blocks: BTreeMap<u32, CachedBlock>,
+    len: usize,
+    used: u64,
+}
+
+struct CachedBlock {
+    data: Box<[u8]>,
+    locations: Vec<Location>,
+}
+
 pub struct Entry {
     used: bool,
     alloc_failed: bool,
@@ -92,6 +120,231 @@ impl BufCache {
     }
 }
 
+impl PieceCache {
+    pub fn new(budget: usize) -> PieceCache {
+        PieceCache {
+            pieces: MHashMap::default(),
+            size: 0,
+            budget,
+            clock: 0,
+        }
+    }
+
+    /// Copies a downloaded block into the cache. The caller should then
+    /// evict any pieces over the budget.
+    pub fn insert(
+        &mut self,
+        tid: usize,
+        piece: u32,
+        begin: u32,
+        data: &[u8],
+        locations: LocIter,
+        path: Option<String>,
+    ) {
+        let locations: Vec<_> = locations.collect();
+        let len = locations.iter().map(|l| l.end).max().unwrap_or(0);
+        self.clock += 1;
+        let entry = self
+            .pieces
+            .entry((tid, piece))
+            .or_insert_with(|| CachedPiece {
+                path,
+                blocks: BTreeMap::new(),
+                len: 0,
+                used: 0,
+            });
+        entry.used = self.clock;
+        let block = CachedBlock {
+            data: data[..len].into(),
+            locations,
+        };
+        if let Some(prev) = entry.blocks.insert(begin, block) {
+            entry.len -= prev.data.len();
+            self.size -= prev.data.len();
+        }
+        entry.len += len;
+        self.size += len;
+    }
+
+    /// Writes out the least recently written pieces until the cache
+    /// is within its budget.
+    pub fn evict(&mut self, fc: &mut FileCache, tpb: &mut TempPB<'_>) -> io::Result<()> {
+        while self.size > self.budget {
+            let key = match self.pieces.iter().min_by_key(|(_, p)| p.used) {
+                Some((key, _)) => *key,
+                None => break,
+            };
+            let piece = self.remove_piece(key);
+            piece.write(fc, tpb)?;
+        }
+        Ok(())
+    }
+
+    /// Removes a piece, returning it if all len bytes of it are held.
+    /// Otherwise any held blocks are written out so the piece can be
+    /// read from disk.
+    pub fn take(
+        &mut self,
+        fc: &mut FileCache,
+        tpb: &mut TempPB<'_>,
+        tid: usize,
+        piece: u32,
+        len: usize,
+    ) -> io::Result<Option<CachedPiece>> {
+        if !self.pieces.contains_key(&(tid, piece)) {
+            return Ok(None);
+        }
+        let piece = self.remove_piece((tid, piece));
+        if piece.len == len {
+            Ok(Some(piece))
+        } else {
+            piece.write(fc, tpb)?;
+            Ok(None)
+        }
+    }
+
+    /// Writes out the held pieces of a torrent, or of every torrent.
+    pub fn flush(
+        &mut self,
+        fc: &mut FileCache,
+        tpb: &mut TempPB<'_>,
+        tid: Option<usize>,
+    ) -> io::Result<()> {
+        let keys: Vec<_> = self
+            .pieces
+            .keys()
+            .filter(|(t, _)| tid.map(|tid| tid == *t).unwrap_or(true))
+            .cloned()
+            .collect();
+        for key in keys {
+            let piece = self.remove_piece(key);
+            piece.write(fc, tpb)?;
+        }
+        Ok(())
+    }
+
+    /// Discards the held pieces of a torrent.
+    pub fn remove(&mut self, tid: usize) {
+        let size = &mut self.size;
+        self.pieces.retain(|(t, _), p| {
+            if *t == tid {
+                *size -= p.len;
+            }
+            *t != tid
+        });
+    }
+
+    fn remove_piece(&mut self, key: (usize, u32)) -> CachedPiece {
+        let piece = self.pieces.remove(&key).unwrap();
+        self.size -= piece.len;
+        piece
+    }
+}
+
+impl CachedPiece {
+    /// Copies the piece's data into buf.
+    pub fn copy_to(&self, buf: &mut [u8]) {
+        for (begin, block) in &self.blocks {
+            let start = *begin as usize;
+            buf[start..start + block.data.len()].copy_from_slice(&block.data);
+        }
+    }
+
+    /// Writes the blocks to disk, combining contiguous ranges of each
+    /// file into a single vectored write.
+    pub fn write(&self, fc: &mut FileCache, tpb: &mut TempPB<'_>) -> io::Result<()> {
+        let base = self.path.as_ref().unwrap_or(&CONFIG.disk.directory);
+        let extents = self.blocks.values().flat_map(|b| {
+            b.locations
+                .iter()
+                .filter(|l| !l.padding())
+                .map(move |l| (l, &b.data[l.start..l.end]))
+        });
+        let mut run = Vec::new();
+        let mut head: Option<&Location> = None;
+        let mut end = 0;
+        for (loc, data) in extents {
+            if let Some(h) = head {
+                if h.file == loc.file && end == loc.offset {
+                    run.push(IoSlice::new(data));
+                    end += data.len() as u64;
+                    continue;
+                }
+                write_run(fc, tpb, base, h, &mut run)?;
+            }
+            head = Some(loc);
+            end = loc.offset + data.len() as u64;
+            run.push(IoSlice::new(data));
+        }
+        if let Some(h) = head {
+            write_run(fc, tpb, base, h, &mut run)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes every slice in full, like write_all, coping with partial writes
+/// of the vector.
+fn write_all_vectored<W: Write>(w: &mut W, bufs: &[IoSlice<'_>]) -> io::Result<()> {
+    // Index of the first unwritten slice, and bytes of it already written
+    let (mut idx, mut pos) = (0, 0);
+    loop {
+        while idx < bufs.len() && pos == bufs[idx].len() {
+            idx += 1;
+            pos = 0;
+        }
+        if idx == bufs.len() {
+            return Ok(());
+        }
+        let res = if pos == 0 {
+            w.write_vectored(&bufs[idx..])
+        } else {
+            w.write(&bufs[idx][pos..])
+        };
+        match res {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ));
+            }
+            Ok(mut n) => {
+                while n > 0 {
+                    let m = cmp::min(n, bufs[idx].len() - pos);
+                    pos += m;
+                    n -= m;
+                    if pos == bufs[idx].len() {
+                        idx += 1;
+                        pos = 0;
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Writes a run of contiguous data starting at the location head.
+fn write_run(
+    fc: &mut FileCache,
+    tpb: &mut TempPB<'_>,
+    base: &str,
+    head: &Location,
+    run: &mut Vec<IoSlice<'_>>,
+) -> io::Result<()> {
+    let pb = tpb.get(base);
+    pb.push(head.path());
+    let size = if head.allocate {
+        Ok(head.file_len)
+    } else {
+        Err(head.file_len)
+    };
+    fc.write_file_vectored(pb, size, head.offset, run)?;
+    run.clear();
+    Ok(())
+}
+
 impl FileCache {
     pub fn new() -> FileCache {
         FileCache {
@@ -112,26 +365,27 @@ impl FileCache {
         Ok(())
     }
 
-    pub fn write_file_range(
+    pub fn write_file_vectored(
         &mut self,
         path: &path::Path,
         size: Result<u64, u64>,
         offset: u64,
-        buf: &[u8],
+        bufs: &[IoSlice<'_>],
     ) -> io::Result<()> {
         self.ensure_exists(path, size)?;
         let entry = self.files.get_mut(path).unwrap();
         entry.file.seek(SeekFrom::Start(offset))?;
-        entry.file.write_all(&buf)?;
-        Ok(())
+        write_all_vectored(&mut entry.file, bufs)
     }
 
-    pub fn remove_file(&mut self, path: &path::Path) {
-        self.files.remove(path);
+    /// Syncs a file's data to disk, opening it if needed.
+    pub fn sync_file(&mut self, path: &path::Path) -> io::Result<()> {
+        self.ensure_exists(path, Err(0))?;
+        self.files.get_mut(path).unwrap().file.sync_data()
     }
 
-    pub fn flush_file(&mut self, path: &path::Path) {
-        self.files.get_mut(path).map(|e| e.file.sync_all().ok());
+    pub fn remove_file(&mut self, path: &path::Path) {
+        self.files.remove(path);
     }
 
     fn ensure_exists(&mut self, path: &path::Path, len: Result<u64, u64>) -> io::Result<()> {
@@ -223,4 +477,74 @@ mod tests {
         assert_eq!(buf.get(30).len(), 30);
         assert_eq!(buf.get(10).len(), 10);
     }
+
+    /// Writer which accepts at most a few bytes per call.
+    struct Trickle(Vec<u8>, usize);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = cmp::min(buf.len(), self.1);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_all_vectored() {
+        let bufs = [
+            IoSlice::new(b"ab"),
+            IoSlice::new(b""),
+            IoSlice::new(b"cdefg"),
+            IoSlice::new(b"h"),
+        ];
+        let mut w = Trickle(Vec::new(), 3);
+        write_all_vectored(&mut w, &bufs).unwrap();
+        assert_eq!(w.0, b"abcdefgh");
+
+        let mut w = Trickle(Vec::new(), 0);
+        let err = write_all_vectored(&mut w, &bufs).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        write_all_vectored(&mut w, &[IoSlice::new(b"")]).unwrap();
+    }
+
+    #[test]
+    fn test_piece_cache() {
+        use crate::torrent::Info;
+        use std::sync::Arc;
+
+        let mut info = Info::with_pieces(2);
+        info.files[0].path = "data".into();
+        info.piece_idx =
+            Info::generate_piece_idx(info.hashes.len(), info.piece_len as u64, &info.files);
+        let info = Arc::new(info);
+        let dir = std::env::temp_dir().join(format!("synapse-cache-{}", std::process::id()));
+        let base = dir.to_string_lossy().into_owned();
+
+        let mut fc = FileCache::new();
+        let mut bc = BufCache::new();
+        let (_, mut tpb, _) = bc.data();
+        let mut pc = PieceCache::new(16_384);
+        let locs = Info::block_disk_locs(&info, 0, 0);
+        pc.insert(0, 0, 0, &[1; 16_384], locs, Some(base.clone()));
+        pc.evict(&mut fc, &mut tpb).unwrap();
+        let locs = Info::block_disk_locs(&info, 1, 0);
+        pc.insert(0, 1, 0, &[2; 16_384], locs, Some(base.clone()));
+        // Piece 0 is written out to keep within the budget
+        pc.evict(&mut fc, &mut tpb).unwrap();
+        assert!(pc.take(&mut fc, &mut tpb, 0, 0, 16_384).unwrap().is_none());
+
+        let mut buf = vec![0; 16_384];
+        let piece = pc.take(&mut fc, &mut tpb, 0, 1, 16_384).unwrap().unwrap();
+        piece.copy_to(&mut buf);
+        assert!(buf.iter().all(|b| *b == 2));
+        assert_eq!(pc.size, 0);
+
+        fc.read_file_range(&dir.join("data"), 0, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 1));
+        fs::remove_dir_all(&dir).ok();
+    }
 }
@@ -5,13 +5,13 @@ use std::{cmp, fmt, fs, path, time};
 
 use fs2;
 use http_range::HttpRange;
-use sha1::{Digest, Sha1};
 use sstream::SStream;
 
-use super::{BufCache, FileCache, JOB_TIME_SLICE};
+use super::cache::TempPB;
+use super::{BufCache, FileCache, PieceCache, JOB_TIME_SLICE, STREAM_TIMEOUT};
 use crate::buffers::Buffer;
-use crate::torrent::{Info, LocIter};
-use crate::util::{hash_to_id, io_err};
+use crate::torrent::{Bitfield, Info, LocIter};
+use crate::util::{hash_to_id, io_err, sha1_hash};
 use crate::CONFIG;
 
 static MP_BOUNDARY: &str = "qxyllcqgNchqyob";
@@ -35,6 +35,8 @@ pub struct Location {
 pub enum Request {
     Write {
         tid: usize,
+        piece: u32,
+        begin: u32,
         data: Buffer,
         locations: LocIter,
         path: Option<String>,
@@ -75,11 +77,18 @@ pub enum Request {
         info: Arc<Info>,
         path: Option<String>,
         piece: u32,
+        /// Hash each block even if the piece is valid
+        hash_blocks: bool,
     },
     WriteFile {
         data: Vec<u8>,
         path: PathBuf,
     },
+    /// Makes a piece available to the streaming downloads of a torrent
+    StreamPiece {
+        tid: usize,
+        piece: u32,
+    },
     Download {
         client: SStream,
         ranges: Vec<HttpRange>,
@@ -88,20 +97,69 @@ pub enum Request {
         file_path: String,
         buf: Vec<u8>,
         buf_idx: usize,
+        stream: Option<Stream>,
     },
     FreeSpace,
     Ping,
     Shutdown,
 }
 
+/// Restricts a download of an incomplete file to the pieces
+/// which have been validated so far.
+pub struct Stream {
+    tid: usize,
+    /// ID of the stream within its torrent
+    id: usize,
+    pieces: Bitfield,
+    piece_len: u64,
+    /// Offset of the file in the torrent
+    offset: u64,
+    /// When the download started waiting on a piece
+    blocked: Option<time::Instant>,
+    /// Piece the torrent was last told the download reached
+    reported: Option<u64>,
+}
+
 pub enum Response {
-    Read { context: Ctx, data: Buffer },
-    ValidationComplete { tid: usize, invalid: Vec<u32> },
-    PieceValidated { tid: usize, piece: u32, valid: bool },
-    ValidationUpdate { tid: usize, percent: f32 },
-    Moved { tid: usize, path: String },
+    Read {
+        context: Ctx,
+        data: Buffer,
+    },
+    ValidationComplete {
+        tid: usize,
+        invalid: Vec<u32>,
+    },
+    /// Block hashes are included if the piece was invalid, or if requested
+    PieceValidated {
+        tid: usize,
+        piece: u32,
+        valid: bool,
+        blocks: Vec<[u8; 20]>,
+    },
+    ValidationUpdate {
+        tid: usize,
+        percent: f32,
+    },
+    /// A streaming download reached a piece
+    StreamPosition {
+        tid: usize,
+        stream: usize,
+        piece: u32,
+    },
+    /// A streaming download completed, failed or timed out
+    StreamDone {
+        tid: usize,
+        stream: usize,
+    },
+    Moved {
+        tid: usize,
+        path: String,
+    },
     FreeSpace(u64),
-    Error { tid: usize, err: io::Error },
+    Error {
+        tid: usize,
+        err: io::Error,
+    },
 }
 
 pub struct Ctx {
@@ -117,12 +175,22 @@ pub enum JobRes {
     Update(Request, Response),
     Done,
     Paused(Request),
+    Blocked(Request),
 }
 
 impl Request {
-    pub fn write(tid: usize, data: Buffer, locations: LocIter, path: Option<String>) -> Request {
+    pub fn write(
+        tid: usize,
+        piece: u32,
+        begin: u32,
+        data: Buffer,
+        locations: LocIter,
+        path: Option<String>,
+    ) -> Request {
         Request::Write {
             tid,
+            piece,
+            begin,
             data,
             locations,
             path,
@@ -157,12 +225,14 @@ impl Request {
         info: Arc<Info>,
         path: Option<String>,
         piece: u32,
+        hash_blocks: bool,
     ) -> Request {
         Request::ValidatePiece {
             tid,
             info,
             path,
             piece,
+            hash_blocks,
         }
     }
 
@@ -263,6 +333,63 @@ impl Request {
             file_len,
             buf,
             buf_idx: 0,
+            stream: None,
+        }
+    }
+
+    /// Restricts a download to the validated pieces of its torrent.
+    pub fn set_stream(&mut self, s: Stream) {
+        if let Request::Download { ref mut stream, .. } = *self {
+            *stream = Some(s);
+        }
+    }
+
+    /// The remaining byte ranges of a download, with the next range to
+    /// be sent last.
+    pub fn ranges(&self) -> &[HttpRange] {
+        match *self {
+            Request::Download { ref ranges, .. } => ranges,
+            _ => &[],
+        }
+    }
+
+    /// Marks a piece as available to a streaming download, returning
+    /// whether the request belongs to the piece's torrent.
+    pub fn piece_validated(&mut self, tid: usize, piece: u32) -> bool {
+        match *self {
+            Request::Download {
+                stream: Some(ref mut s),
+                ..
+            } if s.tid == tid => {
+                s.pieces.set_bit(u64::from(piece));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The torrent a streaming download belongs to and its ID.
+    pub fn stream_id(&self) -> Option<(usize, usize)> {
+        match *self {
+            Request::Download {
+                stream: Some(ref s),
+                ..
+            } => Some((s.tid, s.id)),
+            _ => None,
+        }
+    }
+
+    /// Whether a download has waited too long for a piece.
+    pub fn expired(&self) -> bool {
+        match *self {
+            Request::Download {
+                stream: Some(ref s),
+                ..
+            } => s
+                .blocked
+                .map(|t| t.elapsed() > time::Duration::from_secs(STREAM_TIMEOUT))
+                .unwrap_or(false),
+            _ => false,
         }
     }
 
@@ -277,7 +404,12 @@ impl Request {
         }
     }
 
-    pub fn execute(self, fc: &mut FileCache, bc: &mut BufCache) -> io::Result<JobRes> {
+    pub fn execute(
+        self,
+        fc: &mut FileCache,
+        pc: &mut PieceCache,
+        bc: &mut BufCache,
+    ) -> io::Result<JobRes> {
         let sd = &CONFIG.disk.session;
         let dd = &CONFIG.disk.directory;
         let (mut tb, mut tpb, mut tpb2) = bc.data();
@@ -309,28 +441,15 @@ impl Request {
                 }
             }
             Request::Write {
+                tid,
+                piece,
+                begin,
                 data,
                 locations,
                 path,
-                ..
             } => {
-                for loc in locations {
-                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
-                    pb.push(loc.path());
-                    fc.write_file_range(
-                        &pb,
-                        if loc.allocate {
-                            Ok(loc.file_len)
-                        } else {
-                            Err(loc.file_len)
-                        },
-                        loc.offset,
-                        &data[loc.start..loc.end],
-                    )?;
-                    if loc.end - loc.start != 16_384 {
-                        fc.flush_file(&pb);
-                    }
-                }
+                pc.insert(tid, piece, begin, &data, locations, path);
+                pc.evict(fc, &mut tpb)?;
             }
             Request::Read {
                 context,
@@ -340,6 +459,10 @@ impl Request {
                 ..
             } => {
                 for loc in locations {
+                    if loc.padding() {
+                        data[loc.start..loc.end].iter_mut().for_each(|b| *b = 0);
+                        continue;
+                    }
                     let pb = tpb.get(path.as_ref().unwrap_or(dd));
                     pb.push(loc.path());
                     fc.read_file_range(&pb, loc.offset, &mut data[loc.start..loc.end])?;
@@ -352,6 +475,7 @@ impl Request {
                 to,
                 target,
             } => {
+                pc.flush(fc, &mut tpb, Some(tid))?;
                 let fp = tpb.get(&from);
                 let tp = tpb2.get(&to);
                 fp.push(target.clone());
@@ -391,12 +515,19 @@ impl Request {
                 fs::rename(temp, actual)?;
             }
             Request::Delete {
+                tid,
                 hash,
                 files,
                 path,
                 artifacts,
-                ..
             } => {
+                // Blocks are only worth writing out if the files are kept
+                if !artifacts {
+                    if let Err(e) = pc.flush(fc, &mut tpb, Some(tid)) {
+                        error!("Failed to write out blocks of removed torrent: {}", e);
+                    }
+                }
+                pc.remove(tid);
                 {
                     let spb = tpb.get(sd);
                     spb.push(hash_to_id(&hash));
@@ -429,22 +560,38 @@ impl Request {
                 info,
                 path,
                 piece,
+                hash_blocks,
             } => {
                 let buf = tb.get(info.piece_len as usize);
-                let mut ctx = Sha1::new();
-                let locs = Info::piece_disk_locs(&info, piece);
-                for loc in locs {
-                    let pb = tpb.get(path.as_ref().unwrap_or(dd));
-                    pb.push(loc.path());
-                    fc.read_file_range(&pb, loc.offset, &mut buf[loc.start..loc.end])
-                        .map(|_| ctx.update(&buf[loc.start..loc.end]))
-                        .ok();
+                let len = info.piece_len(piece) as usize;
+                let base = path.as_ref().unwrap_or(dd);
+                let cached = pc.take(fc, &mut tpb, tid, piece, len)?;
+                let read = match cached {
+                    Some(ref p) => {
+                        p.copy_to(&mut buf[..len]);
+                        true
+                    }
+                    None => read_piece(fc, &mut tpb, base, &info, piece, &mut buf[..len]),
+                };
+                let valid = read && info.verify_piece(piece, &buf[..len]);
+                // Pieces are only written once valid, and must be on disk
+                // before they're reported as such
+                if valid {
+                    if let Some(ref p) = cached {
+                        p.write(fc, &mut tpb)?;
+                    }
+                    sync_piece(fc, &mut tpb, base, &info, piece)?;
                 }
-                let digest = ctx.finalize();
+                let blocks = if read && (!valid || hash_blocks) {
+                    buf[..len].chunks(16_384).map(sha1_hash).collect()
+                } else {
+                    Vec::new()
+                };
                 return Ok(JobRes::Resp(Response::PieceValidated {
                     tid,
                     piece,
-                    valid: digest[..] == info.hashes[piece as usize][..],
+                    valid,
+                    blocks,
                 }));
             }
             Request::Validate {
@@ -454,28 +601,17 @@ impl Request {
                 mut idx,
                 mut invalid,
             } => {
+                pc.flush(fc, &mut tpb, Some(tid))?;
                 let buf = tb.get(info.piece_len as usize);
                 let start = time::Instant::now();
 
                 while idx < info.pieces()
                     && start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE)
                 {
-                    let mut valid = true;
-                    let mut ctx = Sha1::new();
-                    let locs = Info::piece_disk_locs(&info, idx);
-                    for loc in locs {
-                        if !valid {
-                            break;
-                        }
-                        let pb = tpb.get(path.as_ref().unwrap_or(dd));
-                        pb.push(loc.path());
-                        valid &= fc
-                            .read_file_range(&pb, loc.offset, &mut buf[loc.start..loc.end])
-                            .map(|_| ctx.update(&buf[loc.start..loc.end]))
-                            .is_ok();
-                    }
-                    let digest = ctx.finalize();
-                    if !valid || digest[..] != info.hashes[idx as usize][..] {
+                    let len = info.piece_len(idx) as usize;
+                    let base = path.as_ref().unwrap_or(dd);
+                    let valid = read_piece(fc, &mut tpb, base, &info, idx, &mut buf[..len]);
+                    if !valid || !info.verify_piece(idx, &buf[..len]) {
                         invalid.push(idx);
                     }
 
@@ -508,6 +644,7 @@ impl Request {
                 mut buf,
                 mut buf_idx,
                 multipart,
+                mut stream,
             } => {
                 let start = time::Instant::now();
                 'outer: while start.elapsed() < time::Duration::from_millis(JOB_TIME_SLICE) {
@@ -534,7 +671,53 @@ impl Request {
                     // buf and the current range appropriately
                     let cur_range = ranges.last_mut().unwrap();
                     // Either read 128 KiB or the rest of the range
-                    let chunk_len = cmp::min(1024 * 128, cur_range.length) as usize;
+                    let mut chunk_len = cmp::min(1024 * 128, cur_range.length);
+                    // Streams are further limited to the rest of the current piece,
+                    // waiting for it to be validated if needed
+                    if let Some(ref mut s) = stream {
+                        let pos = s.offset + cur_range.start;
+                        let piece = pos / s.piece_len;
+                        if !s.pieces.has_bit(piece) {
+                            if s.blocked.is_none() {
+                                s.blocked = Some(time::Instant::now());
+                            }
+                            return Ok(JobRes::Blocked(Request::Download {
+                                client,
+                                file_path,
+                                file_len,
+                                ranges,
+                                buf,
+                                buf_idx,
+                                multipart,
+                                stream,
+                            }));
+                        }
+                        s.blocked = None;
+                        // Let the torrent move its deadlines along
+                        if s.reported != Some(piece) {
+                            s.reported = Some(piece);
+                            let resp = Response::StreamPosition {
+                                tid: s.tid,
+                                stream: s.id,
+                                piece: piece as u32,
+                            };
+                            return Ok(JobRes::Update(
+                                Request::Download {
+                                    client,
+                                    file_path,
+                                    file_len,
+                                    ranges,
+                                    buf,
+                                    buf_idx,
+                                    multipart,
+                                    stream,
+                                },
+                                resp,
+                            ));
+                        }
+                        chunk_len = cmp::min(chunk_len, (piece + 1) * s.piece_len - pos);
+                    }
+                    let chunk_len = chunk_len as usize;
                     buf.resize(chunk_len, 0);
                     buf_idx = 0;
                     fc.read_file_range(path::Path::new(&file_path), cur_range.start, &mut buf)?;
@@ -574,9 +757,10 @@ impl Request {
                     buf,
                     buf_idx,
                     multipart,
+                    stream,
                 }));
             }
-            Request::Shutdown => unreachable!(),
+            Request::StreamPiece { .. } | Request::Shutdown => unreachable!(),
         }
         Ok(JobRes::Done)
     }
@@ -601,6 +785,7 @@ impl Request {
             | Request::ValidatePiece { tid, .. }
             | Request::Delete { tid, .. }
             | Request::Move { tid, .. }
+            | Request::StreamPiece { tid, .. }
             | Request::Write { tid, .. } => Some(tid),
             Request::WriteFile { .. }
             | Request::Download { .. }
@@ -611,12 +796,68 @@ impl Request {
     }
 }
 
+/// Reads a piece into buf, returning false if any part could not be read.
+fn read_piece(
+    fc: &mut FileCache,
+    tpb: &mut TempPB<'_>,
+    base: &str,
+    info: &Arc<Info>,
+    piece: u32,
+    buf: &mut [u8],
+) -> bool {
+    for loc in Info::piece_disk_locs(info, piece) {
+        if loc.padding() {
+            buf[loc.start..loc.end].iter_mut().for_each(|b| *b = 0);
+            continue;
+        }
+        let pb = tpb.get(base);
+        pb.push(loc.path());
+        if fc
+            .read_file_range(pb, loc.offset, &mut buf[loc.start..loc.end])
+            .is_err()
+        {
+            return false;
+        }
+    }
+    true
+}
+
+/// Syncs the files a piece is stored in.
+fn sync_piece(
+    fc: &mut FileCache,
+    tpb: &mut TempPB<'_>,
+    base: &str,
+    info: &Arc<Info>,
+    piece: u32,
+) -> io::Result<()> {
+    for loc in Info::piece_disk_locs(info, piece).filter(|l| !l.padding()) {
+        let pb = tpb.get(base);
+        pb.push(loc.path());
+        fc.sync_file(pb)?;
+    }
+    Ok(())
+}
+
 impl fmt::Debug for Request {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "disk::Request")
     }
 }
 
+impl Stream {
+    pub fn new(tid: usize, id: usize, pieces: Bitfield, piece_len: u64, offset: u64) -> Stream {
+        Stream {
+            tid,
+            id,
+            pieces,
+            piece_len,
+            offset,
+            blocked: None,
+            reported: None,
+        }
+    }
+}
+
 impl Location {
     pub fn new(
         file: usize,
@@ -641,6 +882,11 @@ impl Location {
     pub fn path(&self) -> &Path {
         &self.info.files[self.file].path
     }
+
+    /// Padding files only exist to align pieces and are never stored.
+    pub fn padding(&self) -> bool {
+        self.info.files[self.file].padding
+    }
 }
 
 impl fmt::Debug for Location {
@@ -676,6 +922,8 @@ impl Response {
             Response::ValidationComplete { tid, .. }
             | Response::Moved { tid, .. }
             | Response::ValidationUpdate { tid, .. }
+            | Response::StreamPosition { tid, .. }
+            | Response::StreamDone { tid, .. }
             | Response::PieceValidated { tid, .. }
             | Response::Error { tid, .. } => tid,
             Response::FreeSpace(_) => unreachable!(),
@@ -700,3 +948,113 @@ impl Ctx {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    const PIECE_LEN: u64 = 16_384;
+
+    /// Sets up a streaming download of a two piece file, returning it
+    /// along with the client's end of the connection.
+    fn stream_download(path: &Path) -> (Request, TcpStream) {
+        let data: Vec<u8> = (0..PIECE_LEN * 2)
+            .map(|i| (i / PIECE_LEN) as u8 + 1)
+            .collect();
+        fs::write(path, &data).unwrap();
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let mut req = Request::download(
+            SStream::from_plain(conn).unwrap(),
+            vec![],
+            path.to_string_lossy().into_owned(),
+            PIECE_LEN * 2,
+        );
+        req.set_stream(Stream::new(0, 1, Bitfield::new(2), PIECE_LEN, 0));
+        req.setup().unwrap();
+        (req, client)
+    }
+
+    fn caches() -> (FileCache, PieceCache, BufCache) {
+        (FileCache::new(), PieceCache::new(0), BufCache::new())
+    }
+
+    #[test]
+    fn test_stream_blocked() {
+        let (mut fc, mut pc, mut bc) = caches();
+        let path = std::env::temp_dir().join("synapse_test_stream_blocked");
+        let (req, mut client) = stream_download(&path);
+
+        let mut req = match req.execute(&mut fc, &mut pc, &mut bc).unwrap() {
+            JobRes::Blocked(r) => r,
+            _ => panic!("Stream should wait for the first piece"),
+        };
+        assert!(!req.piece_validated(1, 0));
+        assert!(req.piece_validated(0, 0));
+
+        // The torrent is told the stream reached the piece, then it's sent
+        let mut req = match req.execute(&mut fc, &mut pc, &mut bc).unwrap() {
+            JobRes::Update(r, Response::StreamPosition { tid, stream, piece }) => {
+                assert_eq!((tid, stream, piece), (0, 1, 0));
+                r
+            }
+            _ => panic!("Stream should report its position"),
+        };
+        loop {
+            match req.execute(&mut fc, &mut pc, &mut bc).unwrap() {
+                JobRes::Paused(r) => req = r,
+                JobRes::Blocked(r) => {
+                    req = r;
+                    break;
+                }
+                _ => panic!("Stream should wait for the second piece"),
+            }
+        }
+        assert!(!req.expired());
+
+        let mut data = Vec::new();
+        client
+            .set_read_timeout(Some(time::Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = client.read(&mut buf) {
+            data.extend_from_slice(&buf[..n]);
+            if n == 0 {
+                break;
+            }
+        }
+        fs::remove_file(path).ok();
+        // Only the header and first piece were sent
+        let body = data.len() - PIECE_LEN as usize;
+        assert!(data.starts_with(b"HTTP/1.1 200 OK"));
+        assert!(data[..body].ends_with(b"\r\n\r\n"));
+        assert!(data[body..].iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn test_stream_expired() {
+        let (mut fc, mut pc, mut bc) = caches();
+        let path = std::env::temp_dir().join("synapse_test_stream_expired");
+        let (req, _client) = stream_download(&path);
+        fs::remove_file(path).ok();
+        let mut req = match req.execute(&mut fc, &mut pc, &mut bc).unwrap() {
+            JobRes::Blocked(r) => r,
+            _ => panic!("Stream should wait for the first piece"),
+        };
+        assert!(!req.expired());
+        if let Request::Download {
+            stream: Some(ref mut s),
+            ..
+        } = req
+        {
+            let timeout = time::Duration::from_secs(STREAM_TIMEOUT + 1);
+            s.blocked = Some(time::Instant::now() - timeout);
+        }
+        assert!(req.expired());
+        assert_eq!(req.stream_id(), Some((0, 1)));
+    }
+}
@@ -5,24 +5,36 @@ pub use self::job::Ctx;
 pub use self::job::Location;
 pub use self::job::Request;
 pub use self::job::Response;
+pub use self::job::Stream;
 
 use std::collections::VecDeque;
 use std::{fs, io, thread};
 
-use self::cache::{BufCache, FileCache};
+use self::cache::{BufCache, FileCache, PieceCache};
 use self::job::JobRes;
+use crate::util::UHashMap;
 use crate::{handle, CONFIG};
 
 const POLL_INT_MS: usize = 1000;
 const JOB_TIME_SLICE: u64 = 150;
+/// Seconds a streaming download may wait for a piece before being dropped
+const STREAM_TIMEOUT: u64 = 60;
 
 pub struct Disk {
     poll: amy::Poller,
     ch: handle::Handle<Request, Response>,
     jobs: amy::Receiver<Request>,
     files: FileCache,
+    /// Blocks of pieces which haven't been validated yet
+    pieces: PieceCache,
     active: VecDeque<Request>,
     sequential: VecDeque<Request>,
+    /// Streaming downloads waiting for pieces to be validated
+    blocked: Vec<Request>,
+    /// Pieces validated while a torrent has streaming downloads. The
+    /// torrent's own view lags behind, so later streams are brought up
+    /// to date when received.
+    validated: UHashMap<Vec<u32>>,
     bufs: BufCache,
 }
 
@@ -37,9 +49,12 @@ impl Disk {
             ch,
             jobs,
             files: FileCache::new(),
+            pieces: PieceCache::new(CONFIG.disk.write_cache * 1_048_576),
             bufs: BufCache::new(),
             active: VecDeque::new(),
             sequential: VecDeque::new(),
+            blocked: Vec::new(),
+            validated: UHashMap::default(),
         }
     }
 
@@ -61,14 +76,32 @@ impl Disk {
             if !self.active.is_empty() && self.handle_active() {
                 break;
             }
+            let mut i = 0;
+            while i < self.blocked.len() {
+                if self.blocked[i].expired() {
+                    debug!("Dropped a timed out download");
+                    let r = self.blocked.swap_remove(i);
+                    if let Some((tid, stream)) = r.stream_id() {
+                        self.stream_done(tid, stream);
+                    }
+                } else {
+                    i += 1;
+                }
+            }
         }
 
         // Try to finish up remaining jobs
         for job in self.active.drain(..) {
             if job.concurrent() {
-                job.execute(&mut self.files, &mut self.bufs).ok();
+                job.execute(&mut self.files, &mut self.pieces, &mut self.bufs)
+                    .ok();
             }
         }
+        // Keep the blocks of partially downloaded pieces
+        let (_, mut tpb, _) = self.bufs.data();
+        if let Err(e) = self.pieces.flush(&mut self.files, &mut tpb, None) {
+            error!("Failed to write cached pieces: {}", e);
+        }
     }
 
     fn enqueue_req(&mut self, req: Request) {
@@ -83,11 +116,28 @@ impl Disk {
         let mut rotate = 1;
         while let Some(j) = self.active.pop_front() {
             let tid = j.tid();
+            let stream = j.stream_id();
             let seq = !j.concurrent();
             let mut done = false;
-            match j.execute(&mut self.files, &mut self.bufs) {
+            match j.execute(&mut self.files, &mut self.pieces, &mut self.bufs) {
                 Ok(JobRes::Resp(r)) => {
                     done = true;
+                    match r {
+                        Response::PieceValidated {
+                            tid,
+                            piece,
+                            valid: true,
+                            ..
+                        } => {
+                            self.unblock(tid, piece);
+                        }
+                        Response::ValidationComplete { tid, ref invalid } => {
+                            if let Some(v) = self.validated.get_mut(&tid) {
+                                v.retain(|p| !invalid.contains(p));
+                            }
+                        }
+                        _ => {}
+                    }
                     self.ch.send(r).ok();
                 }
                 Ok(JobRes::Update(s, r)) => {
@@ -105,6 +155,9 @@ impl Disk {
                         self.active.push_front(s);
                     }
                 }
+                Ok(JobRes::Blocked(s)) => {
+                    self.blocked.push(s);
+                }
                 Ok(JobRes::Done) => {
                     done = true;
                 }
@@ -122,6 +175,9 @@ impl Disk {
                     self.active.push_back(r);
                 }
             }
+            if let Some((tid, id)) = stream.filter(|_| done) {
+                self.stream_done(tid, id);
+            }
             match self.poll.wait(0) {
                 Ok(_) => {
                     if self.handle_events() {
@@ -137,14 +193,63 @@ impl Disk {
         false
     }
 
+    /// Makes a newly validated piece available to streaming downloads,
+    /// resuming any which were waiting on it.
+    fn unblock(&mut self, tid: usize, piece: u32) {
+        if !self.streaming(tid) {
+            return;
+        }
+        self.validated.entry(tid).or_default().push(piece);
+        for r in self.active.iter_mut() {
+            r.piece_validated(tid, piece);
+        }
+        let mut i = 0;
+        while i < self.blocked.len() {
+            if self.blocked[i].piece_validated(tid, piece) {
+                let r = self.blocked.swap_remove(i);
+                self.active.push_back(r);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Whether a torrent has streaming downloads in progress.
+    fn streaming(&self, tid: usize) -> bool {
+        self.active
+            .iter()
+            .chain(self.blocked.iter())
+            .any(|r| r.stream_id().map(|(t, _)| t) == Some(tid))
+    }
+
+    /// Lets the torrent clear the deadlines of a finished stream,
+    /// forgetting its validated pieces once none are left.
+    fn stream_done(&mut self, tid: usize, stream: usize) {
+        self.ch.send(Response::StreamDone { tid, stream }).ok();
+        if !self.streaming(tid) {
+            self.validated.remove(&tid);
+        }
+    }
+
     pub fn handle_events(&mut self) -> bool {
         loop {
             match self.ch.recv() {
                 Ok(Request::Shutdown) => {
                     return true;
                 }
+                Ok(Request::StreamPiece { tid, piece }) => {
+                    self.unblock(tid, piece);
+                }
                 Ok(mut r) => {
                     trace!("Handling disk job!");
+                    if let Request::Delete { tid, .. } = r {
+                        self.validated.remove(&tid);
+                    }
+                    if let Some((tid, _)) = r.stream_id() {
+                        for &piece in self.validated.get(&tid).into_iter().flatten() {
+                            r.piece_validated(tid, piece);
+                        }
+                    }
                     let tid = r.tid();
                     if let Err(e) = r.setup() {
                         if let Some(t) = tid {
@@ -66,7 +66,9 @@ fn init_threads() -> io::Result<Vec<thread::JoinHandle<()>>> {
     let chj = thread::Builder::new()
         .name("control".to_string())
         .spawn(move || {
-            let throttler = throttle::Throttler::new(None, None, THROT_TOKS, &creg).unwrap();
+            let mut throttler = throttle::Throttler::new(None, None, THROT_TOKS, &creg).unwrap();
+            throttler.set_lan_ul_rate(CONFIG.net.lan_throttle_up);
+            throttler.set_lan_dl_rate(CONFIG.net.lan_throttle_down);
             let acio = acio::ACIO::new(cpoll, creg, chans).expect("Could not initialize IO");
             match control::Control::new(acio, throttler, cdb) {
                 Ok(mut c) => {
@@ -51,6 +51,7 @@ pub use crate::protocol::DHT_EXT;
 pub use crate::protocol::EXT_PROTO;
 pub use crate::protocol::UT_META_ID;
 pub use crate::protocol::UT_PEX_ID;
+pub use crate::protocol::{FAST_EXT, V2_EXT};
 
 /// Throttler max token amount
 pub const THROT_TOKS: usize = 2 * 1024 * 1024;
@@ -9,8 +9,10 @@ mod writer;
 use std::io::Write;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
 use std::sync::Arc;
+use std::time::Instant;
 use std::{fs, io, result, str, thread};
 
+use chrono::{DateTime, Utc};
 use http_range::HttpRange;
 use rustls;
 use sstream::SStream;
@@ -106,11 +108,21 @@ pub enum CtlMessage {
 
 #[derive(Debug)]
 pub enum Message {
-    UpdateTorrent(resource::CResourceUpdate),
+    UpdateTorrent {
+        resource: resource::CResourceUpdate,
+        client: usize,
+        serial: u64,
+    },
     UpdateServer {
         id: String,
         throttle_up: Option<Option<i64>>,
         throttle_down: Option<Option<i64>>,
+        ratio_limit: Option<Option<f32>>,
+        seed_time_limit: Option<Option<i64>>,
+        idle_limit: Option<Option<i64>>,
+        limit_action: Option<Option<resource::SeedAction>>,
+        schedule: Option<Vec<resource::RateProfile>>,
+        turtle: Option<bool>,
     },
     UpdateFile {
         id: String,
@@ -126,6 +138,18 @@ pub enum Message {
     Pause(String),
     Resume(String),
     Validate(Vec<String>),
+    SetDeadline {
+        id: String,
+        torrent_id: String,
+        offset: u64,
+        length: Option<u64>,
+        deadline: Instant,
+    },
+    Stream {
+        id: String,
+        torrent_id: String,
+        req: Box<disk::Request>,
+    },
     AddPeer {
         id: String,
         client: usize,
@@ -154,6 +178,23 @@ pub enum Message {
         client: usize,
         serial: u64,
     },
+    AddThrottleGroup {
+        name: String,
+        client: usize,
+        serial: u64,
+        throttle_up: Option<i64>,
+        throttle_down: Option<i64>,
+    },
+    UpdateThrottleGroup {
+        id: String,
+        throttle_up: Option<Option<i64>>,
+        throttle_down: Option<Option<i64>>,
+    },
+    RemoveThrottleGroup {
+        id: String,
+        client: usize,
+        serial: u64,
+    },
     Torrent {
         info: torrent::Info,
         client: usize,
@@ -163,6 +204,24 @@ pub enum Message {
         import: bool,
     },
     PurgeDNS,
+    ReloadBlocklist {
+        client: usize,
+        serial: u64,
+    },
+    BanPeer {
+        ip: String,
+        torrent_id: Option<String>,
+        expires: Option<DateTime<Utc>>,
+        reason: Option<String>,
+        client: usize,
+        serial: u64,
+    },
+    UnbanPeer {
+        ip: String,
+        torrent_id: Option<String>,
+        client: usize,
+        serial: u64,
+    },
 }
 
 pub struct RPC {
@@ -510,10 +569,21 @@ impl RPC {
                             }
                             None => vec![],
                         };
-                        debug!("Initiating DL");
-                        self.disk
-                            .send(disk::Request::download(conn, ranges, path, size))
-                            .ok();
+                        let req = disk::Request::download(conn, ranges, path, size);
+                        // Incomplete files are streamed as their torrent validates pieces
+                        if let Some(torrent_id) = self.processor.get_stream(&id) {
+                            debug!("Initiating streaming DL");
+                            self.ch
+                                .send(Message::Stream {
+                                    id,
+                                    torrent_id,
+                                    req: Box::new(req),
+                                })
+                                .ok();
+                        } else {
+                            debug!("Initiating DL");
+                            self.disk.send(req).ok();
+                        }
                     } else {
                         debug!("ID {} invalid, stopping DL", id);
                         conn.write(&EMPTY_HTTP_RESP).ok();
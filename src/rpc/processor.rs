@@ -16,7 +16,7 @@ use super::proto::resource::{merge_json, Resource, ResourceKind, SResourceUpdate
 use super::{CtlMessage, Message};
 use crate::disk;
 use crate::torrent::info::Info;
-use crate::util::{random_string, FHashMap, FHashSet, MHashSet, SHashMap};
+use crate::util::{group_rpc_id, random_string, FHashMap, FHashSet, MHashSet, SHashMap};
 use crate::CONFIG;
 
 const USER_DATA_FILE: &str = "rpc_user_data";
@@ -112,7 +112,7 @@ impl Processor {
             resources: SHashMap::default(),
             tokens: SHashMap::default(),
             torrent_idx: SHashMap::default(),
-            kinds: vec![MHashSet::default(); 6],
+            kinds: vec![MHashSet::default(); 7],
             db,
             user_data,
         }
@@ -132,6 +132,14 @@ impl Processor {
         }
     }
 
+    /// Returns the torrent of a file which is still being downloaded.
+    pub fn get_stream(&self, id: &str) -> Option<String> {
+        match self.resources.get(id) {
+            Some(&Resource::File(ref f)) if f.progress < 1.0 => Some(f.torrent_id.clone()),
+            _ => None,
+        }
+    }
+
     pub fn get_transfer(&mut self, tok: String) -> Option<(usize, u64, TransferKind)> {
         let mut res = None;
         let rem = match self.tokens.get(&tok) {
@@ -230,8 +238,32 @@ impl Processor {
                 resource.user_data = udo;
 
                 match self.resources.get(&resource.id) {
-                    Some(&Resource::Torrent(_)) => {
-                        rmsg = Some(Message::UpdateTorrent(resource));
+                    Some(&Resource::Torrent(_)) => match resource.throttle_group {
+                        Some(Some(ref group))
+                            if self.resources.get(group).map(|r| r.kind())
+                                != Some(ResourceKind::ThrottleGroup) =>
+                        {
+                            resp.push(SMessage::UnknownResource(Error {
+                                serial: Some(serial),
+                                reason: format!("unknown throttle group {}", group),
+                            }));
+                        }
+                        _ => {
+                            rmsg = Some(Message::UpdateTorrent {
+                                resource,
+                                client,
+                                serial,
+                            });
+                        }
+                    },
+                    Some(&Resource::ThrottleGroup(_)) => {
+                        if resource.throttle_up.is_some() || resource.throttle_down.is_some() {
+                            rmsg = Some(Message::UpdateThrottleGroup {
+                                id: resource.id,
+                                throttle_up: resource.throttle_up,
+                                throttle_down: resource.throttle_down,
+                            });
+                        }
                     }
                     Some(&Resource::File(ref f)) => {
                         // TODO: Validate other fields(make sure they're not present)
@@ -248,6 +280,12 @@ impl Processor {
                             id: resource.id,
                             throttle_up: resource.throttle_up,
                             throttle_down: resource.throttle_down,
+                            ratio_limit: resource.ratio_limit,
+                            seed_time_limit: resource.seed_time_limit,
+                            idle_limit: resource.idle_limit,
+                            limit_action: resource.limit_action,
+                            schedule: resource.schedule,
+                            turtle: resource.turtle,
                         });
                     }
                     Some(_) => {}
@@ -288,10 +326,15 @@ impl Processor {
                         serial,
                     });
                 }
+                Some(&Resource::ThrottleGroup(_)) => {
+                    rmsg = Some(Message::RemoveThrottleGroup { id, client, serial });
+                }
                 Some(_) => {
                     resp.push(SMessage::InvalidResource(Error {
                         serial: Some(serial),
-                        reason: format!("Only torrents, trackers, and peers may be removed"),
+                        reason:
+                            "Only torrents, trackers, peers, and throttle groups may be removed"
+                                .to_owned(),
                     }));
                 }
                 None => {
@@ -457,6 +500,40 @@ impl Processor {
                     reason: format!("Unknown resource {}", id),
                 })),
             },
+            CMessage::SetDeadline {
+                serial,
+                id,
+                offset,
+                length,
+                deadline,
+            } => match self.resources.get(&id) {
+                Some(&Resource::File(ref f)) => {
+                    let delay = std::time::Duration::from_millis(deadline);
+                    match std::time::Instant::now().checked_add(delay) {
+                        Some(deadline) => {
+                            rmsg = Some(Message::SetDeadline {
+                                torrent_id: f.torrent_id.clone(),
+                                id,
+                                offset,
+                                length,
+                                deadline,
+                            })
+                        }
+                        None => resp.push(SMessage::InvalidRequest(Error {
+                            serial: Some(serial),
+                            reason: format!("Invalid deadline: {}", deadline),
+                        })),
+                    }
+                }
+                Some(_) => resp.push(SMessage::InvalidResource(Error {
+                    serial: Some(serial),
+                    reason: "SET_DEADLINE not used with file".to_owned(),
+                })),
+                None => resp.push(SMessage::UnknownResource(Error {
+                    serial: Some(serial),
+                    reason: format!("Unknown resource {}", id),
+                })),
+            },
             CMessage::ValidateResources { serial, mut ids } => {
                 ids.retain(|id| match self.resources.get(id) {
                     Some(&Resource::Torrent(_)) => true,
@@ -528,6 +605,71 @@ impl Processor {
             CMessage::PurgeDns { .. } => {
                 rmsg = Some(Message::PurgeDNS);
             }
+            CMessage::ReloadBlocklist { serial } => {
+                rmsg = Some(Message::ReloadBlocklist { client, serial });
+            }
+            CMessage::BanPeer {
+                serial,
+                ip,
+                torrent_id,
+                expires,
+                reason,
+            } => {
+                if let Some(err) = self.torrent_error(serial, torrent_id.as_ref()) {
+                    resp.push(err);
+                } else {
+                    rmsg = Some(Message::BanPeer {
+                        ip,
+                        torrent_id,
+                        expires,
+                        reason,
+                        client,
+                        serial,
+                    });
+                }
+            }
+            CMessage::UnbanPeer {
+                serial,
+                ip,
+                torrent_id,
+            } => {
+                if let Some(err) = self.torrent_error(serial, torrent_id.as_ref()) {
+                    resp.push(err);
+                } else {
+                    rmsg = Some(Message::UnbanPeer {
+                        ip,
+                        torrent_id,
+                        client,
+                        serial,
+                    });
+                }
+            }
+            CMessage::AddThrottleGroup {
+                serial,
+                name,
+                throttle_up,
+                throttle_down,
+            } => {
+                if name.is_empty() {
+                    resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: "Throttle group name must not be empty".to_owned(),
+                    }));
+                } else if self.resources.contains_key(&group_rpc_id(&name)) {
+                    resp.push(SMessage::InvalidRequest(Error {
+                        serial: Some(serial),
+                        reason: format!("Throttle group {} already exists", name),
+                    }));
+                } else {
+                    rmsg = Some(Message::AddThrottleGroup {
+                        name,
+                        client,
+                        serial,
+                        throttle_up,
+                        throttle_down,
+                    });
+                }
+            }
         }
         (resp, rmsg)
     }
@@ -691,6 +833,22 @@ impl Processor {
         self.filter_subs.retain(|&(c, _), _| c != client);
     }
 
+    /// Checks that an optional id refers to a torrent.
+    fn torrent_error(&self, serial: u64, id: Option<&String>) -> Option<SMessage<'static>> {
+        let id = id?;
+        match self.resources.get(id) {
+            Some(&Resource::Torrent(_)) => None,
+            Some(_) => Some(SMessage::InvalidResource(Error {
+                serial: Some(serial),
+                reason: format!("{} is not a torrent", id),
+            })),
+            None => Some(SMessage::UnknownResource(Error {
+                serial: Some(serial),
+                reason: format!("Unknown resource {}", id),
+            })),
+        }
+    }
+
     /// Produces a map of the form Map<(Client ID, Serial), messages)>.
     fn get_matching_filters<'a, I: Iterator<Item = &'a str>>(
         &'a self,
@@ -0,0 +1,299 @@
+pub mod mse;
+pub mod utp;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use net2::{TcpBuilder, TcpStreamExt};
+
+use crate::config::EncryptionPolicy;
+use crate::throttle::Throttle;
+
+/// Wrapper type over Mio sockets, allowing for use of UDP/TCP, encryption,
+/// rate limiting, etc.
+pub struct Socket {
+    conn: Conn,
+    addr: SocketAddr,
+    pub throttle: Option<Throttle>,
+    /// In progress MSE handshake, reads and writes block until it's done
+    handshake: Option<Box<mse::Handshake>>,
+    cipher: Option<mse::Cipher>,
+    /// Data received during the handshake which has not been read yet
+    rbuf: Vec<u8>,
+    /// Handshake data which has not been sent yet
+    wbuf: Vec<u8>,
+    /// Whether or not a failed handshake should be retried in plaintext
+    fallback: bool,
+    connected: bool,
+}
+
+/// Underlying transport of a peer connection
+enum Conn {
+    Tcp(TcpStream),
+    Utp(utp::Stream),
+}
+
+const EINPROGRESS: i32 = 115;
+
+impl Socket {
+    pub fn new(addr: &SocketAddr) -> io::Result<Socket> {
+        let sock = (match *addr {
+            SocketAddr::V4(..) => TcpBuilder::new_v4(),
+            SocketAddr::V6(..) => TcpBuilder::new_v6(),
+        })?;
+        let conn = sock.to_tcp_stream()?;
+        conn.set_nonblocking(true)?;
+        if let Err(e) = conn.connect(addr) {
+            // OSX gives the AddrNotAvailable error sometimes
+            if Some(EINPROGRESS) != e.raw_os_error() && e.kind() != ErrorKind::AddrNotAvailable {
+                return Err(e);
+            }
+        }
+        Ok(Socket::from_conn(Conn::Tcp(conn), *addr))
+    }
+
+    /// Creates an outgoing uTP connection, which will be
+    /// established once attached to the uTP socket.
+    pub fn new_utp(addr: &SocketAddr) -> Socket {
+        Socket::from_utp(utp::Stream::new(*addr))
+    }
+
+    pub fn from_utp(stream: utp::Stream) -> Socket {
+        let addr = stream.addr();
+        Socket::from_conn(Conn::Utp(stream), addr)
+    }
+
+    #[cfg(test)]
+    pub fn empty() -> Socket {
+        let conn = TcpBuilder::new_v4().unwrap().to_tcp_stream().unwrap();
+        Socket::from_conn(Conn::Tcp(conn), "127.0.0.1:0".parse().unwrap())
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn from_stream(conn: TcpStream) -> io::Result<Socket> {
+        conn.set_nonblocking(true)?;
+        let addr = conn.peer_addr()?;
+        Ok(Socket::from_conn(Conn::Tcp(conn), addr))
+    }
+
+    fn from_conn(conn: Conn, addr: SocketAddr) -> Socket {
+        Socket {
+            conn,
+            addr,
+            throttle: None,
+            handshake: None,
+            cipher: None,
+            rbuf: Vec::new(),
+            wbuf: Vec::new(),
+            fallback: false,
+            connected: false,
+        }
+    }
+
+    /// Begins an MSE handshake as the initiator, using the torrent
+    /// infohash as the shared secret.
+    pub fn initiate_mse(&mut self, hash: [u8; 20], policy: EncryptionPolicy) {
+        let hs = mse::Handshake::outgoing(hash, policy, &mut self.wbuf);
+        self.handshake = Some(Box::new(hs));
+        self.fallback = policy == EncryptionPolicy::Prefer;
+    }
+
+    /// Waits for an MSE handshake as the receiver, plaintext
+    /// connections are still accepted if the policy allows it.
+    pub fn accept_mse(&mut self, keys: mse::Keys, policy: EncryptionPolicy) {
+        self.handshake = Some(Box::new(mse::Handshake::incoming(keys, policy)));
+    }
+
+    /// Whether or not an MSE handshake is still in progress.
+    pub fn negotiating(&self) -> bool {
+        self.handshake.is_some()
+    }
+
+    pub fn encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Whether or not the connection should be retried in plaintext,
+    /// which is the case if we connected but the remote did not
+    /// complete the handshake.
+    pub fn plaintext_fallback(&self) -> bool {
+        let connected = match self.conn {
+            Conn::Tcp(_) => self.connected,
+            Conn::Utp(ref s) => s.connected(),
+        };
+        self.fallback && connected && self.negotiating()
+    }
+
+    /// Returns the TCP stream if this is a TCP connection. Only TCP
+    /// sockets are polled directly, uTP streams are polled through
+    /// their shared UDP socket.
+    pub fn tcp(&self) -> Option<&TcpStream> {
+        match self.conn {
+            Conn::Tcp(ref c) => Some(c),
+            Conn::Utp(_) => None,
+        }
+    }
+
+    /// Returns the uTP stream if this is a uTP connection.
+    pub fn utp(&self) -> Option<&utp::Stream> {
+        match self.conn {
+            Conn::Tcp(_) => None,
+            Conn::Utp(ref s) => Some(s),
+        }
+    }
+
+    /// Whether or not the connection should be retried over TCP, which
+    /// is the case if an outgoing uTP connection was never established.
+    pub fn tcp_fallback(&self) -> bool {
+        match self.conn {
+            Conn::Tcp(_) => false,
+            Conn::Utp(ref s) => s.outgoing() && !s.connected(),
+        }
+    }
+
+    /// Advances the MSE handshake, returning true once it's complete.
+    pub fn negotiate(&mut self) -> io::Result<bool> {
+        self.flush_handshake()?;
+        let res = match self.handshake {
+            Some(ref mut hs) => hs.read(&mut self.conn, &mut self.wbuf)?,
+            None => return Ok(true),
+        };
+        if let Some(est) = res {
+            self.handshake = None;
+            self.cipher = est.cipher;
+            self.rbuf = est.data;
+        }
+        self.flush_handshake()?;
+        Ok(self.handshake.is_none())
+    }
+
+    /// Writes out any remaining handshake data.
+    pub fn flush_handshake(&mut self) -> io::Result<()> {
+        while !self.wbuf.is_empty() {
+            match self.conn.write(&self.wbuf) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "EOF")),
+                Ok(amnt) => {
+                    self.connected = true;
+                    self.wbuf.drain(..amnt);
+                }
+                Err(ref e)
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::NotConnected =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn recv(conn: &mut Conn, cipher: &mut Option<mse::Cipher>, buf: &mut [u8]) -> io::Result<usize> {
+    match *cipher {
+        Some(ref mut c) => c.read(conn, buf),
+        None => conn.read(buf),
+    }
+}
+
+fn send(conn: &mut Conn, cipher: &mut Option<mse::Cipher>, buf: &[u8]) -> io::Result<usize> {
+    match *cipher {
+        Some(ref mut c) => c.write(conn, buf),
+        None => conn.write(buf),
+    }
+}
+
+impl io::Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Conn::Tcp(ref mut c) => c.read(buf),
+            Conn::Utp(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl io::Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Conn::Tcp(ref mut c) => c.write(buf),
+            Conn::Utp(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Conn::Tcp(ref mut c) => c.flush(),
+            Conn::Utp(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl io::Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.rbuf.is_empty() {
+            let amnt = buf.len().min(self.rbuf.len());
+            buf[..amnt].copy_from_slice(&self.rbuf[..amnt]);
+            self.rbuf.drain(..amnt);
+            return Ok(amnt);
+        }
+        if self.negotiating() {
+            return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+        }
+        // Don't bother rate limiting small requests
+        if buf.len() < 20 {
+            return recv(&mut self.conn, &mut self.cipher, buf);
+        }
+        if let Some(ref mut t) = self.throttle {
+            match t.get_bytes_dl(buf.len()) {
+                Ok(()) => match recv(&mut self.conn, &mut self.cipher, buf) {
+                    Ok(amnt) => {
+                        t.restore_bytes_dl(buf.len() - amnt);
+                        Ok(amnt)
+                    }
+                    Err(e) => {
+                        t.restore_bytes_dl(buf.len());
+                        Err(e)
+                    }
+                },
+                Err(()) => Err(io::Error::new(ErrorKind::WouldBlock, "")),
+            }
+        } else {
+            recv(&mut self.conn, &mut self.cipher, buf)
+        }
+    }
+}
+
+impl io::Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.flush_handshake()?;
+        if self.negotiating() || !self.wbuf.is_empty() {
+            return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+        }
+        if buf.len() < 20 {
+            return send(&mut self.conn, &mut self.cipher, buf);
+        }
+        if let Some(ref mut t) = self.throttle {
+            match t.get_bytes_ul(buf.len()) {
+                Ok(()) => match send(&mut self.conn, &mut self.cipher, buf) {
+                    Ok(amnt) => {
+                        t.restore_bytes_ul(buf.len() - amnt);
+                        Ok(amnt)
+                    }
+                    Err(e) => {
+                        t.restore_bytes_ul(buf.len());
+                        Err(e)
+                    }
+                },
+                Err(()) => Err(io::Error::new(ErrorKind::WouldBlock, "")),
+            }
+        } else {
+            send(&mut self.conn, &mut self.cipher, buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush()
+    }
+}
@@ -0,0 +1,565 @@
+//! Message Stream Encryption(MSE/PE) handshake and RC4 stream cipher.
+
+use std::cell::RefCell;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::rc::Rc;
+
+use byteorder::{BigEndian, ByteOrder};
+use num_bigint::BigUint;
+use rand::{self, Rng};
+use sha1::{Digest, Sha1};
+
+use crate::config::EncryptionPolicy;
+use crate::util::{find_subseq, io_err, MHashMap};
+
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+const VC: [u8; 8] = [0u8; 8];
+const CRYPTO_PLAIN: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+const PROTO_HEADER: &[u8] = b"\x13BitTorrent protocol";
+const READ_CHUNK: usize = 1024;
+
+lazy_static! {
+    static ref P: BigUint = BigUint::parse_bytes(PRIME, 16).unwrap();
+    static ref G: BigUint = BigUint::from(2u8);
+}
+
+/// Infohashes which incoming connections may use as the shared
+/// secret, indexed by HASH('req2', SKEY).
+#[derive(Clone, Default)]
+pub struct Keys(Rc<RefCell<MHashMap<[u8; 20], [u8; 20]>>>);
+
+/// RC4 keystream, with state kept separate per direction.
+#[derive(Clone)]
+pub struct RC4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+/// Ciphers for an established encrypted connection.
+pub struct Cipher {
+    enc: RC4,
+    dec: RC4,
+    buf: Vec<u8>,
+}
+
+/// Result of a completed handshake.
+pub struct Established {
+    pub cipher: Option<Cipher>,
+    /// Payload received during the handshake, already decrypted
+    pub data: Vec<u8>,
+}
+
+pub struct Handshake {
+    state: State,
+    policy: EncryptionPolicy,
+    keys: Option<Keys>,
+    skey: [u8; 20],
+    private: BigUint,
+    secret: [u8; KEY_LEN],
+    provide: u32,
+    enc: Option<RC4>,
+    dec: Option<RC4>,
+    buf: Vec<u8>,
+}
+
+enum State {
+    /// Incoming connection which may still be plaintext
+    Detect,
+    /// Remote DH public key
+    PublicKey,
+    /// Outgoing: skipping PadB until the encrypted VC is found
+    SyncVC([u8; 8]),
+    /// Outgoing: crypto_select, len(PadD)
+    Select,
+    /// Outgoing: PadD
+    PadD { select: u32, len: usize },
+    /// Incoming: skipping PadA until HASH('req1', S) is found
+    SyncReq([u8; 20]),
+    /// Incoming: HASH('req2', SKEY) xor HASH('req3', S)
+    SKey,
+    /// Incoming: VC, crypto_provide, len(PadC)
+    Provide,
+    /// Incoming: PadC, len(IA)
+    PadC { provide: u32, len: usize },
+    /// Incoming: IA
+    IA { provide: u32, len: usize },
+}
+
+impl Keys {
+    pub fn new() -> Keys {
+        Default::default()
+    }
+
+    pub fn insert(&self, hash: [u8; 20]) {
+        self.0.borrow_mut().insert(sha1(&[b"req2", &hash]), hash);
+    }
+
+    pub fn remove(&self, hash: &[u8; 20]) {
+        self.0.borrow_mut().remove(&sha1(&[b"req2", hash]));
+    }
+
+    fn get(&self, req2: &[u8; 20]) -> Option<[u8; 20]> {
+        self.0.borrow().get(req2).cloned()
+    }
+}
+
+impl RC4 {
+    pub fn new(key: &[u8]) -> RC4 {
+        let mut s = [0u8; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        RC4 { s, i: 0, j: 0 }
+    }
+
+    /// Creates a cipher keyed from the handshake secrets, discarding
+    /// the first 1024 bytes of keystream as required by MSE.
+    fn keyed(name: &[u8], secret: &[u8], skey: &[u8]) -> RC4 {
+        let mut rc4 = RC4::new(&sha1(&[name, secret, skey]));
+        rc4.skip(1024);
+        rc4
+    }
+
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for b in data {
+            *b ^= self.next_byte();
+        }
+    }
+
+    pub fn skip(&mut self, amnt: usize) {
+        for _ in 0..amnt {
+            self.next_byte();
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        self.i = self.i.wrapping_add(1);
+        self.j = self.j.wrapping_add(self.s[self.i as usize]);
+        self.s.swap(self.i as usize, self.j as usize);
+        let idx = self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+        self.s[idx as usize]
+    }
+}
+
+impl Cipher {
+    pub fn read<R: Read>(&mut self, conn: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        let amnt = conn.read(buf)?;
+        self.dec.apply(&mut buf[..amnt]);
+        Ok(amnt)
+    }
+
+    pub fn write<W: Write>(&mut self, conn: &mut W, buf: &[u8]) -> io::Result<usize> {
+        self.buf.clear();
+        self.buf.extend_from_slice(buf);
+        let mut enc = self.enc.clone();
+        enc.apply(&mut self.buf);
+        let amnt = conn.write(&self.buf)?;
+        // Only advance the keystream by what was actually sent, the
+        // rest will be encrypted again when the write is retried.
+        if amnt == buf.len() {
+            self.enc = enc;
+        } else {
+            self.enc.skip(amnt);
+        }
+        Ok(amnt)
+    }
+}
+
+impl Handshake {
+    /// Starts a handshake as the initiator, writing our public key to out.
+    pub fn outgoing(skey: [u8; 20], policy: EncryptionPolicy, out: &mut Vec<u8>) -> Handshake {
+        let provide = match policy {
+            EncryptionPolicy::Require => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAIN,
+        };
+        let hs = Handshake::new(State::PublicKey, policy, None, skey, provide);
+        hs.write_public_key(out);
+        hs
+    }
+
+    /// Starts a handshake as the receiver, the remote may
+    /// also turn out to be using plaintext.
+    pub fn incoming(keys: Keys, policy: EncryptionPolicy) -> Handshake {
+        Handshake::new(State::Detect, policy, Some(keys), [0u8; 20], 0)
+    }
+
+    fn new(
+        state: State,
+        policy: EncryptionPolicy,
+        keys: Option<Keys>,
+        skey: [u8; 20],
+        provide: u32,
+    ) -> Handshake {
+        let mut private = [0u8; 20];
+        rand::thread_rng().fill(&mut private[..]);
+        Handshake {
+            state,
+            policy,
+            keys,
+            skey,
+            private: BigUint::from_bytes_be(&private),
+            secret: [0u8; KEY_LEN],
+            provide,
+            enc: None,
+            dec: None,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Reads all available data from conn, advancing the handshake and
+    /// appending any data which must be sent to out. Once complete the
+    /// established stream state is returned.
+    pub fn read<R: Read>(
+        &mut self,
+        conn: &mut R,
+        out: &mut Vec<u8>,
+    ) -> io::Result<Option<Established>> {
+        loop {
+            if let Some(est) = self.advance(out)? {
+                return Ok(Some(est));
+            }
+            let start = self.buf.len();
+            self.buf.resize(start + READ_CHUNK, 0);
+            let res = conn.read(&mut self.buf[start..]);
+            self.buf.truncate(start + *res.as_ref().unwrap_or(&0));
+            match res {
+                Ok(0) => return io_err("EOF"),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn advance(&mut self, out: &mut Vec<u8>) -> io::Result<Option<Established>> {
+        loop {
+            match self.state {
+                State::Detect => {
+                    if self.buf.len() < PROTO_HEADER.len() {
+                        return Ok(None);
+                    }
+                    if &self.buf[..PROTO_HEADER.len()] == PROTO_HEADER {
+                        if self.policy == EncryptionPolicy::Require {
+                            return io_err("Peer attempted an unencrypted connection");
+                        }
+                        return Ok(Some(Established {
+                            cipher: None,
+                            data: mem::replace(&mut self.buf, Vec::new()),
+                        }));
+                    }
+                    self.state = State::PublicKey;
+                }
+                State::PublicKey => {
+                    if self.buf.len() < KEY_LEN {
+                        return Ok(None);
+                    }
+                    let remote = BigUint::from_bytes_be(&self.buf[..KEY_LEN]);
+                    self.buf.drain(..KEY_LEN);
+                    self.secret = pad_key(&remote.modpow(&self.private, &P));
+                    let req1 = sha1(&[b"req1", &self.secret]);
+                    if self.keys.is_some() {
+                        self.write_public_key(out);
+                        self.state = State::SyncReq(req1);
+                    } else {
+                        let req2 = sha1(&[b"req2", &self.skey]);
+                        let req3 = sha1(&[b"req3", &self.secret]);
+                        out.extend_from_slice(&req1);
+                        out.extend(req2.iter().zip(req3.iter()).map(|(a, b)| a ^ b));
+
+                        let mut enc = RC4::keyed(b"keyA", &self.secret, &self.skey);
+                        let dec = RC4::keyed(b"keyB", &self.secret, &self.skey);
+                        // VC, crypto_provide, len(PadC), len(IA)
+                        let mut msg = [0u8; 16];
+                        BigEndian::write_u32(&mut msg[8..12], self.provide);
+                        enc.apply(&mut msg);
+                        out.extend_from_slice(&msg);
+
+                        let mut vc = VC;
+                        dec.clone().apply(&mut vc);
+                        self.enc = Some(enc);
+                        self.dec = Some(dec);
+                        self.state = State::SyncVC(vc);
+                    }
+                }
+                State::SyncVC(vc) => match find_subseq(&self.buf, &vc) {
+                    Some(idx) => {
+                        self.buf.drain(..idx + vc.len());
+                        self.dec.as_mut().unwrap().skip(vc.len());
+                        self.state = State::Select;
+                    }
+                    None if self.buf.len() >= MAX_PAD + vc.len() => {
+                        return io_err("Failed to synchronize MSE stream");
+                    }
+                    None => return Ok(None),
+                },
+                State::Select => {
+                    if self.buf.len() < 6 {
+                        return Ok(None);
+                    }
+                    let data = self.decrypt(6);
+                    let select = BigEndian::read_u32(&data[..4]);
+                    let len = BigEndian::read_u16(&data[4..]) as usize;
+                    if select.count_ones() != 1 || select & self.provide == 0 {
+                        return io_err("Peer selected an invalid MSE crypto method");
+                    }
+                    if len > MAX_PAD {
+                        return io_err("Invalid MSE padding length");
+                    }
+                    self.state = State::PadD { select, len };
+                }
+                State::PadD { select, len } => {
+                    if self.buf.len() < len {
+                        return Ok(None);
+                    }
+                    self.decrypt(len);
+                    return Ok(Some(self.establish(select, Vec::new())));
+                }
+                State::SyncReq(req1) => match find_subseq(&self.buf, &req1) {
+                    Some(idx) => {
+                        self.buf.drain(..idx + req1.len());
+                        self.state = State::SKey;
+                    }
+                    None if self.buf.len() >= MAX_PAD + req1.len() => {
+                        return io_err("Failed to synchronize MSE stream");
+                    }
+                    None => return Ok(None),
+                },
+                State::SKey => {
+                    if self.buf.len() < 20 {
+                        return Ok(None);
+                    }
+                    let req3 = sha1(&[b"req3", &self.secret]);
+                    let mut req2 = [0u8; 20];
+                    for (i, b) in self.buf.drain(..20).enumerate() {
+                        req2[i] = b ^ req3[i];
+                    }
+                    self.skey = match self.keys.as_ref().and_then(|k| k.get(&req2)) {
+                        Some(skey) => skey,
+                        None => return io_err("Peer requested an unknown torrent"),
+                    };
+                    self.enc = Some(RC4::keyed(b"keyB", &self.secret, &self.skey));
+                    self.dec = Some(RC4::keyed(b"keyA", &self.secret, &self.skey));
+                    self.state = State::Provide;
+                }
+                State::Provide => {
+                    if self.buf.len() < 14 {
+                        return Ok(None);
+                    }
+                    let data = self.decrypt(14);
+                    if data[..8] != VC {
+                        return io_err("Invalid MSE verification constant");
+                    }
+                    let provide = BigEndian::read_u32(&data[8..12]);
+                    let len = BigEndian::read_u16(&data[12..]) as usize;
+                    if len > MAX_PAD {
+                        return io_err("Invalid MSE padding length");
+                    }
+                    self.state = State::PadC { provide, len };
+                }
+                State::PadC { provide, len } => {
+                    if self.buf.len() < len + 2 {
+                        return Ok(None);
+                    }
+                    let data = self.decrypt(len + 2);
+                    let len = BigEndian::read_u16(&data[len..]) as usize;
+                    self.state = State::IA { provide, len };
+                }
+                State::IA { provide, len } => {
+                    if self.buf.len() < len {
+                        return Ok(None);
+                    }
+                    let ia = self.decrypt(len);
+                    let select = if provide & CRYPTO_RC4 != 0 {
+                        CRYPTO_RC4
+                    } else if provide & CRYPTO_PLAIN != 0
+                        && self.policy != EncryptionPolicy::Require
+                    {
+                        CRYPTO_PLAIN
+                    } else {
+                        return io_err("Peer provided no acceptable MSE crypto method");
+                    };
+                    // VC, crypto_select, len(PadD)
+                    let mut msg = [0u8; 14];
+                    BigEndian::write_u32(&mut msg[8..12], select);
+                    self.enc.as_mut().unwrap().apply(&mut msg);
+                    out.extend_from_slice(&msg);
+                    return Ok(Some(self.establish(select, ia)));
+                }
+            }
+        }
+    }
+
+    fn write_public_key(&self, out: &mut Vec<u8>) {
+        let mut rng = rand::thread_rng();
+        out.extend_from_slice(&pad_key(&G.modpow(&self.private, &P)));
+        let pad = rng.gen_range(0, MAX_PAD + 1);
+        let start = out.len();
+        out.resize(start + pad, 0);
+        rng.fill(&mut out[start..]);
+    }
+
+    fn decrypt(&mut self, amnt: usize) -> Vec<u8> {
+        let mut data: Vec<u8> = self.buf.drain(..amnt).collect();
+        self.dec.as_mut().unwrap().apply(&mut data);
+        data
+    }
+
+    fn establish(&mut self, select: u32, mut data: Vec<u8>) -> Established {
+        let mut rest = mem::replace(&mut self.buf, Vec::new());
+        let cipher = if select == CRYPTO_RC4 {
+            let mut dec = self.dec.take().unwrap();
+            dec.apply(&mut rest);
+            Some(Cipher {
+                enc: self.enc.take().unwrap(),
+                dec,
+                buf: Vec::new(),
+            })
+        } else {
+            None
+        };
+        data.extend_from_slice(&rest);
+        Established { cipher, data }
+    }
+}
+
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut ctx = Sha1::new();
+    for part in parts {
+        ctx.update(part);
+    }
+    ctx.finalize().into()
+}
+
+fn pad_key(n: &BigUint) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    let bytes = n.to_bytes_be();
+    key[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Non blocking in memory pipe
+    struct Pipe(Vec<u8>);
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+            }
+            let amnt = buf.len().min(self.0.len());
+            buf[..amnt].copy_from_slice(&self.0[..amnt]);
+            self.0.drain(..amnt);
+            Ok(amnt)
+        }
+    }
+
+    fn handshake(
+        policy: EncryptionPolicy,
+        hash: [u8; 20],
+        keys: Keys,
+    ) -> io::Result<(Established, Established)> {
+        let mut a_out = Vec::new();
+        let mut b_out = Vec::new();
+        let mut a = Handshake::outgoing(hash, policy, &mut a_out);
+        let mut b = Handshake::incoming(keys, policy);
+        assert!(b.read(&mut Pipe(a_out.split_off(0)), &mut b_out)?.is_none());
+        assert!(a.read(&mut Pipe(b_out.split_off(0)), &mut a_out)?.is_none());
+        let b_res = b.read(&mut Pipe(a_out.split_off(0)), &mut b_out)?.unwrap();
+        let a_res = a.read(&mut Pipe(b_out.split_off(0)), &mut a_out)?.unwrap();
+        Ok((a_res, b_res))
+    }
+
+    #[test]
+    fn test_rc4() {
+        let mut data = *b"Plaintext";
+        RC4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+    }
+
+    #[test]
+    fn test_handshake() {
+        let keys = Keys::new();
+        keys.insert([1u8; 20]);
+        keys.insert([2u8; 20]);
+        let (a, b) = handshake(EncryptionPolicy::Prefer, [2u8; 20], keys).unwrap();
+        let mut a = a.cipher.unwrap();
+        let mut b = b.cipher.unwrap();
+
+        let mut wire = Vec::new();
+        assert_eq!(a.write(&mut wire, b"hello").unwrap(), 5);
+        assert_ne!(&wire[..], b"hello");
+        let mut buf = [0u8; 5];
+        b.read(&mut Pipe(wire), &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let mut wire = Vec::new();
+        b.write(&mut wire, b"world").unwrap();
+        a.read(&mut Pipe(wire), &mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[test]
+    fn test_handshake_unknown_hash() {
+        let keys = Keys::new();
+        keys.insert([1u8; 20]);
+        assert!(handshake(EncryptionPolicy::Require, [2u8; 20], keys).is_err());
+    }
+
+    #[test]
+    fn test_partial_write() {
+        struct Short(Vec<u8>);
+        impl Write for Short {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let amnt = buf.len().min(3);
+                self.0.extend_from_slice(&buf[..amnt]);
+                Ok(amnt)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut a = Cipher {
+            enc: RC4::new(b"key"),
+            dec: RC4::new(b"key"),
+            buf: Vec::new(),
+        };
+        let mut wire = Short(Vec::new());
+        let data = b"partial write";
+        let mut idx = 0;
+        while idx < data.len() {
+            idx += a.write(&mut wire, &data[idx..]).unwrap();
+        }
+        RC4::new(b"key").apply(&mut wire.0);
+        assert_eq!(&wire.0[..], &data[..]);
+    }
+
+    #[test]
+    fn test_detect_plaintext() {
+        let mut hs = Handshake::incoming(Keys::new(), EncryptionPolicy::Prefer);
+        let mut data = PROTO_HEADER.to_vec();
+        data.extend_from_slice(&[0u8; 48]);
+        let est = hs
+            .read(&mut Pipe(data.clone()), &mut Vec::new())
+            .unwrap()
+            .unwrap();
+        assert!(est.cipher.is_none());
+        assert_eq!(est.data, data);
+
+        let mut hs = Handshake::incoming(Keys::new(), EncryptionPolicy::Require);
+        assert!(hs.read(&mut Pipe(data), &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,899 @@
+//! Micro Transport Protocol(uTP, BEP 29) streams multiplexed
+//! over a single UDP socket, using LEDBAT congestion control.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+use rand::random;
+
+use crate::util::FHashMap;
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 20;
+/// Maximum payload per packet, kept small enough to avoid fragmentation
+const MSS: usize = 1400;
+const RECV_CHUNK: usize = 4096;
+/// Amount of unsent data buffered before writes block
+const SEND_BUF: usize = 256 * 1024;
+/// Receive window advertised to the remote
+const RECV_BUF: usize = 1024 * 1024;
+/// Maximum distance ahead of the ack number out of order packets are kept
+const MAX_OOO: u16 = 1024;
+/// LEDBAT target queuing delay in microseconds
+const TARGET_DELAY: f64 = 100_000.0;
+/// Maximum congestion window growth per RTT in bytes
+const MAX_CWND_INCREASE: f64 = 3000.0;
+const MIN_CWND: f64 = (2 * MSS) as f64;
+const INIT_CWND: f64 = (4 * MSS) as f64;
+const MAX_CWND: f64 = RECV_BUF as f64;
+const INIT_RTO_MS: u64 = 1000;
+const MIN_RTO_MS: u64 = 500;
+const MAX_RTO_MS: u64 = 30_000;
+const MAX_SYN_RETRIES: u8 = 3;
+const MAX_RETRIES: u8 = 6;
+const DUP_ACK_LIMIT: u8 = 3;
+/// Duration after which the base delay is resampled, allowing for route changes
+const BASE_DELAY_WINDOW: Duration = Duration::from_secs(120);
+/// Stream IDs are allocated above this so they never clash with poller IDs
+const ID_OFFSET: usize = std::usize::MAX / 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Type {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// Outgoing stream which has not been attached to a socket yet
+    Idle,
+    SynSent,
+    Connected,
+    Reset,
+    TimedOut,
+}
+
+#[derive(Debug, PartialEq)]
+struct Header {
+    ty: Type,
+    conn_id: u16,
+    ts: u32,
+    ts_diff: u32,
+    wnd: u32,
+    seq: u16,
+    ack: u16,
+}
+
+struct Packet {
+    ty: Type,
+    seq: u16,
+    data: Vec<u8>,
+    sent: Instant,
+    transmissions: u8,
+}
+
+/// State of a single connection, shared between the stream
+/// and the socket which demultiplexes packets for it.
+struct Conn {
+    id: Option<usize>,
+    addr: SocketAddr,
+    sock: Option<Rc<UdpSocket>>,
+    state: State,
+    outgoing: bool,
+    established: bool,
+    /// Set once the stream is dropped, a FIN is sent after pending data
+    closed: bool,
+    fin_sent: bool,
+    recv_id: u16,
+    send_id: u16,
+    seq_nr: u16,
+    ack_nr: u16,
+    sendq: VecDeque<u8>,
+    unacked: VecDeque<Packet>,
+    in_flight: usize,
+    cwnd: f64,
+    peer_wnd: usize,
+    srtt: u64,
+    rtt_var: u64,
+    rto: u64,
+    retries: u8,
+    dup_acks: u8,
+    syn_sent: Instant,
+    reply_micro: u32,
+    base_delay: Option<u32>,
+    base_delay_at: Instant,
+    recvq: VecDeque<u8>,
+    ooo: FHashMap<u16, Vec<u8>>,
+    fin: Option<u16>,
+    eof: bool,
+    need_ack: bool,
+}
+
+/// A single uTP connection, which can be read from and written to like a
+/// nonblocking TCP stream. Outgoing streams must be attached to a `Utp`
+/// socket before any data is sent.
+pub struct Stream {
+    conn: Rc<RefCell<Conn>>,
+}
+
+pub enum Event {
+    /// A new incoming connection
+    Incoming(Stream),
+    /// An attached stream may be readable or writable
+    Ready(usize),
+}
+
+/// UDP socket over which all uTP streams are multiplexed.
+pub struct Utp {
+    sock: Rc<UdpSocket>,
+    conns: FHashMap<(SocketAddr, u16), Rc<RefCell<Conn>>>,
+    buf: Vec<u8>,
+    next_id: usize,
+}
+
+impl Utp {
+    pub fn new(port: u16) -> io::Result<Utp> {
+        let sock = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port))?;
+        sock.set_nonblocking(true)?;
+        Ok(Utp {
+            sock: Rc::new(sock),
+            conns: FHashMap::default(),
+            buf: vec![0; RECV_CHUNK],
+            next_id: ID_OFFSET,
+        })
+    }
+
+    #[cfg(test)]
+    fn local_addr(&self) -> SocketAddr {
+        self.sock.local_addr().unwrap()
+    }
+
+    /// Assigns an ID to the stream, which is used in `Event::Ready`.
+    /// Outgoing streams will begin connecting.
+    pub fn attach(&mut self, stream: &Stream) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut c = stream.conn.borrow_mut();
+        c.id = Some(id);
+        if c.state == State::Idle {
+            let recv_id = loop {
+                let r: u16 = random();
+                if !self.conns.contains_key(&(c.addr, r)) {
+                    break r;
+                }
+            };
+            c.sock = Some(self.sock.clone());
+            c.connect(recv_id);
+            self.conns.insert((c.addr, recv_id), stream.conn.clone());
+        }
+        id
+    }
+
+    /// Processes all pending packets.
+    pub fn readable(&mut self, events: &mut Vec<Event>) {
+        let mut ready = Vec::new();
+        loop {
+            match self.sock.recv_from(&mut self.buf) {
+                Ok((len, addr)) => {
+                    let buf = self.buf[..len].to_vec();
+                    self.process(addr, &buf, &mut ready, events);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                // ICMP errors for previously sent packets may show up here
+                Err(e) => debug!("uTP receive error: {}", e),
+            }
+        }
+        for conn in self.conns.values() {
+            let mut c = conn.borrow_mut();
+            if c.need_ack {
+                c.send_state();
+            }
+        }
+        ready.sort();
+        ready.dedup();
+        events.extend(ready.into_iter().map(Event::Ready));
+    }
+
+    /// Handles retransmissions and timeouts, and cleans up closed
+    /// streams. Should be called regularly.
+    pub fn tick(&mut self, events: &mut Vec<Event>) {
+        let mut dead = Vec::new();
+        for (key, conn) in &self.conns {
+            let orphaned = Rc::strong_count(conn) == 1;
+            let mut c = conn.borrow_mut();
+            if c.tick() {
+                if let Some(id) = c.id {
+                    events.push(Event::Ready(id));
+                }
+            }
+            if orphaned && c.finished() {
+                dead.push(*key);
+            }
+        }
+        for key in dead {
+            self.conns.remove(&key);
+        }
+    }
+
+    fn process(
+        &mut self,
+        addr: SocketAddr,
+        data: &[u8],
+        ready: &mut Vec<usize>,
+        events: &mut Vec<Event>,
+    ) {
+        let (hdr, payload) = match Header::parse(data) {
+            Some(p) => p,
+            None => return,
+        };
+        if let Some(conn) = self.conns.get(&(addr, hdr.conn_id)) {
+            let mut c = conn.borrow_mut();
+            c.handle(&hdr, payload);
+            if let Some(id) = c.id {
+                ready.push(id);
+            }
+        } else if hdr.ty == Type::Syn {
+            let recv_id = hdr.conn_id.wrapping_add(1);
+            if let Some(conn) = self.conns.get(&(addr, recv_id)) {
+                // Our ack was lost, the remote is retrying
+                conn.borrow_mut().need_ack = true;
+                return;
+            }
+            let mut c = Conn::new(addr, false);
+            c.sock = Some(self.sock.clone());
+            c.accept(&hdr);
+            let conn = Rc::new(RefCell::new(c));
+            self.conns.insert((addr, recv_id), conn.clone());
+            events.push(Event::Incoming(Stream { conn }));
+        }
+    }
+}
+
+impl AsRawFd for Utp {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl Stream {
+    /// Creates an unconnected outgoing stream.
+    pub fn new(addr: SocketAddr) -> Stream {
+        Stream {
+            conn: Rc::new(RefCell::new(Conn::new(addr, true))),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.conn.borrow().addr
+    }
+
+    /// Whether or not the connection was ever established.
+    pub fn connected(&self) -> bool {
+        self.conn.borrow().established
+    }
+
+    pub fn outgoing(&self) -> bool {
+        self.conn.borrow().outgoing
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut c = self.conn.borrow_mut();
+        if !c.recvq.is_empty() {
+            let full = c.recvq.len() + 2 * MSS > RECV_BUF;
+            let amnt = c.recvq.read(buf)?;
+            // Let the remote know the window has reopened
+            if full {
+                c.send_state();
+            }
+            return Ok(amnt);
+        }
+        match c.state {
+            State::Reset => Err(io::Error::new(ErrorKind::ConnectionReset, "uTP reset")),
+            State::TimedOut => Err(io::Error::new(ErrorKind::TimedOut, "uTP timeout")),
+            _ if c.eof => Ok(0),
+            _ => Err(io::Error::new(ErrorKind::WouldBlock, "")),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut c = self.conn.borrow_mut();
+        match c.state {
+            State::Reset => return Err(io::Error::new(ErrorKind::ConnectionReset, "uTP reset")),
+            State::TimedOut => return Err(io::Error::new(ErrorKind::TimedOut, "uTP timeout")),
+            _ => {}
+        }
+        let amnt = SEND_BUF.saturating_sub(c.sendq.len()).min(buf.len());
+        if amnt == 0 && !buf.is_empty() {
+            return Err(io::Error::new(ErrorKind::WouldBlock, ""));
+        }
+        c.sendq.extend(&buf[..amnt]);
+        c.flush();
+        Ok(amnt)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let mut c = self.conn.borrow_mut();
+        c.closed = true;
+        c.flush();
+    }
+}
+
+impl Conn {
+    fn new(addr: SocketAddr, outgoing: bool) -> Conn {
+        let now = Instant::now();
+        Conn {
+            id: None,
+            addr,
+            sock: None,
+            state: State::Idle,
+            outgoing,
+            established: false,
+            closed: false,
+            fin_sent: false,
+            recv_id: 0,
+            send_id: 0,
+            seq_nr: 1,
+            ack_nr: 0,
+            sendq: VecDeque::new(),
+            unacked: VecDeque::new(),
+            in_flight: 0,
+            cwnd: INIT_CWND,
+            peer_wnd: RECV_BUF,
+            srtt: 0,
+            rtt_var: 0,
+            rto: INIT_RTO_MS,
+            retries: 0,
+            dup_acks: 0,
+            syn_sent: now,
+            reply_micro: 0,
+            base_delay: None,
+            base_delay_at: now,
+            recvq: VecDeque::new(),
+            ooo: FHashMap::default(),
+            fin: None,
+            eof: false,
+            need_ack: false,
+        }
+    }
+
+    fn connect(&mut self, recv_id: u16) {
+        self.recv_id = recv_id;
+        self.send_id = recv_id.wrapping_add(1);
+        self.state = State::SynSent;
+        self.send_syn();
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+    }
+
+    fn accept(&mut self, syn: &Header) {
+        self.recv_id = syn.conn_id.wrapping_add(1);
+        self.send_id = syn.conn_id;
+        self.seq_nr = random();
+        self.ack_nr = syn.seq;
+        self.reply_micro = micros().wrapping_sub(syn.ts);
+        self.peer_wnd = syn.wnd as usize;
+        self.state = State::Connected;
+        self.established = true;
+        self.send_state();
+    }
+
+    fn handle(&mut self, hdr: &Header, payload: &[u8]) {
+        self.reply_micro = micros().wrapping_sub(hdr.ts);
+        self.peer_wnd = hdr.wnd as usize;
+        match hdr.ty {
+            Type::Reset => {
+                self.state = State::Reset;
+                return;
+            }
+            Type::Syn => {
+                self.need_ack = true;
+                return;
+            }
+            _ => {}
+        }
+        match self.state {
+            State::SynSent if hdr.ty == Type::State => {
+                self.state = State::Connected;
+                self.established = true;
+                self.retries = 0;
+                // The remote's first data packet will use the same seq
+                self.ack_nr = hdr.seq.wrapping_sub(1);
+            }
+            State::Connected => {}
+            _ => return,
+        }
+        self.process_ack(hdr, payload.is_empty());
+        match hdr.ty {
+            Type::Data => self.recv_data(hdr.seq, payload),
+            Type::Fin => {
+                self.fin = Some(hdr.seq);
+                self.need_ack = true;
+                self.drain_ooo();
+            }
+            _ => {}
+        }
+        self.flush();
+    }
+
+    fn process_ack(&mut self, hdr: &Header, empty: bool) {
+        let mut acked = 0;
+        let mut rtt = None;
+        while let Some(seq) = self.unacked.front().map(|p| p.seq) {
+            if seq_after(seq, hdr.ack) {
+                break;
+            }
+            let p = self.unacked.pop_front().unwrap();
+            acked += p.data.len();
+            if p.transmissions == 1 {
+                rtt = Some(p.sent.elapsed());
+            }
+        }
+        if acked > 0 || rtt.is_some() {
+            self.in_flight -= acked;
+            self.dup_acks = 0;
+            self.retries = 0;
+            if let Some(rtt) = rtt {
+                self.update_rto(rtt);
+            }
+            self.ledbat(acked, hdr.ts_diff);
+        } else if empty && hdr.ty == Type::State {
+            let lost = self
+                .unacked
+                .front()
+                .map(|p| p.seq.wrapping_sub(1) == hdr.ack)
+                .unwrap_or(false);
+            if lost {
+                self.dup_acks += 1;
+                if self.dup_acks == DUP_ACK_LIMIT {
+                    self.dup_acks = 0;
+                    self.cwnd = (self.cwnd / 2.0).max(MIN_CWND);
+                    self.retransmit();
+                }
+            }
+        }
+    }
+
+    fn update_rto(&mut self, rtt: Duration) {
+        let rtt = rtt.as_millis() as u64;
+        if self.srtt == 0 {
+            self.srtt = rtt;
+            self.rtt_var = rtt / 2;
+        } else {
+            let diff = if rtt > self.srtt {
+                rtt - self.srtt
+            } else {
+                self.srtt - rtt
+            };
+            self.rtt_var = (3 * self.rtt_var + diff) / 4;
+            self.srtt = (7 * self.srtt + rtt) / 8;
+        }
+        self.rto = cmp::max(
+            cmp::min(self.srtt + 4 * self.rtt_var, MAX_RTO_MS),
+            MIN_RTO_MS,
+        );
+    }
+
+    /// Adjusts the congestion window based on the queuing delay
+    /// our packets experienced.
+    fn ledbat(&mut self, acked: usize, delay: u32) {
+        if delay == 0 {
+            return;
+        }
+        let base = match self.base_delay {
+            Some(base)
+                if self.base_delay_at.elapsed() < BASE_DELAY_WINDOW
+                    && delay.wrapping_sub(base) < std::u32::MAX / 2 =>
+            {
+                base
+            }
+            _ => {
+                self.base_delay = Some(delay);
+                self.base_delay_at = Instant::now();
+                delay
+            }
+        };
+        let queuing = f64::from(delay - base);
+        let off_target = (TARGET_DELAY - queuing) / TARGET_DELAY;
+        let gain = MAX_CWND_INCREASE * off_target * acked as f64 / self.cwnd;
+        self.cwnd = (self.cwnd + gain).min(MAX_CWND).max(MIN_CWND);
+    }
+
+    fn recv_data(&mut self, seq: u16, payload: &[u8]) {
+        self.need_ack = true;
+        let next = self.ack_nr.wrapping_add(1);
+        if seq == next {
+            self.recvq.extend(payload);
+            self.ack_nr = seq;
+            self.drain_ooo();
+        } else if seq_after(seq, next) && seq.wrapping_sub(next) < MAX_OOO {
+            self.ooo.entry(seq).or_insert_with(|| payload.to_vec());
+        }
+    }
+
+    fn drain_ooo(&mut self) {
+        loop {
+            let next = self.ack_nr.wrapping_add(1);
+            if let Some(data) = self.ooo.remove(&next) {
+                self.recvq.extend(data);
+                self.ack_nr = next;
+            } else if self.fin == Some(next) {
+                self.ack_nr = next;
+                self.eof = true;
+                return;
+            } else {
+                return;
+            }
+        }
+    }
+
+    /// Sends as much queued data as the windows allow, followed by a FIN
+    /// once the stream has been closed.
+    fn flush(&mut self) {
+        if self.state != State::Connected || self.fin_sent {
+            return;
+        }
+        let wnd = (self.cwnd as usize).min(self.peer_wnd.max(MSS));
+        while !self.sendq.is_empty() {
+            let len = self.sendq.len().min(MSS);
+            if self.in_flight > 0 && self.in_flight + len > wnd {
+                return;
+            }
+            let data: Vec<u8> = self.sendq.drain(..len).collect();
+            self.in_flight += len;
+            self.queue_packet(Type::Data, data);
+        }
+        if self.closed {
+            self.fin_sent = true;
+            self.queue_packet(Type::Fin, Vec::new());
+        }
+    }
+
+    fn queue_packet(&mut self, ty: Type, data: Vec<u8>) {
+        let mut p = Packet {
+            ty,
+            seq: self.seq_nr,
+            data,
+            sent: Instant::now(),
+            transmissions: 0,
+        };
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(&mut p);
+        self.unacked.push_back(p);
+    }
+
+    fn retransmit(&mut self) {
+        if let Some(mut p) = self.unacked.pop_front() {
+            self.transmit(&mut p);
+            self.unacked.push_front(p);
+        }
+    }
+
+    fn transmit(&mut self, p: &mut Packet) {
+        p.sent = Instant::now();
+        p.transmissions = p.transmissions.saturating_add(1);
+        let mut buf = vec![0u8; HEADER_LEN + p.data.len()];
+        self.header(p.ty, p.seq).write(&mut buf);
+        buf[HEADER_LEN..].copy_from_slice(&p.data);
+        self.send(&buf);
+        self.need_ack = false;
+    }
+
+    fn send_syn(&mut self) {
+        let mut hdr = self.header(Type::Syn, self.seq_nr);
+        hdr.conn_id = self.recv_id;
+        hdr.ack = 0;
+        let mut buf = [0u8; HEADER_LEN];
+        hdr.write(&mut buf);
+        self.syn_sent = Instant::now();
+        self.send(&buf);
+    }
+
+    fn send_state(&mut self) {
+        let mut buf = [0u8; HEADER_LEN];
+        self.header(Type::State, self.seq_nr).write(&mut buf);
+        self.send(&buf);
+        self.need_ack = false;
+    }
+
+    fn header(&self, ty: Type, seq: u16) -> Header {
+        Header {
+            ty,
+            conn_id: self.send_id,
+            ts: micros(),
+            ts_diff: self.reply_micro,
+            wnd: RECV_BUF.saturating_sub(self.recvq.len()) as u32,
+            seq,
+            ack: self.ack_nr,
+        }
+    }
+
+    fn send(&self, buf: &[u8]) {
+        if let Some(ref sock) = self.sock {
+            // Lost packets are handled by retransmission
+            if let Err(e) = sock.send_to(buf, self.addr) {
+                debug!("Failed to send uTP packet to {}: {}", self.addr, e);
+            }
+        }
+    }
+
+    /// Retransmits timed out packets, returning true if the
+    /// connection has failed.
+    fn tick(&mut self) -> bool {
+        let rto = Duration::from_millis(self.rto);
+        match self.state {
+            State::SynSent if self.syn_sent.elapsed() > rto => {
+                if self.retries >= MAX_SYN_RETRIES {
+                    self.state = State::TimedOut;
+                    return true;
+                }
+                self.retries += 1;
+                self.rto = (self.rto * 2).min(MAX_RTO_MS);
+                self.seq_nr = self.seq_nr.wrapping_sub(1);
+                self.send_syn();
+                self.seq_nr = self.seq_nr.wrapping_add(1);
+            }
+            State::Connected => {
+                let expired = self
+                    .unacked
+                    .front()
+                    .map(|p| p.sent.elapsed() > rto)
+                    .unwrap_or(false);
+                if expired {
+                    if self.retries >= MAX_RETRIES {
+                        self.state = State::TimedOut;
+                        return true;
+                    }
+                    self.retries += 1;
+                    self.rto = (self.rto * 2).min(MAX_RTO_MS);
+                    self.cwnd = MIN_CWND;
+                    self.retransmit();
+                }
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// Whether or not the connection can be discarded once its
+    /// stream is gone.
+    fn finished(&self) -> bool {
+        self.state != State::Connected || (self.fin_sent && self.unacked.is_empty())
+    }
+}
+
+impl Header {
+    fn parse(buf: &[u8]) -> Option<(Header, &[u8])> {
+        if buf.len() < HEADER_LEN || buf[0] & 0xF != VERSION {
+            return None;
+        }
+        let ty = match buf[0] >> 4 {
+            0 => Type::Data,
+            1 => Type::Fin,
+            2 => Type::State,
+            3 => Type::Reset,
+            4 => Type::Syn,
+            _ => return None,
+        };
+        let hdr = Header {
+            ty,
+            conn_id: BigEndian::read_u16(&buf[2..4]),
+            ts: BigEndian::read_u32(&buf[4..8]),
+            ts_diff: BigEndian::read_u32(&buf[8..12]),
+            wnd: BigEndian::read_u32(&buf[12..16]),
+            seq: BigEndian::read_u16(&buf[16..18]),
+            ack: BigEndian::read_u16(&buf[18..20]),
+        };
+        // Skip over any extensions, e.g. selective acks
+        let mut ext = buf[1];
+        let mut pos = HEADER_LEN;
+        while ext != 0 {
+            if buf.len() < pos + 2 {
+                return None;
+            }
+            ext = buf[pos];
+            pos += 2 + buf[pos + 1] as usize;
+            if buf.len() < pos {
+                return None;
+            }
+        }
+        Some((hdr, &buf[pos..]))
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[0] = (self.ty as u8) << 4 | VERSION;
+        buf[1] = 0;
+        BigEndian::write_u16(&mut buf[2..4], self.conn_id);
+        BigEndian::write_u32(&mut buf[4..8], self.ts);
+        BigEndian::write_u32(&mut buf[8..12], self.ts_diff);
+        BigEndian::write_u32(&mut buf[12..16], self.wnd);
+        BigEndian::write_u16(&mut buf[16..18], self.seq);
+        BigEndian::write_u16(&mut buf[18..20], self.ack);
+    }
+}
+
+/// Whether or not sequence number a comes after b, accounting for wrapping.
+fn seq_after(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+fn micros() -> u32 {
+    let d = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    d.as_secs()
+        .wrapping_mul(1_000_000)
+        .wrapping_add(u64::from(d.subsec_micros())) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn pump(a: &mut Utp, b: &mut Utp, events: &mut Vec<Event>) {
+        for _ in 0..10 {
+            a.readable(events);
+            b.readable(events);
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    fn accept(events: &mut Vec<Event>) -> Stream {
+        let pos = events
+            .iter()
+            .position(|e| match e {
+                Event::Incoming(_) => true,
+                _ => false,
+            })
+            .unwrap();
+        match events.remove(pos) {
+            Event::Incoming(s) => s,
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_all(s: &mut Stream) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+        while let Ok(amnt) = s.read(&mut buf) {
+            if amnt == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..amnt]);
+        }
+        data
+    }
+
+    #[test]
+    fn test_header() {
+        let hdr = Header {
+            ty: Type::Syn,
+            conn_id: 1234,
+            ts: 5,
+            ts_diff: 6,
+            wnd: 7,
+            seq: 8,
+            ack: 9,
+        };
+        let mut buf = [0u8; HEADER_LEN + 3];
+        hdr.write(&mut buf);
+        buf[HEADER_LEN..].copy_from_slice(b"abc");
+        let (parsed, payload) = Header::parse(&buf).unwrap();
+        assert_eq!(parsed, hdr);
+        assert_eq!(payload, b"abc");
+
+        // Extension headers are skipped
+        let mut buf = vec![0u8; HEADER_LEN];
+        hdr.write(&mut buf);
+        buf[1] = 1;
+        buf.extend_from_slice(&[0, 4, 0, 0, 0, 0, 1]);
+        assert_eq!(Header::parse(&buf).unwrap().1, &[1]);
+        buf.truncate(HEADER_LEN + 3);
+        assert!(Header::parse(&buf).is_none());
+    }
+
+    #[test]
+    fn test_seq_after() {
+        assert!(seq_after(2, 1));
+        assert!(!seq_after(1, 2));
+        assert!(!seq_after(1, 1));
+        assert!(seq_after(0, 0xFFFF));
+        assert!(!seq_after(0xFFFF, 0));
+    }
+
+    #[test]
+    fn test_reorder() {
+        let mut c = Conn::new("127.0.0.1:1".parse().unwrap(), true);
+        c.state = State::Connected;
+        c.ack_nr = 0xFFFE;
+        c.recv_data(1, b"c");
+        c.recv_data(0, b"b");
+        assert!(c.recvq.is_empty());
+        c.recv_data(0xFFFF, b"a");
+        assert_eq!(c.recvq.iter().cloned().collect::<Vec<_>>(), b"abc");
+        assert_eq!(c.ack_nr, 1);
+        // Duplicates are ignored
+        c.recv_data(0, b"b");
+        assert_eq!(c.recvq.len(), 3);
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut a = Utp::new(0).unwrap();
+        let mut b = Utp::new(0).unwrap();
+        let addr = SocketAddr::new("127.0.0.1".parse().unwrap(), b.local_addr().port());
+
+        let mut out = Stream::new(addr);
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        assert_eq!(out.write(&data).unwrap(), data.len());
+        let oid = a.attach(&out);
+        assert_eq!(out.conn.borrow().id, Some(oid));
+        assert!(!out.connected());
+
+        let mut events = Vec::new();
+        pump(&mut a, &mut b, &mut events);
+        assert!(out.connected());
+        let mut inc = accept(&mut events);
+        assert!(!inc.outgoing());
+        assert_eq!(Some(b.attach(&inc)), inc.conn.borrow().id);
+
+        let mut recv = Vec::new();
+        for _ in 0..100 {
+            pump(&mut a, &mut b, &mut events);
+            recv.extend(read_all(&mut inc));
+            if recv.len() == data.len() {
+                break;
+            }
+        }
+        assert_eq!(recv, data);
+
+        inc.write_all(b"reply").unwrap();
+        pump(&mut a, &mut b, &mut events);
+        assert_eq!(read_all(&mut out), b"reply");
+
+        drop(out);
+        pump(&mut a, &mut b, &mut events);
+        assert_eq!(inc.read(&mut [0u8; 10]).unwrap(), 0);
+        a.tick(&mut events);
+        assert!(a.conns.is_empty());
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let mut a = Utp::new(0).unwrap();
+        let mut s = Stream::new("127.0.0.1:1".parse().unwrap());
+        a.attach(&s);
+        {
+            let mut c = s.conn.borrow_mut();
+            c.retries = MAX_SYN_RETRIES;
+            c.syn_sent -= Duration::from_secs(60);
+        }
+        let mut events = Vec::new();
+        a.tick(&mut events);
+        match events[0] {
+            Event::Ready(_) => {}
+            _ => panic!("expected ready event"),
+        }
+        let err = s.read(&mut [0u8; 10]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+}
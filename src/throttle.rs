@@ -1,6 +1,7 @@
 use amy::Registrar;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
 use std::rc::Rc;
 
 /// Creates a throttler from which sub throttles may be created.
@@ -12,9 +13,17 @@ pub struct Throttler {
     fid: usize,
     dl_data: Rc<RefCell<ThrottleData>>,
     ul_data: Rc<RefCell<ThrottleData>>,
+    dl_lan: Rc<RefCell<ThrottleData>>,
+    ul_lan: Rc<RefCell<ThrottleData>>,
 }
 
 const URATE: usize = 15;
+/// Virtual time advances by bytes scaled by this over the weight, which
+/// divides evenly by each priority
+const WEIGHT_SCALE: u64 = 60;
+/// How far, in virtual time, a torrent may get ahead of the earliest
+/// backlogged torrent before it yields
+const WFQ_SLACK: u64 = 65_536 * WEIGHT_SCALE;
 
 impl Throttler {
     /// Creates a new throttler and sets two timers on reg,
@@ -35,6 +44,8 @@ impl Throttler {
             fid,
             ul_data: Rc::new(RefCell::new(ut)),
             dl_data: Rc::new(RefCell::new(dt)),
+            ul_lan: Rc::new(RefCell::new(ThrottleData::new(None, max_tokens))),
+            dl_lan: Rc::new(RefCell::new(ThrottleData::new(None, max_tokens))),
         })
     }
 
@@ -44,6 +55,14 @@ impl Throttler {
         (ul, dl)
     }
 
+    /// Updates the LAN tokens, which must happen along with update,
+    /// returning the bytes used by LAN peers.
+    pub fn update_lan(&self) -> (u64, u64) {
+        let ul = self.ul_lan.borrow_mut().add_tokens();
+        let dl = self.dl_lan.borrow_mut().add_tokens();
+        (ul, dl)
+    }
+
     pub fn get_throttle(&self, id: usize) -> Throttle {
         Throttle {
             ul_data: self.ul_data.clone(),
@@ -56,10 +75,25 @@ impl Throttler {
                 None,
                 self.dl_data.borrow().max_tokens,
             ))),
+            ul_lan: self.ul_lan.clone(),
+            dl_lan: self.dl_lan.clone(),
             id,
         }
     }
 
+    /// Creates a group whose rates are shared by every throttle
+    /// placed in it.
+    pub fn new_group(&self, dl_rate: Option<i64>, ul_rate: Option<i64>) -> ThrottleGroup {
+        let mut ut = ThrottleData::new(ul_rate, self.ul_data.borrow().max_tokens);
+        ut.epoch = self.ul_data.borrow().epoch;
+        let mut dt = ThrottleData::new(dl_rate, self.dl_data.borrow().max_tokens);
+        dt.epoch = self.dl_data.borrow().epoch;
+        ThrottleGroup {
+            ul_data: Rc::new(RefCell::new(ut)),
+            dl_data: Rc::new(RefCell::new(dt)),
+        }
+    }
+
     pub fn ul_rate(&mut self) -> Option<i64> {
         self.ul_data.borrow().rate
     }
@@ -76,6 +110,14 @@ impl Throttler {
         self.dl_data.borrow_mut().rate = rate;
     }
 
+    pub fn set_lan_ul_rate(&mut self, rate: Option<i64>) {
+        self.ul_lan.borrow_mut().rate = rate;
+    }
+
+    pub fn set_lan_dl_rate(&mut self, rate: Option<i64>) {
+        self.dl_lan.borrow_mut().rate = rate;
+    }
+
     pub fn id(&self) -> usize {
         self.id
     }
@@ -84,16 +126,20 @@ impl Throttler {
         self.fid
     }
 
+    /// Returns blocked peers in the order they should be woken, which
+    /// is by the virtual time of their torrent.
     pub fn flush_ul(&mut self) -> Vec<usize> {
-        let mut ul_data = self.ul_data.borrow_mut();
-        let flushed = ul_data.throttled.drain().collect();
-        flushed
+        let mut flushed = self.ul_data.borrow_mut().flush();
+        flushed.extend(self.ul_lan.borrow_mut().flush());
+        flushed.sort_unstable();
+        flushed.into_iter().map(|(_, id)| id).collect()
     }
 
     pub fn flush_dl(&mut self) -> Vec<usize> {
-        let mut dl_data = self.dl_data.borrow_mut();
-        let flushed = dl_data.throttled.drain().collect();
-        flushed
+        let mut flushed = self.dl_data.borrow_mut().flush();
+        flushed.extend(self.dl_lan.borrow_mut().flush());
+        flushed.sort_unstable();
+        flushed.into_iter().map(|(_, id)| id).collect()
     }
 }
 
@@ -103,7 +149,43 @@ struct ThrottleData {
     epoch: usize,
     max_tokens: usize,
     last_used: u64,
-    throttled: HashSet<usize>,
+    throttled: HashMap<usize, Blocked>,
+    /// Start tags of peers waiting on this bucket's tokens
+    backlog: BTreeSet<(u64, usize)>,
+    /// Virtual time of a global bucket, or finish tag of a torrent's
+    vtime: u64,
+    weight: u64,
+    /// Group bucket sitting between the global and a torrent's bucket
+    group: Option<Rc<RefCell<ThrottleData>>>,
+}
+
+/// A peer waiting to be woken once tokens are available.
+struct Blocked {
+    /// Virtual time at which the peer's next request starts
+    tag: u64,
+    /// Whether it's waiting on the global tokens, rather than those of
+    /// its group or torrent
+    backlogged: bool,
+    /// Whether it was woken without requesting tokens since
+    flushed: bool,
+}
+
+/// Rates shared by a set of torrents, applied after the global rates
+/// and before each torrent's own.
+#[derive(Clone)]
+pub struct ThrottleGroup {
+    ul_data: Rc<RefCell<ThrottleData>>,
+    dl_data: Rc<RefCell<ThrottleData>>,
+}
+
+impl ThrottleGroup {
+    pub fn set_ul_rate(&mut self, rate: Option<i64>) {
+        self.ul_data.borrow_mut().rate = rate;
+    }
+
+    pub fn set_dl_rate(&mut self, rate: Option<i64>) {
+        self.dl_data.borrow_mut().rate = rate;
+    }
 }
 
 /// Throttle mechanism based on the token bucket algorithm.
@@ -116,69 +198,78 @@ pub struct Throttle {
     dl_tier: Rc<RefCell<ThrottleData>>,
     ul_data: Rc<RefCell<ThrottleData>>,
     dl_data: Rc<RefCell<ThrottleData>>,
+    ul_lan: Rc<RefCell<ThrottleData>>,
+    dl_lan: Rc<RefCell<ThrottleData>>,
 }
 
 impl Throttle {
+    /// Creates an unlimited throttle which isn't part of any throttler.
+    #[cfg(test)]
+    pub fn test(id: usize) -> Throttle {
+        let bucket = || Rc::new(RefCell::new(ThrottleData::new(None, 100)));
+        Throttle {
+            id,
+            ul_tier: bucket(),
+            dl_tier: bucket(),
+            ul_data: bucket(),
+            dl_data: bucket(),
+            ul_lan: bucket(),
+            dl_lan: bucket(),
+        }
+    }
+
     pub fn new_sibling(&self, id: usize) -> Throttle {
         Throttle {
             ul_data: self.ul_data.clone(),
             ul_tier: self.ul_tier.clone(),
             dl_data: self.dl_data.clone(),
             dl_tier: self.dl_tier.clone(),
+            ul_lan: self.ul_lan.clone(),
+            dl_lan: self.dl_lan.clone(),
             id,
         }
     }
 
-    pub fn get_bytes_dl(&mut self, amnt: usize) -> Result<(), ()> {
-        while self.dl_tier.borrow().epoch != self.dl_data.borrow().epoch {
-            self.dl_tier.borrow_mut().add_tokens();
-        }
-        if self.dl_rate() == Some(-1) {
-            self.dl_tier.borrow_mut().last_used += amnt as u64;
-            self.dl_data.borrow_mut().last_used += amnt as u64;
-            return Ok(());
-        }
-        let pres = self.dl_data.borrow_mut().get_tokens(amnt);
-        if pres.is_err() {
-            self.dl_data.borrow_mut().throttled.insert(self.id);
-            return Err(());
+    /// Creates a throttle for a peer on the local network, which is
+    /// limited by the LAN rates alone rather than the global, group
+    /// and torrent rates.
+    pub fn new_lan(&self, id: usize) -> Throttle {
+        let tier = |lan: &Rc<RefCell<ThrottleData>>| {
+            let lan = lan.borrow();
+            let mut tier = ThrottleData::new(None, lan.max_tokens);
+            tier.epoch = lan.epoch;
+            Rc::new(RefCell::new(tier))
+        };
+        Throttle {
+            ul_data: self.ul_lan.clone(),
+            ul_tier: tier(&self.ul_lan),
+            dl_data: self.dl_lan.clone(),
+            dl_tier: tier(&self.dl_lan),
+            ul_lan: self.ul_lan.clone(),
+            dl_lan: self.dl_lan.clone(),
+            id,
         }
+    }
 
-        let res = self.dl_tier.borrow_mut().get_tokens(amnt);
-        if res.is_err() {
-            self.dl_data.borrow_mut().restore_tokens(amnt);
-            self.dl_data.borrow_mut().throttled.insert(self.id);
-            return Err(());
-        }
-        Ok(())
+    pub fn get_bytes_dl(&mut self, amnt: usize) -> Result<(), ()> {
+        get_bytes(self.id, &self.dl_data, &self.dl_tier, amnt)
     }
 
     pub fn get_bytes_ul(&mut self, amnt: usize) -> Result<(), ()> {
-        while self.ul_tier.borrow().epoch != self.ul_data.borrow().epoch {
-            self.ul_tier.borrow_mut().add_tokens();
-        }
-        if self.ul_rate() == Some(-1) {
-            self.ul_tier.borrow_mut().last_used += amnt as u64;
-            self.ul_data.borrow_mut().last_used += amnt as u64;
-            return Ok(());
-        }
-        let pres = self.ul_data.borrow_mut().get_tokens(amnt);
-        if pres.is_err() {
-            self.ul_data.borrow_mut().throttled.insert(self.id);
-            return Err(());
-        }
-
-        let res = self.ul_tier.borrow_mut().get_tokens(amnt);
-        if res.is_err() {
-            self.ul_data.borrow_mut().restore_tokens(amnt);
-            self.ul_data.borrow_mut().throttled.insert(self.id);
-            return Err(());
-        }
-        Ok(())
+        get_bytes(self.id, &self.ul_data, &self.ul_tier, amnt)
     }
 
     pub fn set_stalled_dl(&mut self) {
-        self.dl_data.borrow_mut().throttled.insert(self.id);
+        let tag = self.dl_data.borrow().start_tag(&self.dl_tier.borrow());
+        self.dl_data.borrow_mut().block(self.id, tag, false);
+    }
+
+    /// Sets the share of a saturated global rate the throttle and its
+    /// siblings receive, relative to other torrents.
+    pub fn set_weight(&mut self, weight: u8) {
+        let weight = u64::from(cmp::max(weight, 1));
+        self.ul_tier.borrow_mut().weight = weight;
+        self.dl_tier.borrow_mut().weight = weight;
     }
 
     pub fn ul_rate(&self) -> Option<i64> {
@@ -197,24 +288,106 @@ impl Throttle {
         self.dl_tier.borrow_mut().rate = rate;
     }
 
+    /// Places this throttle and its siblings in a group, or removes
+    /// them from their current one.
+    pub fn set_group(&mut self, group: Option<&ThrottleGroup>) {
+        self.ul_tier.borrow_mut().group = group.map(|g| g.ul_data.clone());
+        self.dl_tier.borrow_mut().group = group.map(|g| g.dl_data.clone());
+    }
+
     pub fn restore_bytes_dl(&mut self, amnt: usize) {
         self.dl_data.borrow_mut().restore_tokens(amnt);
+        if let Some(ref g) = self.dl_tier.borrow().group {
+            g.borrow_mut().restore_tokens(amnt);
+        }
         self.dl_tier.borrow_mut().restore_tokens(amnt);
     }
 
     pub fn restore_bytes_ul(&mut self, amnt: usize) {
         self.ul_data.borrow_mut().restore_tokens(amnt);
+        if let Some(ref g) = self.ul_tier.borrow().group {
+            g.borrow_mut().restore_tokens(amnt);
+        }
         self.ul_tier.borrow_mut().restore_tokens(amnt);
     }
 }
 
 impl Drop for Throttle {
     fn drop(&mut self) {
-        self.ul_data.borrow_mut().throttled.remove(&self.id);
-        self.dl_data.borrow_mut().throttled.remove(&self.id);
+        self.ul_data.borrow_mut().unblock(self.id);
+        self.dl_data.borrow_mut().unblock(self.id);
     }
 }
 
+/// Takes tokens from the global, group and torrent buckets in turn.
+/// While the global rate is saturated it's shared by start-time fair
+/// queuing: each request is tagged with the virtual time it starts at,
+/// and torrents which are ahead of the earliest backlogged torrent
+/// yield to it.
+fn get_bytes(
+    id: usize,
+    data: &Rc<RefCell<ThrottleData>>,
+    tier: &Rc<RefCell<ThrottleData>>,
+    amnt: usize,
+) -> Result<(), ()> {
+    let group = tier.borrow().group.clone();
+    let epoch = data.borrow().epoch;
+    while tier.borrow().epoch != epoch {
+        tier.borrow_mut().add_tokens();
+    }
+    if let Some(ref g) = group {
+        while g.borrow().epoch != epoch {
+            g.borrow_mut().add_tokens();
+        }
+    }
+    data.borrow_mut().unblock(id);
+    if tier.borrow().rate == Some(-1) {
+        tier.borrow_mut().last_used += amnt as u64;
+        data.borrow_mut().last_used += amnt as u64;
+        if let Some(ref g) = group {
+            g.borrow_mut().last_used += amnt as u64;
+        }
+        return Ok(());
+    }
+    let limited = data.borrow().limited();
+    let tag = data.borrow().start_tag(&tier.borrow());
+    if limited && data.borrow().yields(tag) {
+        data.borrow_mut().block(id, tag, true);
+        return Err(());
+    }
+    let pres = data.borrow_mut().get_tokens(amnt);
+    if pres.is_err() {
+        data.borrow_mut().block(id, tag, limited);
+        return Err(());
+    }
+
+    if let Some(ref g) = group {
+        let gres = g.borrow_mut().get_tokens(amnt);
+        if gres.is_err() {
+            data.borrow_mut().restore_tokens(amnt);
+            data.borrow_mut().block(id, tag, false);
+            return Err(());
+        }
+    }
+
+    let res = tier.borrow_mut().get_tokens(amnt);
+    if res.is_err() {
+        data.borrow_mut().restore_tokens(amnt);
+        if let Some(ref g) = group {
+            g.borrow_mut().restore_tokens(amnt);
+        }
+        data.borrow_mut().block(id, tag, false);
+        return Err(());
+    }
+    if limited {
+        let mut tier = tier.borrow_mut();
+        tier.vtime = tag + amnt as u64 * WEIGHT_SCALE / tier.weight;
+        let mut data = data.borrow_mut();
+        data.vtime = cmp::max(data.vtime, tag);
+    }
+    Ok(())
+}
+
 impl ThrottleData {
     /// Creates a new Throttle with the given rate and max token amount.
     fn new(rate: Option<i64>, max_tokens: usize) -> ThrottleData {
@@ -222,12 +395,79 @@ impl ThrottleData {
             tokens: 0,
             rate,
             max_tokens,
-            throttled: HashSet::with_capacity(0),
+            throttled: HashMap::with_capacity(0),
+            backlog: BTreeSet::new(),
             last_used: 0,
             epoch: 0,
+            vtime: 0,
+            weight: 1,
+            group: None,
         }
     }
 
+    /// Whether the bucket has a rate limiting it.
+    fn limited(&self) -> bool {
+        self.rate.map_or(false, |r| r >= 0)
+    }
+
+    /// Virtual time at which a torrent's next request starts, which is
+    /// never behind the bucket's, so idle torrents can't build up credit.
+    fn start_tag(&self, tier: &ThrottleData) -> u64 {
+        cmp::max(self.vtime, tier.vtime)
+    }
+
+    /// Whether a request should yield to an earlier backlogged one.
+    fn yields(&self, tag: u64) -> bool {
+        self.backlog
+            .iter()
+            .next()
+            .map_or(false, |&(min, _)| tag > min + WFQ_SLACK)
+    }
+
+    fn block(&mut self, id: usize, tag: u64, backlogged: bool) {
+        self.unblock(id);
+        if backlogged {
+            self.backlog.insert((tag, id));
+        }
+        let blocked = Blocked {
+            tag,
+            backlogged,
+            flushed: false,
+        };
+        self.throttled.insert(id, blocked);
+    }
+
+    fn unblock(&mut self, id: usize) {
+        if let Some(b) = self.throttled.remove(&id) {
+            if b.backlogged {
+                self.backlog.remove(&(b.tag, id));
+            }
+        }
+    }
+
+    /// Returns the blocked peers with their tags. They remain in the
+    /// backlog until they request tokens again, so the woken peers are
+    /// served in order, while those flushed previously without
+    /// requesting any are dropped.
+    fn flush(&mut self) -> Vec<(u64, usize)> {
+        let stale: Vec<_> = self
+            .throttled
+            .iter()
+            .filter(|(_, b)| b.flushed)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in stale {
+            self.unblock(id);
+        }
+        self.throttled
+            .iter_mut()
+            .map(|(id, b)| {
+                b.flushed = true;
+                (b.tag, *id)
+            })
+            .collect()
+    }
+
     /// Adds some amount of tokens back.
     fn restore_tokens(&mut self, amnt: usize) {
         self.last_used -= amnt as u64;
@@ -278,3 +518,107 @@ impl ThrottleData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Throttle, ThrottleData, ThrottleGroup};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn bucket(rate: Option<i64>) -> Rc<RefCell<ThrottleData>> {
+        Rc::new(RefCell::new(ThrottleData::new(rate, 100)))
+    }
+
+    #[test]
+    fn test_group() {
+        let global = bucket(None);
+        let group = ThrottleGroup {
+            ul_data: bucket(Some(1000)),
+            dl_data: bucket(Some(1000)),
+        };
+        let mut t = Throttle {
+            id: 0,
+            ul_tier: bucket(None),
+            dl_tier: bucket(None),
+            ul_data: global.clone(),
+            dl_data: bucket(None),
+            ul_lan: bucket(None),
+            dl_lan: bucket(None),
+        };
+        let mut sibling = t.new_sibling(1);
+        t.set_group(Some(&group));
+
+        // 1000 B/s gives the group 15 bytes each epoch
+        global.borrow_mut().add_tokens();
+        assert!(t.get_bytes_ul(10).is_ok());
+        assert!(sibling.get_bytes_ul(10).is_err());
+        assert!(global.borrow().throttled.contains_key(&1));
+        assert!(sibling.get_bytes_ul(5).is_ok());
+
+        t.set_group(None);
+        assert!(sibling.get_bytes_ul(10).is_ok());
+    }
+
+    #[test]
+    fn test_lan() {
+        let global = bucket(Some(0));
+        let lan = bucket(Some(1000));
+        let t = Throttle {
+            id: 0,
+            ul_tier: bucket(Some(0)),
+            dl_tier: bucket(None),
+            ul_data: global.clone(),
+            dl_data: bucket(None),
+            ul_lan: lan.clone(),
+            dl_lan: bucket(None),
+        };
+        let mut wan = t.new_sibling(0);
+        let mut local = t.new_lan(1);
+
+        global.borrow_mut().add_tokens();
+        lan.borrow_mut().add_tokens();
+        assert!(wan.get_bytes_ul(1).is_err());
+        assert!(local.get_bytes_ul(15).is_ok());
+        assert!(local.get_bytes_ul(1).is_err());
+        assert!(lan.borrow().throttled.contains_key(&1));
+    }
+
+    #[test]
+    fn test_weighted() {
+        let global = bucket(Some(1000));
+        let throttle = |id, weight| {
+            let mut t = Throttle {
+                id,
+                ul_tier: bucket(None),
+                dl_tier: bucket(None),
+                ul_data: global.clone(),
+                dl_data: bucket(None),
+                ul_lan: bucket(None),
+                dl_lan: bucket(None),
+            };
+            t.set_weight(weight);
+            t
+        };
+        let mut light = throttle(0, 1);
+        let mut heavy = throttle(1, 5);
+
+        // The heavier torrent's virtual time advances slower, so it's woken first
+        global.borrow_mut().add_tokens();
+        assert!(light.get_bytes_ul(10).is_ok());
+        assert!(heavy.get_bytes_ul(5).is_ok());
+        assert!(light.get_bytes_ul(10).is_err());
+        assert!(heavy.get_bytes_ul(10).is_err());
+        let mut flushed = global.borrow_mut().flush();
+        flushed.sort_unstable();
+        let order: Vec<_> = flushed.into_iter().map(|(_, id)| id).collect();
+        assert_eq!(order, vec![1, 0]);
+
+        // Once far enough ahead, the light torrent yields to the backlog
+        global.borrow_mut().tokens = 200_000;
+        assert!(light.get_bytes_ul(150_000).is_ok());
+        assert!(heavy.get_bytes_ul(100_000).is_err());
+        global.borrow_mut().tokens = 200_000;
+        assert!(light.get_bytes_ul(10).is_err());
+        assert!(heavy.get_bytes_ul(100_000).is_ok());
+    }
+}
@@ -26,6 +26,9 @@ impl Choker {
     }
 
     pub fn add_peer<T: cio::CIO>(&mut self, peer: &mut Peer<T>) {
+        if self.unchoked.contains(&peer.id()) {
+            return;
+        }
         if self.unchoked.len() < 5 {
             self.unchoked.push(peer.id());
             peer.flush();
@@ -6,9 +6,10 @@ use std::{cmp, fmt, mem};
 use rand::{self, Rng};
 use url::Url;
 
+use super::merkle::{self, Hash};
 use crate::bencode::BEncode;
 use crate::disk;
-use crate::util::{hash_to_id, id_to_hash, sha1_hash};
+use crate::util::{hash_to_id, id_to_hash, id_to_hash_v2, sha1_hash, sha256_hash};
 
 #[derive(Clone)]
 pub struct Info {
@@ -20,12 +21,17 @@ pub struct Info {
     pub total_len: u64,
     pub hashes: Vec<Vec<u8>>,
     pub hash: [u8; 20],
+    /// SHA-256 infohash of v2 and hybrid torrents
+    pub hash_v2: Option<[u8; 32]>,
+    /// BEP 52 piece layer hashes, indexed by piece. Unknown hashes are zeroed.
+    pub hashes_v2: Vec<Hash>,
     pub files: Vec<File>,
     pub private: bool,
     pub be_name: Option<Vec<u8>>,
     /// Maps piece idx -> file idx + file offset
     pub piece_idx: Vec<(usize, u64)>,
     pub url_list: Vec<Vec<Arc<Url>>>,
+    pub web_seeds: Vec<WebSeed>,
 }
 
 impl fmt::Debug for Info {
@@ -50,20 +56,50 @@ impl fmt::Debug for Info {
     }
 }
 
+/// An HTTP server which can be downloaded from in addition to peers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebSeed {
+    pub url: Url,
+    pub kind: WebSeedKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WebSeedKind {
+    /// BEP 19 `url-list` seed, serving the torrent's files by path
+    Url,
+    /// BEP 17 `httpseeds` seed, serving pieces by infohash and index
+    Http,
+}
+
 #[derive(Clone, Debug)]
 pub struct File {
     pub path: PathBuf,
     pub length: u64,
+    /// Root of the file's merkle tree for v2 torrents
+    pub pieces_root: Option<Hash>,
+    /// BEP 47 padding files are never written to disk
+    pub padding: bool,
 }
 
 impl File {
     fn from_bencode(data: BEncode) -> Result<File, &'static str> {
         let mut d = data.into_dict().ok_or("File must be a dictionary type!")?;
-        match (d.remove("name"), d.remove("path"), d.remove("length")) {
+        let padding = d
+            .remove(&b"attr"[..])
+            .and_then(BEncode::into_bytes)
+            .map(|a| a.contains(&b'p'))
+            .unwrap_or(false);
+        match (
+            d.remove(&b"name"[..]),
+            d.remove(&b"path"[..]),
+            d.remove(&b"length"[..]),
+        ) {
             (Some(v), None, Some(l)) => {
                 let f = File {
                     path: PathBuf::from(v.into_string().ok_or("Path must be a valid string.")?),
                     length: l.into_int().ok_or("File length must be a valid int")? as u64,
+                    pieces_root: None,
+                    padding,
                 };
                 Ok(f)
             }
@@ -78,6 +114,8 @@ impl File {
                 let f = File {
                     path: p,
                     length: l.into_int().ok_or("File length must be a valid int")? as u64,
+                    pieces_root: None,
+                    padding,
                 };
                 Ok(f)
             }
@@ -96,6 +134,11 @@ impl Info {
         if url.scheme() != "magnet" {
             return Err("magnet URL must use magnet URL scheme");
         };
+        // v2 infohashes are given as a sha2-256 multihash
+        let hash_v2 = url
+            .query_pairs()
+            .find(|&(ref k, ref v)| k == "xt" && v.starts_with("urn:btmh:1220"))
+            .and_then(|(_, ref v)| id_to_hash_v2(&v[13..]));
         let hash =
             url.query_pairs()
                 .find(|&(ref k, ref v)| k == "xt" && v.starts_with("urn:btih:"))
@@ -112,6 +155,7 @@ impl Info {
                             })
                     })
                 })
+                .or_else(|| hash_v2.as_ref().map(truncate_hash))
                 .ok_or("No hash found in magnet")?;
 
         let mut url_list: Vec<_> = url
@@ -122,6 +166,17 @@ impl Info {
             .collect();
         rand::thread_rng().shuffle(&mut url_list[..]);
 
+        let web_seeds = url
+            .query_pairs()
+            .filter(|(k, _)| k == "ws")
+            .filter_map(|(_, v)| Url::parse(&v).ok())
+            .filter(|u| u.scheme() == "http" || u.scheme() == "https")
+            .map(|url| WebSeed {
+                url,
+                kind: WebSeedKind::Url,
+            })
+            .collect();
+
         let name = url
             .query_pairs()
             .find(|&(ref k, _)| k == "dn")
@@ -136,16 +191,33 @@ impl Info {
             total_len: 0,
             hashes: vec![],
             hash,
+            hash_v2,
+            hashes_v2: vec![],
             files: vec![],
             private: false,
             be_name: None,
             piece_idx: vec![],
             url_list: vec![url_list],
+            web_seeds,
         })
     }
 
     pub fn complete(&self) -> bool {
-        !self.hashes.is_empty()
+        self.pieces() != 0
+    }
+
+    /// Whether or not the torrent supports the v2 protocol.
+    pub fn v2(&self) -> bool {
+        self.hash_v2.is_some()
+    }
+
+    /// The hex ID used to identify the torrent over RPC. Torrents which can
+    /// only be identified by a v2 infohash use the full 32 byte hash.
+    pub fn rpc_id(&self) -> String {
+        match self.hash_v2 {
+            Some(ref h) if truncate_hash(h) == self.hash => hash_to_id(h),
+            _ => hash_to_id(&self.hash),
+        }
     }
 
     pub fn to_torrent_bencode(&self) -> BEncode {
@@ -153,34 +225,55 @@ impl Info {
         let info = self.to_bencode();
         self.announce.as_ref().map(|url| {
             torrent.insert(
-                "announce".to_owned(),
+                b"announce".to_vec(),
                 BEncode::String(url.as_str().as_bytes().to_owned()),
             )
         });
-        torrent.insert("info".to_owned(), info);
+        for (key, kind) in &[
+            ("url-list", WebSeedKind::Url),
+            ("httpseeds", WebSeedKind::Http),
+        ] {
+            let seeds: Vec<_> = self
+                .web_seeds
+                .iter()
+                .filter(|s| s.kind == *kind)
+                .map(|s| BEncode::String(s.url.as_str().as_bytes().to_owned()))
+                .collect();
+            if !seeds.is_empty() {
+                torrent.insert(key.as_bytes().to_vec(), BEncode::List(seeds));
+            }
+        }
+        torrent.insert(b"info".to_vec(), info);
         BEncode::Dict(torrent)
     }
 
     pub fn to_bencode(&self) -> BEncode {
         let mut info = BTreeMap::new();
         if let Some(ref n) = self.be_name {
-            info.insert("name".to_owned(), BEncode::String(n.clone()));
+            info.insert(b"name".to_vec(), BEncode::String(n.clone()));
         }
         if self.private {
-            info.insert("private".to_owned(), BEncode::Int(1));
+            info.insert(b"private".to_vec(), BEncode::Int(1));
         }
         info.insert(
-            "piece length".to_owned(),
+            b"piece length".to_vec(),
             BEncode::Int(i64::from(self.piece_len)),
         );
+        if self.v2() {
+            info.insert(b"meta version".to_vec(), BEncode::Int(2));
+            info.insert(b"file tree".to_vec(), self.file_tree());
+            if self.hashes.is_empty() {
+                return BEncode::Dict(info);
+            }
+        }
         let mut pieces = Vec::with_capacity(self.hashes.len() * 20);
         for h in &self.hashes {
             pieces.extend_from_slice(h);
         }
-        info.insert("pieces".to_owned(), BEncode::String(pieces));
+        info.insert(b"pieces".to_vec(), BEncode::String(pieces));
         if self.files.len() == 1 {
             info.insert(
-                "length".to_owned(),
+                b"length".to_vec(),
                 BEncode::Int(self.files[0].length as i64),
             );
         } else {
@@ -189,9 +282,12 @@ impl Info {
                 .iter()
                 .map(|f| {
                     let mut fb = BTreeMap::new();
-                    fb.insert("length".to_owned(), BEncode::Int(f.length as i64));
+                    fb.insert(b"length".to_vec(), BEncode::Int(f.length as i64));
+                    if f.padding {
+                        fb.insert(b"attr".to_vec(), BEncode::from_str("p"));
+                    }
                     fb.insert(
-                        "path".to_owned(),
+                        b"path".to_vec(),
                         BEncode::String(
                             f.path
                                 .clone()
@@ -204,50 +300,96 @@ impl Info {
                     BEncode::Dict(fb)
                 })
                 .collect();
-            info.insert("files".to_owned(), BEncode::List(files));
+            info.insert(b"files".to_vec(), BEncode::List(files));
         }
         BEncode::Dict(info)
     }
 
+    fn file_tree(&self) -> BEncode {
+        let files: Vec<_> = self.files.iter().filter(|f| !f.padding).collect();
+        let single = files.len() == 1 && files[0].path.components().count() == 1;
+        let mut tree = BTreeMap::new();
+        for f in files {
+            let mut entry = BTreeMap::new();
+            entry.insert(b"length".to_vec(), BEncode::Int(f.length as i64));
+            if let Some(ref root) = f.pieces_root {
+                entry.insert(b"pieces root".to_vec(), BEncode::String(root.to_vec()));
+            }
+            let mut node = &mut tree;
+            for c in f.path.components().skip(if single { 0 } else { 1 }) {
+                let c = c.as_os_str().to_string_lossy().into_owned().into_bytes();
+                node = match node
+                    .entry(c)
+                    .or_insert_with(|| BEncode::Dict(BTreeMap::new()))
+                {
+                    BEncode::Dict(ref mut d) => d,
+                    _ => unreachable!(),
+                };
+            }
+            node.insert(b"".to_vec(), BEncode::Dict(entry));
+        }
+        BEncode::Dict(tree)
+    }
+
     pub fn from_bencode(data: BEncode) -> Result<Info, &'static str> {
         data.into_dict()
-            .and_then(|mut d| d.remove("info").and_then(|i| i.into_dict()).map(|i| (d, i)))
+            .and_then(|mut d| {
+                d.remove(&b"info"[..])
+                    .and_then(|i| i.into_dict())
+                    .map(|i| (d, i))
+            })
             .ok_or("invalid info field")
             .and_then(|(mut d, mut i)| {
                 let mut info_bytes = Vec::new();
                 BEncode::Dict(i.clone()).encode(&mut info_bytes).unwrap();
-                let hash = sha1_hash(&info_bytes);
+                let hash_v2 = match i.remove(&b"meta version"[..]).and_then(BEncode::into_int) {
+                    Some(2) => Some(sha256_hash(&info_bytes)),
+                    None | Some(1) => None,
+                    Some(_) => return Err("Unsupported meta version"),
+                };
+                let v1 = hash_v2.is_none() || i.contains_key(&b"pieces"[..]);
+                let hash = if v1 {
+                    sha1_hash(&info_bytes)
+                } else {
+                    truncate_hash(hash_v2.as_ref().unwrap())
+                };
 
                 let announce = d
-                    .remove("announce")
+                    .remove(&b"announce"[..])
                     .and_then(BEncode::into_string)
                     .and_then(|a| Url::parse(&a).ok().map(Arc::new));
-                let comment = d.remove("comment").and_then(|b| b.into_string());
-                let creator = d.remove("created by").and_then(|b| b.into_string());
+                let comment = d.remove(&b"comment"[..]).and_then(|b| b.into_string());
+                let creator = d.remove(&b"created by"[..]).and_then(|b| b.into_string());
                 let pl = i
-                    .remove("piece length")
+                    .remove(&b"piece length"[..])
                     .and_then(|i| i.into_int())
                     .ok_or("Info must specify piece length")? as u64;
-                let hashes = i
-                    .remove("pieces")
-                    .and_then(|p| p.into_bytes())
-                    .and_then(|p| {
-                        let mut v = Vec::new();
-                        let mut s = &p[..];
-                        while s.len() >= 20 {
-                            let mut next = vec![0u8; 20];
-                            next.clone_from_slice(&s[..20]);
-                            v.push(next);
-                            s = &s[20..];
-                        }
-                        if !s.is_empty() {
-                            return None;
-                        }
-                        Some(v)
-                    })
-                    .ok_or("Info must provide valid hashes")?;
+                if hash_v2.is_some() && (pl < 16_384 || !pl.is_power_of_two()) {
+                    return Err("v2 piece length must be a power of two of at least 16 KiB");
+                }
+                let hashes = if !v1 {
+                    vec![]
+                } else {
+                    i.remove(&b"pieces"[..])
+                        .and_then(|p| p.into_bytes())
+                        .and_then(|p| {
+                            let mut v = Vec::new();
+                            let mut s = &p[..];
+                            while s.len() >= 20 {
+                                let mut next = vec![0u8; 20];
+                                next.clone_from_slice(&s[..20]);
+                                v.push(next);
+                                s = &s[20..];
+                            }
+                            if !s.is_empty() {
+                                return None;
+                            }
+                            Some(v)
+                        })
+                        .ok_or("Info must provide valid hashes")?
+                };
 
-                let private = if let Some(v) = i.remove("private") {
+                let private = if let Some(v) = i.remove(&b"private"[..]) {
                     v.into_int()
                         .and_then(|p| {
                             if p == 0 {
@@ -263,13 +405,41 @@ impl Info {
                     false
                 };
 
-                let be_name = if let Some(v) = i.get("name").cloned() {
+                let be_name = if let Some(v) = i.get(&b"name"[..]).cloned() {
                     Some(v.into_bytes().ok_or("name field must be a bitstring!")?)
                 } else {
                     None
                 };
 
-                let files = parse_bencode_files(i)?;
+                let files = match (hash_v2, i.remove(&b"file tree"[..])) {
+                    (Some(_), Some(tree)) => {
+                        let name = i
+                            .get(&b"name"[..])
+                            .and_then(BEncode::as_str)
+                            .ok_or("v2 torrents must have a name")?
+                            .to_owned();
+                        let tree = parse_file_tree(tree, &name)?;
+                        if v1 {
+                            let mut files = parse_bencode_files(i)?;
+                            let mut real = files.iter_mut().filter(|f| !f.padding);
+                            for f2 in tree {
+                                match real.next() {
+                                    Some(ref mut f)
+                                        if f.path == f2.path && f.length == f2.length =>
+                                    {
+                                        f.pieces_root = f2.pieces_root
+                                    }
+                                    _ => return Err("Hybrid torrent v1 and v2 files differ"),
+                                }
+                            }
+                            files
+                        } else {
+                            pad_files(tree, pl)
+                        }
+                    }
+                    (Some(_), None) => return Err("v2 torrents must have a file tree"),
+                    (None, _) => parse_bencode_files(i)?,
+                };
                 let name = if files.is_empty() {
                     files[0]
                         .path
@@ -291,10 +461,24 @@ impl Info {
                 };
 
                 let total_len = files.iter().map(|f| f.length).sum();
-                let piece_idx = Info::generate_piece_idx(hashes.len(), pl, &files);
+                let pieces = if v1 {
+                    hashes.len()
+                } else {
+                    div_round_up!(total_len, pl) as usize
+                };
+                let piece_idx = Info::generate_piece_idx(pieces, pl, &files);
+                let hashes_v2 = if hash_v2.is_some() {
+                    let layers = d
+                        .remove(&b"piece layers"[..])
+                        .and_then(BEncode::into_dict)
+                        .unwrap_or_else(BTreeMap::new);
+                    piece_hashes_v2(&files, &piece_idx, pl, layers)?
+                } else {
+                    vec![]
+                };
 
                 let url_list: Vec<_> = d
-                    .remove("announce-list")
+                    .remove(&b"announce-list"[..])
                     .and_then(BEncode::into_list)
                     .unwrap_or_else(Vec::new)
                     .into_iter()
@@ -310,6 +494,7 @@ impl Info {
                         l
                     })
                     .collect();
+                let web_seeds = parse_web_seeds(&mut d);
 
                 Ok(Info {
                     name,
@@ -319,12 +504,15 @@ impl Info {
                     piece_len: pl as u32,
                     hashes,
                     hash,
+                    hash_v2,
+                    hashes_v2,
                     files,
                     total_len,
                     private,
                     be_name,
                     piece_idx,
                     url_list,
+                    web_seeds,
                 })
             })
     }
@@ -355,10 +543,14 @@ impl Info {
             total_len: 16_384 * pieces as u64,
             hashes: vec![vec![0u8]; pieces],
             hash: [0u8; 20],
+            hash_v2: None,
+            hashes_v2: vec![],
             files: vec![
                 File {
                     path: PathBuf::new(),
                     length: 16_384 * pieces as u64,
+                    pieces_root: None,
+                    padding: false,
                 };
                 1
             ],
@@ -366,6 +558,7 @@ impl Info {
             be_name: None,
             piece_idx: vec![],
             url_list: vec![],
+            web_seeds: vec![],
         }
     }
 
@@ -380,11 +573,14 @@ impl Info {
             total_len: 16_384 * pieces as u64 * scale as u64,
             hashes: vec![vec![0u8]; pieces as usize],
             hash: [0u8; 20],
+            hash_v2: None,
+            hashes_v2: vec![],
             files: vec![],
             private: false,
             be_name: None,
             piece_idx: vec![],
             url_list: vec![],
+            web_seeds: vec![],
         }
     }
 
@@ -416,7 +612,74 @@ impl Info {
     }
 
     pub fn pieces(&self) -> u32 {
-        self.hashes.len() as u32
+        cmp::max(self.hashes.len(), self.hashes_v2.len()) as u32
+    }
+
+    /// Checks a piece's data against its v1 hash and, if known, its v2 hash.
+    pub fn verify_piece(&self, idx: u32, data: &[u8]) -> bool {
+        let idx = idx as usize;
+        if !self.hashes.is_empty() && sha1_hash(data)[..] != self.hashes[idx][..] {
+            return false;
+        }
+        match self.hashes_v2.get(idx) {
+            // Hybrid torrents may lack piece layers, in which case v1 suffices
+            Some(h) if *h == [0; 32] => !self.hashes.is_empty(),
+            Some(h) => {
+                let (len, width) = self.piece_tree(idx as u32);
+                len <= data.len() as u64 && merkle::data_root(&data[..len as usize], width) == *h
+            }
+            None => true,
+        }
+    }
+
+    /// Returns the file a v2 piece belongs to.
+    pub fn piece_file(&self, idx: u32) -> usize {
+        let (mut file, _) = self.piece_idx[idx as usize];
+        while file + 1 < self.files.len() && self.files[file].length == 0 {
+            file += 1;
+        }
+        file
+    }
+
+    /// Index of the piece layer within v2 merkle trees, counting up from the leaves.
+    pub fn piece_layer(&self) -> u32 {
+        (self.piece_len as usize / merkle::BLOCK_LEN).trailing_zeros()
+    }
+
+    /// Finds the file with the given pieces root, if it's large enough to
+    /// have a piece layer.
+    pub fn layer_file(&self, root: &Hash) -> Option<usize> {
+        self.files.iter().position(|f| {
+            f.pieces_root.as_ref() == Some(root) && f.length > u64::from(self.piece_len)
+        })
+    }
+
+    /// Range of pieces covering a file of a v2 torrent.
+    pub fn file_pieces(&self, file: usize) -> (u32, u32) {
+        let start = self
+            .piece_idx
+            .iter()
+            .position(|&(f, o)| f == file && o == 0)
+            .unwrap_or(self.piece_idx.len()) as u32;
+        let len = div_round_up!(self.files[file].length, u64::from(self.piece_len)) as u32;
+        (start, start + len)
+    }
+
+    /// Computes the length of a v2 piece's data, excluding padding, and
+    /// the number of leaves in the merkle subtree the piece hash covers.
+    fn piece_tree(&self, idx: u32) -> (u64, usize) {
+        let (_, offset) = self.piece_idx[idx as usize];
+        let file = &self.files[self.piece_file(idx)];
+        let len = cmp::min(
+            u64::from(self.piece_len),
+            file.length.saturating_sub(offset),
+        );
+        let width = if file.length > u64::from(self.piece_len) {
+            self.piece_len as usize / merkle::BLOCK_LEN
+        } else {
+            div_round_up!(file.length as usize, merkle::BLOCK_LEN).next_power_of_two()
+        };
+        (len, width)
     }
 
     /// Calculates the file offsets for a given block at index/begin
@@ -546,12 +809,36 @@ impl Iterator for LocIter {
     }
 }
 
-fn parse_bencode_files(mut data: BTreeMap<String, BEncode>) -> Result<Vec<File>, &'static str> {
-    match data.remove("files").and_then(|l| l.into_list()) {
+/// Parses the BEP 19 `url-list` and BEP 17 `httpseeds` keys. A `url-list`
+/// holding a single URL may be given as a string rather than a list.
+fn parse_web_seeds(d: &mut BTreeMap<Vec<u8>, BEncode>) -> Vec<WebSeed> {
+    let mut seeds = Vec::new();
+    for (key, kind) in &[
+        ("url-list", WebSeedKind::Url),
+        ("httpseeds", WebSeedKind::Http),
+    ] {
+        let urls = match d.remove(key.as_bytes()) {
+            Some(BEncode::List(l)) => l,
+            Some(s @ BEncode::String(_)) => vec![s],
+            _ => vec![],
+        };
+        seeds.extend(
+            urls.into_iter()
+                .filter_map(BEncode::into_string)
+                .filter_map(|s| Url::parse(&s).ok())
+                .filter(|u| u.scheme() == "http" || u.scheme() == "https")
+                .map(|url| WebSeed { url, kind: *kind }),
+        );
+    }
+    seeds
+}
+
+fn parse_bencode_files(mut data: BTreeMap<Vec<u8>, BEncode>) -> Result<Vec<File>, &'static str> {
+    match data.remove(&b"files"[..]).and_then(|l| l.into_list()) {
         Some(fs) => {
             let mut path = PathBuf::new();
             path.push(
-                data.remove("name")
+                data.remove(&b"name"[..])
                     .and_then(|v| v.into_string())
                     .ok_or("Multifile mode must have a name field")?,
             );
@@ -567,6 +854,140 @@ fn parse_bencode_files(mut data: BTreeMap<String, BEncode>) -> Result<Vec<File>,
     }
 }
 
+fn truncate_hash(hash: &[u8; 32]) -> [u8; 20] {
+    let mut h = [0u8; 20];
+    h.copy_from_slice(&hash[..20]);
+    h
+}
+
+/// Flattens a BEP 52 file tree. Multifile torrents are placed in a
+/// directory with the torrent's name, as in v1.
+fn parse_file_tree(tree: BEncode, name: &str) -> Result<Vec<File>, &'static str> {
+    let mut files = Vec::new();
+    let mut dirs = vec![(PathBuf::new(), tree)];
+    while let Some((path, node)) = dirs.pop() {
+        let mut d = node
+            .into_dict()
+            .ok_or("File tree nodes must be dictionaries")?;
+        if let Some(f) = d.remove(&b""[..]) {
+            let mut f = f
+                .into_dict()
+                .ok_or("File tree entries must be dictionaries")?;
+            let length = f
+                .remove(&b"length"[..])
+                .and_then(BEncode::into_int)
+                .ok_or("File tree entries must have a length")? as u64;
+            let pieces_root = match f.remove(&b"pieces root"[..]).and_then(BEncode::into_bytes) {
+                Some(ref r) if r.len() == 32 => {
+                    let mut h = [0u8; 32];
+                    h.copy_from_slice(r);
+                    Some(h)
+                }
+                None if length == 0 => None,
+                _ => return Err("Non empty files must have a valid pieces root"),
+            };
+            files.push(File {
+                path,
+                length,
+                pieces_root,
+                padding: false,
+            });
+            continue;
+        }
+        // Push in reverse so files are produced in key order
+        for (k, v) in d.into_iter().rev() {
+            let k = String::from_utf8(k).map_err(|_| "File tree paths must be UTF8")?;
+            if k.is_empty() || k == "." || k == ".." || k.contains('/') {
+                return Err("Invalid file tree path component");
+            }
+            dirs.push((path.join(k), v));
+        }
+    }
+    if files.len() != 1 || files[0].path.components().count() != 1 {
+        for f in &mut files {
+            f.path = PathBuf::from(name).join(&f.path);
+        }
+    }
+    Ok(files)
+}
+
+/// Inserts padding files so that every file of a v2 only torrent starts
+/// on a piece boundary, allowing it to use the v1 piece layout.
+fn pad_files(tree: Vec<File>, pl: u64) -> Vec<File> {
+    let mut files = Vec::with_capacity(tree.len() * 2);
+    let count = tree.len();
+    for (i, f) in tree.into_iter().enumerate() {
+        let pad = (pl - f.length % pl) % pl;
+        files.push(f);
+        if pad != 0 && i + 1 != count {
+            files.push(File {
+                path: PathBuf::from(".pad").join(pad.to_string()),
+                length: pad,
+                pieces_root: None,
+                padding: true,
+            });
+        }
+    }
+    files
+}
+
+/// Maps the piece layers of each file onto the torrent's pieces, verifying
+/// them against their files' pieces roots.
+fn piece_hashes_v2(
+    files: &[File],
+    piece_idx: &[(usize, u64)],
+    pl: u64,
+    mut layers: BTreeMap<Vec<u8>, BEncode>,
+) -> Result<Vec<Hash>, &'static str> {
+    let pad = merkle::pad_hash((pl as usize / merkle::BLOCK_LEN).trailing_zeros());
+    let mut file_layers = BTreeMap::new();
+    for f in files.iter().filter(|f| f.length > pl) {
+        let root = f.pieces_root.ok_or("v2 files must have a pieces root")?;
+        let layer = match layers.remove(&root[..]) {
+            Some(l) => l.into_bytes().ok_or("Piece layers must be strings")?,
+            None => continue,
+        };
+        let count = div_round_up!(f.length, pl) as usize;
+        if layer.len() != count * 32 {
+            return Err("Piece layer has an invalid length");
+        }
+        let hashes: Vec<Hash> = layer
+            .chunks(32)
+            .map(|c| {
+                let mut h = [0u8; 32];
+                h.copy_from_slice(c);
+                h
+            })
+            .collect();
+        if merkle::root(&hashes, count, pad) != root {
+            return Err("Piece layer does not match pieces root");
+        }
+        file_layers.insert(root, hashes);
+    }
+
+    let mut hashes = Vec::with_capacity(piece_idx.len());
+    for &(mut file, offset) in piece_idx {
+        while file + 1 < files.len() && files[file].length == 0 {
+            file += 1;
+        }
+        let f = &files[file];
+        let root = match f.pieces_root {
+            Some(r) if !f.padding => r,
+            _ => return Err("v2 pieces must start within a file"),
+        };
+        if f.length <= pl {
+            hashes.push(root);
+        } else {
+            let h = file_layers
+                .get(&root)
+                .map(|l| l[(offset / pl) as usize])
+                .unwrap_or([0; 32]);
+            hashes.push(h);
+        }
+    }
+    Ok(hashes)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -597,10 +1018,14 @@ mod tests {
         info.files.push(File {
             path: PathBuf::from(""),
             length: 40000,
+            pieces_root: None,
+            padding: false,
         });
         info.files.push(File {
             path: PathBuf::from(""),
             length: 10000,
+            pieces_root: None,
+            padding: false,
         });
         info.total_len = 50000;
         info.piece_idx =
@@ -642,4 +1067,160 @@ mod tests {
         assert_eq!(n.file, 1);
         assert_eq!(n.offset, 16384 - 7232);
     }
+
+    /// Builds a v2 torrent with a two piece file and a single piece file,
+    /// returning it along with the file contents.
+    fn v2_torrent(layers: bool) -> (BEncode, Vec<u8>, Vec<u8>) {
+        const PL: usize = 32_768;
+        let a: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        let b = vec![7u8; 100];
+        let pad = merkle::pad_hash(1);
+        let layer = vec![
+            merkle::data_root(&a[..PL], 2),
+            merkle::data_root(&a[PL..], 2),
+        ];
+        let a_root = merkle::root(&layer, 2, pad);
+        let b_root = merkle::data_root(&b, 1);
+
+        let file = |len: usize, root: Hash| {
+            let mut f = BTreeMap::new();
+            f.insert(b"length".to_vec(), BEncode::Int(len as i64));
+            f.insert(b"pieces root".to_vec(), BEncode::String(root.to_vec()));
+            let mut entry = BTreeMap::new();
+            entry.insert(b"".to_vec(), BEncode::Dict(f));
+            BEncode::Dict(entry)
+        };
+        let mut tree = BTreeMap::new();
+        tree.insert(b"a".to_vec(), file(a.len(), a_root));
+        tree.insert(b"b".to_vec(), file(b.len(), b_root));
+        let mut info = BTreeMap::new();
+        info.insert(b"file tree".to_vec(), BEncode::Dict(tree));
+        info.insert(b"meta version".to_vec(), BEncode::Int(2));
+        info.insert(b"name".to_vec(), BEncode::from_str("test"));
+        info.insert(b"piece length".to_vec(), BEncode::Int(PL as i64));
+
+        let mut torrent = BTreeMap::new();
+        torrent.insert(b"info".to_vec(), BEncode::Dict(info));
+        if layers {
+            let mut pl = BTreeMap::new();
+            let data = layer.iter().flat_map(|h| h.iter().cloned()).collect();
+            pl.insert(a_root.to_vec(), BEncode::String(data));
+            torrent.insert(b"piece layers".to_vec(), BEncode::Dict(pl));
+        }
+        (BEncode::Dict(torrent), a, b)
+    }
+
+    #[test]
+    fn parse_v2() {
+        let (torrent, a, b) = v2_torrent(true);
+        let info = Info::from_bencode(torrent.clone()).unwrap();
+        assert!(info.v2());
+        assert_eq!(info.pieces(), 3);
+        assert!(info.hashes.is_empty());
+        assert_eq!(info.rpc_id().len(), 64);
+        assert_eq!(info.hash[..], info.hash_v2.unwrap()[..20]);
+        assert_eq!(info.files.len(), 3);
+        assert!(info.files[1].padding);
+        assert_eq!(info.files[0].path, PathBuf::from("test/a"));
+        assert_eq!(info.files[2].path, PathBuf::from("test/b"));
+        assert_eq!(info.file_pieces(0), (0, 2));
+        assert_eq!(
+            info.layer_file(&info.files[0].pieces_root.unwrap()),
+            Some(0)
+        );
+
+        assert!(info.verify_piece(0, &a[..32_768]));
+        assert!(info.verify_piece(1, &[&a[32_768..], &[0u8; 25_536][..]].concat()));
+        assert!(info.verify_piece(2, &b));
+        assert!(!info.verify_piece(2, &a[..100]));
+
+        // The regenerated info dict must hash identically
+        let info2 = Info::from_bencode(info.to_torrent_bencode()).unwrap();
+        assert_eq!(info2.hash_v2, info.hash_v2);
+
+        // Without piece layers, only the small file can be verified
+        let (torrent, a, b) = v2_torrent(false);
+        let info = Info::from_bencode(torrent).unwrap();
+        assert!(!info.verify_piece(0, &a[..32_768]));
+        assert!(info.verify_piece(2, &b));
+    }
+
+    #[test]
+    fn parse_hybrid() {
+        let (torrent, a, b) = v2_torrent(true);
+        let mut torrent = torrent.into_dict().unwrap();
+        let mut info = torrent.remove(&b"info"[..]).unwrap().into_dict().unwrap();
+        let padded = [&a[..], &[0u8; 25_536][..], &b[..]].concat();
+        let pieces = padded
+            .chunks(32_768)
+            .flat_map(|p| sha1_hash(p).to_vec())
+            .collect();
+        let file = |path: &str, len: usize, pad: bool| {
+            let mut f = BTreeMap::new();
+            f.insert(b"length".to_vec(), BEncode::Int(len as i64));
+            let path = path.split('/').map(BEncode::from_str).collect();
+            f.insert(b"path".to_vec(), BEncode::List(path));
+            if pad {
+                f.insert(b"attr".to_vec(), BEncode::from_str("p"));
+            }
+            BEncode::Dict(f)
+        };
+        let files = vec![
+            file("a", a.len(), false),
+            file(".pad/25536", 25_536, true),
+            file("b", b.len(), false),
+        ];
+        info.insert(b"files".to_vec(), BEncode::List(files));
+        info.insert(b"pieces".to_vec(), BEncode::String(pieces));
+        torrent.insert(b"info".to_vec(), BEncode::Dict(info));
+
+        let info = Info::from_bencode(BEncode::Dict(torrent)).unwrap();
+        assert!(info.v2());
+        assert_eq!(info.hashes.len(), 3);
+        assert_eq!(info.hashes_v2.len(), 3);
+        assert_eq!(info.rpc_id().len(), 40);
+        assert!(info.files[0].pieces_root.is_some());
+        assert!(info.verify_piece(1, &padded[32_768..65_536]));
+        assert!(info.verify_piece(2, &b));
+    }
+
+    #[test]
+    fn parse_web_seeds() {
+        let mut info = BTreeMap::new();
+        info.insert(b"name".to_vec(), BEncode::String(b"a".to_vec()));
+        info.insert(b"length".to_vec(), BEncode::Int(16_384));
+        info.insert(b"piece length".to_vec(), BEncode::Int(16_384));
+        info.insert(b"pieces".to_vec(), BEncode::String(vec![0; 20]));
+        let mut torrent = BTreeMap::new();
+        torrent.insert(b"info".to_vec(), BEncode::Dict(info));
+        torrent.insert(
+            b"url-list".to_vec(),
+            BEncode::String(b"http://example.com/files/".to_vec()),
+        );
+        torrent.insert(
+            b"httpseeds".to_vec(),
+            BEncode::List(vec![
+                BEncode::String(b"http://example.com/seed.php".to_vec()),
+                BEncode::String(b"ftp://example.com/".to_vec()),
+            ]),
+        );
+
+        let info = Info::from_bencode(BEncode::Dict(torrent)).unwrap();
+        assert_eq!(
+            info.web_seeds,
+            vec![
+                WebSeed {
+                    url: Url::parse("http://example.com/files/").unwrap(),
+                    kind: WebSeedKind::Url,
+                },
+                WebSeed {
+                    url: Url::parse("http://example.com/seed.php").unwrap(),
+                    kind: WebSeedKind::Http,
+                },
+            ]
+        );
+
+        let info = Info::from_bencode(info.to_torrent_bencode()).unwrap();
+        assert_eq!(info.web_seeds.len(), 2);
+    }
 }
@@ -0,0 +1,73 @@
+//! Limits on how long a complete torrent seeds for. Each torrent has its
+//! own limits which fall back to the server's, and once any is reached
+//! the torrent is paused or removed.
+
+use crate::rpc::resource::SeedAction;
+
+/// Seeding limits, where a negative value disables the limit. A torrent's
+/// limits which are None defer to the server's.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SeedLimits {
+    /// Ratio of uploaded to downloaded data
+    pub ratio: Option<f32>,
+    /// Seconds spent seeding
+    pub seed_time: Option<i64>,
+    /// Seconds spent seeding without uploading anything
+    pub idle: Option<i64>,
+    pub action: Option<SeedAction>,
+}
+
+impl SeedLimits {
+    /// Combines a torrent's limits with the server's, leaving only
+    /// those which are enabled.
+    pub fn resolve(&self, server: &SeedLimits) -> SeedLimits {
+        SeedLimits {
+            ratio: enabled(self.ratio.or(server.ratio)),
+            seed_time: enabled(self.seed_time.or(server.seed_time)),
+            idle: enabled(self.idle.or(server.idle)),
+            action: self.action.or(server.action),
+        }
+    }
+
+    /// Whether any limit has been reached.
+    pub fn reached(&self, ratio: f32, seed_time: u64, idle: u64) -> bool {
+        self.ratio.map_or(false, |r| ratio >= r)
+            || self.seed_time.map_or(false, |t| seed_time >= t as u64)
+            || self.idle.map_or(false, |t| idle >= t as u64)
+    }
+}
+
+fn enabled<T: PartialOrd + Default>(limit: Option<T>) -> Option<T> {
+    limit.filter(|l| *l >= T::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SeedLimits;
+    use crate::rpc::resource::SeedAction;
+
+    #[test]
+    fn test_resolve() {
+        let server = SeedLimits {
+            ratio: Some(2.0),
+            seed_time: Some(3600),
+            idle: None,
+            action: Some(SeedAction::Remove),
+        };
+        let torrent = SeedLimits {
+            ratio: Some(-1.0),
+            idle: Some(600),
+            ..Default::default()
+        };
+        let limits = torrent.resolve(&server);
+        assert_eq!(limits.ratio, None);
+        assert_eq!(limits.seed_time, Some(3600));
+        assert_eq!(limits.idle, Some(600));
+        assert_eq!(limits.action, Some(SeedAction::Remove));
+
+        assert!(!limits.reached(5.0, 3599, 599));
+        assert!(limits.reached(0.0, 3600, 0));
+        assert!(limits.reached(0.0, 0, 600));
+        assert!(!SeedLimits::default().reached(100.0, 1 << 40, 1 << 40));
+    }
+}
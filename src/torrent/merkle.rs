@@ -0,0 +1,151 @@
+//! BEP 52 merkle tree helpers. Layers are numbered from the 16 KiB
+//! block hashes upwards, with layer 0 being the leaves.
+
+use sha2::{Digest, Sha256};
+
+use crate::util::sha256_hash;
+
+pub const BLOCK_LEN: usize = 16_384;
+
+pub type Hash = [u8; 32];
+
+pub fn hash_pair(l: &Hash, r: &Hash) -> Hash {
+    let mut ctx = Sha256::new();
+    ctx.update(l);
+    ctx.update(r);
+    ctx.finalize().into()
+}
+
+/// Root hash of a subtree of the given height whose leaves are all zero.
+pub fn pad_hash(height: u32) -> Hash {
+    (0..height).fold([0; 32], |h, _| hash_pair(&h, &h))
+}
+
+/// Computes the root of a layer of hashes, padded out to width
+/// entries with the given padding hash.
+pub fn root(hashes: &[Hash], width: usize, mut pad: Hash) -> Hash {
+    let mut width = width.max(1).next_power_of_two();
+    if hashes.is_empty() {
+        return (0..width.trailing_zeros()).fold(pad, |h, _| hash_pair(&h, &h));
+    }
+    let mut layer = hashes.to_vec();
+    while width > 1 {
+        if layer.len() % 2 == 1 {
+            layer.push(pad);
+        }
+        layer = layer.chunks(2).map(|p| hash_pair(&p[0], &p[1])).collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer[0]
+}
+
+/// Computes the root of the tree spanning data, with width leaves.
+pub fn data_root(data: &[u8], width: usize) -> Hash {
+    let leaves: Vec<_> = data.chunks(BLOCK_LEN).map(sha256_hash).collect();
+    root(&leaves, width, [0; 32])
+}
+
+/// Collects the uncle hashes needed to prove the range of len hashes at
+/// index within layer, going up at most layers levels past the range root.
+pub fn proof(layer: &[Hash], pad: Hash, index: usize, len: usize, layers: usize) -> Vec<Hash> {
+    let width = layer.len().max(1).next_power_of_two();
+    let mut proof = Vec::new();
+    let mut pad = pad;
+    let mut cur = layer.to_vec();
+    let mut idx = index;
+    let mut span = 1;
+    let mut w = width;
+    while w > 1 {
+        if cur.len() % 2 == 1 {
+            cur.push(pad);
+        }
+        if span >= len && proof.len() < layers {
+            let sibling = idx ^ 1;
+            proof.push(*cur.get(sibling).unwrap_or(&pad));
+        }
+        cur = cur.chunks(2).map(|p| hash_pair(&p[0], &p[1])).collect();
+        pad = hash_pair(&pad, &pad);
+        idx /= 2;
+        span *= 2;
+        w /= 2;
+    }
+    proof
+}
+
+/// Verifies that hashes, located at index within a layer of the given
+/// width, combine with the proof hashes to form the expected root.
+pub fn verify(
+    hashes: &[Hash],
+    index: usize,
+    width: usize,
+    pad: Hash,
+    proof: &[Hash],
+    expected: &Hash,
+) -> bool {
+    if hashes.is_empty() || !hashes.len().is_power_of_two() || index % hashes.len() != 0 {
+        return false;
+    }
+    let mut h = root(hashes, hashes.len(), pad);
+    let mut idx = index / hashes.len();
+    let mut w = width.max(1).next_power_of_two() / hashes.len();
+    let mut proof = proof.iter();
+    while w > 1 {
+        let p = match proof.next() {
+            Some(p) => p,
+            None => return false,
+        };
+        h = if idx % 2 == 0 {
+            hash_pair(&h, p)
+        } else {
+            hash_pair(p, &h)
+        };
+        idx /= 2;
+        w /= 2;
+    }
+    &h == expected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_padding() {
+        let a = sha256_hash(b"a");
+        let b = sha256_hash(b"b");
+        let c = sha256_hash(b"c");
+        let z = [0; 32];
+        let expected = hash_pair(&hash_pair(&a, &b), &hash_pair(&c, &z));
+        assert_eq!(root(&[a, b, c], 4, z), expected);
+        assert_eq!(root(&[a, b, c], 3, z), expected);
+        assert_eq!(root(&[a], 1, z), a);
+        assert_eq!(pad_hash(1), hash_pair(&z, &z));
+        assert_eq!(root(&[], 1, z), z);
+        assert_eq!(root(&[], 4, z), pad_hash(2));
+        assert_eq!(root(&[], 3, z), pad_hash(2));
+
+        let data = vec![1u8; BLOCK_LEN * 2 + 10];
+        let leaves = [
+            sha256_hash(&data[..BLOCK_LEN]),
+            sha256_hash(&data[BLOCK_LEN..BLOCK_LEN * 2]),
+            sha256_hash(&data[BLOCK_LEN * 2..]),
+        ];
+        assert_eq!(data_root(&data, 4), root(&leaves, 4, z));
+    }
+
+    #[test]
+    fn test_proof() {
+        let layer: Vec<_> = (0..5u8).map(|i| sha256_hash(&[i])).collect();
+        let pad = pad_hash(2);
+        let r = root(&layer, 8, pad);
+        for &(idx, len) in &[(0, 2), (2, 2), (4, 2), (0, 4), (4, 4), (0, 8)] {
+            let end = (idx + len).min(layer.len());
+            let mut hashes = layer[idx..end].to_vec();
+            hashes.resize(len, pad);
+            let p = proof(&layer, pad, idx, len, 10);
+            assert!(verify(&hashes, idx, 8, pad, &p, &r));
+            assert!(!verify(&hashes, idx, 8, pad, &p, &[0; 32]));
+        }
+    }
+}
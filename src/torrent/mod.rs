@@ -1,40 +1,62 @@
 pub mod bitfield;
 mod choker;
 pub mod info;
+mod limits;
+mod merkle;
 pub mod peer;
 mod picker;
+mod smartban;
+mod superseed;
+mod webseed;
 
 use std::borrow::Cow;
 use std::collections::{BTreeMap, VecDeque};
-use std::fmt;
+use std::ops::Range;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
+use std::{cmp, fmt};
 
 use crate::bencode::BEncode;
-use byteorder::{BigEndian, ByteOrder};
 use chrono::{DateTime, Utc};
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, SocketAddr};
 use url::Url;
 
 pub use self::bitfield::Bitfield;
 pub use self::info::{Info, LocIter};
+pub use self::limits::SeedLimits;
 pub use self::peer::Message;
 pub use self::peer::{Peer, PeerConn};
 pub use self::picker::Block;
 
 use self::picker::Picker;
+use self::smartban::SmartBan;
+use self::superseed::SuperSeed;
+use self::webseed::WebSeed;
 use crate::buffers::Buffer;
 use crate::control::cio;
+use crate::protocol::HashRequest;
 use crate::rpc::resource::{self, Resource, SResourceUpdate};
 use crate::session::torrent::current::Session;
-use crate::throttle::Throttle;
+use crate::throttle::{Throttle, ThrottleGroup};
 use crate::tracker::{self, TrackerResponse};
 use crate::util::{FHashSet, UHashMap};
 use crate::{bencode, disk, rpc, util, CONFIG, EXT_PROTO, UT_META_ID, UT_PEX_ID};
 use crate::{session, stat};
 
 const MAX_PEERS: usize = 50;
+/// BEP 52 caps the number of base layer hashes in a single request
+const MAX_HASH_REQ: usize = 512;
+/// BEP 11 flags, indicating a seed and a reachable peer respectively
+const PEX_SEED: u8 = 0x02;
+const PEX_OUTGOING: u8 = 0x10;
+/// Most peers remembered as advertised over PEX by a single peer
+const MAX_PEX_PEERS: usize = 500;
+/// Interval in milliseconds by which the deadlines of successive
+/// pieces of a streaming download are staggered
+const STREAM_INTERVAL_MS: u64 = 500;
+/// Number of pieces from a streaming download's position given deadlines
+const STREAM_WINDOW: usize = 8;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum TrackerStatus {
@@ -55,18 +77,45 @@ pub struct Torrent<T: cio::CIO> {
     cio: T,
     uploaded: u64,
     downloaded: u64,
+    /// Bytes of duplicate blocks received this session
     wasted: u64,
+    /// Whether the picker was in endgame when last checked
+    endgame: bool,
     stat: stat::EMA,
     files: Files,
     priority: u8,
     priorities: Arc<Vec<u8>>,
     throttle: Throttle,
+    /// RPC ID of the throttle group the torrent is in
+    throttle_group: Option<String>,
+    /// Trackers ordered by tier
     trackers: VecDeque<Tracker>,
+    /// Index of the tracker currently in use for each tier
+    trk_cursor: Vec<usize>,
+    /// Tier currently announced to, unless announcing to all tiers
+    trk_tier: usize,
+    webseeds: Vec<WebSeed>,
+    /// Streaming downloads being sent by disk
+    streams: Vec<Stream>,
+    /// Last ID given to a stream
+    stream_cnt: usize,
     peers: UHashMap<Peer<T>>,
     leechers: FHashSet<usize>,
     picker: Picker,
     status: Status,
     choker: choker::Choker,
+    /// Whether super-seeding was requested, it only happens once every piece is present
+    super_seed: bool,
+    super_seeder: Option<SuperSeed>,
+    smart_ban: SmartBan,
+    /// Bans which apply to this torrent, as maintained by control
+    bans: Vec<resource::Ban>,
+    limits: SeedLimits,
+    /// Seconds spent seeding, excluding the current run
+    seed_time: u64,
+    /// When the current run of seeding began
+    seeding: Option<Instant>,
+    last_upload: Instant,
     dirty: bool,
     path: Option<String>,
     info_bytes: Vec<u8>,
@@ -92,9 +141,29 @@ pub enum StatusState {
 
 pub struct Tracker {
     pub url: Arc<Url>,
+    pub tier: usize,
     pub status: TrackerStatus,
     pub last_announce: DateTime<Utc>,
     pub update: Option<Instant>,
+    /// Swarm statistics, from the latest announce or scrape
+    pub complete: Option<u32>,
+    pub incomplete: Option<u32>,
+    pub downloaded: Option<u32>,
+}
+
+impl Tracker {
+    fn new(url: Arc<Url>, tier: usize) -> Tracker {
+        Tracker {
+            tier,
+            status: TrackerStatus::Updating,
+            update: None,
+            last_announce: Utc::now(),
+            url,
+            complete: None,
+            incomplete: None,
+            downloaded: None,
+        }
+    }
 }
 
 struct Files {
@@ -102,6 +171,17 @@ struct Files {
     dirty: FHashSet<usize>,
 }
 
+/// A streaming download, of which only the pieces just ahead of
+/// its position have deadlines.
+struct Stream {
+    id: usize,
+    /// Ranges of pieces left to send, in order
+    ranges: VecDeque<Range<u32>>,
+    /// Pieces being validated when the stream was sent to disk, which
+    /// it has to be told about if they turn out valid
+    pending: Vec<u32>,
+}
+
 impl Status {
     pub fn magnet(&self) -> bool {
         match self.state {
@@ -132,7 +212,11 @@ impl Status {
         self.leeching() && !self.stopped() && self.validating.is_none()
     }
 
-    pub fn as_rpc(&self, ul: u64, dl: u64) -> rpc::resource::Status {
+    pub fn should_ul(&self) -> bool {
+        self.completed() && !self.stopped()
+    }
+
+    pub fn as_rpc(&self, ul: u64, dl: u64, partial: bool) -> rpc::resource::Status {
         if self.paused {
             return rpc::resource::Status::Paused;
         }
@@ -152,7 +236,9 @@ impl Status {
                 }
             }
             StatusState::Complete => {
-                if ul == 0 {
+                if partial {
+                    rpc::resource::Status::PartialSeed
+                } else if ul == 0 {
                     rpc::resource::Status::Idle
                 } else {
                     rpc::resource::Status::Seeding
@@ -163,6 +249,24 @@ impl Status {
     }
 }
 
+impl Stream {
+    /// Moves the stream up to a piece it has reached.
+    fn advance(&mut self, piece: u32) {
+        if let Some(i) = self.ranges.iter().position(|r| r.contains(&piece)) {
+            self.ranges.drain(..i);
+            self.ranges[0].start = piece;
+        }
+    }
+
+    /// The pieces which should have deadlines, in the order they're needed.
+    fn window<'a>(&'a self) -> impl Iterator<Item = u32> + 'a {
+        self.ranges
+            .iter()
+            .flat_map(|r| r.clone())
+            .take(STREAM_WINDOW)
+    }
+}
+
 impl Files {
     fn new(info: &Arc<Info>, pieces: &Bitfield) -> Files {
         let mut f = Files {
@@ -248,26 +352,24 @@ impl<T: cio::CIO> Torrent<T> {
         if !info.url_list.is_empty() {
             for (i, list) in info.url_list.iter().enumerate() {
                 for (j, _) in list.iter().enumerate() {
-                    let tracker = Tracker {
-                        status: TrackerStatus::Updating,
-                        update: None,
-                        last_announce: Utc::now(),
-                        url: Arc::clone(&info.url_list[i][j]),
-                    };
+                    let tracker = Tracker::new(Arc::clone(&info.url_list[i][j]), i);
                     trackers.push_back(tracker);
                 }
             }
         } else if let Some(ref announce) = info.announce {
-            let tracker = Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: announce.clone(),
-            };
+            let tracker = Tracker::new(announce.clone(), 0);
             trackers.push_back(tracker);
         }
 
         let files = Files::new(&info, &pieces);
+        let trk_cursor = tier_starts(&trackers);
+        let webseeds = info
+            .web_seeds
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(i, s)| WebSeed::new(i, s))
+            .collect();
 
         let mut t = Torrent {
             id,
@@ -282,19 +384,35 @@ impl<T: cio::CIO> Torrent<T> {
             uploaded: 0,
             downloaded: 0,
             wasted: 0,
+            endgame: false,
             files,
             stat: stat::EMA::new(),
             cio,
             leechers,
             throttle,
+            throttle_group: None,
             trackers,
+            trk_cursor,
+            trk_tier: 0,
+            webseeds,
+            streams: Vec::new(),
+            stream_cnt: 0,
             choker: choker::Choker::new(),
+            super_seed: false,
+            super_seeder: None,
+            smart_ban: SmartBan::new(),
+            bans: Vec::new(),
+            limits: SeedLimits::default(),
+            seed_time: 0,
+            seeding: None,
+            last_upload: Instant::now(),
             dirty: true,
             status,
             info_bytes,
             info_idx,
             created: Utc::now(),
         };
+        t.throttle.set_weight(t.priority);
         t.start(true);
         if import {
             t.cio.msg_disk(disk::Request::validate_piece(
@@ -302,6 +420,7 @@ impl<T: cio::CIO> Torrent<T> {
                 t.info.clone(),
                 t.path.clone(),
                 0,
+                false,
             ));
             t.validating.insert(0);
         } else if CONFIG.disk.validate && t.info_idx.is_none() {
@@ -336,6 +455,8 @@ impl<T: cio::CIO> Torrent<T> {
             total_len: d.info.total_len,
             hashes: d.info.hashes,
             hash: d.info.hash,
+            hash_v2: d.info.hash_v2,
+            hashes_v2: d.info.hashes_v2,
             files: d
                 .info
                 .files
@@ -343,12 +464,28 @@ impl<T: cio::CIO> Torrent<T> {
                 .map(|f| info::File {
                     path: f.path,
                     length: f.length,
+                    pieces_root: f.pieces_root,
+                    padding: f.padding,
                 })
                 .collect(),
             private: d.info.private,
             be_name: d.info.be_name,
             piece_idx: d.info.piece_idx,
             url_list: vec![],
+            web_seeds: d
+                .info
+                .web_seeds
+                .into_iter()
+                .filter_map(|s| {
+                    let kind = match s.kind {
+                        session::torrent::current::WebSeedKind::Url => info::WebSeedKind::Url,
+                        session::torrent::current::WebSeedKind::Http => info::WebSeedKind::Http,
+                    };
+                    Url::parse(&s.url)
+                        .ok()
+                        .map(|url| info::WebSeed { url, kind })
+                })
+                .collect(),
         });
 
         let info_idx = if info.complete() {
@@ -365,32 +502,36 @@ impl<T: cio::CIO> Torrent<T> {
         let picker = picker::Picker::new(&info, &pieces, &d.priorities);
         throttle.set_ul_rate(d.throttle_ul);
         throttle.set_dl_rate(d.throttle_dl);
+        throttle.set_weight(d.priority);
 
-        let mut trackers: VecDeque<_> = d
+        let mut trackers: Vec<_> = d
             .trackers
             .into_iter()
-            .filter_map(|url| Url::parse(&url).ok())
-            .map(|url| Tracker {
-                status: TrackerStatus::Updating,
-                update: None,
-                last_announce: Utc::now(),
-                url: Arc::new(url),
+            .filter_map(|t| {
+                Url::parse(&t.url)
+                    .ok()
+                    .map(|url| Tracker::new(Arc::new(url), t.tier))
             })
             .collect();
+        trackers.sort_by_key(|t| t.tier);
+        let mut trackers = VecDeque::from(trackers);
 
         if trackers.is_empty() {
             if let Some(ref announce) = info.announce {
-                let tracker = Tracker {
-                    status: TrackerStatus::Updating,
-                    update: None,
-                    last_announce: Utc::now(),
-                    url: announce.clone(),
-                };
+                let tracker = Tracker::new(announce.clone(), 0);
                 trackers.push_back(tracker);
             }
         }
 
         let files = Files::new(&info, &pieces);
+        let trk_cursor = tier_starts(&trackers);
+        let webseeds = info
+            .web_seeds
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(i, s)| WebSeed::new(i, s))
+            .collect();
 
         let mut t = Torrent {
             id,
@@ -402,6 +543,7 @@ impl<T: cio::CIO> Torrent<T> {
             uploaded: d.uploaded,
             downloaded: d.downloaded,
             wasted: 0,
+            endgame: false,
             files,
             stat: stat::EMA::new(),
             priorities: Arc::new(d.priorities),
@@ -409,8 +551,33 @@ impl<T: cio::CIO> Torrent<T> {
             cio,
             leechers,
             throttle,
+            throttle_group: d.throttle_group,
             trackers,
+            trk_cursor,
+            trk_tier: 0,
+            webseeds,
+            streams: Vec::new(),
+            stream_cnt: 0,
             choker: choker::Choker::new(),
+            super_seed: d.super_seed,
+            super_seeder: None,
+            smart_ban: SmartBan::new(),
+            bans: Vec::new(),
+            limits: SeedLimits {
+                ratio: d.ratio_limit,
+                seed_time: d.seed_time_limit,
+                idle: d.idle_limit,
+                action: d.limit_action.map(|a| match a {
+                    session::torrent::current::SeedAction::Pause => resource::SeedAction::Pause,
+                    session::torrent::current::SeedAction::Remove => resource::SeedAction::Remove,
+                    session::torrent::current::SeedAction::RemoveData => {
+                        resource::SeedAction::RemoveData
+                    }
+                }),
+            },
+            seed_time: d.seed_time,
+            seeding: None,
+            last_upload: Instant::now(),
             dirty: false,
             status: Status {
                 paused: d.status.paused,
@@ -429,6 +596,7 @@ impl<T: cio::CIO> Torrent<T> {
         };
         t.status.error = None;
         t.start(false);
+        t.update_super_seed();
         if d.status.validating {
             t.validate();
         } else {
@@ -448,6 +616,8 @@ impl<T: cio::CIO> Torrent<T> {
                 total_len: self.info.total_len,
                 hashes: self.info.hashes.clone(),
                 hash: self.info.hash,
+                hash_v2: self.info.hash_v2,
+                hashes_v2: self.info.hashes_v2.clone(),
                 files: self
                     .info
                     .files
@@ -456,11 +626,25 @@ impl<T: cio::CIO> Torrent<T> {
                     .map(|f| session::torrent::current::File {
                         path: f.path,
                         length: f.length,
+                        pieces_root: f.pieces_root,
+                        padding: f.padding,
                     })
                     .collect(),
                 private: self.info.private,
                 be_name: self.info.be_name.clone(),
                 piece_idx: self.info.piece_idx.clone(),
+                web_seeds: self
+                    .info
+                    .web_seeds
+                    .iter()
+                    .map(|s| session::torrent::current::WebSeed {
+                        url: s.url.as_str().to_owned(),
+                        kind: match s.kind {
+                            info::WebSeedKind::Url => session::torrent::current::WebSeedKind::Url,
+                            info::WebSeedKind::Http => session::torrent::current::WebSeedKind::Http,
+                        },
+                    })
+                    .collect(),
             },
             pieces: session::torrent::Bitfield {
                 data: self.pieces.data(),
@@ -489,8 +673,24 @@ impl<T: cio::CIO> Torrent<T> {
             trackers: self
                 .trackers
                 .iter()
-                .map(|trk| trk.url.as_str().to_owned())
+                .map(|trk| session::torrent::current::Tracker {
+                    url: trk.url.as_str().to_owned(),
+                    tier: trk.tier,
+                })
                 .collect(),
+            super_seed: self.super_seed,
+            seed_time: self.seed_time(),
+            ratio_limit: self.limits.ratio,
+            seed_time_limit: self.limits.seed_time,
+            idle_limit: self.limits.idle,
+            limit_action: self.limits.action.map(|a| match a {
+                resource::SeedAction::Pause => session::torrent::current::SeedAction::Pause,
+                resource::SeedAction::Remove => session::torrent::current::SeedAction::Remove,
+                resource::SeedAction::RemoveData => {
+                    session::torrent::current::SeedAction::RemoveData
+                }
+            }),
+            throttle_group: self.throttle_group.clone(),
         };
         let data = bincode::serialize(&d).expect("Serialization failed!");
         debug!("Sending serialization request!");
@@ -500,7 +700,7 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn rpc_id(&self) -> String {
-        util::hash_to_id(&self.info.hash[..])
+        self.info.rpc_id()
     }
 
     pub fn delete(&mut self, artifacts: bool) {
@@ -532,7 +732,6 @@ impl<T: cio::CIO> Torrent<T> {
 
     pub fn set_tracker_response(&mut self, url: &Url, resp: &tracker::Result<TrackerResponse>) {
         let mut time = Instant::now();
-        let mut empty = false;
         match *resp {
             Ok(ref r) => {
                 if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
@@ -543,11 +742,10 @@ impl<T: cio::CIO> Torrent<T> {
                         leechers: r.leechers,
                         interval: r.interval,
                     };
+                    tracker.complete = Some(r.seeders);
+                    tracker.incomplete = Some(r.leechers);
                     tracker.update = Some(time);
                     tracker.last_announce = Utc::now();
-                    if r.peers.is_empty() {
-                        empty = true;
-                    }
                 }
             }
             Err(tracker::Error(tracker::ErrorKind::TrackerError(ref s), _)) => {
@@ -572,40 +770,123 @@ impl<T: cio::CIO> Torrent<T> {
             }
         }
 
-        if (resp.is_err() || empty) && self.trackers.iter().any(|t| &*t.url == url) {
-            if let Some(front) = self.trackers.pop_front() {
-                self.trackers.push_back(front);
-                self.try_update_tracker();
+        if let Some(idx) = self.trackers.iter().position(|t| &*t.url == url) {
+            let tiers = tier_ranges(&self.trackers);
+            let tier = tiers.iter().position(|r| r.contains(&idx)).unwrap();
+            let range = tiers[tier].clone();
+            if resp.is_ok() {
+                // BEP 12: a working tracker is moved to the front of its tier
+                let trk = self.trackers.remove(idx).unwrap();
+                self.trackers.insert(range.start, trk);
+                self.trk_cursor[tier] = range.start;
+            } else if self.trk_cursor[tier] == idx {
+                // Try the next tracker in the tier, falling through to the
+                // next tier once every tracker in this one has failed.
+                if idx + 1 < range.end {
+                    self.trk_cursor[tier] = idx + 1;
+                } else {
+                    self.trk_cursor[tier] = range.start;
+                    if self.trk_tier == tier {
+                        self.trk_tier = (tier + 1) % tiers.len();
+                    }
+                }
+                self.announce_due();
             }
         }
         self.update_rpc_tracker();
     }
 
+    pub fn set_scrape_response(&mut self, url: &Url, resp: &tracker::ScrapeResponse) {
+        if let Some(tracker) = self.trackers.iter_mut().find(|t| &*t.url == url) {
+            tracker.complete = Some(resp.complete);
+            tracker.incomplete = Some(resp.incomplete);
+            tracker.downloaded = Some(resp.downloaded);
+        }
+        self.update_rpc_tracker();
+    }
+
     pub fn try_update_tracker(&mut self) {
         if self.status.stopped() {
             return;
         }
-        if let Some(end) = self.trackers.front().and_then(|t| t.update) {
-            debug!("Updating tracker at interval!");
-            let cur = Instant::now();
-            if cur >= end {
-                self.update_tracker();
+        // BEP 12: each announce starts again from the first tier, so once
+        // a fallback tier is due the first tier is retried instead.
+        if self.trk_tier != 0 && !CONFIG.trk.announce_all_tiers && !self.due_trackers().is_empty() {
+            self.trk_tier = 0;
+            if let Some(&idx) = self.trk_cursor.first() {
+                self.trackers[idx].update = None;
             }
-        } else {
+        }
+        self.announce_due();
+    }
+
+    /// Announces to the trackers in use which are due an announce.
+    fn announce_due(&mut self) {
+        if self.status.stopped() {
+            return;
+        }
+        if self.active_trackers().is_empty() {
             self.update_tracker();
+            return;
+        }
+        let due = self.due_trackers();
+        if due.is_empty() {
+            return;
         }
+        debug!("Updating tracker at interval!");
+        for url in due {
+            let req = tracker::Request::announce(self, url, None);
+            self.cio.msg_trk(req);
+        }
+        self.dht_announce();
     }
 
     pub fn update_tracker(&mut self) {
         if self.status.stopped() {
             return;
         }
-        if let Some(req) = tracker::Request::interval(self) {
+        self.trk_tier = 0;
+        for req in tracker::Request::interval(self) {
             self.cio.msg_trk(req);
         }
         self.dht_announce();
     }
 
+    /// URLs of the trackers announces are currently sent to.
+    pub fn announce_urls(&self) -> Vec<Arc<Url>> {
+        self.active_trackers()
+            .into_iter()
+            .map(|i| self.trackers[i].url.clone())
+            .collect()
+    }
+
+    fn due_trackers(&self) -> Vec<Arc<Url>> {
+        let cur = Instant::now();
+        self.active_trackers()
+            .into_iter()
+            .map(|i| &self.trackers[i])
+            .filter(|t| t.update.map(|end| cur >= end).unwrap_or(true))
+            .map(|t| t.url.clone())
+            .collect()
+    }
+
+    fn active_trackers(&self) -> Vec<usize> {
+        if CONFIG.trk.announce_all_tiers {
+            self.trk_cursor.clone()
+        } else {
+            self.trk_cursor
+                .get(self.trk_tier)
+                .cloned()
+                .into_iter()
+                .collect()
+        }
+    }
+
+    fn reset_tiers(&mut self) {
+        self.trk_cursor = tier_starts(&self.trackers);
+        self.trk_tier = 0;
+    }
+
     pub fn remove_peer(&mut self, rpc_id: &str) {
         let ih = &self.info.hash;
         let cio = &mut self.cio;
@@ -620,12 +901,9 @@ impl<T: cio::CIO> Torrent<T> {
 
     pub fn add_tracker(&mut self, url: Url) -> String {
         let id = util::trk_rpc_id(&self.info.hash, url.as_str());
-        self.trackers.push_front(Tracker {
-            status: TrackerStatus::Updating,
-            update: None,
-            last_announce: Utc::now(),
-            url: Arc::new(url),
-        });
+        let tier = self.trackers.front().map(|t| t.tier).unwrap_or(0);
+        self.trackers.push_front(Tracker::new(Arc::new(url), tier));
+        self.reset_tiers();
         {
             let trk = &self.trackers[0];
             let res = vec![resource::Resource::Tracker(resource::Tracker {
@@ -634,6 +912,7 @@ impl<T: cio::CIO> Torrent<T> {
                 url: trk.url.as_ref().clone(),
                 last_report: trk.last_announce,
                 error: None,
+                tier: trk.tier,
                 ..Default::default()
             })];
             self.cio.msg_rpc(rpc::CtlMessage::Extant(res));
@@ -656,6 +935,7 @@ impl<T: cio::CIO> Torrent<T> {
 
         if let Some(idx) = res {
             self.trackers.remove(idx);
+            self.reset_tiers();
         }
     }
 
@@ -664,7 +944,7 @@ impl<T: cio::CIO> Torrent<T> {
             .trackers
             .iter()
             .find(|trk| util::trk_rpc_id(&self.info.hash, trk.url.as_str()) == rpc_id)
-            .and_then(|trk| tracker::Request::custom(self, trk.url.clone()))
+            .map(|trk| tracker::Request::custom(self, trk.url.clone()))
         {
             self.cio.msg_trk(req)
         }
@@ -674,6 +954,34 @@ impl<T: cio::CIO> Torrent<T> {
         self.throttle.new_sibling(id)
     }
 
+    pub fn throttle_group(&self) -> Option<&str> {
+        self.throttle_group.as_ref().map(String::as_str)
+    }
+
+    /// Attaches the throttle group loaded from the session, leaving the
+    /// group if it no longer exists.
+    pub fn restore_throttle_group(&mut self, group: Option<&ThrottleGroup>) {
+        if group.is_none() {
+            self.throttle_group = None;
+        }
+        self.throttle.set_group(group);
+    }
+
+    /// Moves the torrent into a throttle group, or out of its current one.
+    pub fn set_throttle_group(&mut self, group: Option<(&str, &ThrottleGroup)>) {
+        self.throttle.set_group(group.map(|(_, g)| g));
+        self.throttle_group = group.map(|(id, _)| id.to_owned());
+        self.dirty = true;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentThrottleGroup {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                throttle_group: self.throttle_group.clone(),
+            },
+        ]));
+    }
+
     pub fn id(&self) -> usize {
         self.id
     }
@@ -703,10 +1011,15 @@ impl<T: cio::CIO> Torrent<T> {
             disk::Response::Read { context, data } => {
                 trace!("Received piece from disk, uploading!");
                 if let Some(peer) = self.peers.get_mut(&context.pid) {
+                    // Dropped if cancelled or rejected while being read
+                    if !peer.take_request(context.idx, context.begin) {
+                        return;
+                    }
                     let p = Message::piece(context.idx, context.begin, context.length, data);
                     // This may not be 100% accurate, but close enough for now.
                     self.uploaded += u64::from(context.length);
                     self.stat.add_ul(u64::from(context.length));
+                    self.last_upload = Instant::now();
                     self.dirty = true;
                     peer.send_message(p);
                 }
@@ -723,7 +1036,12 @@ impl<T: cio::CIO> Torrent<T> {
                     },
                 ]));
             }
-            disk::Response::PieceValidated { piece, valid, .. } => {
+            disk::Response::PieceValidated {
+                piece,
+                valid,
+                blocks,
+                ..
+            } => {
                 self.validating.remove(&piece);
                 if let StatusState::Import = self.status.state {
                     self.status.state = StatusState::Incomplete;
@@ -741,8 +1059,28 @@ impl<T: cio::CIO> Torrent<T> {
                     self.update_rpc_transfer();
                     return;
                 }
+                let sources = self.picker.sources(piece);
+                let mut pending = false;
+                for s in &mut self.streams {
+                    if let Some(i) = s.pending.iter().position(|&p| p == piece) {
+                        s.pending.swap_remove(i);
+                        pending = true;
+                    }
+                }
+                if valid && pending {
+                    // Disk may have validated the piece before the stream
+                    // arrived, in which case it was never made available
+                    self.cio.msg_disk(disk::Request::StreamPiece {
+                        tid: self.id,
+                        piece,
+                    });
+                }
                 if valid {
+                    for ip in self.smart_ban.validated(piece, &blocks) {
+                        self.ban(ip, format!("Sent corrupt data for piece {}", piece));
+                    }
                     self.pieces.set_bit(u64::from(piece));
+                    self.picker.validated(piece);
                     // Tell all relevant peers we got the piece
                     let m = Message::Have(piece);
                     for pid in &self.leechers {
@@ -755,8 +1093,10 @@ impl<T: cio::CIO> Torrent<T> {
                     self.files.update(&self.info, piece);
                     self.check_complete();
                 } else {
-                    // TODO: trace down the bad peer and block it
                     debug!("Invalid piece downloaded!");
+                    if let Some(ip) = self.smart_ban.failed(piece, &sources, &blocks) {
+                        self.ban(ip, format!("Sent corrupt piece {}", piece));
+                    }
                     self.picker.invalidate_piece(piece);
                     if !self.stat.active() {
                         self.request_all();
@@ -836,6 +1176,23 @@ impl<T: cio::CIO> Torrent<T> {
                     self.pieces.unset_bit(u64::from(piece));
                 }
             }
+            disk::Response::StreamPosition { stream, piece, .. } => {
+                if let Some(s) = self.streams.iter_mut().find(|s| s.id == stream) {
+                    s.advance(piece);
+                }
+                self.update_stream_deadlines();
+                self.request_all();
+            }
+            disk::Response::StreamDone { stream, .. } => {
+                if let Some(i) = self.streams.iter().position(|s| s.id == stream) {
+                    let s = self.streams.swap_remove(i);
+                    for piece in s.window() {
+                        self.picker.clear_deadline(piece);
+                    }
+                    // Restore any deadlines shared with other streams
+                    self.update_stream_deadlines();
+                }
+            }
             disk::Response::FreeSpace(_) => unreachable!(),
         }
     }
@@ -868,13 +1225,15 @@ impl<T: cio::CIO> Torrent<T> {
             self.announce_status();
             self.announce_start();
             self.request_all();
+            self.announce_upload_only();
         }
+        self.update_super_seed();
     }
     /// Signal that we've downloaded and verified the torrent
     fn set_finished(&mut self) {
         info!("Torrent {} completed!", self.rpc_id());
-        debug!("Wasted: {} MiB", (self.wasted * 16_384) / (1024 * 1024));
-        if let Some(req) = tracker::Request::completed(self) {
+        debug!("Wasted: {} MiB", self.wasted / (1024 * 1024));
+        for req in tracker::Request::completed(self) {
             self.cio.msg_trk(req);
         }
         // Order here is important, if we're in an idle status,
@@ -883,13 +1242,13 @@ impl<T: cio::CIO> Torrent<T> {
         self.status.state = StatusState::Complete;
         self.announce_status();
 
-        // Remove all seeding peers.
+        // Remove all seeding peers, and partial seeds.
         let leechers = &self.leechers;
         {
             let seeders = self
                 .peers
                 .iter()
-                .filter(|&(id, _)| !leechers.contains(id))
+                .filter(|&(id, p)| !leechers.contains(id) || p.upload_only())
                 .map(|(id, _)| *id);
             for seeder in seeders {
                 self.cio.remove_peer(seeder);
@@ -900,12 +1259,13 @@ impl<T: cio::CIO> Torrent<T> {
         for pid in leechers {
             if let Some(peer) = self.peers.get_mut(pid) {
                 for i in 0..self.pieces.len() {
-                    if !peer.pieces().has_bit(i) {
+                    if self.pieces.has_bit(i) && !peer.pieces().has_bit(i) {
                         peer.send_message(Message::Have(i as u32));
                     }
                 }
             }
         }
+        self.announce_upload_only();
     }
 
     pub fn peer_ev(&mut self, pid: cio::PID, evt: cio::Result<Message>) -> Result<(), ()> {
@@ -944,40 +1304,39 @@ impl<T: cio::CIO> Torrent<T> {
         match msg {
             Message::Handshake { rsv, .. } => {
                 if (rsv[EXT_PROTO.0] & EXT_PROTO.1) != 0 {
-                    let mut ed = BTreeMap::new();
-                    let mut m = BTreeMap::new();
-
-                    m.insert(
-                        "ut_metadata".to_owned(),
-                        bencode::BEncode::Int(i64::from(UT_META_ID)),
-                    );
-                    if !self.info.private {
-                        m.insert(
-                            "ut_pex".to_owned(),
-                            bencode::BEncode::Int(i64::from(UT_PEX_ID)),
-                        );
-                    }
-
-                    ed.insert("m".to_owned(), bencode::BEncode::Dict(m));
-                    ed.insert(
-                        "metadata_size".to_owned(),
-                        bencode::BEncode::Int(self.info_bytes.len() as i64),
-                    );
-                    let payload = bencode::BEncode::Dict(ed).encode_to_buf();
-
-                    peer.send_message(Message::Extension { id: 0, payload });
+                    peer.send_message(self.ext_handshake());
                 }
+                if peer.fast() && self.info.complete() && self.super_seeder.is_none() {
+                    peer.send_allowed_fast(&self.pieces);
+                }
+                if self.info.complete() {
+                    Torrent::request_hashes(&self.info, peer);
+                }
+            }
+            Message::HashRequest(req) => self.handle_hash_request(req, peer),
+            Message::Hashes { req, hashes } => self.handle_hashes(req, hashes)?,
+            Message::HashReject(req) => {
+                debug!("Peer rejected hash request {:?}", req);
             }
             Message::Extension { id, payload } => {
                 self.handle_ext(id, payload, peer)?;
             }
-            Message::Bitfield(_) => {
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
                 if self.pieces.usable(peer.pieces()) && self.status.validating.is_none() {
                     peer.interested();
                 }
                 if self.info.complete() {
                     self.picker.add_peer(peer);
                 }
+                if let Some(ref mut s) = self.super_seeder {
+                    s.available(peer.pieces());
+                    if let Some(piece) = s.offer(peer.id(), peer.pieces()) {
+                        peer.send_message(Message::Have(piece));
+                        if peer.is_interested() {
+                            self.choker.add_peer(peer);
+                        }
+                    }
+                }
                 if !peer.pieces().complete() {
                     self.leechers.insert(peer.id());
                 } else if self.complete() {
@@ -989,6 +1348,25 @@ impl<T: cio::CIO> Torrent<T> {
                 if self.info.complete() {
                     self.picker.piece_available(idx);
                 }
+                if let Some(ref mut s) = self.super_seeder {
+                    for pid in s.have(peer.id(), idx) {
+                        let p = if pid == peer.id() {
+                            Some(&mut *peer)
+                        } else {
+                            self.peers.get_mut(&pid)
+                        };
+                        if let Some(p) = p {
+                            if let Some(piece) = s.offer(pid, p.pieces()) {
+                                p.send_message(Message::Have(piece));
+                                // Interest shown while we had nothing
+                                // to offer was ignored by the choker
+                                if p.is_interested() {
+                                    self.choker.add_peer(p);
+                                }
+                            }
+                        }
+                    }
+                }
                 if peer.pieces().complete() {
                     self.leechers.remove(&peer.id());
                     // If they're now a seeder and we're also seeding, drop the conn
@@ -1000,11 +1378,16 @@ impl<T: cio::CIO> Torrent<T> {
                     peer.interested();
                 }
             }
-            Message::Unchoke => {
+            Message::Unchoke | Message::AllowedFast(_) | Message::SuggestPiece(_) => {
                 if self.status.should_dl() && self.info.complete() {
                     Torrent::make_requests(peer, &mut self.picker, &self.info);
                 }
             }
+            Message::RejectRequest { index, begin, .. } => {
+                if self.info.complete() && !self.status.completed() {
+                    self.picker.rejected(Block::new(index, begin), peer.id());
+                }
+            }
             Message::Piece {
                 index,
                 begin,
@@ -1013,7 +1396,7 @@ impl<T: cio::CIO> Torrent<T> {
             } => {
                 // Ignore a piece we already have, this could happen from endgame
                 if self.pieces.has_bit(u64::from(index)) || self.validating.contains(&index) {
-                    self.wasted += 1;
+                    self.wasted += u64::from(length);
                     return Ok(());
                 }
 
@@ -1031,47 +1414,17 @@ impl<T: cio::CIO> Torrent<T> {
                 // We already have this block, don't do anything with it, could happen
                 // from endgame
                 if self.picker.have_block(Block::new(index, begin)) {
+                    self.wasted += u64::from(length);
                     return Ok(());
                 }
 
-                let pr = {
-                    let picker = &mut self.picker;
-                    let peers = &mut self.peers;
-
-                    picker.completed(Block::new(index, begin), |pid| {
-                        if let Some(p) = peers.get_mut(&pid) {
-                            p.send_message(Message::Cancel {
-                                index,
-                                begin,
-                                length,
-                            })
-                        }
-                    })
-                };
-                let piece_done = if let Ok(r) = pr {
-                    r
-                } else {
+                if !self.block_received(index, begin, length, data, Some(peer.addr().ip())) {
                     return Ok(());
-                };
-
-                self.dirty = true;
-                self.write_piece(index, begin, data);
-
-                self.downloaded += u64::from(length);
-                self.stat.add_dl(u64::from(length));
-
-                if piece_done {
-                    self.cio.msg_disk(disk::Request::validate_piece(
-                        self.id,
-                        self.info.clone(),
-                        self.path.clone(),
-                        index,
-                    ));
-                    self.validating.insert(index);
                 }
 
                 if self.status.should_dl() {
                     Torrent::make_requests(peer, &mut self.picker, &self.info);
+                    self.update_endgame();
                 }
             }
             Message::Request {
@@ -1079,7 +1432,20 @@ impl<T: cio::CIO> Torrent<T> {
                 begin,
                 length,
             } => {
-                if !self.pieces.has_bit(u64::from(index)) {
+                if !peer.may_request(index) {
+                    peer.reject(index, begin, length);
+                    return Ok(());
+                }
+                let revealed = self
+                    .super_seeder
+                    .as_ref()
+                    .map(|s| s.revealed(peer.id(), index))
+                    .unwrap_or(true);
+                if !revealed || !self.pieces.has_bit(u64::from(index)) {
+                    if peer.fast() {
+                        peer.reject(index, begin, length);
+                        return Ok(());
+                    }
                     return Err(());
                 }
                 if length != self.info.block_len(index, begin) {
@@ -1087,15 +1453,23 @@ impl<T: cio::CIO> Torrent<T> {
                 }
                 if !self.status.stopped() {
                     if let Some(buf) = Buffer::get() {
+                        peer.add_request(index, begin, length);
                         self.request_read(peer.id(), index, begin, buf);
                         return Ok(());
                     }
                 }
 
-                // TODO: add this to a queue to fulfill later
+                peer.reject(index, begin, length);
             }
             Message::Interested => {
-                self.choker.add_peer(peer);
+                let unchoke = self
+                    .super_seeder
+                    .as_ref()
+                    .map(|s| s.may_unchoke(peer.id()))
+                    .unwrap_or(true);
+                if unchoke {
+                    self.choker.add_peer(peer);
+                }
             }
             Message::Uninterested => {
                 self.choker.remove_peer(peer, &mut self.peers);
@@ -1108,15 +1482,57 @@ impl<T: cio::CIO> Torrent<T> {
         Ok(())
     }
 
+    fn ext_handshake(&self) -> Message {
+        let mut ed = BTreeMap::new();
+        let mut m = BTreeMap::new();
+
+        m.insert(
+            b"ut_metadata".to_vec(),
+            bencode::BEncode::Int(i64::from(UT_META_ID)),
+        );
+        if !self.info.private {
+            m.insert(
+                b"ut_pex".to_vec(),
+                bencode::BEncode::Int(i64::from(UT_PEX_ID)),
+            );
+        }
+
+        ed.insert(b"m".to_vec(), bencode::BEncode::Dict(m));
+        ed.insert(
+            b"metadata_size".to_vec(),
+            bencode::BEncode::Int(self.info_bytes.len() as i64),
+        );
+        // BEP 21, let peers know we won't download anything, which matters
+        // when we've only finished the files selected.
+        ed.insert(
+            b"upload_only".to_vec(),
+            bencode::BEncode::Int(if self.complete() { 1 } else { 0 }),
+        );
+        let payload = bencode::BEncode::Dict(ed).encode_to_buf();
+        Message::Extension { id: 0, payload }
+    }
+
+    /// Resends the extension handshake, updating upload_only.
+    fn announce_upload_only(&mut self) {
+        let msg = self.ext_handshake();
+        for peer in self.peers.values_mut().filter(|p| p.extended()) {
+            peer.send_message(msg.clone());
+        }
+    }
+
     fn handle_ext(&mut self, id: u8, payload: Vec<u8>, peer: &mut Peer<T>) -> Result<(), ()> {
         if id == 0 {
             const MAX_INFO_BYTES: i64 = 100 * 1000 * 1000;
+            // Neither side would download anything from the other
+            if peer.upload_only() && self.complete() {
+                return Err(());
+            }
             let b = bencode::decode_buf(&payload).map_err(|_| ())?;
             let mut d = b.into_dict().ok_or(())?;
-            let m = d.remove("m").and_then(|v| v.into_dict()).ok_or(())?;
-            if m.contains_key("ut_metadata") {
+            let m = d.remove(&b"m"[..]).and_then(|v| v.into_dict()).ok_or(())?;
+            if m.contains_key(&b"ut_metadata"[..]) {
                 let size = d
-                    .remove("metadata_size")
+                    .remove(&b"metadata_size"[..])
                     .and_then(|v| v.into_int())
                     .ok_or(())?;
                 if let Some(std::usize::MAX) = self.info_idx {
@@ -1134,8 +1550,8 @@ impl<T: cio::CIO> Torrent<T> {
                 if !self.info.complete() {
                     // Request the first index chunk to see if they have it
                     let mut respb = BTreeMap::new();
-                    respb.insert("msg_type".to_owned(), bencode::BEncode::Int(0));
-                    respb.insert("piece".to_owned(), bencode::BEncode::Int(0));
+                    respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(0));
+                    respb.insert(b"piece".to_vec(), bencode::BEncode::Int(0));
                     let payload = bencode::BEncode::Dict(respb).encode_to_buf();
                     let utm_id = if let Some(i) = peer.exts().ut_meta {
                         i
@@ -1157,10 +1573,13 @@ impl<T: cio::CIO> Torrent<T> {
             let buf = bencode::decode_buf_first(&payload).map_err(|_| ())?;
             let mut dict = buf.into_dict().ok_or(())?;
             let msg = dict
-                .remove("msg_type")
+                .remove(&b"msg_type"[..])
                 .and_then(|v| v.into_int())
                 .ok_or(())?;
-            let piece_len = dict.remove("piece").and_then(|v| v.into_int()).ok_or(())? as usize;
+            let piece_len = dict
+                .remove(&b"piece"[..])
+                .and_then(|v| v.into_int())
+                .ok_or(())? as usize;
             if piece_len * 16_384 >= self.info_bytes.len() {
                 return Err(());
             }
@@ -1172,15 +1591,15 @@ impl<T: cio::CIO> Torrent<T> {
                 0 => {
                     let mut respb = BTreeMap::new();
                     if self.info_idx.is_none() {
-                        respb.insert("msg_type".to_owned(), bencode::BEncode::Int(1));
-                        respb.insert("piece".to_owned(), bencode::BEncode::Int(piece_len as i64));
+                        respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(1));
+                        respb.insert(b"piece".to_vec(), bencode::BEncode::Int(piece_len as i64));
                         let size = if self.info_bytes.len() / 16_384 == piece_len {
                             self.info_bytes.len() % 16_384
                         } else {
                             16_384
                         };
                         let total_size = self.info_bytes.len() as i64;
-                        respb.insert("total_size".to_owned(), bencode::BEncode::Int(total_size));
+                        respb.insert(b"total_size".to_vec(), bencode::BEncode::Int(total_size));
                         let mut payload = bencode::BEncode::Dict(respb).encode_to_buf();
                         let s = piece_len * 16_384;
                         payload.extend_from_slice(&self.info_bytes[s..s + size]);
@@ -1189,8 +1608,8 @@ impl<T: cio::CIO> Torrent<T> {
                             payload,
                         });
                     } else {
-                        respb.insert("msg_type".to_owned(), bencode::BEncode::Int(2));
-                        respb.insert("piece".to_owned(), bencode::BEncode::Int(piece_len as i64));
+                        respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(2));
+                        respb.insert(b"piece".to_vec(), bencode::BEncode::Int(piece_len as i64));
                         let payload = bencode::BEncode::Dict(respb).encode_to_buf();
                         peer.send_message(Message::Extension {
                             id: utm_id,
@@ -1220,7 +1639,7 @@ impl<T: cio::CIO> Torrent<T> {
                             let mut b = BTreeMap::new();
                             let bni = bencode::decode_buf(&self.info_bytes).map_err(|_| ())?;
                             b.insert(
-                                "announce".to_owned(),
+                                b"announce".to_vec(),
                                 bencode::BEncode::String(
                                     self.info
                                         .announce
@@ -1231,10 +1650,15 @@ impl<T: cio::CIO> Torrent<T> {
                                         .to_vec(),
                                 ),
                             );
-                            b.insert("info".to_owned(), bni);
-                            let ni =
+                            b.insert(b"info".to_vec(), bni);
+                            let mut ni =
                                 Info::from_bencode(bencode::BEncode::Dict(b)).map_err(|_| ())?;
-                            if ni.hash == self.info.hash {
+                            // Hybrid torrents may be located by either infohash, in which
+                            // case we stay in the swarm the magnet pointed us to.
+                            let v2_match = self.info.v2() && ni.hash_v2 == self.info.hash_v2;
+                            if ni.hash == self.info.hash || v2_match {
+                                ni.hash = self.info.hash;
+                                ni.web_seeds = self.info.web_seeds.clone();
                                 debug!("Magnet file acquired succesfully!");
                                 self.info_idx = None;
                                 self.info = Arc::new(ni);
@@ -1245,8 +1669,8 @@ impl<T: cio::CIO> Torrent<T> {
                         } else if piece_len == 0 {
                             for i in 1..=idx {
                                 let mut respb = BTreeMap::new();
-                                respb.insert("msg_type".to_owned(), bencode::BEncode::Int(0));
-                                respb.insert("piece".to_owned(), bencode::BEncode::Int(i as i64));
+                                respb.insert(b"msg_type".to_vec(), bencode::BEncode::Int(0));
+                                respb.insert(b"piece".to_vec(), bencode::BEncode::Int(i as i64));
                                 let payload = bencode::BEncode::Dict(respb).encode_to_buf();
                                 peer.send_message(Message::Extension {
                                     id: utm_id,
@@ -1262,8 +1686,6 @@ impl<T: cio::CIO> Torrent<T> {
                 }
             }
         } else if id == UT_PEX_ID {
-            const PEX_SEED: u8 = 0x02;
-            const PEX_OUTGOING: u8 = 0x10;
             if peer.exts().ut_pex.is_none() {
                 return Ok(());
             }
@@ -1271,26 +1693,8 @@ impl<T: cio::CIO> Torrent<T> {
                 return Err(());
             }
             let b = bencode::decode_buf(&payload).map_err(|_| ())?;
-            let mut d = b.into_dict().ok_or(())?;
-            let mut peers = vec![];
-            let flags = d
-                .remove("added.f")
-                .and_then(bencode::BEncode::into_bytes)
-                .unwrap_or_else(|| vec![0; 50]);
-            if let Some(bencode::BEncode::String(ref data)) = d.remove("added") {
-                for (p, flag) in data.chunks(6).zip(flags) {
-                    if (flag & PEX_SEED != 0) && self.complete() {
-                        continue;
-                    }
-                    if flag & PEX_OUTGOING == 0 {
-                        continue;
-                    }
-
-                    let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
-                    let socket = SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..]));
-                    peers.push(SocketAddr::V4(socket));
-                }
-            }
+            let d = b.into_dict().ok_or(())?;
+            let peers = parse_pex(d, self.complete(), peer.pex_peers());
             if !peers.is_empty() {
                 self.cio
                     .propagate(cio::Event::Tracker(Ok(tracker::Response::PEX {
@@ -1335,6 +1739,23 @@ impl<T: cio::CIO> Torrent<T> {
             None => {}
         }
 
+        if let Some(s) = u.super_seed {
+            self.set_super_seed(s);
+        }
+
+        if u.ratio_limit.is_some()
+            || u.seed_time_limit.is_some()
+            || u.idle_limit.is_some()
+            || u.limit_action.is_some()
+        {
+            let mut limits = self.limits;
+            limits.ratio = u.ratio_limit.unwrap_or(limits.ratio);
+            limits.seed_time = u.seed_time_limit.unwrap_or(limits.seed_time);
+            limits.idle = u.idle_limit.unwrap_or(limits.idle);
+            limits.action = u.limit_action.unwrap_or(limits.action);
+            self.set_limits(limits);
+        }
+
         if let Some(user_data) = u.user_data {
             let id = self.rpc_id();
             self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
@@ -1372,6 +1793,83 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
+    /// Makes a byte range of a file time critical, defaulting to the
+    /// piece which covers the offset.
+    pub fn set_deadline(&mut self, id: &str, offset: u64, length: Option<u64>, deadline: Instant) {
+        if let Some((start, len)) = self.file_bounds(id) {
+            let length = length.unwrap_or(1);
+            let end = cmp::min(offset.saturating_add(length), len);
+            let range = start + cmp::min(offset, end)..start + end;
+            self.picker.set_deadline_range(range, deadline);
+            self.request_all();
+        }
+    }
+
+    /// Sends a download of an incomplete file to disk, which streams
+    /// pieces as they're validated. The pieces just ahead of the stream
+    /// are given deadlines so they're fetched in order, moving along as
+    /// disk reports its position.
+    pub fn stream(&mut self, id: &str, mut req: disk::Request) {
+        let start = match self.file_bounds(id) {
+            Some((start, _)) => start,
+            None => return,
+        };
+        let piece_len = u64::from(self.info.piece_len);
+        let ranges = req
+            .ranges()
+            .iter()
+            .rev()
+            .map(|r| {
+                let first = (start + r.start) / piece_len;
+                let last = (start + r.start + r.length.saturating_sub(1)) / piece_len;
+                first as u32..last as u32 + 1
+            })
+            .collect();
+        self.stream_cnt += 1;
+        let stream = Stream {
+            id: self.stream_cnt,
+            ranges,
+            pending: self.validating.iter().cloned().collect(),
+        };
+        req.set_stream(disk::Stream::new(
+            self.id,
+            stream.id,
+            self.pieces.clone(),
+            piece_len,
+            start,
+        ));
+        self.streams.push(stream);
+        self.update_stream_deadlines();
+        self.cio.msg_disk(req);
+        self.request_all();
+    }
+
+    /// Gives the window of each stream deadlines, staggered so its
+    /// pieces are fetched in order.
+    fn update_stream_deadlines(&mut self) {
+        let now = Instant::now();
+        for s in &self.streams {
+            for (i, piece) in s.window().enumerate() {
+                let deadline = now + Duration::from_millis(STREAM_INTERVAL_MS * i as u64);
+                self.picker.set_deadline(piece, deadline);
+            }
+        }
+    }
+
+    /// Returns the offset of a file in the torrent and its length.
+    fn file_bounds(&self, id: &str) -> Option<(u64, u64)> {
+        let mut start = 0;
+        for f in &self.info.files {
+            let fid =
+                util::file_rpc_id(&self.info.hash, f.path.as_path().to_string_lossy().as_ref());
+            if fid == id {
+                return Some((start, f.length));
+            }
+            start += f.length;
+        }
+        None
+    }
+
     pub fn rpc_update_pieces(&mut self) {
         let id = self.rpc_id();
         let piece_field = self.pieces.b64();
@@ -1390,6 +1888,12 @@ impl<T: cio::CIO> Torrent<T> {
         let mut resources = Vec::new();
         resources.push(self.rpc_info());
         resources.extend(self.rpc_trk_info());
+        let torrent_id = self.rpc_id();
+        resources.extend(
+            self.webseeds
+                .iter()
+                .map(|s| s.rpc_info(&self.info.hash, torrent_id.clone())),
+        );
         if self.info_idx.is_none() {
             resources.extend(self.rpc_rel_info());
         }
@@ -1406,8 +1910,11 @@ impl<T: cio::CIO> Torrent<T> {
         if self.status.stopped() {
             return;
         }
-        if let Some(req) = tracker::Request::started(self) {
-            self.cio.msg_trk(req);
+        let reqs = tracker::Request::started(self);
+        if !reqs.is_empty() {
+            for req in reqs {
+                self.cio.msg_trk(req);
+            }
             self.dump_torrent_file();
         }
         self.dht_announce();
@@ -1425,6 +1932,20 @@ impl<T: cio::CIO> Torrent<T> {
                 hash: self.info.hash,
             });
             self.cio.msg_trk(req);
+            if CONFIG.trk.lsd {
+                req = tracker::Request::LSDAnnounce(tracker::GetPeers {
+                    id: self.id,
+                    hash: self.info.hash,
+                });
+                self.cio.msg_trk(req);
+            }
+        }
+    }
+
+    fn lsd_remove(&mut self) {
+        if CONFIG.trk.lsd && !self.info.private {
+            self.cio
+                .msg_trk(tracker::Request::LSDRemove(self.info.hash));
         }
     }
 
@@ -1432,6 +1953,11 @@ impl<T: cio::CIO> Torrent<T> {
         self.status.completed()
     }
 
+    /// Whether every selected file is downloaded, but not every piece.
+    fn partial(&self) -> bool {
+        self.status.state == StatusState::Complete && !self.pieces.complete()
+    }
+
     fn set_throttle(&mut self, ul: Option<i64>, dl: Option<i64>) {
         self.throttle.set_ul_rate(ul);
         self.throttle.set_dl_rate(dl);
@@ -1462,6 +1988,8 @@ impl<T: cio::CIO> Torrent<T> {
         for peer in self.peers.values_mut() {
             if peer.magnet_complete(&self.info).is_err() {
                 self.cio.remove_peer(peer.id());
+            } else {
+                Torrent::request_hashes(&self.info, peer);
             }
         }
 
@@ -1498,6 +2026,7 @@ impl<T: cio::CIO> Torrent<T> {
 
     fn set_priority(&mut self, priority: u8) {
         self.priority = priority;
+        self.throttle.set_weight(priority);
         let id = self.rpc_id();
         self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
             resource::SResourceUpdate::TorrentPriority {
@@ -1508,6 +2037,105 @@ impl<T: cio::CIO> Torrent<T> {
         ]));
     }
 
+    fn set_super_seed(&mut self, super_seed: bool) {
+        if self.super_seed == super_seed {
+            return;
+        }
+        self.super_seed = super_seed;
+        self.dirty = true;
+        self.update_super_seed();
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::TorrentSuperSeed {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                super_seed,
+            },
+        ]));
+    }
+
+    fn set_limits(&mut self, limits: SeedLimits) {
+        self.limits = limits;
+        self.dirty = true;
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            resource::SResourceUpdate::SeedLimits {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                ratio_limit: limits.ratio,
+                seed_time_limit: limits.seed_time,
+                idle_limit: limits.idle,
+                limit_action: limits.action,
+            },
+        ]));
+    }
+
+    /// Seconds spent seeding in total.
+    fn seed_time(&self) -> u64 {
+        self.seed_time + self.seeding.map_or(0, |s| s.elapsed().as_secs())
+    }
+
+    /// Starts or stops counting seeding time as the status changes.
+    fn update_seeding(&mut self) {
+        match (self.status.should_ul(), self.seeding) {
+            (true, None) => self.seeding = Some(Instant::now()),
+            (false, Some(start)) => {
+                self.seed_time += start.elapsed().as_secs();
+                self.seeding = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Checks whether the torrent has reached a seeding limit, returning
+    /// the action which should be taken if so.
+    pub fn seed_limit(&self, server: &SeedLimits) -> Option<resource::SeedAction> {
+        let start = self.seeding?;
+        let limits = self.limits.resolve(server);
+        // Torrents which were added complete are measured against their size
+        let base = if self.downloaded == 0 {
+            self.info.total_len
+        } else {
+            self.downloaded
+        };
+        let ratio = self.uploaded as f32 / cmp::max(base, 1) as f32;
+        let idle = cmp::max(start, self.last_upload).elapsed().as_secs();
+        if limits.reached(ratio, self.seed_time(), idle) {
+            Some(limits.action.unwrap_or_default())
+        } else {
+            None
+        }
+    }
+
+    /// Starts or stops super-seeding. When stopping, every piece hidden
+    /// from a peer is announced to it.
+    fn update_super_seed(&mut self) {
+        let active = self.super_seed && self.info.complete() && self.pieces.complete();
+        if active && self.super_seeder.is_none() {
+            debug!("Starting super-seeding");
+            let mut s = SuperSeed::new(self.info.pieces());
+            for peer in self.peers.values() {
+                s.available(peer.pieces());
+            }
+            self.super_seeder = Some(s);
+        } else if !active {
+            let s = match self.super_seeder.take() {
+                Some(s) => s,
+                None => return,
+            };
+            debug!("Stopping super-seeding");
+            for (pid, revealed) in s.into_revealed() {
+                if let Some(peer) = self.peers.get_mut(&pid) {
+                    for piece in self.pieces.iter() {
+                        if !revealed.contains(&(piece as u32)) {
+                            peer.send_message(Message::Have(piece as u32));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     fn rpc_info(&self) -> resource::Resource {
         let (name, size, pieces, piece_size, files) = if self.info_idx.is_none() {
             (
@@ -1515,7 +2143,7 @@ impl<T: cio::CIO> Torrent<T> {
                 Some(self.info.total_len),
                 Some(u64::from(self.info.pieces())),
                 Some(self.info.piece_len),
-                Some(self.info.files.len() as u32),
+                Some(self.info.files.iter().filter(|f| !f.padding).count() as u32),
             )
         } else {
             let name = if self.info.name == "" {
@@ -1533,7 +2161,9 @@ impl<T: cio::CIO> Torrent<T> {
             path: self.path.as_ref().unwrap_or(&CONFIG.disk.directory).clone(),
             created: self.created,
             modified: Utc::now(),
-            status: self.status.as_rpc(self.stat.avg_ul(), self.stat.avg_dl()),
+            status: self
+                .status
+                .as_rpc(self.stat.avg_ul(), self.stat.avg_dl(), self.partial()),
             error: self.error(),
             priority: self.priority,
             progress: self.progress(),
@@ -1543,14 +2173,24 @@ impl<T: cio::CIO> Torrent<T> {
             } else {
                 resource::Strategy::Rarest
             },
+            super_seed: self.super_seed,
+            endgame: self.endgame,
+            wasted: self.wasted,
             rate_up: 0,
             rate_down: 0,
             throttle_up: self.throttle.ul_rate(),
             throttle_down: self.throttle.dl_rate(),
             transferred_up: self.uploaded,
             transferred_down: self.downloaded,
+            seed_time: self.seed_time(),
+            ratio_limit: self.limits.ratio,
+            seed_time_limit: self.limits.seed_time,
+            idle_limit: self.limits.idle,
+            limit_action: self.limits.action,
+            throttle_group: self.throttle_group.clone(),
             peers: 0,
             trackers: self.trackers.len() as u8,
+            bans: self.bans.clone(),
             pieces,
             piece_size,
             piece_field: self.pieces.b64(),
@@ -1576,6 +2216,9 @@ impl<T: cio::CIO> Torrent<T> {
         }
 
         for (i, (done, total)) in files.into_iter().enumerate() {
+            if self.info.files[i].padding {
+                continue;
+            }
             let id = util::file_rpc_id(
                 &self.info.hash,
                 self.info.files[i].path.to_string_lossy().as_ref(),
@@ -1615,6 +2258,10 @@ impl<T: cio::CIO> Torrent<T> {
                     url: trk.url.as_ref().clone(),
                     last_report: trk.last_announce,
                     error: None,
+                    complete: trk.complete,
+                    incomplete: trk.incomplete,
+                    downloaded: trk.downloaded,
+                    tier: trk.tier,
                     ..Default::default()
                 }))
             })
@@ -1624,7 +2271,7 @@ impl<T: cio::CIO> Torrent<T> {
     pub fn send_rpc_removal(&mut self) {
         let mut r = Vec::new();
         r.push(self.rpc_id());
-        for f in &self.info.files {
+        for f in self.info.files.iter().filter(|f| !f.padding) {
             let id =
                 util::file_rpc_id(&self.info.hash, f.path.as_path().to_string_lossy().as_ref());
             r.push(id)
@@ -1637,6 +2284,9 @@ impl<T: cio::CIO> Torrent<T> {
             seen_urls.insert(tracker.url.as_str());
             r.push(util::trk_rpc_id(&self.info.hash, tracker.url.as_str()));
         }
+        for seed in &self.webseeds {
+            r.push(util::peer_rpc_id(&self.info.hash, seed.id() as u64));
+        }
         self.cio.msg_rpc(rpc::CtlMessage::Removed(r));
     }
 
@@ -1678,19 +2328,153 @@ impl<T: cio::CIO> Torrent<T> {
         peers_have.len() as f32 / self.pieces.len() as f32
     }
 
+    /// Records a downloaded block and the peer which sent it, writing it to disk
+    /// and starting validation of its piece once every block has arrived.
+    /// Returns false if the block wasn't being downloaded.
+    fn block_received(
+        &mut self,
+        index: u32,
+        begin: u32,
+        length: u32,
+        data: Buffer,
+        from: Option<IpAddr>,
+    ) -> bool {
+        let pr = {
+            let picker = &mut self.picker;
+            let peers = &mut self.peers;
+
+            picker.completed(Block::new(index, begin), from, |pid| {
+                if let Some(p) = peers.get_mut(&pid) {
+                    p.send_message(Message::Cancel {
+                        index,
+                        begin,
+                        length,
+                    })
+                }
+            })
+        };
+        let piece_done = if let Ok(r) = pr {
+            r
+        } else {
+            return false;
+        };
+
+        self.dirty = true;
+        self.write_piece(index, begin, data);
+
+        self.downloaded += u64::from(length);
+        self.stat.add_dl(u64::from(length));
+
+        if piece_done {
+            self.cio.msg_disk(disk::Request::validate_piece(
+                self.id,
+                self.info.clone(),
+                self.path.clone(),
+                index,
+                self.smart_ban.suspect(index),
+            ));
+            self.validating.insert(index);
+        }
+        true
+    }
+
     /// Resets the last upload/download statistics, adjusting the internal
     /// status if nothing has been uploaded/downloaded in the interval.
     pub fn tick(&mut self) -> bool {
         self.stat.tick();
+        self.update_seeding();
         let mut active = self.stat.active();
         self.picker.tick();
+        self.update_endgame();
 
         for (_, peer) in self.peers.iter_mut() {
             active |= peer.tick();
         }
+        for seed in &mut self.webseeds {
+            active |= seed.tick();
+        }
+        self.update_webseeds();
         active
     }
 
+    /// Starts piece downloads from any idle web seeds.
+    fn update_webseeds(&mut self) {
+        if !self.info.complete() || !self.status.should_dl() {
+            return;
+        }
+        for seed in &mut self.webseeds {
+            if !seed.ready() {
+                continue;
+            }
+            if let Some(piece) = self.picker.pick_whole(seed.id()) {
+                for req in seed.request(self.id, &self.info, piece) {
+                    self.cio.msg_trk(req);
+                }
+            }
+        }
+    }
+
+    pub fn handle_webseed_resp(
+        &mut self,
+        seed: usize,
+        piece: u32,
+        offset: u32,
+        resp: tracker::Result<Vec<u8>>,
+    ) {
+        let idx = match self.webseeds.iter().position(|s| s.id() == seed) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let done = match resp {
+            _ if self.status.stopped() => self.webseeds[idx].reset().map(|p| (p, None)),
+            Ok(data) => self.webseeds[idx]
+                .received(piece, offset, &data)
+                .map(|(p, d)| (p, Some(d))),
+            Err(e) => {
+                debug!("Web seed download of piece {} failed: {}", piece, e);
+                self.webseeds[idx].failed(piece).map(|p| (p, None))
+            }
+        };
+        match done {
+            Some((piece, Some(data))) => self.webseed_piece(seed, piece, &data),
+            Some((piece, None)) => self.release_piece(seed, piece),
+            None => {}
+        }
+        self.update_webseeds();
+    }
+
+    /// Splits a piece downloaded from a web seed into blocks, handling
+    /// them as if they came from a peer.
+    fn webseed_piece(&mut self, seed: usize, index: u32, data: &[u8]) {
+        if self.pieces.has_bit(u64::from(index)) || self.validating.contains(&index) {
+            return;
+        }
+        for begin in (0..data.len() as u32).step_by(16_384) {
+            let length = self.info.block_len(index, begin);
+            if self.picker.have_block(Block::new(index, begin)) {
+                continue;
+            }
+            let mut buf = match Buffer::get() {
+                Some(buf) => buf,
+                None => {
+                    self.picker.rejected(Block::new(index, begin), seed);
+                    continue;
+                }
+            };
+            let start = begin as usize;
+            buf[..length as usize].copy_from_slice(&data[start..start + length as usize]);
+            self.block_received(index, begin, length, buf, None);
+        }
+    }
+
+    /// Frees the blocks of a piece a web seed failed to download.
+    fn release_piece(&mut self, seed: usize, index: u32) {
+        let len = self.info.piece_len(index);
+        for begin in (0..len).step_by(16_384) {
+            self.picker.rejected(Block::new(index, begin), seed);
+        }
+    }
+
     pub fn get_last_tx_rate(&self) -> (u64, u64) {
         (self.stat.avg_ul(), self.stat.avg_dl())
     }
@@ -1700,8 +2484,14 @@ impl<T: cio::CIO> Torrent<T> {
     /// The disk send handle is also provided.
     fn write_piece(&mut self, index: u32, begin: u32, data: Buffer) {
         let locs = Info::block_disk_locs_pri(&self.info, &self.priorities, index, begin);
-        self.cio
-            .msg_disk(disk::Request::write(self.id, data, locs, self.path.clone()));
+        self.cio.msg_disk(disk::Request::write(
+            self.id,
+            index,
+            begin,
+            data,
+            locs,
+            self.path.clone(),
+        ));
     }
 
     /// Issues a read request of the given torrent
@@ -1713,6 +2503,115 @@ impl<T: cio::CIO> Torrent<T> {
             .msg_disk(disk::Request::read(ctx, data, locs, self.path.clone()));
     }
 
+    /// Responds to a BEP 52 hash request with the requested piece layer
+    /// hashes and the proof needed to verify them.
+    fn handle_hash_request(&mut self, req: Box<HashRequest>, peer: &mut Peer<T>) {
+        let (start, end) = match self.piece_layer_range(&req) {
+            Some((start, end, _)) => (start, end),
+            None => {
+                peer.send_message(Message::HashReject(req));
+                return;
+            }
+        };
+        let layer = &self.info.hashes_v2[start..end];
+        if layer.contains(&[0; 32]) {
+            peer.send_message(Message::HashReject(req));
+            return;
+        }
+        let pad = merkle::pad_hash(self.info.piece_layer());
+        let (idx, len) = (req.index as usize, req.length as usize);
+        let mut hashes: Vec<_> = (idx..idx + len)
+            .map(|i| *layer.get(i).unwrap_or(&pad))
+            .collect();
+        hashes.extend(merkle::proof(
+            layer,
+            pad,
+            idx,
+            len,
+            req.proof_layers as usize,
+        ));
+        peer.send_message(Message::Hashes { req, hashes });
+    }
+
+    /// Verifies piece layer hashes sent by a peer, filling in any we lack.
+    fn handle_hashes(
+        &mut self,
+        req: Box<HashRequest>,
+        hashes: Vec<merkle::Hash>,
+    ) -> Result<(), ()> {
+        let (start, end, width) = match self.piece_layer_range(&req) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        let len = req.length as usize;
+        if hashes.len() < len {
+            return Err(());
+        }
+        let pad = merkle::pad_hash(self.info.piece_layer());
+        let (base, proof) = hashes.split_at(len);
+        let idx = req.index as usize;
+        if !merkle::verify(base, idx, width, pad, proof, &req.pieces_root) {
+            return Err(());
+        }
+        let end = cmp::min(end, start + idx + len);
+        let info = Arc::make_mut(&mut self.info);
+        info.hashes_v2[start + idx..end].copy_from_slice(&base[..end - start - idx]);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Checks that a hash request is for a piece layer range we can
+    /// serve or verify, returning the file's piece range and the layer width.
+    fn piece_layer_range(&self, req: &HashRequest) -> Option<(usize, usize, usize)> {
+        if !self.info.v2() || !self.info.complete() || req.base_layer != self.info.piece_layer() {
+            return None;
+        }
+        let file = self.info.layer_file(&req.pieces_root)?;
+        let (start, end) = self.info.file_pieces(file);
+        let width = ((end - start) as usize).next_power_of_two();
+        let (idx, len) = (req.index as usize, req.length as usize);
+        if !(2..=MAX_HASH_REQ).contains(&len)
+            || !len.is_power_of_two()
+            || idx % len != 0
+            || idx + len > width
+        {
+            return None;
+        }
+        Some((start as usize, end as usize, width))
+    }
+
+    /// Requests any piece layer hashes we're missing, which is the case
+    /// for v2 torrents acquired through magnets.
+    fn request_hashes(info: &Info, peer: &mut Peer<T>) {
+        if !info.v2() || !peer.v2() {
+            return;
+        }
+        let pl = u64::from(info.piece_len);
+        for (i, f) in info.files.iter().enumerate() {
+            let root = match f.pieces_root {
+                Some(r) if f.length > pl => r,
+                _ => continue,
+            };
+            let (start, end) = info.file_pieces(i);
+            let (start, end) = (start as usize, end as usize);
+            let width = (end - start).next_power_of_two();
+            let chunk = cmp::min(width, MAX_HASH_REQ);
+            for idx in (0..end - start).step_by(chunk) {
+                let range = start + idx..cmp::min(start + idx + chunk, end);
+                if !info.hashes_v2[range].contains(&[0; 32]) {
+                    continue;
+                }
+                peer.send_message(Message::HashRequest(Box::new(HashRequest {
+                    pieces_root: root,
+                    base_layer: info.piece_layer(),
+                    index: idx as u32,
+                    length: chunk as u32,
+                    proof_layers: (width / chunk).trailing_zeros(),
+                })));
+            }
+        }
+    }
+
     fn make_requests_pid(&mut self, pid: usize) {
         if self.status.should_dl() {
             let peer = self
@@ -1723,10 +2622,41 @@ impl<T: cio::CIO> Torrent<T> {
         }
     }
 
+    /// Tracks the picker entering or leaving endgame. Upon entering, every
+    /// unchoked peer is given duplicate requests for the outstanding blocks.
+    fn update_endgame(&mut self) {
+        let endgame = self.status.should_dl() && self.picker.endgame();
+        if endgame == self.endgame {
+            return;
+        }
+        self.endgame = endgame;
+        if endgame {
+            debug!("Torrent {} entering endgame", self.rpc_id());
+            for peer in self.peers.values_mut().filter(|p| !p.is_choking()) {
+                Torrent::make_requests(peer, &mut self.picker, &self.info);
+            }
+        }
+        let id = self.rpc_id();
+        self.cio.msg_rpc(rpc::CtlMessage::Update(vec![
+            SResourceUpdate::TorrentEndgame {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                endgame,
+                wasted: self.wasted,
+            },
+        ]));
+    }
+
     fn make_requests(peer: &mut Peer<T>, picker: &mut Picker, info: &Info) {
         if let Some(m) = peer.queue_reqs() {
             for _ in 0..(m) {
-                if let Some(block) = picker.pick(peer) {
+                // Only allowed fast pieces may be requested while choked
+                let block = if peer.is_choking() {
+                    picker.pick_fast(peer)
+                } else {
+                    picker.pick(peer)
+                };
+                if let Some(block) = block {
                     peer.request_piece(
                         block.index,
                         block.offset,
@@ -1784,11 +2714,47 @@ impl<T: cio::CIO> Torrent<T> {
                 id,
                 kind: resource::ResourceKind::Torrent,
                 error: self.status.error.clone(),
-                status: self.status.as_rpc(self.stat.avg_ul(), self.stat.avg_dl()),
+                status: self
+                    .status
+                    .as_rpc(self.stat.avg_ul(), self.stat.avg_dl(), self.partial()),
             },
         ]));
     }
 
+    /// Bans a peer from the torrent, disconnecting it. The ban is
+    /// recorded by control, which bans repeat offenders globally.
+    fn ban(&mut self, ip: IpAddr, reason: String) {
+        info!("Banning {} from {}: {}", ip, self.rpc_id(), reason);
+        self.smart_ban.remove_peer(ip);
+        self.disconnect(|addr| addr == ip);
+        self.cio.propagate(cio::Event::Ban {
+            tid: self.id,
+            ip,
+            reason,
+        });
+    }
+
+    /// Sets the bans which apply to the torrent, updating RPC.
+    pub fn set_bans(&mut self, bans: Vec<resource::Ban>) {
+        self.bans = bans;
+        let id = self.rpc_id();
+        self.cio
+            .msg_rpc(rpc::CtlMessage::Update(vec![SResourceUpdate::Bans {
+                id,
+                kind: resource::ResourceKind::Torrent,
+                bans: self.bans.clone(),
+            }]));
+    }
+
+    /// Disconnects every peer whose address matches a predicate.
+    pub fn disconnect<F: Fn(IpAddr) -> bool>(&mut self, f: F) {
+        for (id, peer) in &self.peers {
+            if f(peer.addr().ip()) {
+                self.cio.remove_peer(*id);
+            }
+        }
+    }
+
     pub fn update_rpc_peers(&mut self) {
         let availability = self.availability();
         let id = self.rpc_id();
@@ -1806,18 +2772,27 @@ impl<T: cio::CIO> Torrent<T> {
         let updates = self
             .trackers
             .iter()
-            .map(|tracker| {
+            .flat_map(|tracker| {
                 let id = util::trk_rpc_id(&self.info.hash, tracker.url.as_str());
                 let error = match tracker.status {
                     TrackerStatus::Failure(ref r) => Some(r.clone()),
                     _ => None,
                 };
-                SResourceUpdate::TrackerStatus {
-                    id,
-                    kind: resource::ResourceKind::Tracker,
-                    last_report: tracker.last_announce,
-                    error,
-                }
+                vec![
+                    SResourceUpdate::TrackerStatus {
+                        id: id.clone(),
+                        kind: resource::ResourceKind::Tracker,
+                        last_report: tracker.last_announce,
+                        error,
+                    },
+                    SResourceUpdate::TrackerScrape {
+                        id,
+                        kind: resource::ResourceKind::Tracker,
+                        complete: tracker.complete,
+                        incomplete: tracker.incomplete,
+                        downloaded: tracker.downloaded,
+                    },
+                ]
             })
             .collect();
         self.cio.msg_rpc(rpc::CtlMessage::Update(updates));
@@ -1837,6 +2812,21 @@ impl<T: cio::CIO> Torrent<T> {
             transferred_down: self.downloaded,
             progress,
         });
+        if self.seeding.is_some() {
+            updates.push(SResourceUpdate::TorrentSeedTime {
+                id: self.rpc_id(),
+                kind: resource::ResourceKind::Torrent,
+                seed_time: self.seed_time(),
+            });
+        }
+        if self.status.leeching() {
+            updates.push(SResourceUpdate::TorrentEndgame {
+                id: self.rpc_id(),
+                kind: resource::ResourceKind::Torrent,
+                endgame: self.endgame,
+                wasted: self.wasted,
+            });
+        }
 
         for (pid, p) in &mut self.peers {
             if !p.active() {
@@ -1851,7 +2841,20 @@ impl<T: cio::CIO> Torrent<T> {
             });
         }
 
+        for seed in &self.webseeds {
+            let (rate_up, rate_down) = seed.get_tx_rates();
+            updates.push(SResourceUpdate::Rate {
+                id: util::peer_rpc_id(&self.info.hash, seed.id() as u64),
+                kind: resource::ResourceKind::Peer,
+                rate_up,
+                rate_down,
+            });
+        }
+
         for (idx, done) in self.files.flush() {
+            if self.info.files[idx].padding {
+                continue;
+            }
             let id = util::file_rpc_id(
                 &self.info.hash,
                 self.info.files[idx].path.to_string_lossy().as_ref(),
@@ -1873,15 +2876,19 @@ impl<T: cio::CIO> Torrent<T> {
         if self.info.complete() {
             self.picker.remove_peer(peer);
         }
+        if let Some(ref mut s) = self.super_seeder {
+            s.remove_peer(peer.id(), peer.pieces());
+        }
     }
 
     pub fn pause(&mut self) {
         debug!("Pausing torrent!");
         if !self.status.paused {
             debug!("Sending stopped request to trk");
-            if let Some(req) = tracker::Request::stopped(self) {
+            for req in tracker::Request::stopped(self) {
                 self.cio.msg_trk(req);
             }
+            self.lsd_remove();
             self.status.paused = true;
             self.announce_status();
         }
@@ -1895,7 +2902,7 @@ impl<T: cio::CIO> Torrent<T> {
             }
             if self.status.paused {
                 debug!("Sending started request to trk");
-                if let Some(req) = tracker::Request::started(self) {
+                for req in tracker::Request::started(self) {
                     self.cio.msg_trk(req);
                 }
                 self.status.paused = false;
@@ -1925,35 +2932,53 @@ impl<T: cio::CIO> Torrent<T> {
     }
 
     pub fn update_pex(&mut self, added: &[SocketAddr], removed: &[SocketAddr]) {
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
         let mut a = vec![];
+        let mut af = vec![];
         let mut a6 = vec![];
-        let mut r = vec![];
-        let mut r6 = vec![];
+        let mut a6f = vec![];
+        let mut d = vec![];
+        let mut d6 = vec![];
         for addr in added {
-            match &addr {
-                SocketAddr::V4(addr) => {
-                    a.extend(&addr.ip().octets());
-                }
-                SocketAddr::V6(addr) => {
-                    a6.extend(&addr.ip().octets());
-                }
+            let flags = self
+                .peers
+                .values()
+                .find(|p| p.addr() == *addr)
+                .map(|p| {
+                    let mut f = 0;
+                    if p.pieces().complete() || p.upload_only() {
+                        f |= PEX_SEED;
+                    }
+                    if p.outgoing() {
+                        f |= PEX_OUTGOING;
+                    }
+                    f
+                })
+                .unwrap_or(0);
+            if addr.is_ipv4() {
+                a.extend(util::addr_to_bytes(addr));
+                af.push(flags);
+            } else {
+                a6.extend(util::addr_to_bytes(addr));
+                a6f.push(flags);
             }
         }
         for addr in removed {
-            match &addr {
-                SocketAddr::V4(addr) => {
-                    r.extend(&addr.ip().octets());
-                }
-                SocketAddr::V6(addr) => {
-                    r6.extend(&addr.ip().octets());
-                }
+            if addr.is_ipv4() {
+                d.extend(util::addr_to_bytes(addr));
+            } else {
+                d6.extend(util::addr_to_bytes(addr));
             }
         }
         let mut dict = BTreeMap::new();
-        dict.insert("added".to_string(), BEncode::String(a));
-        dict.insert("added6".to_string(), BEncode::String(a6));
-        dict.insert("removed".to_string(), BEncode::String(r));
-        dict.insert("removed6".to_string(), BEncode::String(r6));
+        dict.insert(b"added".to_vec(), BEncode::String(a));
+        dict.insert(b"added.f".to_vec(), BEncode::String(af));
+        dict.insert(b"added6".to_vec(), BEncode::String(a6));
+        dict.insert(b"added6.f".to_vec(), BEncode::String(a6f));
+        dict.insert(b"dropped".to_vec(), BEncode::String(d));
+        dict.insert(b"dropped6".to_vec(), BEncode::String(d6));
         let payload = BEncode::Dict(dict).encode_to_buf();
 
         for peer in self.peers.values_mut() {
@@ -2046,10 +3071,271 @@ impl<T: cio::CIO> Drop for Torrent<T> {
             self.leechers.remove(&id);
         }
         if !self.status.paused {
-            if let Some(msg) = tracker::Request::stopped(self) {
+            for msg in tracker::Request::stopped(self) {
                 self.cio.msg_trk(msg);
             }
         }
+        self.lsd_remove();
         self.send_rpc_removal();
     }
 }
+
+/// Ranges of the tier-ordered tracker list covered by each tier.
+fn tier_ranges(trackers: &VecDeque<Tracker>) -> Vec<Range<usize>> {
+    let mut tiers: Vec<Range<usize>> = Vec::new();
+    for (i, trk) in trackers.iter().enumerate() {
+        match tiers.last_mut() {
+            Some(r) if trackers[r.start].tier == trk.tier => r.end = i + 1,
+            _ => tiers.push(i..i + 1),
+        }
+    }
+    tiers
+}
+
+fn tier_starts(trackers: &VecDeque<Tracker>) -> Vec<usize> {
+    tier_ranges(trackers).into_iter().map(|r| r.start).collect()
+}
+
+/// Parses the compact peer list under key, which holds 18 byte entries
+/// if v6 is set and 6 byte entries otherwise.
+fn pex_addrs(d: &mut BTreeMap<Vec<u8>, BEncode>, key: &str, v6: bool) -> Vec<SocketAddr> {
+    let len = if v6 { 18 } else { 6 };
+    d.remove(key.as_bytes())
+        .and_then(BEncode::into_bytes)
+        .map(|data| data.chunks_exact(len).map(util::bytes_to_addr).collect())
+        .unwrap_or_default()
+}
+
+/// Extracts the peers worth connecting to from a ut_pex message, updating
+/// known, the peers the sender advertises. Peers without flags are assumed
+/// reachable, seeds are skipped when we are complete, and peers which were
+/// already advertised or are also listed as dropped are ignored.
+fn parse_pex(
+    mut d: BTreeMap<Vec<u8>, BEncode>,
+    complete: bool,
+    known: &mut FHashSet<SocketAddr>,
+) -> Vec<SocketAddr> {
+    let mut dropped = pex_addrs(&mut d, "dropped", false);
+    dropped.extend(pex_addrs(&mut d, "dropped6", true));
+    for addr in &dropped {
+        known.remove(addr);
+    }
+    let mut peers = vec![];
+    for &(key, v6) in &[("added", false), ("added6", true)] {
+        let flags = d
+            .remove(format!("{}.f", key).as_bytes())
+            .and_then(BEncode::into_bytes)
+            .unwrap_or_default();
+        for (i, addr) in pex_addrs(&mut d, key, v6).into_iter().enumerate() {
+            if let Some(&flag) = flags.get(i) {
+                if (flag & PEX_SEED != 0) && complete {
+                    continue;
+                }
+                if flag & PEX_OUTGOING == 0 {
+                    continue;
+                }
+            }
+            if dropped.contains(&addr) || known.contains(&addr) {
+                continue;
+            }
+            if known.len() < MAX_PEX_PEERS {
+                known.insert(addr);
+            }
+            peers.push(addr);
+        }
+    }
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::cio::{test, CIO};
+    use crate::throttle::Throttle;
+
+    #[test]
+    fn test_parse_pex() {
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v4_seed: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let v6_dropped: SocketAddr = "[2001:db8::2]:6881".parse().unwrap();
+        let mut added = util::addr_to_bytes(&v4);
+        added.extend(util::addr_to_bytes(&v4_seed));
+        let mut added6 = util::addr_to_bytes(&v6);
+        added6.extend(util::addr_to_bytes(&v6_dropped));
+        let mut d = BTreeMap::new();
+        d.insert(b"added".to_vec(), BEncode::String(added));
+        d.insert(
+            b"added.f".to_vec(),
+            BEncode::String(vec![PEX_OUTGOING, PEX_OUTGOING | PEX_SEED]),
+        );
+        d.insert(b"added6".to_vec(), BEncode::String(added6));
+        d.insert(
+            b"dropped6".to_vec(),
+            BEncode::String(util::addr_to_bytes(&v6_dropped)),
+        );
+
+        let mut known = FHashSet::default();
+        assert_eq!(
+            parse_pex(d.clone(), false, &mut known),
+            vec![v4, v4_seed, v6]
+        );
+        assert_eq!(parse_pex(d, true, &mut FHashSet::default()), vec![v4, v6]);
+
+        // Peers are only handed out again once dropped by a later message
+        let mut added = BTreeMap::new();
+        added.insert(b"added".to_vec(), BEncode::String(util::addr_to_bytes(&v4)));
+        assert_eq!(parse_pex(added.clone(), false, &mut known), vec![]);
+        let mut dropped = BTreeMap::new();
+        dropped.insert(
+            b"dropped".to_vec(),
+            BEncode::String(util::addr_to_bytes(&v4)),
+        );
+        assert_eq!(parse_pex(dropped, false, &mut known), vec![]);
+        assert!(!known.contains(&v4));
+        assert!(known.contains(&v6));
+        assert_eq!(parse_pex(added, false, &mut known), vec![v4]);
+    }
+
+    #[test]
+    fn test_tier_ranges() {
+        let trackers: VecDeque<_> = [0, 0, 1, 3, 3, 3]
+            .iter()
+            .enumerate()
+            .map(|(i, &tier)| {
+                let url = Url::parse(&format!("http://t{}.example.com/announce", i)).unwrap();
+                Tracker::new(Arc::new(url), tier)
+            })
+            .collect();
+        assert_eq!(tier_ranges(&trackers), vec![0..2, 2..3, 3..6]);
+        assert_eq!(tier_starts(&trackers), vec![0, 2, 3]);
+        assert!(tier_ranges(&VecDeque::new()).is_empty());
+    }
+
+    fn torrent(tcio: &test::TCIO, files: &[u64]) -> Torrent<test::TCIO> {
+        let mut info = Info::with_pieces(4);
+        info.files = files
+            .iter()
+            .enumerate()
+            .map(|(i, &length)| info::File {
+                path: PathBuf::from(i.to_string()),
+                length,
+                pieces_root: None,
+                padding: false,
+            })
+            .collect();
+        info.piece_idx =
+            Info::generate_piece_idx(info.hashes.len(), info.piece_len as u64, &info.files);
+        Torrent::new(
+            0,
+            None,
+            info,
+            Throttle::test(0),
+            tcio.new_handle(),
+            true,
+            false,
+        )
+    }
+
+    #[test]
+    fn test_tier_failover() {
+        let tcio = test::TCIO::new();
+        let mut t = torrent(&tcio, &[65_536]);
+        let url = |i| Arc::new(Url::parse(&format!("http://t{}.example.com/announce", i)).unwrap());
+        t.trackers = (0..2).map(|i| Tracker::new(url(i), i)).collect();
+        t.reset_tiers();
+        assert_eq!(t.announce_urls(), vec![url(0)]);
+
+        // Once the first tier fails the second is used
+        t.set_tracker_response(
+            &url(0),
+            &Err(tracker::ErrorKind::TrackerError(String::new()).into()),
+        );
+        assert_eq!(t.announce_urls(), vec![url(1)]);
+        t.set_tracker_response(&url(1), &Ok(tracker::TrackerResponse::empty()));
+        assert_eq!(t.announce_urls(), vec![url(1)]);
+        t.try_update_tracker();
+        assert_eq!(t.announce_urls(), vec![url(1)]);
+
+        // But the next announce starts from the first tier again
+        t.trackers[1].update = Some(Instant::now());
+        t.try_update_tracker();
+        assert_eq!(t.announce_urls(), vec![url(0)]);
+    }
+
+    fn handshake_upload_only(msg: &Message) -> Option<i64> {
+        match *msg {
+            Message::Extension { id: 0, ref payload } => bencode::decode_buf(payload)
+                .ok()
+                .and_then(|b| b.into_dict())
+                .and_then(|mut d| d.remove(&b"upload_only"[..]))
+                .and_then(|v| v.into_int()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_upload_only() {
+        let tcio = test::TCIO::new();
+        // Only the first file, covering pieces 0 and 1, is selected
+        let mut t = torrent(&tcio, &[32_768, 32_768]);
+        t.priorities = Arc::new(vec![3, 0]);
+        // Skip the initial validation of the files
+        t.status.validating = None;
+        assert_eq!(handshake_upload_only(&t.ext_handshake()), Some(0));
+
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        let mut d = BTreeMap::new();
+        d.insert(b"m".to_vec(), BEncode::Dict(BTreeMap::new()));
+        d.insert(b"upload_only".to_vec(), BEncode::Int(1));
+        let payload = BEncode::Dict(d).encode_to_buf();
+        let mut msg = Message::Extension {
+            id: 0,
+            payload: payload.clone(),
+        };
+        peer.handle_msg(&mut msg).unwrap();
+        assert!(peer.upload_only());
+        // A partial seed is still useful while we're downloading
+        assert!(t.handle_ext(0, payload.clone(), &mut peer).is_ok());
+
+        t.pieces.set_bit(0);
+        t.pieces.set_bit(1);
+        t.check_complete();
+        assert!(t.complete());
+        assert!(t.partial());
+        assert_eq!(
+            t.status.as_rpc(0, 0, t.partial()),
+            rpc::resource::Status::PartialSeed
+        );
+        assert_eq!(handshake_upload_only(&t.ext_handshake()), Some(1));
+        // Neither side would download from the other once we're done
+        assert!(t.handle_ext(0, payload, &mut peer).is_err());
+
+        t.pieces.set_bit(2);
+        t.pieces.set_bit(3);
+        assert!(!t.partial());
+        assert_eq!(
+            t.status.as_rpc(0, 0, t.partial()),
+            rpc::resource::Status::Idle
+        );
+    }
+
+    #[test]
+    fn test_stream_window() {
+        let mut s = Stream {
+            id: 1,
+            ranges: vec![2..4, 0..20].into_iter().collect(),
+            pending: vec![],
+        };
+        let window: Vec<_> = s.window().collect();
+        assert_eq!(window, vec![2, 3, 0, 1, 2, 3, 4, 5]);
+        s.advance(3);
+        assert_eq!(s.window().next(), Some(3));
+        // The window moves on to later ranges, never back
+        s.advance(10);
+        let window: Vec<_> = s.window().collect();
+        assert_eq!(window, (10..18).collect::<Vec<_>>());
+        s.advance(2);
+        assert_eq!(s.window().next(), Some(10));
+    }
+}
@@ -1,23 +1,25 @@
 pub mod reader;
 pub mod writer;
 
-use std::net::SocketAddr;
-use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr};
 use std::{cmp, fmt, io, mem, time};
 
+use byteorder::{BigEndian, ByteOrder};
+
 pub use self::message::Message;
 use self::reader::{RRes, Reader};
 use self::writer::Writer;
 use crate::bencode;
+use crate::config::{EncryptionPolicy, Transport};
 use crate::control::cio;
 use crate::rpc::{self, resource};
-use crate::socket::Socket;
+use crate::socket::{mse, Socket};
 use crate::stat;
 use crate::throttle::Throttle;
 use crate::torrent::{Bitfield, Info, Torrent};
 use crate::tracker;
 use crate::util;
-use crate::{CONFIG, DHT_EXT, PEER_ID};
+use crate::{CONFIG, DHT_EXT, EXT_PROTO, FAST_EXT, PEER_ID, V2_EXT};
 
 error_chain! {
     errors {
@@ -30,6 +32,11 @@ error_chain! {
 
 const INIT_MAX_QUEUE: u16 = 5;
 const MAX_QUEUE_CAP: u16 = 600;
+/// Number of pieces we allow fast extension peers to request while choked
+const ALLOWED_FAST_COUNT: usize = 10;
+/// Maximum number of allowed fast pieces accepted from a peer
+const MAX_ALLOWED_FAST: usize = 32;
+const MAX_SUGGESTED: usize = 16;
 
 pub mod message {
     use crate::buffers;
@@ -60,9 +67,25 @@ pub struct Peer<T: cio::CIO> {
     stat: stat::EMA,
     addr: SocketAddr,
     t_hash: [u8; 20],
+    /// RPC id of the torrent, which is the v2 hash for v2 torrents
+    t_rpc_id: String,
     cid: Option<[u8; 20]>,
     rsv: Option<[u8; 8]>,
     ext_ids: ExtIDs,
+    /// Pieces the peer allows us to request while choked
+    allowed_fast: Vec<u32>,
+    /// Pieces we allow the peer to request while choked
+    allowed_fast_out: Vec<u32>,
+    /// Pieces suggested by the peer, most recent last
+    suggested: Vec<u32>,
+    /// Requests from the peer which are still being read from disk
+    pending: Vec<(u32, u32, u32)>,
+    /// Peers the peer currently advertises over PEX
+    pex: util::FHashSet<SocketAddr>,
+    /// Whether we initiated the connection
+    outgoing: bool,
+    /// Whether the peer advertised BEP 21 upload_only, and won't download
+    upload_only: bool,
     pub rank: usize,
 }
 
@@ -121,24 +144,67 @@ impl PeerConn {
     }
 
     /// Creates a new "outgoing" peer, which acts as a client.
-    /// Once created, set_torrent should be called.
-    pub fn new_outgoing(ip: &SocketAddr) -> io::Result<PeerConn> {
+    /// The connection is encrypted with the torrent's hash if
+    /// the configured policy allows it.
+    pub fn new_outgoing(ip: &SocketAddr, hash: &[u8; 20]) -> io::Result<PeerConn> {
+        // The uTP socket only listens over IPv4
+        let sock = if CONFIG.peer.utp && CONFIG.peer.transport == Transport::Utp && ip.is_ipv4() {
+            Socket::new_utp(ip)
+        } else {
+            Socket::new(ip)?
+        };
+        Ok(PeerConn::new_outgoing_with(sock, hash))
+    }
+
+    /// Creates a new "outgoing" peer which always uses TCP.
+    pub fn new_outgoing_tcp(ip: &SocketAddr, hash: &[u8; 20]) -> io::Result<PeerConn> {
+        Ok(PeerConn::new_outgoing_with(Socket::new(ip)?, hash))
+    }
+
+    fn new_outgoing_with(mut sock: Socket, hash: &[u8; 20]) -> PeerConn {
+        if CONFIG.peer.encryption != EncryptionPolicy::Disabled {
+            sock.initiate_mse(*hash, CONFIG.peer.encryption);
+        }
+        PeerConn::new(sock)
+    }
+
+    /// Creates a new "outgoing" peer which uses plaintext regardless
+    /// of the configured policy.
+    pub fn new_outgoing_plain(ip: &SocketAddr) -> io::Result<PeerConn> {
         Ok(PeerConn::new(Socket::new(ip)?))
     }
 
     /// Creates a peer where we are acting as the server.
     /// Once the handshake is received, set_torrent should be called.
-    pub fn new_incoming(sock: TcpStream) -> io::Result<PeerConn> {
-        Ok(PeerConn::new(Socket::from_stream(sock)?))
+    pub fn new_incoming(mut sock: Socket, keys: &mse::Keys) -> PeerConn {
+        if CONFIG.peer.encryption != EncryptionPolicy::Disabled {
+            sock.accept_mse(keys.clone(), CONFIG.peer.encryption);
+        }
+        PeerConn::new(sock)
     }
 
     pub fn writable(&mut self) -> io::Result<()> {
         self.last_action = time::Instant::now();
+        if self.sock.negotiating() {
+            return self.sock.flush_handshake();
+        }
         self.writer.writable(&mut self.sock)
     }
 
     pub fn readable(&mut self) -> RRes {
         self.last_action = time::Instant::now();
+        if self.sock.negotiating() {
+            match self.sock.negotiate() {
+                // Flush anything that was queued up during the handshake
+                Ok(true) => {
+                    if let Err(e) = self.writer.writable(&mut self.sock) {
+                        return RRes::Err(e);
+                    }
+                }
+                Ok(false) => return RRes::Blocked,
+                Err(e) => return RRes::Err(e),
+            }
+        }
         self.reader.readable(&mut self.sock)
     }
 
@@ -186,14 +252,28 @@ impl Peer<cio::test::TCIO> {
             piece_count,
             tid: 0,
             t_hash: [0u8; 20],
+            t_rpc_id: String::new(),
             rsv: None,
             cid: None,
             ext_ids: ExtIDs::new(),
+            allowed_fast: Vec::new(),
+            allowed_fast_out: Vec::new(),
+            suggested: Vec::new(),
+            pending: Vec::new(),
+            pex: util::FHashSet::default(),
+            outgoing: false,
+            upload_only: false,
             pieces_updated: false,
             rank: 0,
         }
     }
 
+    pub fn enable_fast(&mut self) {
+        let mut rsv = [0u8; 8];
+        rsv[FAST_EXT.0] |= FAST_EXT.1;
+        self.rsv = Some(rsv);
+    }
+
     pub fn test_from_pieces(id: usize, pieces: Bitfield) -> Peer<cio::test::TCIO> {
         Peer::test(id, 0, 0, 0, pieces)
     }
@@ -220,7 +300,7 @@ impl<T: cio::CIO> Peer<T> {
         cid: Option<[u8; 20]>,
         rsv: Option<[u8; 8]>,
     ) -> cio::Result<Peer<T>> {
-        let throttle = t.get_throttle(0);
+        let throttle = t.get_throttle(id);
         let addr = Peer::setup_conn(&mut t.cio, id, throttle)?;
         let mut p = Peer {
             id,
@@ -233,20 +313,47 @@ impl<T: cio::CIO> Peer<T> {
             cio: t.cio.new_handle(),
             queued: 0,
             max_queue: INIT_MAX_QUEUE,
-            pieces: Bitfield::new(t.info.hashes.len() as u64),
+            pieces: Bitfield::new(u64::from(t.info.pieces())),
             piece_cache: Vec::new(),
             piece_count: 0,
             tid: t.id,
             t_hash: t.info.hash,
+            t_rpc_id: t.rpc_id(),
             rsv,
             cid,
             ext_ids: ExtIDs::new(),
+            allowed_fast: Vec::new(),
+            allowed_fast_out: Vec::new(),
+            suggested: Vec::new(),
+            pending: Vec::new(),
+            pex: util::FHashSet::default(),
+            outgoing: cid.is_none(),
+            upload_only: false,
             pieces_updated: false,
             rank: t.num_peers(),
         };
         p.send_message(Message::handshake(&*PEER_ID, &t.info.hash));
-        if t.info.complete() {
-            p.send_message(Message::Bitfield(t.pieces.clone()));
+        if let Some(ref mut s) = t.super_seeder {
+            // Pieces are revealed one at a time once the peer's bitfield is known
+            s.add_peer(id);
+            let msg = if p.fast() {
+                Message::HaveNone
+            } else {
+                Message::Bitfield(Bitfield::new(t.pieces.len()))
+            };
+            p.send_message(msg);
+        } else if t.info.complete() {
+            let msg = if p.fast() && t.pieces.complete() {
+                Message::HaveAll
+            } else if p.fast() && t.pieces.set() == 0 {
+                Message::HaveNone
+            } else {
+                Message::Bitfield(t.pieces.clone())
+            };
+            p.send_message(msg);
+            if p.fast() {
+                p.send_allowed_fast(&t.pieces);
+            }
         }
         p.send_rpc_info();
         Ok(p)
@@ -254,8 +361,13 @@ impl<T: cio::CIO> Peer<T> {
 
     fn setup_conn(cio: &mut T, pid: usize, throttle: Throttle) -> cio::Result<SocketAddr> {
         if let Some(addr) = cio.get_peer(pid, |pconn| {
-            pconn.set_throttle(throttle);
-            pconn.sock().addr()
+            let addr = pconn.sock().addr();
+            if CONFIG.lan.contains(addr.ip()) {
+                pconn.set_throttle(throttle.new_lan(throttle.id));
+            } else {
+                pconn.set_throttle(throttle);
+            }
+            addr
         }) {
             Ok(addr)
         } else {
@@ -266,7 +378,14 @@ impl<T: cio::CIO> Peer<T> {
 
     pub fn magnet_complete(&mut self, info: &Info) -> Result<()> {
         if self.pieces.len() == 0 {
-            self.pieces = Bitfield::new(u64::from(info.pieces()));
+            let len = u64::from(info.pieces());
+            // A HaveAll may have been received before the metadata
+            self.pieces = if self.pieces.complete() {
+                Bitfield::C { len }
+            } else {
+                Bitfield::new(len)
+            };
+            self.piece_count = self.pieces.set() as usize;
         } else if !self.pieces.cap(u64::from(info.pieces())) {
             return Err(ErrorKind::ProtocolError("Invalid pieces size").into());
         }
@@ -282,10 +401,68 @@ impl<T: cio::CIO> Peer<T> {
         &self.ext_ids
     }
 
+    /// Whether or not the peer supports the extension protocol
+    pub fn extended(&self) -> bool {
+        self.rsv
+            .map(|rsv| rsv[EXT_PROTO.0] & EXT_PROTO.1 != 0)
+            .unwrap_or(false)
+    }
+
+    /// Whether or not the peer supports the fast extension
+    pub fn fast(&self) -> bool {
+        self.rsv
+            .map(|rsv| rsv[FAST_EXT.0] & FAST_EXT.1 != 0)
+            .unwrap_or(false)
+    }
+
+    /// Whether or not the peer supports BEP 52 hash messages
+    pub fn v2(&self) -> bool {
+        self.rsv
+            .map(|rsv| rsv[V2_EXT.0] & V2_EXT.1 != 0)
+            .unwrap_or(false)
+    }
+
+    /// Whether or not the peer is choking us
+    pub fn is_choking(&self) -> bool {
+        self.remote_status.choked
+    }
+
+    /// Whether or not the peer is interested in our pieces
+    pub fn is_interested(&self) -> bool {
+        self.remote_status.interested
+    }
+
+    pub fn allowed_fast(&self) -> &[u32] {
+        &self.allowed_fast
+    }
+
+    pub fn suggested(&self) -> &[u32] {
+        &self.suggested
+    }
+
+    pub fn pex_peers(&mut self) -> &mut util::FHashSet<SocketAddr> {
+        &mut self.pex
+    }
+
+    /// Whether or not the peer may currently request blocks of a piece
+    pub fn may_request(&self, idx: u32) -> bool {
+        !self.local_status.choked || self.allowed_fast_out.contains(&idx)
+    }
+
     pub fn addr(&self) -> SocketAddr {
         self.addr
     }
 
+    /// Whether the peer won't download anything from us (BEP 21)
+    pub fn upload_only(&self) -> bool {
+        self.upload_only
+    }
+
+    /// Whether we connected to the peer, and thus know it to be reachable
+    pub fn outgoing(&self) -> bool {
+        self.outgoing
+    }
+
     pub fn pieces(&self) -> &Bitfield {
         &self.pieces
     }
@@ -342,7 +519,8 @@ impl<T: cio::CIO> Peer<T> {
     }
 
     pub fn queue_reqs(&mut self) -> Option<u16> {
-        if self.remote_status.choked || self.queued > self.max_queue.saturating_sub(16) {
+        let choked = self.remote_status.choked && self.allowed_fast.is_empty();
+        if choked || self.queued > self.max_queue.saturating_sub(16) {
             None
         } else {
             let amnt = self.max_queue.saturating_sub(self.queued);
@@ -366,7 +544,8 @@ impl<T: cio::CIO> Peer<T> {
                 self.queued -= 1;
             }
             Message::Request { .. } => {
-                if self.local_status.choked {
+                // Fast extension peers get explicit rejections instead
+                if self.local_status.choked && !self.fast() {
                     info!("Got request while choked!");
                     return Err(ErrorKind::ProtocolError("Peer requested while choked!").into());
                 }
@@ -406,10 +585,53 @@ impl<T: cio::CIO> Peer<T> {
                 self.piece_count = self.pieces.iter().count();
                 self.send_rpc_update();
             }
+            Message::HaveAll => {
+                self.check_fast()?;
+                self.pieces = Bitfield::C {
+                    len: self.pieces.len(),
+                };
+                self.piece_count = self.pieces.len() as usize;
+                self.send_rpc_update();
+            }
+            Message::HaveNone => {
+                self.check_fast()?;
+                self.pieces = Bitfield::new(self.pieces.len());
+                self.piece_count = 0;
+                self.send_rpc_update();
+            }
+            Message::SuggestPiece(idx) => {
+                self.check_fast()?;
+                if u64::from(idx) < self.pieces.len() && !self.suggested.contains(&idx) {
+                    if self.suggested.len() == MAX_SUGGESTED {
+                        self.suggested.remove(0);
+                    }
+                    self.suggested.push(idx);
+                }
+            }
+            Message::AllowedFast(idx) => {
+                self.check_fast()?;
+                if u64::from(idx) < self.pieces.len()
+                    && self.allowed_fast.len() < MAX_ALLOWED_FAST
+                    && !self.allowed_fast.contains(&idx)
+                {
+                    self.allowed_fast.push(idx);
+                }
+            }
+            Message::RejectRequest { .. } => {
+                self.check_fast()?;
+                self.queued = self.queued.saturating_sub(1);
+            }
+            Message::HashRequest(_) | Message::Hashes { .. } | Message::HashReject(_) => {
+                if !self.v2() {
+                    return Err(ErrorKind::ProtocolError("Hash message without v2 support").into());
+                }
+            }
             Message::KeepAlive => {
                 self.send_message(Message::KeepAlive);
             }
             Message::Cancel { index, begin, .. } => {
+                self.pending
+                    .retain(|&(i, b, _)| !(i == index && b == begin));
                 self.cio.get_peer(self.id, |conn| {
                     conn.writer.write_queue.retain(|m| {
                         if let Message::Piece {
@@ -435,15 +657,22 @@ impl<T: cio::CIO> Peer<T> {
                     let mut d = b.into_dict().ok_or_else(|| {
                         ErrorKind::ProtocolError("Invalid bencode type in ext handshake")
                     })?;
-                    let mut m = d.remove("m").and_then(|v| v.into_dict()).ok_or_else(|| {
-                        ErrorKind::ProtocolError("Invalid metadata in in ext handshake")
-                    })?;
+                    // Later handshakes only need to include what changed
+                    if let Some(u) = d.remove(&b"upload_only"[..]).and_then(|v| v.into_int()) {
+                        self.upload_only = u != 0;
+                    }
+                    let mut m =
+                        d.remove(&b"m"[..])
+                            .and_then(|v| v.into_dict())
+                            .ok_or_else(|| {
+                                ErrorKind::ProtocolError("Invalid metadata in in ext handshake")
+                            })?;
                     self.ext_ids.ut_meta = m
-                        .remove("ut_metadata")
+                        .remove(&b"ut_metadata"[..])
                         .and_then(|v| v.into_int())
                         .map(|v| v as u8);
                     self.ext_ids.ut_pex = m
-                        .remove("ut_pex")
+                        .remove(&b"ut_pex"[..])
                         .and_then(|v| v.into_int())
                         .map(|v| v as u8);
                 }
@@ -452,6 +681,63 @@ impl<T: cio::CIO> Peer<T> {
         Ok(())
     }
 
+    fn check_fast(&self) -> Result<()> {
+        if self.fast() {
+            Ok(())
+        } else {
+            Err(ErrorKind::ProtocolError("Fast extension message without support").into())
+        }
+    }
+
+    /// Sends the set of pieces the peer may request while choked,
+    /// limited to pieces we actually have.
+    pub fn send_allowed_fast(&mut self, pieces: &Bitfield) {
+        let set = allowed_fast_set(
+            &self.addr,
+            &self.t_hash,
+            pieces.len() as u32,
+            ALLOWED_FAST_COUNT,
+        );
+        for idx in set {
+            if pieces.has_bit(u64::from(idx)) && !self.allowed_fast_out.contains(&idx) {
+                self.allowed_fast_out.push(idx);
+                self.send_message(Message::AllowedFast(idx));
+            }
+        }
+    }
+
+    /// Explicitly rejects a request, if the peer supports it.
+    pub fn reject(&mut self, index: u32, begin: u32, length: u32) {
+        if self.fast() {
+            self.send_message(Message::RejectRequest {
+                index,
+                begin,
+                length,
+            });
+        }
+    }
+
+    /// Records a request from the peer whose data is being read from disk.
+    pub fn add_request(&mut self, index: u32, begin: u32, length: u32) {
+        self.pending.push((index, begin, length));
+    }
+
+    /// Removes a request once its data has been read, returning
+    /// whether the peer still wants it.
+    pub fn take_request(&mut self, index: u32, begin: u32) -> bool {
+        match self
+            .pending
+            .iter()
+            .position(|&(i, b, _)| i == index && b == begin)
+        {
+            Some(pos) => {
+                self.pending.swap_remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn request_piece(&mut self, idx: u32, offset: u32, len: u32) {
         let m = Message::request(idx, offset, len);
         self.queued += 1;
@@ -462,6 +748,43 @@ impl<T: cio::CIO> Peer<T> {
         if !self.local_status.choked {
             self.local_status.choked = true;
             self.send_message(Message::Choke);
+            if self.fast() {
+                self.reject_pending();
+            }
+        }
+    }
+
+    /// Rejects every outstanding request which a choked peer may no
+    /// longer make, as BEP 6 requires.
+    fn reject_pending(&mut self) {
+        let allowed = &self.allowed_fast_out;
+        let mut rejected = Vec::new();
+        self.pending.retain(|&(i, b, l)| {
+            if allowed.contains(&i) {
+                return true;
+            }
+            rejected.push((i, b, l));
+            false
+        });
+        self.cio.get_peer(self.id, |conn| {
+            conn.writer.write_queue.retain(|m| {
+                if let Message::Piece {
+                    index,
+                    begin,
+                    length,
+                    ..
+                } = *m
+                {
+                    if !allowed.contains(&index) {
+                        rejected.push((index, begin, length));
+                        return false;
+                    }
+                }
+                true
+            });
+        });
+        for (index, begin, length) in rejected {
+            self.reject(index, begin, length);
         }
     }
 
@@ -490,13 +813,18 @@ impl<T: cio::CIO> Peer<T> {
     fn send_rpc_info(&mut self) {
         if let Some(cid) = self.cid {
             let id = util::peer_rpc_id(&self.t_hash, self.id as u64);
+            let encrypted = self
+                .cio
+                .get_peer(self.id, |conn| conn.sock().encrypted())
+                .unwrap_or(false);
             self.cio
                 .msg_rpc(rpc::CtlMessage::Extant(vec![resource::Resource::Peer(
                     resource::Peer {
                         id,
-                        torrent_id: util::hash_to_id(&self.t_hash[..]),
+                        torrent_id: self.t_rpc_id.clone(),
                         client_id: util::hash_to_id(&cid[..]),
                         ip: self.addr.to_string(),
+                        encrypted,
                         rate_up: 0,
                         rate_down: 0,
                         availability: self.piece_count as f32 / self.pieces.len() as f32,
@@ -546,6 +874,29 @@ impl<T: cio::CIO> fmt::Debug for Peer<T> {
     }
 }
 
+/// Generates the canonical allowed fast set of a peer as described in BEP 6.
+fn allowed_fast_set(addr: &SocketAddr, hash: &[u8; 20], pieces: u32, k: usize) -> Vec<u32> {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Vec::new(),
+    };
+    let k = k.min(pieces as usize);
+    let mut x = ip.octets().to_vec();
+    x[3] = 0;
+    x.extend_from_slice(hash);
+    let mut set = Vec::with_capacity(k);
+    while set.len() < k {
+        x = util::sha1_hash(&x).to_vec();
+        for chunk in x.chunks(4) {
+            let idx = BigEndian::read_u32(chunk) % pieces;
+            if set.len() < k && !set.contains(&idx) {
+                set.push(idx);
+            }
+        }
+    }
+    set
+}
+
 impl ExtIDs {
     fn new() -> ExtIDs {
         ExtIDs {
@@ -557,10 +908,12 @@ impl ExtIDs {
 
 #[cfg(test)]
 mod tests {
-    use super::Peer;
+    use super::{allowed_fast_set, Peer};
+    use crate::bencode::BEncode;
     use crate::buffers::Buffer;
     use crate::control::cio::{test, CIO};
     use crate::torrent::Message;
+    use std::collections::BTreeMap;
 
     #[test]
     fn test_cancel() {
@@ -602,4 +955,98 @@ mod tests {
         assert_eq!(wq[0], p1);
         assert_eq!(wq[1], p3);
     }
+
+    #[test]
+    fn test_upload_only() {
+        let mut peer = Peer::test_from_pieces(0, crate::torrent::Bitfield::new(4));
+        let handshake = |upload_only: Option<i64>| {
+            let mut d = BTreeMap::new();
+            d.insert(b"m".to_vec(), BEncode::Dict(BTreeMap::new()));
+            if let Some(u) = upload_only {
+                d.insert(b"upload_only".to_vec(), BEncode::Int(u));
+            }
+            Message::Extension {
+                id: 0,
+                payload: BEncode::Dict(d).encode_to_buf(),
+            }
+        };
+        assert!(!peer.upload_only());
+        peer.handle_msg(&mut handshake(Some(1))).unwrap();
+        assert!(peer.upload_only());
+        // Later handshakes which leave it out don't change it
+        peer.handle_msg(&mut handshake(None)).unwrap();
+        assert!(peer.upload_only());
+        peer.handle_msg(&mut handshake(Some(0))).unwrap();
+        assert!(!peer.upload_only());
+    }
+
+    #[test]
+    fn test_allowed_fast_set() {
+        let addr = "80.4.4.200:6881".parse().unwrap();
+        let hash = [0xAA; 20];
+        assert_eq!(
+            allowed_fast_set(&addr, &hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(&addr, &hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        assert_eq!(allowed_fast_set(&addr, &hash, 3, 10).len(), 3);
+    }
+
+    #[test]
+    fn test_fast_msgs() {
+        let mut peer = Peer::test_from_pieces(0, crate::torrent::Bitfield::new(4));
+        assert!(peer.handle_msg(&mut Message::HaveAll).is_err());
+        peer.enable_fast();
+        peer.handle_msg(&mut Message::HaveAll).unwrap();
+        assert!(peer.pieces().complete());
+        peer.handle_msg(&mut Message::AllowedFast(2)).unwrap();
+        peer.handle_msg(&mut Message::AllowedFast(9)).unwrap();
+        assert_eq!(peer.allowed_fast(), &[2]);
+        // Requests may be queued while choked once pieces are allowed
+        assert!(peer.queue_reqs().is_some());
+        peer.handle_msg(&mut Message::HaveNone).unwrap();
+        assert_eq!(peer.pieces().set(), 0);
+    }
+
+    #[test]
+    fn test_choke_reject() {
+        let mut tcio = test::TCIO::new();
+        let mut peer = Peer::test_with_tcio(tcio.new_handle());
+        peer.enable_fast();
+        peer.unchoke();
+        peer.allowed_fast_out.push(2);
+        peer.add_request(3, 0, 16_384);
+        for idx in 1..3 {
+            peer.send_message(Message::Piece {
+                index: idx,
+                begin: 0,
+                data: Buffer::get().unwrap(),
+                length: 16_384,
+            });
+        }
+        peer.choke();
+        assert!(!peer.take_request(3, 0));
+        let wq = tcio
+            .get_peer(peer.id, |p| p.writer.write_queue.clone())
+            .unwrap();
+        let reject = |index| Message::RejectRequest {
+            index,
+            begin: 0,
+            length: 16_384,
+        };
+        assert!(wq.contains(&reject(1)));
+        assert!(wq.contains(&reject(3)));
+        assert!(!wq.contains(&reject(2)));
+        assert!(wq.iter().any(|m| match *m {
+            Message::Piece { index, .. } => index == 2,
+            _ => false,
+        }));
+        assert!(!wq.iter().any(|m| match *m {
+            Message::Piece { index, .. } => index == 1,
+            _ => false,
+        }));
+    }
 }
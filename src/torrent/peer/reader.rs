@@ -4,11 +4,14 @@ use std::mem;
 use byteorder::{BigEndian, ByteOrder};
 
 use crate::buffers::{Buffer, BUF_SIZE};
+use crate::protocol::HashRequest;
 use crate::torrent::peer::Message;
 use crate::torrent::Bitfield;
 use crate::util::{aread, io_err_val, IOR};
 
 const MAX_EXT_MSG_BYTES: u32 = 100 * 1000 * 1000;
+/// BEP 52 limits hash requests to 512 hashes, plus proof hashes
+const MAX_HASHES_BYTES: u32 = 48 + 32 * (512 + 64);
 
 pub struct Reader {
     state: State,
@@ -23,12 +26,16 @@ enum State {
     Request,
     Cancel,
     Port,
+    Suggest,
+    Reject,
+    AllowedFast,
     Handshake { data: [u8; 68] },
     PiecePrefix,
     Piece { data: Option<Buffer>, len: u32 },
     Bitfield { data: Vec<u8> },
     ExtensionID,
     Extension { id: u8, payload: Vec<u8> },
+    Hash { id: u8, data: Vec<u8> },
 }
 
 #[derive(Debug)]
@@ -143,7 +150,28 @@ impl Reader {
                             7 => self.state = State::PiecePrefix,
                             8 => self.state = State::Cancel,
                             9 => self.state = State::Port,
+                            0x0D => self.state = State::Suggest,
+                            0x0E => return RRes::Success(Message::HaveAll),
+                            0x0F => return RRes::Success(Message::HaveNone),
+                            0x10 => self.state = State::Reject,
+                            0x11 => self.state = State::AllowedFast,
                             20 => self.state = State::ExtensionID,
+                            id @ 21..=23 => {
+                                let plen = BigEndian::read_u32(&self.prefix[0..4]) - 1;
+                                let valid = if id == 22 {
+                                    (48..=MAX_HASHES_BYTES).contains(&plen) && plen % 32 == 16
+                                } else {
+                                    plen == 48
+                                };
+                                if !valid {
+                                    return RRes::Err(io_err_val("Invalid hash message length"));
+                                }
+                                self.idx = 0;
+                                self.state = State::Hash {
+                                    id,
+                                    data: vec![0u8; plen as usize],
+                                };
+                            }
                             _ => return RRes::Err(io_err_val("Invalid ID used!")),
                         }
                     }
@@ -263,6 +291,42 @@ impl Reader {
                     IOR::EOF => return RRes::Err(io_err_val("EOF")),
                     IOR::Err(e) => return RRes::Err(e),
                 },
+                State::Suggest => match aread(&mut self.prefix[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let piece = BigEndian::read_u32(&self.prefix[5..9]);
+                        return RRes::Success(Message::SuggestPiece(piece));
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return RRes::Blocked,
+                    IOR::EOF => return RRes::Err(io_err_val("EOF")),
+                    IOR::Err(e) => return RRes::Err(e),
+                },
+                State::Reject => match aread(&mut self.prefix[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let index = BigEndian::read_u32(&self.prefix[5..9]);
+                        let begin = BigEndian::read_u32(&self.prefix[9..13]);
+                        let length = BigEndian::read_u32(&self.prefix[13..17]);
+                        return RRes::Success(Message::RejectRequest {
+                            index,
+                            begin,
+                            length,
+                        });
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return RRes::Blocked,
+                    IOR::EOF => return RRes::Err(io_err_val("EOF")),
+                    IOR::Err(e) => return RRes::Err(e),
+                },
+                State::AllowedFast => match aread(&mut self.prefix[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let piece = BigEndian::read_u32(&self.prefix[5..9]);
+                        return RRes::Success(Message::AllowedFast(piece));
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return RRes::Blocked,
+                    IOR::EOF => return RRes::Err(io_err_val("EOF")),
+                    IOR::Err(e) => return RRes::Err(e),
+                },
                 State::ExtensionID => match aread(&mut self.prefix[5..6], conn) {
                     IOR::Complete => {
                         let id = self.prefix[5];
@@ -292,6 +356,31 @@ impl Reader {
                     IOR::EOF => return RRes::Err(io_err_val("EOF")),
                     IOR::Err(e) => return RRes::Err(e),
                 },
+                State::Hash { id, ref mut data } => match aread(&mut data[self.idx..len], conn) {
+                    IOR::Complete => {
+                        let req = Box::new(HashRequest::decode(&data[..48]));
+                        let msg = match id {
+                            21 => Message::HashRequest(req),
+                            22 => Message::Hashes {
+                                req,
+                                hashes: data[48..]
+                                    .chunks(32)
+                                    .map(|c| {
+                                        let mut h = [0u8; 32];
+                                        h.copy_from_slice(c);
+                                        h
+                                    })
+                                    .collect(),
+                            },
+                            _ => Message::HashReject(req),
+                        };
+                        return RRes::Success(msg);
+                    }
+                    IOR::Incomplete(a) => self.idx += a,
+                    IOR::Blocked => return RRes::Blocked,
+                    IOR::EOF => return RRes::Err(io_err_val("EOF")),
+                    IOR::Err(e) => return RRes::Err(e),
+                },
             }
         }
     }
@@ -302,8 +391,8 @@ impl State {
         match *self {
             State::Len => 4,
             State::ID => 5,
-            State::Have => 9,
-            State::Request | State::Cancel => 17,
+            State::Have | State::Suggest | State::AllowedFast => 9,
+            State::Request | State::Cancel | State::Reject => 17,
             State::PiecePrefix => 13,
             State::Port => 7,
             State::Handshake { .. } => 68,
@@ -311,6 +400,7 @@ impl State {
             State::Bitfield { ref data, .. } => data.len(),
             State::ExtensionID => 6,
             State::Extension { ref payload, .. } => payload.len(),
+            State::Hash { ref data, .. } => data.len(),
         }
     }
 }
@@ -513,6 +603,53 @@ mod tests {
         test_message(data, Message::Port(6881));
     }
 
+    #[test]
+    fn test_read_fast() {
+        test_message(vec![0u8, 0, 0, 1, 0x0E], Message::HaveAll);
+        test_message(
+            vec![0u8, 0, 0, 5, 0x11, 0, 0, 1, 0],
+            Message::AllowedFast(256),
+        );
+        let msgs = vec![
+            Message::HaveNone,
+            Message::SuggestPiece(7),
+            Message::RejectRequest {
+                index: 1,
+                begin: 16_384,
+                length: 16_384,
+            },
+        ];
+        for msg in msgs {
+            let mut data = vec![0; msg.len()];
+            msg.encode(&mut data[..]).unwrap();
+            test_message(data, msg);
+        }
+    }
+
+    #[test]
+    fn test_read_hashes() {
+        let req = HashRequest {
+            pieces_root: [3; 32],
+            base_layer: 2,
+            index: 4,
+            length: 4,
+            proof_layers: 1,
+        };
+        let msgs = vec![
+            Message::HashRequest(Box::new(req.clone())),
+            Message::HashReject(Box::new(req.clone())),
+            Message::Hashes {
+                req: Box::new(req),
+                hashes: vec![[1; 32], [2; 32], [3; 32], [4; 32], [5; 32]],
+            },
+        ];
+        for msg in msgs {
+            let mut data = vec![0; msg.len()];
+            msg.encode(&mut data[..]).unwrap();
+            test_message(data, msg);
+        }
+    }
+
     #[test]
     fn test_read_handshake() {
         use crate::PEER_ID;
@@ -1,10 +1,13 @@
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::ops::Range;
 use std::sync::Arc;
 use std::time;
 
 use crate::control::cio;
 use crate::torrent::{Bitfield, Info, Peer};
-use crate::util::FHashSet;
+use crate::util::{FHashMap, FHashSet};
 
 mod rarest;
 mod sequential;
@@ -14,6 +17,7 @@ mod tests;
 
 #[derive(Clone, Debug)]
 pub struct Picker {
+    piece_len: u64,
     /// Number of blocks per piece
     scale: u32,
     last_piece_scale: u32,
@@ -24,11 +28,19 @@ pub struct Picker {
     downloading: HashMap<Block, Request>,
     /// Blocks requested/completed per piece picked
     blocks: Vec<(usize, usize)>,
+    /// Peers which supplied the completed blocks of unvalidated pieces
+    sources: HashMap<Block, IpAddr>,
     /// Pieces which we've picked fully, but ended up not being downloaded due to a slow peer
     stalled: FHashSet<Block>,
     /// Bitfield of unpicked pieces, not in progress or
     /// completed yet. A set bit is picked, unset is unpicked.
     unpicked: Bitfield,
+    /// Number of wanted pieces which still have unrequested blocks
+    remaining: usize,
+    /// Time critical pieces, ordered by deadline then index
+    deadlines: BTreeSet<(time::Instant, u32)>,
+    /// Deadline of each time critical piece
+    piece_deadlines: FHashMap<u32, time::Instant>,
     /// The current picker in use
     picker: PickerKind,
     /// Piece priorities
@@ -100,15 +112,20 @@ impl Picker {
         };
         let mut picker = Picker {
             picker: PickerKind::Rarest(picker),
+            piece_len: u64::from(info.piece_len),
             scale,
             last_piece,
             last_piece_scale,
             downloading,
             seeders: 0,
             unpicked: pieces.clone(),
+            remaining: 0,
+            deadlines: BTreeSet::new(),
+            piece_deadlines: FHashMap::default(),
             stalled: FHashSet::default(),
             priorities: vec![3; info.pieces() as usize],
             blocks,
+            sources: HashMap::new(),
         };
         picker.set_priorities(priorities, info);
         picker
@@ -122,10 +139,62 @@ impl Picker {
         }
     }
 
+    /// Whether every remaining block has been requested, in which case
+    /// blocks are requested from several peers at once so that the last
+    /// few aren't held up by slow peers.
+    pub fn endgame(&self) -> bool {
+        self.remaining == 0 && !self.downloading.is_empty()
+    }
+
     pub fn done(&mut self) {
         self.downloading = HashMap::with_capacity(0);
         self.blocks = vec![];
+        self.sources = HashMap::new();
         self.stalled = FHashSet::default();
+        self.deadlines = BTreeSet::new();
+        self.piece_deadlines = FHashMap::default();
+    }
+
+    /// Sets a deadline for a piece, which will be picked before any
+    /// other piece and re-requested from other peers once it is late.
+    /// Pieces which are already downloaded are ignored, otherwise the
+    /// deadline holds until the piece is validated.
+    pub fn set_deadline(&mut self, piece: u32, deadline: time::Instant) {
+        if (piece as usize) >= self.blocks.len() {
+            return;
+        }
+        let (picked, completed) = self.blocks[piece as usize];
+        if self.unpicked.has_bit(u64::from(piece)) && picked == completed {
+            return;
+        }
+        if let Some(old) = self.piece_deadlines.insert(piece, deadline) {
+            self.deadlines.remove(&(old, piece));
+        }
+        self.deadlines.insert((deadline, piece));
+    }
+
+    /// Marks a piece as validated, so it is no longer time critical.
+    pub fn validated(&mut self, piece: u32) {
+        self.clear_deadline(piece);
+    }
+
+    /// Removes the deadline of a piece, if it has one.
+    pub fn clear_deadline(&mut self, piece: u32) {
+        if let Some(deadline) = self.piece_deadlines.remove(&piece) {
+            self.deadlines.remove(&(deadline, piece));
+        }
+    }
+
+    /// Sets a deadline for every piece overlapping a range of bytes.
+    pub fn set_deadline_range(&mut self, bytes: Range<u64>, deadline: time::Instant) {
+        if bytes.start >= bytes.end {
+            return;
+        }
+        let start = bytes.start / self.piece_len;
+        let end = cmp::min((bytes.end - 1) / self.piece_len, u64::from(self.last_piece));
+        for piece in start..=end {
+            self.set_deadline(piece as u32, deadline);
+        }
     }
 
     pub fn tick(&mut self) {
@@ -157,6 +226,10 @@ impl Picker {
 
     /// Attempts to select a block for a peer.
     pub fn pick<T: cio::CIO>(&mut self, peer: &mut Peer<T>) -> Option<Block> {
+        if let Some(b) = self.pick_critical(peer) {
+            return Some(b);
+        }
+
         if !self.stalled.is_empty() {
             let block = self.stalled.iter().cloned().find(|b| {
                 peer.pieces().has_bit(u64::from(b.index))
@@ -171,13 +244,122 @@ impl Picker {
             }
         }
 
+        let suggested = peer
+            .suggested()
+            .iter()
+            .rev()
+            .cloned()
+            .find(|&p| self.pickable(peer, p));
+        if let Some(p) = suggested {
+            return Some(self.pick_piece(p, peer.id(), peer.rank));
+        }
+
         let piece = match self.picker {
             PickerKind::Sequential(ref mut p) => p.pick(peer),
             PickerKind::Rarest(ref mut p) => p.pick(peer),
         };
         piece
             .map(|p| self.pick_piece(p, peer.id(), peer.rank))
-            .or_else(|| self.pick_dl(peer))
+            .or_else(|| {
+                if self.endgame() {
+                    self.pick_dl(peer)
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Attempts to select a block from the pieces a choking
+    /// peer allows us to request.
+    pub fn pick_fast<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+        let piece = peer
+            .allowed_fast()
+            .iter()
+            .cloned()
+            .find(|&p| self.pickable(peer, p))?;
+        Some(self.pick_piece(piece, peer.id(), peer.rank))
+    }
+
+    /// Picks every block of a piece which hasn't been started yet, for
+    /// sources such as web seeds which download whole pieces at once.
+    pub fn pick_whole(&mut self, id: usize) -> Option<u32> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        let (unpicked, priorities, blocks) = (&self.unpicked, &self.priorities, &self.blocks);
+        let unstarted = |p: u32| {
+            priorities[p as usize] != 0
+                && !unpicked.has_bit(u64::from(p))
+                && blocks[p as usize].0 == 0
+        };
+        let piece = match self.picker {
+            PickerKind::Sequential(ref p) => p.pick_with(unstarted),
+            PickerKind::Rarest(ref mut p) => p.pick_with(unstarted),
+        }?;
+        for _ in 0..self.piece_blocks(piece) {
+            self.pick_piece(piece, id, 0);
+        }
+        Some(piece)
+    }
+
+    /// Picks a block from the most urgent time critical piece the peer has.
+    /// Blocks of late pieces are requested again, even if already requested
+    /// from other peers.
+    fn pick_critical<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
+        let now = time::Instant::now();
+        // The piece picked, and the block to request again if it's late
+        let mut critical = None;
+        for &(deadline, piece) in &self.deadlines {
+            if !peer.pieces().has_bit(u64::from(piece)) || self.priorities[piece as usize] == 0 {
+                continue;
+            }
+            if !self.unpicked.has_bit(u64::from(piece)) {
+                critical = Some((piece, None));
+                break;
+            }
+            if deadline > now {
+                continue;
+            }
+            let downloading = &self.downloading;
+            let block = (0..self.piece_blocks(piece))
+                .map(|b| Block::new(piece, b * 16_384))
+                .find(|b| {
+                    downloading
+                        .get(b)
+                        .map(|req| req.num_reqd < MAX_DUP_REQS && !req.has_peer(peer.id()))
+                        .unwrap_or(false)
+                });
+            if block.is_some() {
+                critical = Some((piece, block));
+                break;
+            }
+        }
+        match critical? {
+            (_, Some(b)) => {
+                if let Some(req) = self.downloading.get_mut(&b) {
+                    req.rereq(peer.id(), peer.rank);
+                }
+                Some(b)
+            }
+            (piece, None) => Some(self.pick_piece(piece, peer.id(), peer.rank)),
+        }
+    }
+
+    fn piece_blocks(&self, piece: u32) -> u32 {
+        if piece == self.last_piece {
+            self.last_piece_scale
+        } else {
+            self.scale
+        }
+    }
+
+    /// Whether or not a piece of the peer's still has unrequested blocks
+    /// and is wanted.
+    fn pickable<T: cio::CIO>(&self, peer: &Peer<T>, piece: u32) -> bool {
+        (piece as usize) < self.priorities.len()
+            && self.priorities[piece as usize] != 0
+            && !self.unpicked.has_bit(u64::from(piece))
+            && peer.pieces().has_bit(u64::from(piece))
     }
 
     /// Picks a block from a given piece for a peer
@@ -193,6 +375,7 @@ impl Picker {
                 PickerKind::Rarest(ref mut p) => p.completed(piece),
             }
             self.unpicked.set_bit(u64::from(piece));
+            self.remaining = self.remaining.saturating_sub(1);
         }
         let block = Block {
             index: piece,
@@ -206,7 +389,11 @@ impl Picker {
     fn pick_dl<T: cio::CIO>(&mut self, peer: &Peer<T>) -> Option<Block> {
         self.downloading
             .iter_mut()
-            .filter(|&(_, ref req)| req.num_reqd < MAX_DUP_REQS && !req.has_peer(peer.id()))
+            .filter(|&(block, ref req)| {
+                req.num_reqd < MAX_DUP_REQS
+                    && !req.has_peer(peer.id())
+                    && peer.pieces().has_bit(u64::from(block.index))
+            })
             .take(MAX_DL_REREQ)
             .fold(None, |c: Option<(&Block, &mut Request)>, this| match &c {
                 Some(min) => {
@@ -224,17 +411,25 @@ impl Picker {
             })
     }
 
-    /// Marks a block as completed. Returns a result indicating if the block
-    /// was actually requested, the success value containing a bool indicating
-    /// if the block is complete.
-    pub fn completed<F: FnMut(usize)>(&mut self, b: Block, mut cancel: F) -> Result<bool, ()> {
+    /// Marks a block as completed, recording the peer which supplied it.
+    /// Returns a result indicating if the block was actually requested,
+    /// the success value containing a bool indicating if the piece is complete.
+    pub fn completed<F: FnMut(usize)>(
+        &mut self,
+        b: Block,
+        from: Option<IpAddr>,
+        mut cancel: F,
+    ) -> Result<bool, ()> {
         self.stalled.remove(&b);
         let dl = self.downloading.remove(&b);
         let dl = match dl {
             Some(dl) => dl,
             None => return Err(()),
         };
-        for peer in dl.reqd_from.iter() {
+        if let Some(ip) = from {
+            self.sources.insert(b, ip);
+        }
+        for peer in dl.reqd_from[..dl.num_reqd].iter() {
             cancel(*peer);
         }
 
@@ -249,6 +444,25 @@ impl Picker {
         }
     }
 
+    /// Frees a block the peer refused to send, so that
+    /// it can be requested again straight away.
+    pub fn rejected(&mut self, b: Block, peer: usize) {
+        if let Some(req) = self.downloading.get_mut(&b) {
+            if req.remove_peer(peer) {
+                self.stalled.insert(b);
+            }
+        }
+    }
+
+    /// Returns the offset and supplier of each block of a piece which
+    /// came from a peer, forgetting them.
+    pub fn sources(&mut self, piece: u32) -> Vec<(u32, IpAddr)> {
+        (0..self.piece_blocks(piece))
+            .map(|b| Block::new(piece, b * 16_384))
+            .filter_map(|b| self.sources.remove(&b).map(|ip| (b.offset, ip)))
+            .collect()
+    }
+
     pub fn have_block(&mut self, b: Block) -> bool {
         !self.downloading.contains_key(&b)
     }
@@ -263,6 +477,9 @@ impl Picker {
             self.blocks = vec![(0, 0); self.priorities.len()];
         }
         self.blocks[idx as usize] = (0, 0);
+        if self.unpicked.has_bit(u64::from(idx)) && self.priorities[idx as usize] != 0 {
+            self.remaining += 1;
+        }
         self.unpicked.unset_bit(u64::from(idx));
     }
 
@@ -289,15 +506,7 @@ impl Picker {
         }
 
         for (_, req) in self.downloading.iter_mut() {
-            if let Some((idx, _)) = req
-                .reqd_from
-                .iter()
-                .enumerate()
-                .find(|&(_, id)| *id == peer.id())
-            {
-                req.num_reqd -= 1;
-                req.reqd_from[idx] = req.reqd_from[req.num_reqd];
-            }
+            req.remove_peer(peer.id());
         }
     }
 
@@ -316,6 +525,13 @@ impl Picker {
         self.unapply_priorities();
         self.priorities = generate_piece_pri(pri, info);
         self.apply_priorities();
+        let unpicked = &self.unpicked;
+        self.remaining = self
+            .priorities
+            .iter()
+            .enumerate()
+            .filter(|&(p, &pri)| pri != 0 && !unpicked.has_bit(p as u64))
+            .count();
     }
 
     pub fn apply_priorities(&mut self) {
@@ -433,6 +649,20 @@ impl Request {
         }
     }
 
+    /// Removes a peer from the request, returning true if it was present.
+    fn remove_peer(&mut self, peer: usize) -> bool {
+        if let Some(idx) = self.reqd_from[..self.num_reqd]
+            .iter()
+            .position(|id| *id == peer)
+        {
+            self.num_reqd -= 1;
+            self.reqd_from[idx] = self.reqd_from[self.num_reqd];
+            true
+        } else {
+            false
+        }
+    }
+
     fn has_peer(&self, peer: usize) -> bool {
         self.reqd_from.contains(&peer)
     }
@@ -110,6 +110,18 @@ impl Picker {
         self.swap_piece(idx, swap_idx);
     }
 
+    /// Picks the rarest incomplete piece accepted by `f`.
+    pub fn pick_with<F: Fn(u32) -> bool>(&mut self, f: F) -> Option<u32> {
+        let piece_idx = &self.piece_idx;
+        let piece = self
+            .pieces
+            .iter()
+            .cloned()
+            .find(|&p| piece_idx[p as usize].status == PieceStatus::Incomplete && f(p))?;
+        self.mark_picked(piece);
+        Some(piece)
+    }
+
     pub fn pick<T: cio::CIO>(&mut self, peer: &mut Peer<T>) -> Option<u32> {
         while !peer.piece_cache().is_empty() {
             let p = peer.piece_cache().last().cloned().unwrap();
@@ -134,13 +146,17 @@ impl Picker {
             peer.piece_cache().reverse();
         }
 
-        let piece = peer.piece_cache().last();
+        let piece = peer.piece_cache().last().cloned();
         if let Some(p) = piece {
-            if (self.piece_idx[*p as usize].availability % 2) == 0 {
-                self.inc_pri(*p);
-            }
+            self.mark_picked(p);
+        }
+        piece
+    }
+
+    fn mark_picked(&mut self, piece: u32) {
+        if (self.piece_idx[piece as usize].availability % 2) == 0 {
+            self.inc_pri(piece);
         }
-        piece.cloned()
     }
 
     pub fn incomplete(&mut self, piece: u32) {
@@ -76,6 +76,14 @@ impl Picker {
             .map(|p| p.pos)
     }
 
+    /// Picks the first incomplete piece accepted by `f`.
+    pub fn pick_with<F: Fn(u32) -> bool>(&self, f: F) -> Option<u32> {
+        self.pieces[self.piece_idx..]
+            .iter()
+            .find(|p| p.status == PieceStatus::Incomplete && f(p.pos))
+            .map(|p| p.pos)
+    }
+
     /// Returns whether or not the whole piece is complete.
     pub fn completed(&mut self, idx: u32) {
         if let Some(p) = self.pieces[self.piece_idx..]
@@ -96,7 +96,7 @@ impl Simulation {
                     let ref mut received = self.peers.borrow_mut()[req.peer];
                     received
                         .picker
-                        .completed(Block::new(req.piece, 0), |_| ())
+                        .completed(Block::new(req.piece, 0), None, |_| ())
                         .unwrap();
                     received.data.pieces_mut().set_bit(req.piece as u64);
                     if received.data.pieces().complete() {
@@ -264,7 +264,7 @@ fn test_seq_picker() {
     for i in 0..10 {
         let mut canceled = None;
         assert_eq!(
-            p.completed(Block::new(i, 0), |p| {
+            p.completed(Block::new(i, 0), None, |p| {
                 canceled = Some(p);
             }),
             Ok(true)
@@ -276,3 +276,142 @@ fn test_seq_picker() {
 
     assert_eq!(p.pick(&mut peer), Some(Block::new(5, 0)));
 }
+
+#[test]
+fn test_fast_picks() {
+    use crate::protocol::Message;
+
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb.clone());
+    peer.enable_fast();
+
+    // Nothing may be picked while choked until pieces are allowed
+    assert_eq!(p.pick_fast(&peer), None);
+    peer.handle_msg(&mut Message::AllowedFast(7)).unwrap();
+    assert_eq!(p.pick_fast(&peer), Some(Block::new(7, 0)));
+    assert_eq!(p.pick_fast(&peer), None);
+
+    // Suggested pieces take precedence over the normal ordering
+    peer.handle_msg(&mut Message::SuggestPiece(4)).unwrap();
+    assert_eq!(p.pick(&mut peer), Some(Block::new(4, 0)));
+    let next = p.pick(&mut peer).unwrap();
+    assert!(next.index != 4 && next.index != 7);
+
+    // A rejected block is immediately available to other peers
+    let mut other = TPeer::test_from_pieces(1, pb);
+    p.rejected(Block::new(4, 0), peer.id());
+    assert_eq!(p.pick(&mut other), Some(Block::new(4, 0)));
+}
+
+#[test]
+fn test_pick_whole() {
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb.clone());
+
+    // Pieces already being downloaded by peers are skipped
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 0)));
+    assert_eq!(p.pick_whole(100), Some(1));
+    assert_eq!(p.pick(&mut peer), Some(Block::new(2, 0)));
+    assert_eq!(p.completed(Block::new(1, 0), None, |_| {}), Ok(true));
+
+    // Freed blocks go back to peers rather than whole piece sources
+    let mut other = TPeer::test_from_pieces(1, pb);
+    p.rejected(Block::new(2, 0), peer.id());
+    assert_eq!(p.pick_whole(100), Some(3));
+    assert_eq!(p.pick(&mut other), Some(Block::new(2, 0)));
+}
+
+#[test]
+fn test_endgame() {
+    let mut i = Info::with_pieces(2);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(2);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(2);
+    pb.set_bit(0);
+    pb.set_bit(1);
+    let mut peer = TPeer::test_from_pieces(0, pb.clone());
+    let mut other = TPeer::test_from_pieces(1, pb);
+
+    // Endgame starts once every block has been requested
+    assert_eq!(p.pick(&mut peer), Some(Block::new(0, 0)));
+    assert!(!p.endgame());
+    assert_eq!(p.pick(&mut other), Some(Block::new(1, 0)));
+    assert!(p.endgame());
+
+    // Blocks are only requested again from peers which have the piece
+    let mut partial = Bitfield::new(2);
+    partial.set_bit(1);
+    let mut lacking = TPeer::test_from_pieces(2, partial);
+    assert_eq!(p.pick(&mut lacking), Some(Block::new(1, 0)));
+    let mut empty = TPeer::test_from_pieces(3, Bitfield::new(2));
+    assert_eq!(p.pick(&mut empty), None);
+
+    // Once in endgame, outstanding blocks are requested again
+    let dup = p.pick(&mut other).unwrap();
+    assert_eq!(dup, Block::new(0, 0));
+    let mut canceled = vec![];
+    assert_eq!(p.completed(dup, None, |pid| canceled.push(pid)), Ok(true));
+    canceled.sort();
+    assert_eq!(canceled, vec![0, 1]);
+    assert_eq!(p.completed(dup, None, |_| {}), Err(()));
+}
+
+#[test]
+fn test_deadlines() {
+    use std::time::{Duration, Instant};
+
+    let mut i = Info::with_pieces(10);
+    i.piece_idx = Info::generate_piece_idx(i.hashes.len(), i.piece_len as u64, &i.files);
+    let b = Bitfield::new(10);
+    let mut p = Picker::new_sequential(&i, &b);
+    let mut pb = Bitfield::new(10);
+    for i in 0..10 {
+        pb.set_bit(i);
+    }
+    let mut peer = TPeer::test_from_pieces(0, pb.clone());
+    let mut other = TPeer::test_from_pieces(1, pb);
+
+    // Critical pieces come first, most urgent first
+    let later = Instant::now() + Duration::from_secs(60);
+    p.set_deadline(8, later);
+    p.set_deadline_range(
+        u64::from(i.piece_len) * 5..u64::from(i.piece_len) * 5 + 1,
+        later - Duration::from_secs(30),
+    );
+    assert_eq!(p.pick(&mut peer), Some(Block::new(5, 0)));
+    assert_eq!(p.pick(&mut peer), Some(Block::new(8, 0)));
+    let next = p.pick(&mut peer).unwrap();
+    assert!(next.index != 5 && next.index != 8);
+
+    // Late pieces are requested again from other peers
+    p.set_deadline(next.index, Instant::now());
+    assert_eq!(p.pick(&mut other), Some(next));
+    assert_eq!(p.completed(next, None, |_| {}), Ok(true));
+    assert!(p.pick(&mut other) != Some(next));
+
+    // Deadlines outlast failed hash checks, until the piece validates
+    let critical = Block::new(8, 0);
+    assert_eq!(p.completed(critical, None, |_| {}), Ok(true));
+    p.invalidate_piece(8);
+    assert_eq!(p.pick(&mut other), Some(critical));
+    assert_eq!(p.completed(critical, None, |_| {}), Ok(true));
+    p.validated(8);
+    p.invalidate_piece(8);
+    p.set_deadline(9, later + Duration::from_secs(1));
+    assert_eq!(p.pick(&mut other), Some(Block::new(9, 0)));
+}
@@ -0,0 +1,100 @@
+//! Smart banning of peers which send corrupt data. When a piece fails
+//! validation the hash of each block is recorded along with the peer
+//! which sent it. Once the piece is downloaded successfully, peers whose
+//! blocks differ from the valid data can be singled out.
+
+use std::net::IpAddr;
+
+use crate::util::FHashMap;
+
+#[derive(Default)]
+pub struct SmartBan {
+    /// Blocks of pieces which failed validation, as offset, sender and hash
+    suspects: FHashMap<u32, Vec<(u32, IpAddr, [u8; 20])>>,
+}
+
+impl SmartBan {
+    pub fn new() -> SmartBan {
+        SmartBan::default()
+    }
+
+    /// Whether a piece has failed validation before, in which case
+    /// the hashes of its blocks are needed once it's valid.
+    pub fn suspect(&self, piece: u32) -> bool {
+        self.suspects.contains_key(&piece)
+    }
+
+    /// Records the blocks of a piece which failed validation. If a
+    /// single peer sent the entire piece it must be at fault, and
+    /// is returned.
+    pub fn failed(
+        &mut self,
+        piece: u32,
+        sources: &[(u32, IpAddr)],
+        blocks: &[[u8; 20]],
+    ) -> Option<IpAddr> {
+        let sender = sources.first().map(|&(_, ip)| ip)?;
+        if blocks.is_empty() {
+            return None;
+        }
+        if sources.len() == blocks.len() && sources.iter().all(|&(_, ip)| ip == sender) {
+            return Some(sender);
+        }
+        let suspects = self.suspects.entry(piece).or_default();
+        for &(offset, ip) in sources {
+            if let Some(hash) = blocks.get((offset / 16_384) as usize) {
+                suspects.push((offset, ip, *hash));
+            }
+        }
+        None
+    }
+
+    /// Compares the blocks of a piece which failed validation against
+    /// the valid ones, returning the peers which sent corrupt data.
+    pub fn validated(&mut self, piece: u32, blocks: &[[u8; 20]]) -> Vec<IpAddr> {
+        let mut culprits = Vec::new();
+        for (offset, ip, hash) in self.suspects.remove(&piece).unwrap_or_default() {
+            let valid = blocks.get((offset / 16_384) as usize);
+            if valid.map(|v| *v != hash).unwrap_or(false) && !culprits.contains(&ip) {
+                culprits.push(ip);
+            }
+        }
+        culprits
+    }
+
+    /// Forgets a peer's blocks once it's been banned.
+    pub fn remove_peer(&mut self, ip: IpAddr) {
+        for suspects in self.suspects.values_mut() {
+            suspects.retain(|&(_, sender, _)| sender != ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SmartBan;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_culprits() {
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut s = SmartBan::new();
+
+        // A piece sent entirely by one peer identifies it immediately
+        let sources = [(0, a), (16_384, a)];
+        assert_eq!(s.failed(0, &sources, &[[0; 20], [1; 20]]), Some(a));
+        assert!(!s.suspect(0));
+
+        let sources = [(0, a), (16_384, b)];
+        assert_eq!(s.failed(1, &sources, &[[0; 20], [1; 20]]), None);
+        assert!(s.suspect(1));
+        // Only the peer whose block differs is at fault
+        assert_eq!(s.validated(1, &[[0; 20], [2; 20]]), vec![b]);
+        assert!(!s.suspect(1));
+
+        s.failed(2, &sources, &[[0; 20], [1; 20]]);
+        s.remove_peer(b);
+        assert!(s.validated(2, &[[0; 20], [2; 20]]).is_empty());
+    }
+}
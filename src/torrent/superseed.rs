@@ -0,0 +1,146 @@
+//! Super-seeding, as per BEP 16. Peers are sent an empty bitfield and
+//! offered a single piece at a time through Have messages. A peer is
+//! only offered another piece once its last one has been seen elsewhere
+//! in the swarm, so initial seeding isn't wasted on duplicate pieces.
+
+use crate::torrent::Bitfield;
+use crate::util::{FHashSet, UHashMap};
+
+pub struct SuperSeed {
+    /// Number of connected peers which have each piece
+    avail: Vec<u32>,
+    /// Number of times each piece has been offered
+    offered: Vec<u32>,
+    /// Peers which were sent an empty bitfield
+    peers: UHashMap<PeerState>,
+}
+
+#[derive(Default)]
+struct PeerState {
+    offer: Option<u32>,
+    revealed: FHashSet<u32>,
+}
+
+impl SuperSeed {
+    pub fn new(pieces: u32) -> SuperSeed {
+        SuperSeed {
+            avail: vec![0; pieces as usize],
+            offered: vec![0; pieces as usize],
+            peers: UHashMap::default(),
+        }
+    }
+
+    /// Starts tracking a peer which has had our bitfield hidden from it.
+    pub fn add_peer(&mut self, pid: usize) {
+        self.peers.insert(pid, PeerState::default());
+    }
+
+    pub fn remove_peer(&mut self, pid: usize, pieces: &Bitfield) {
+        self.peers.remove(&pid);
+        for piece in pieces.iter() {
+            let avail = &mut self.avail[piece as usize];
+            *avail = avail.saturating_sub(1);
+        }
+    }
+
+    /// Counts the pieces of a peer towards availability.
+    pub fn available(&mut self, pieces: &Bitfield) {
+        for piece in pieces.iter() {
+            self.avail[piece as usize] += 1;
+        }
+    }
+
+    /// Whether a peer has been told about a piece. Peers which were
+    /// connected before super-seeding started know about every piece.
+    pub fn revealed(&self, pid: usize, piece: u32) -> bool {
+        self.peers
+            .get(&pid)
+            .map(|p| p.revealed.contains(&piece))
+            .unwrap_or(true)
+    }
+
+    /// Whether a peer may be unchoked, which is pointless if it has
+    /// not been offered anything.
+    pub fn may_unchoke(&self, pid: usize) -> bool {
+        self.peers
+            .get(&pid)
+            .map(|p| p.offer.is_some())
+            .unwrap_or(true)
+    }
+
+    /// Records that a peer now has a piece, returning the peers whose
+    /// offered piece has spread and are due another.
+    pub fn have(&mut self, pid: usize, piece: u32) -> Vec<usize> {
+        self.avail[piece as usize] += 1;
+        let spread = self.avail[piece as usize] >= 2;
+        self.peers
+            .iter()
+            .filter(|&(&id, p)| p.offer == Some(piece) && (id != pid || spread))
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// Picks the next piece to reveal to a peer, preferring pieces which
+    /// have been offered the fewest times, then the rarest.
+    pub fn offer(&mut self, pid: usize, pieces: &Bitfield) -> Option<u32> {
+        let avail = &self.avail;
+        let offered = &mut self.offered;
+        let peer = self.peers.get_mut(&pid)?;
+        peer.offer = (0..avail.len() as u32)
+            .filter(|&p| !pieces.has_bit(u64::from(p)) && !peer.revealed.contains(&p))
+            .min_by_key(|&p| (offered[p as usize], avail[p as usize]));
+        if let Some(piece) = peer.offer {
+            offered[piece as usize] += 1;
+            peer.revealed.insert(piece);
+        }
+        peer.offer
+    }
+
+    /// Consumes the super-seeding state, returning each tracked peer
+    /// with the pieces revealed to it.
+    pub fn into_revealed(self) -> impl Iterator<Item = (usize, FHashSet<u32>)> {
+        self.peers.into_iter().map(|(id, p)| (id, p.revealed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SuperSeed;
+    use crate::torrent::Bitfield;
+
+    #[test]
+    fn test_offers() {
+        let empty = Bitfield::new(3);
+        let mut s = SuperSeed::new(3);
+        let mut old = Bitfield::new(3);
+        old.set_bit(0);
+        s.available(&old);
+
+        s.add_peer(1);
+        s.add_peer(2);
+        s.available(&empty);
+        s.available(&empty);
+        assert!(!s.may_unchoke(1));
+        // Pieces nobody has are preferred, and aren't offered twice
+        let a = s.offer(1, &empty).unwrap();
+        let b = s.offer(2, &empty).unwrap();
+        assert!(a != 0 && b != 0 && a != b);
+        assert!(s.may_unchoke(1));
+        assert!(s.revealed(1, a));
+        assert!(!s.revealed(1, b));
+        assert!(s.revealed(3, b));
+
+        // Downloading the offered piece isn't enough for another offer
+        assert!(s.have(1, a).is_empty());
+        // But it spreading to another peer is
+        assert_eq!(s.have(2, a), vec![1]);
+        let mut have = Bitfield::new(3);
+        have.set_bit(u64::from(a));
+        assert_eq!(s.offer(1, &have), Some(0));
+        have.set_bit(0);
+        s.remove_peer(2, &have);
+        assert!(s.revealed(2, 0));
+        assert_eq!(s.have(3, 0), vec![1]);
+        assert_eq!(s.offer(1, &have), Some(b));
+    }
+}
@@ -0,0 +1,266 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use crate::rpc::resource;
+use crate::stat;
+use crate::torrent::info::{self, Info};
+use crate::tracker;
+use crate::util;
+
+/// Initial delay before a failed web seed is retried, doubled on
+/// each consecutive failure.
+const RETRY_SECS: u64 = 30;
+const MAX_RETRY_SECS: u64 = 60 * 60;
+
+/// A web seed which downloads a single piece at a time over HTTP.
+pub struct WebSeed {
+    /// ID used for the seed in the picker and over RPC. These are allocated
+    /// downwards from usize::MAX so they never collide with peer IDs.
+    id: usize,
+    seed: info::WebSeed,
+    state: State,
+    failures: u32,
+    stat: stat::EMA,
+}
+
+enum State {
+    Idle,
+    Downloading(Download),
+    Backoff(Instant),
+}
+
+struct Download {
+    piece: u32,
+    data: Vec<u8>,
+    /// Number of outstanding requests for parts of the piece
+    pending: usize,
+}
+
+impl WebSeed {
+    pub fn new(idx: usize, seed: info::WebSeed) -> WebSeed {
+        WebSeed {
+            id: std::usize::MAX - idx,
+            seed,
+            state: State::Idle,
+            failures: 0,
+            stat: stat::EMA::new(),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Whether or not a new piece can be requested from the seed
+    pub fn ready(&mut self) -> bool {
+        match self.state {
+            State::Idle => true,
+            State::Backoff(until) if Instant::now() >= until => {
+                self.state = State::Idle;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Begins downloading a piece, returning the requests which will fetch it.
+    /// BEP 19 seeds need a request for every file the piece overlaps.
+    pub fn request(&mut self, tid: usize, info: &Arc<Info>, piece: u32) -> Vec<tracker::Request> {
+        let len = info.piece_len(piece);
+        let reqs: Vec<_> = match self.seed.kind {
+            info::WebSeedKind::Url => Info::piece_disk_locs(info, piece)
+                .filter(|loc| !info.files[loc.file].padding)
+                .map(|loc| {
+                    tracker::Request::WebSeed(tracker::WebSeed {
+                        tid,
+                        seed: self.id,
+                        url: file_url(&self.seed.url, info, loc.file),
+                        range: tracker::WebSeedRange::File(loc.offset),
+                        piece,
+                        offset: loc.start as u32,
+                        len: (loc.end - loc.start) as u32,
+                    })
+                })
+                .collect(),
+            info::WebSeedKind::Http => vec![tracker::Request::WebSeed(tracker::WebSeed {
+                tid,
+                seed: self.id,
+                url: self.seed.url.clone(),
+                range: tracker::WebSeedRange::Piece(info.hash),
+                piece,
+                offset: 0,
+                len,
+            })],
+        };
+        self.state = State::Downloading(Download {
+            piece,
+            // Padding is never requested, so is left zeroed
+            data: vec![0; len as usize],
+            pending: reqs.len(),
+        });
+        reqs
+    }
+
+    /// Handles data received for the piece being downloaded, returning the
+    /// piece index and its data once every part has arrived.
+    pub fn received(&mut self, piece: u32, offset: u32, data: &[u8]) -> Option<(u32, Vec<u8>)> {
+        let done = match self.state {
+            State::Downloading(ref mut d) if d.piece == piece => {
+                let start = offset as usize;
+                if start + data.len() > d.data.len() {
+                    return None;
+                }
+                d.data[start..start + data.len()].copy_from_slice(data);
+                d.pending -= 1;
+                d.pending == 0
+            }
+            _ => return None,
+        };
+        self.stat.add_dl(data.len() as u64);
+        self.failures = 0;
+        if !done {
+            return None;
+        }
+        match std::mem::replace(&mut self.state, State::Idle) {
+            State::Downloading(d) => Some((d.piece, d.data)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Backs off from the seed after a failed request, returning the piece
+    /// which was being downloaded so it can be freed in the picker.
+    pub fn failed(&mut self, piece: u32) -> Option<u32> {
+        match self.state {
+            State::Downloading(ref d) if d.piece == piece => {}
+            _ => return None,
+        }
+        let delay = (RETRY_SECS << self.failures.min(7)).min(MAX_RETRY_SECS);
+        self.failures += 1;
+        self.state = State::Backoff(Instant::now() + Duration::from_secs(delay));
+        Some(piece)
+    }
+
+    /// Stops the current download, returning the piece which was in progress.
+    pub fn reset(&mut self) -> Option<u32> {
+        match std::mem::replace(&mut self.state, State::Idle) {
+            State::Downloading(d) => Some(d.piece),
+            _ => None,
+        }
+    }
+
+    pub fn tick(&mut self) -> bool {
+        self.stat.tick();
+        self.stat.active()
+    }
+
+    pub fn get_tx_rates(&self) -> (u64, u64) {
+        (self.stat.avg_ul(), self.stat.avg_dl())
+    }
+
+    pub fn rpc_info(&self, hash: &[u8; 20], torrent_id: String) -> resource::Resource {
+        resource::Resource::Peer(resource::Peer {
+            id: util::peer_rpc_id(hash, self.id as u64),
+            torrent_id,
+            client_id: String::new(),
+            ip: self.seed.url.as_str().to_owned(),
+            availability: 1.,
+            ..Default::default()
+        })
+    }
+}
+
+/// Builds the URL of a file for a BEP 19 seed. URLs ending in a slash are
+/// directories holding the torrent, others name the file of a single file torrent.
+fn file_url(base: &Url, info: &Info, file: usize) -> Url {
+    let mut url = base.clone();
+    if info.files.len() == 1 && !base.path().ends_with('/') {
+        return url;
+    }
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop_if_empty();
+        for c in info.files[file].path.components() {
+            segments.push(&c.as_os_str().to_string_lossy());
+        }
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn info(files: &[(&str, u64)]) -> Info {
+        let mut info = Info::with_pieces(2);
+        info.files = files
+            .iter()
+            .map(|&(path, length)| info::File {
+                path: PathBuf::from(path),
+                length,
+                pieces_root: None,
+                padding: false,
+            })
+            .collect();
+        info.piece_idx =
+            Info::generate_piece_idx(info.hashes.len(), u64::from(info.piece_len), &info.files);
+        info
+    }
+
+    #[test]
+    fn test_file_url() {
+        let single = info(&[("a b.iso", 32_768)]);
+        let multi = info(&[("dir/a", 20_000), ("dir/b", 12_768)]);
+        let dir = Url::parse("http://example.com/seeds/").unwrap();
+        let file = Url::parse("http://example.com/files/x.iso").unwrap();
+
+        assert_eq!(
+            file_url(&dir, &single, 0).as_str(),
+            "http://example.com/seeds/a%20b.iso"
+        );
+        assert_eq!(file_url(&file, &single, 0), file);
+        assert_eq!(
+            file_url(&dir, &multi, 1).as_str(),
+            "http://example.com/seeds/dir/b"
+        );
+    }
+
+    #[test]
+    fn test_piece_requests() {
+        let info = Arc::new(info(&[("dir/a", 20_000), ("dir/b", 12_768)]));
+        let mut seed = WebSeed::new(
+            0,
+            info::WebSeed {
+                url: Url::parse("http://example.com/").unwrap(),
+                kind: info::WebSeedKind::Url,
+            },
+        );
+        assert!(seed.ready());
+
+        // The second piece spans both files
+        let reqs = seed.request(0, &info, 1);
+        let parts: Vec<_> = reqs
+            .iter()
+            .map(|r| match r {
+                tracker::Request::WebSeed(w) => match w.range {
+                    tracker::WebSeedRange::File(start) => (start, w.offset, w.len),
+                    _ => panic!("BEP 19 seeds request file ranges"),
+                },
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(parts, vec![(16_384, 0, 3_616), (0, 3_616, 12_768)]);
+        assert!(!seed.ready());
+
+        assert_eq!(seed.received(1, 3_616, &[1; 12_768]), None);
+        let (piece, data) = seed.received(1, 0, &[2; 3_616]).unwrap();
+        assert_eq!(piece, 1);
+        assert_eq!(&data[3_615..3_617], &[2, 1]);
+        assert!(seed.ready());
+
+        seed.request(0, &info, 0);
+        assert_eq!(seed.failed(0), Some(0));
+        assert!(!seed.ready());
+    }
+}
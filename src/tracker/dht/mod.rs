@@ -1,6 +1,6 @@
 use std::fs::OpenOptions;
 use std::io::{self, Read};
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
 use std::path::Path;
 use std::time;
 
@@ -19,79 +19,204 @@ const BUCKET_MAX: usize = 8;
 const MAX_BUCKETS: usize = 512;
 const VERSION: &str = "SY";
 const SESSION_FILE: &str = "dht_data";
+const SESSION_FILE6: &str = "dht6_data";
 const MIN_BOOTSTRAP_BKTS: usize = 32;
 const TX_TIMEOUT_SECS: i64 = 20;
 
 pub struct Manager {
-    id: usize,
-    table: rt::RoutingTable,
+    table: Table,
+    table6: Option<Table>,
     dht_flush: time::Instant,
-    sock: UdpSocket,
     buf: Vec<u8>,
     db: amy::Sender<disk::Request>,
 }
 
+/// A routing table for a single address family, as per BEP 32,
+/// and the socket used to reach its nodes.
+struct Table {
+    id: usize,
+    rt: rt::RoutingTable,
+    sock: UdpSocket,
+    session: &'static str,
+}
+
 impl Manager {
     pub fn new(reg: &amy::Registrar, db: amy::Sender<disk::Request>) -> io::Result<Manager> {
-        let sock = UdpSocket::bind(("0.0.0.0", CONFIG.dht.port))?;
-        sock.set_nonblocking(true)?;
-        let id = reg.register(&sock, amy::Event::Read)?;
+        let table = Table::new(
+            reg,
+            ("0.0.0.0", CONFIG.dht.port),
+            CONFIG.dht.bootstrap_node,
+            SESSION_FILE,
+        )?;
         // Turn off DHT if no bootstrap is specified.
         if CONFIG.dht.bootstrap_node.is_none() {
-            reg.deregister(&sock)?;
+            reg.deregister(&table.sock)?;
         }
+        // IPv6 is optional, so only set it up when a bootstrap is given
+        // and the host is able to bind to an IPv6 socket.
+        let table6 = CONFIG.dht.bootstrap_node6.and_then(|node| {
+            Table::new(reg, ("::", CONFIG.dht.port6), Some(node), SESSION_FILE6)
+                .map_err(|e| info!("IPv6 DHT could not be started: {}", e))
+                .ok()
+        });
+
+        Ok(Manager {
+            table,
+            table6,
+            db,
+            buf: vec![0u8; 1500],
+            dht_flush: time::Instant::now(),
+        })
+    }
+
+    pub fn init(&mut self) {
+        debug!("Initializing DHT nodes!");
+        for t in self.tables_mut() {
+            for (q, a) in t.rt.init() {
+                t.send_msg(&q.encode(), a);
+            }
+        }
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.table.id == id || self.table6.as_ref().map(|t| t.id == id).unwrap_or(false)
+    }
 
-        let p = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
+    pub fn readable(&mut self, id: usize) -> Vec<tracker::Response> {
+        let (table, other) = if self.table.id == id {
+            (&mut self.table, self.table6.as_mut())
+        } else if let Some(ref mut t6) = self.table6 {
+            (t6, Some(&mut self.table))
+        } else {
+            return Vec::new();
+        };
+        table.readable(other.map(|t| &*t), &mut self.buf)
+    }
+
+    pub fn get_peers(&mut self, tid: usize, hash: [u8; 20]) {
+        for t in self.tables_mut() {
+            for (req, a) in t.rt.get_peers(tid, hash) {
+                t.send_msg(&req.encode(), a);
+            }
+        }
+    }
+
+    pub fn add_addr(&mut self, addr: SocketAddr) {
+        if addr.is_ipv4() {
+            self.table.rt.add_addr(addr);
+        } else if let Some(ref mut t) = self.table6 {
+            t.rt.add_addr(addr);
+        }
+    }
+
+    pub fn announce(&mut self, hash: [u8; 20]) {
+        for t in self.tables_mut() {
+            for (req, a) in t.rt.announce(hash) {
+                t.send_msg(&req.encode(), a);
+            }
+        }
+    }
+
+    pub fn tick(&mut self) {
+        let flush = self.dht_flush.elapsed() > time::Duration::from_secs(60);
+        if flush {
+            self.dht_flush = time::Instant::now();
+        }
+        let db = self.db.clone();
+        for t in self.tables_mut() {
+            if flush {
+                let data = t.rt.serialize();
+                let path = Path::new(&CONFIG.disk.session[..]).join(t.session);
+                db.send(disk::Request::WriteFile { data, path }).ok();
+            }
+            for (req, a) in t.rt.tick() {
+                t.send_msg(&req.encode(), a);
+            }
+        }
+    }
+
+    fn tables_mut(&mut self) -> impl Iterator<Item = &mut Table> {
+        Some(&mut self.table)
+            .into_iter()
+            .chain(self.table6.as_mut())
+    }
+}
+
+impl Table {
+    fn new<A: ToSocketAddrs>(
+        reg: &amy::Registrar,
+        bind: A,
+        bootstrap: Option<SocketAddr>,
+        session: &'static str,
+    ) -> io::Result<Table> {
+        let sock = UdpSocket::bind(bind)?;
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+
+        let p = Path::new(&CONFIG.disk.session[..]).join(session);
         let mut data = Vec::new();
         if let Ok(mut f) = OpenOptions::new().read(true).open(&p) {
             f.read_to_end(&mut data)?;
         }
-        let mut table = if let Some(t) = rt::RoutingTable::deserialize(&data[..]) {
+        let mut rt = if let Some(t) = rt::RoutingTable::deserialize(&data[..]) {
             t
         } else {
             info!("DHT table could not be read from disk, creating new table!");
             rt::RoutingTable::new()
         };
-        if !table.is_bootstrapped() {
+        if !rt.is_bootstrapped() {
             info!("Attempting DHT bootstrap!");
-            if let Some(addr) = CONFIG.dht.bootstrap_node {
-                let (msg, _) = table.add_addr(addr);
+            if let Some(addr) = bootstrap {
+                let (msg, _) = rt.add_addr(addr);
                 sock.send_to(&msg.encode(), addr).ok();
             }
         }
 
-        Ok(Manager {
-            table,
-            sock,
+        Ok(Table {
             id,
-            db,
-            buf: vec![0u8; 500],
-            dht_flush: time::Instant::now(),
+            rt,
+            sock,
+            session,
         })
     }
 
-    pub fn init(&mut self) {
-        debug!("Initializing DHT nodes!");
-        for (q, a) in self.table.init() {
-            self.send_msg(&q.encode(), a);
-        }
+    fn v6(&self) -> bool {
+        self.sock.local_addr().map(|a| a.is_ipv6()).unwrap_or(false)
     }
 
-    pub fn id(&self) -> usize {
-        self.id
-    }
-
-    pub fn readable(&mut self) -> Vec<tracker::Response> {
+    /// Processes incoming messages, using other to supply nodes of the
+    /// other address family when a request asks for them.
+    fn readable(&mut self, other: Option<&Table>, buf: &mut [u8]) -> Vec<tracker::Response> {
+        let v6 = self.v6();
+        let (own, alt) = if v6 {
+            (proto::Want::N6, proto::Want::N4)
+        } else {
+            (proto::Want::N4, proto::Want::N6)
+        };
         let mut resps = Vec::new();
         loop {
-            match self.sock.recv_from(&mut self.buf[..]) {
+            match self.sock.recv_from(&mut buf[..]) {
                 Ok((v, addr)) => {
                     trace!("Processing msg from {}", addr);
-                    if let Ok(req) = proto::Request::decode(&self.buf[..v]) {
-                        let resp = self.table.handle_req(req, addr).encode();
-                        self.send_msg(&resp, addr);
-                    } else if let Ok(resp) = proto::Response::decode(&self.buf[..v]) {
-                        match self.table.handle_resp(resp, addr) {
+                    if let Ok(req) = proto::Request::decode(&buf[..v]) {
+                        let want = req.want().to_vec();
+                        let target = req.target();
+                        let mut resp = self.rt.handle_req(req, addr);
+                        if let (Some(nodes), Some(target)) = (resp.nodes_mut(), target) {
+                            if !want.is_empty() && !want.contains(&own) {
+                                nodes.clear();
+                            }
+                            if let (true, Some(o)) = (want.contains(&alt), other) {
+                                nodes.extend(o.rt.closest(&target));
+                            }
+                        }
+                        self.send_msg(&resp.encode(), addr);
+                    } else if let Ok(mut resp) = proto::Response::decode(&buf[..v]) {
+                        // Nodes of the other family belong in the other table
+                        if let Some(nodes) = resp.nodes_mut() {
+                            nodes.retain(|n| n.addr.is_ipv6() == v6);
+                        }
+                        match self.rt.handle_resp(resp, addr) {
                             Ok(r) => resps.push(r),
                             Err(q) => {
                                 for (req, a) in q {
@@ -119,34 +244,6 @@ impl Manager {
         resps
     }
 
-    pub fn get_peers(&mut self, tid: usize, hash: [u8; 20]) {
-        for (req, a) in self.table.get_peers(tid, hash) {
-            self.send_msg(&req.encode(), a);
-        }
-    }
-
-    pub fn add_addr(&mut self, addr: SocketAddr) {
-        self.table.add_addr(addr);
-    }
-
-    pub fn announce(&mut self, hash: [u8; 20]) {
-        for (req, a) in self.table.announce(hash) {
-            self.send_msg(&req.encode(), a);
-        }
-    }
-
-    pub fn tick(&mut self) {
-        if self.dht_flush.elapsed() > time::Duration::from_secs(60) {
-            let data = self.table.serialize();
-            let path = Path::new(&CONFIG.disk.session[..]).join(SESSION_FILE);
-            self.db.send(disk::Request::WriteFile { data, path }).ok();
-            self.dht_flush = time::Instant::now();
-        }
-        for (req, a) in self.table.tick() {
-            self.send_msg(&req.encode(), a);
-        }
-    }
-
     fn send_msg(&mut self, msg: &[u8], addr: SocketAddr) {
         // Cap tries to avoid burning CPU
         for _ in 0..25 {
@@ -54,10 +54,12 @@ pub enum RequestKind {
     FindNode {
         id: ID,
         target: ID,
+        want: Vec<Want>,
     },
     GetPeers {
         id: ID,
         hash: [u8; 20],
+        want: Vec<Want>,
     },
     AnnouncePeer {
         id: ID,
@@ -68,6 +70,13 @@ pub enum RequestKind {
     },
 }
 
+/// Address families a node asks to receive nodes for, as per BEP 32.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Want {
+    N4,
+    N6,
+}
+
 #[derive(Debug)]
 pub struct Response {
     pub transaction: Vec<u8>,
@@ -109,7 +118,11 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
-            kind: RequestKind::FindNode { id, target },
+            kind: RequestKind::FindNode {
+                id,
+                target,
+                want: Vec::new(),
+            },
         }
     }
 
@@ -117,7 +130,11 @@ impl Request {
         Request {
             transaction,
             version: Some(VERSION.to_owned()),
-            kind: RequestKind::GetPeers { id, hash },
+            kind: RequestKind::GetPeers {
+                id,
+                hash,
+                want: Vec::new(),
+            },
         }
     }
 
@@ -137,41 +154,40 @@ impl Request {
 
     pub fn encode(self) -> Vec<u8> {
         let mut b = BTreeMap::new();
-        b.insert(String::from("t"), BEncode::String(self.transaction));
-        b.insert(String::from("y"), BEncode::from_str("q"));
+        b.insert(b"t".to_vec(), BEncode::String(self.transaction));
+        b.insert(b"y".to_vec(), BEncode::from_str("q"));
         if let Some(v) = self.version {
-            b.insert(String::from("v"), BEncode::from_str(&v));
+            b.insert(b"v".to_vec(), BEncode::from_str(&v));
         }
         match self.kind {
             RequestKind::Ping(id) => {
-                b.insert(String::from("q"), BEncode::from_str("ping"));
+                b.insert(b"q".to_vec(), BEncode::from_str("ping"));
 
                 let mut args = BTreeMap::new();
-                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
 
-                b.insert(String::from("a"), BEncode::Dict(args));
+                b.insert(b"a".to_vec(), BEncode::Dict(args));
             }
-            RequestKind::FindNode { id, target } => {
-                b.insert(String::from("q"), BEncode::from_str("find_node"));
+            RequestKind::FindNode { id, target, want } => {
+                b.insert(b"q".to_vec(), BEncode::from_str("find_node"));
 
                 let mut args = BTreeMap::new();
-                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
-                args.insert(
-                    String::from("target"),
-                    BEncode::String(target.to_bytes_be()),
-                );
+                args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
+                args.insert(b"target".to_vec(), BEncode::String(target.to_bytes_be()));
+                encode_want(&mut args, &want);
 
-                b.insert(String::from("a"), BEncode::Dict(args));
+                b.insert(b"a".to_vec(), BEncode::Dict(args));
             }
-            RequestKind::GetPeers { id, hash } => {
-                b.insert(String::from("q"), BEncode::from_str("get_peers"));
+            RequestKind::GetPeers { id, hash, want } => {
+                b.insert(b"q".to_vec(), BEncode::from_str("get_peers"));
 
                 let mut args = BTreeMap::new();
-                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
                 let ib = Vec::from(&hash[..]);
-                args.insert(String::from("info_hash"), BEncode::String(ib));
+                args.insert(b"info_hash".to_vec(), BEncode::String(ib));
+                encode_want(&mut args, &want);
 
-                b.insert(String::from("a"), BEncode::Dict(args));
+                b.insert(b"a".to_vec(), BEncode::Dict(args));
             }
             RequestKind::AnnouncePeer {
                 id,
@@ -180,20 +196,20 @@ impl Request {
                 port,
                 implied_port,
             } => {
-                b.insert(String::from("q"), BEncode::from_str("announce_peer"));
+                b.insert(b"q".to_vec(), BEncode::from_str("announce_peer"));
                 let mut args = BTreeMap::new();
-                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
                 let ib = Vec::from(&hash[..]);
-                args.insert(String::from("info_hash"), BEncode::String(ib));
+                args.insert(b"info_hash".to_vec(), BEncode::String(ib));
                 // TODO: Consider changing this once uTP is implemented
                 args.insert(
-                    String::from("implied_port"),
+                    b"implied_port".to_vec(),
                     BEncode::Int(if implied_port { 1 } else { 0 }),
                 );
-                args.insert(String::from("port"), BEncode::Int(i64::from(port)));
-                args.insert(String::from("token"), BEncode::String(token));
+                args.insert(b"port".to_vec(), BEncode::Int(i64::from(port)));
+                args.insert(b"token".to_vec(), BEncode::String(token));
 
-                b.insert(String::from("a"), BEncode::Dict(args));
+                b.insert(b"a".to_vec(), BEncode::Dict(args));
             }
         }
         BEncode::Dict(b).encode_to_buf()
@@ -205,32 +221,44 @@ impl Request {
         let mut d = b
             .into_dict()
             .ok_or_else(|| ErrorKind::InvalidRequest("Invalid BEncoded data(must be dict)"))?;
-        let transaction = d.remove("t").and_then(|b| b.into_bytes()).ok_or_else(|| {
-            ErrorKind::InvalidRequest("Invalid BEncoded data(dict must have t field)")
-        })?;
-        let version = d.remove("v").and_then(|b| b.into_string());
-        let y = d.remove("y").and_then(|b| b.into_string()).ok_or_else(|| {
-            Error::from(ErrorKind::InvalidRequest(
-                "Invalid BEncoded data(dict must have y field)",
-            ))
-        })?;
+        let transaction = d
+            .remove(&b"t"[..])
+            .and_then(|b| b.into_bytes())
+            .ok_or_else(|| {
+                ErrorKind::InvalidRequest("Invalid BEncoded data(dict must have t field)")
+            })?;
+        let version = d.remove(&b"v"[..]).and_then(|b| b.into_string());
+        let y = d
+            .remove(&b"y"[..])
+            .and_then(|b| b.into_string())
+            .ok_or_else(|| {
+                Error::from(ErrorKind::InvalidRequest(
+                    "Invalid BEncoded data(dict must have y field)",
+                ))
+            })?;
         if y != "q" {
             return Err(Error::from(ErrorKind::InvalidRequest(
                 "Invalid BEncoded data(request must have y: q field)",
             )));
         }
-        let q = d.remove("q").and_then(|b| b.into_string()).ok_or_else(|| {
-            Error::from(ErrorKind::InvalidRequest(
-                "Invalid BEncoded data(dict must have q field)",
-            ))
-        })?;
-        let mut a = d.remove("a").and_then(|b| b.into_dict()).ok_or_else(|| {
-            Error::from(ErrorKind::InvalidRequest(
-                "Invalid BEncoded data(dict must have a field)",
-            ))
-        })?;
+        let q = d
+            .remove(&b"q"[..])
+            .and_then(|b| b.into_string())
+            .ok_or_else(|| {
+                Error::from(ErrorKind::InvalidRequest(
+                    "Invalid BEncoded data(dict must have q field)",
+                ))
+            })?;
+        let mut a = d
+            .remove(&b"a"[..])
+            .and_then(|b| b.into_dict())
+            .ok_or_else(|| {
+                Error::from(ErrorKind::InvalidRequest(
+                    "Invalid BEncoded data(dict must have a field)",
+                ))
+            })?;
         let id = a
-            .remove("id")
+            .remove(&b"id"[..])
             .and_then(|b| b.into_bytes())
             .and_then(|b| b.get(0..20).map(BigUint::from_bytes_be))
             .ok_or_else(|| {
@@ -242,7 +270,7 @@ impl Request {
             "ping" => RequestKind::Ping(id),
             "find_node" => {
                 let target = a
-                    .remove("target")
+                    .remove(&b"target"[..])
                     .and_then(|b| b.into_bytes())
                     .and_then(|b| b.get(0..20).map(BigUint::from_bytes_be))
                     .ok_or_else(|| {
@@ -250,11 +278,12 @@ impl Request {
                             "Invalid BEncoded data(find_node must have target field)",
                         ))
                     })?;
-                RequestKind::FindNode { id, target }
+                let want = decode_want(&mut a);
+                RequestKind::FindNode { id, target, want }
             }
             "get_peers" => {
                 let mut hash = [0u8; 20];
-                a.remove("info_hash")
+                a.remove(&b"info_hash"[..])
                     .and_then(|b| b.into_bytes())
                     .and_then(|b| {
                         if b.len() != 20 {
@@ -268,11 +297,12 @@ impl Request {
                             "Invalid BEncoded data(get_peers must have hash field)",
                         ))
                     })?;
-                RequestKind::GetPeers { id, hash }
+                let want = decode_want(&mut a);
+                RequestKind::GetPeers { id, hash, want }
             }
             "announce_peer" => {
                 let mut hash = [0u8; 20];
-                a.remove("info_hash")
+                a.remove(&b"info_hash"[..])
                     .and_then(|b| b.into_bytes())
                     .and_then(|b| {
                         if b.len() != 20 {
@@ -287,12 +317,12 @@ impl Request {
                         ))
                     })?;
                 let implied_port = a
-                    .remove("implied_port")
+                    .remove(&b"implied_port"[..])
                     .and_then(|b| b.into_int())
                     .map(|b| b > 0)
                     .unwrap_or(false);
                 let port = a
-                    .remove("port")
+                    .remove(&b"port"[..])
                     .and_then(|b| b.into_int())
                     .and_then(|b| {
                         if b > 65_535 || b < 0 {
@@ -307,7 +337,7 @@ impl Request {
                         ))
                     })?;
                 let token = a
-                    .remove("token")
+                    .remove(&b"token"[..])
                     .and_then(|b| b.into_bytes())
                     .ok_or_else(|| {
                         Error::from(ErrorKind::InvalidRequest(
@@ -335,6 +365,23 @@ impl Request {
             kind,
         })
     }
+
+    /// The address families requested, empty if unspecified.
+    pub fn want(&self) -> &[Want] {
+        match self.kind {
+            RequestKind::FindNode { ref want, .. } | RequestKind::GetPeers { ref want, .. } => want,
+            _ => &[],
+        }
+    }
+
+    /// The ID which nodes in the response should be close to.
+    pub fn target(&self) -> Option<ID> {
+        match self.kind {
+            RequestKind::FindNode { ref target, .. } => Some(target.clone()),
+            RequestKind::GetPeers { ref hash, .. } => Some(BigUint::from_bytes_be(&hash[..])),
+            _ => None,
+        }
+    }
 }
 
 impl Response {
@@ -386,19 +433,15 @@ impl Response {
     pub fn encode(self) -> Vec<u8> {
         let mut b = BTreeMap::new();
         let is_err = self.is_err();
-        b.insert(String::from("t"), BEncode::String(self.transaction));
+        b.insert(b"t".to_vec(), BEncode::String(self.transaction));
         let mut args = BTreeMap::new();
         match self.kind {
             ResponseKind::ID(id) => {
-                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
             }
             ResponseKind::FindNode { id, nodes } => {
-                let mut data = Vec::new();
-                for node in nodes {
-                    data.extend(node.to_bytes())
-                }
-                args.insert(String::from("nodes"), BEncode::String(data));
-                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
+                encode_nodes(&mut args, nodes);
+                args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
             }
             ResponseKind::GetPeers {
                 id,
@@ -406,19 +449,14 @@ impl Response {
                 nodes,
                 values,
             } => {
-                args.insert(String::from("id"), BEncode::String(id.to_bytes_be()));
-                args.insert(String::from("token"), BEncode::String(token));
+                args.insert(b"id".to_vec(), BEncode::String(id.to_bytes_be()));
+                args.insert(b"token".to_vec(), BEncode::String(token));
                 let mut values_b = Vec::new();
                 for addr in values {
-                    values_b.push(BEncode::String(addr_to_bytes(&addr).to_vec()));
-                }
-                args.insert(String::from("values"), BEncode::List(values_b));
-
-                let mut nodes_b = Vec::new();
-                for node in nodes {
-                    nodes_b.extend(node.to_bytes())
+                    values_b.push(BEncode::String(addr_to_bytes(&addr)));
                 }
-                args.insert(String::from("nodes"), BEncode::String(nodes_b));
+                args.insert(b"values".to_vec(), BEncode::List(values_b));
+                encode_nodes(&mut args, nodes);
             }
             ResponseKind::Error(e) => {
                 let mut err = Vec::new();
@@ -441,14 +479,14 @@ impl Response {
                     }
                     _ => unreachable!(),
                 }
-                b.insert(String::from("e"), BEncode::List(err));
+                b.insert(b"e".to_vec(), BEncode::List(err));
             }
         }
         if is_err {
-            b.insert(String::from("y"), BEncode::from_str("e"));
+            b.insert(b"y".to_vec(), BEncode::from_str("e"));
         } else {
-            b.insert(String::from("y"), BEncode::from_str("r"));
-            b.insert(String::from("r"), BEncode::Dict(args));
+            b.insert(b"y".to_vec(), BEncode::from_str("r"));
+            b.insert(b"r".to_vec(), BEncode::Dict(args));
         }
         BEncode::Dict(b).encode_to_buf()
     }
@@ -461,23 +499,32 @@ impl Response {
                 "Invalid BEncoded data(must be dict)",
             ))
         })?;
-        let transaction = d.remove("t").and_then(|b| b.into_bytes()).ok_or_else(|| {
-            Error::from(ErrorKind::InvalidResponse(
-                "Invalid BEncoded data(dict must have t field)",
-            ))
-        })?;
-        let y = d.remove("y").and_then(|b| b.into_string()).ok_or_else(|| {
-            Error::from(ErrorKind::InvalidResponse(
-                "Invalid BEncoded data(dict must have y field)",
-            ))
-        })?;
+        let transaction = d
+            .remove(&b"t"[..])
+            .and_then(|b| b.into_bytes())
+            .ok_or_else(|| {
+                Error::from(ErrorKind::InvalidResponse(
+                    "Invalid BEncoded data(dict must have t field)",
+                ))
+            })?;
+        let y = d
+            .remove(&b"y"[..])
+            .and_then(|b| b.into_string())
+            .ok_or_else(|| {
+                Error::from(ErrorKind::InvalidResponse(
+                    "Invalid BEncoded data(dict must have y field)",
+                ))
+            })?;
         match &y[..] {
             "e" => {
-                let mut e = d.remove("e").and_then(|b| b.into_list()).ok_or_else(|| {
-                    Error::from(ErrorKind::InvalidResponse(
-                        "Invalid BEncoded data(error resp must have e field)",
-                    ))
-                })?;
+                let mut e = d
+                    .remove(&b"e"[..])
+                    .and_then(|b| b.into_list())
+                    .ok_or_else(|| {
+                        Error::from(ErrorKind::InvalidResponse(
+                            "Invalid BEncoded data(error resp must have e field)",
+                        ))
+                    })?;
                 if e.len() != 2 {
                     return Err(ErrorKind::InvalidResponse(
                         "Invalid BEncoded data(e field must have two terms)",
@@ -512,14 +559,17 @@ impl Response {
                 })
             }
             "r" => {
-                let mut r = d.remove("r").and_then(|b| b.into_dict()).ok_or_else(|| {
-                    Error::from(ErrorKind::InvalidResponse(
-                        "Invalid BEncoded data(resp must have r field)",
-                    ))
-                })?;
+                let mut r = d
+                    .remove(&b"r"[..])
+                    .and_then(|b| b.into_dict())
+                    .ok_or_else(|| {
+                        Error::from(ErrorKind::InvalidResponse(
+                            "Invalid BEncoded data(resp must have r field)",
+                        ))
+                    })?;
 
                 let id = r
-                    .remove("id")
+                    .remove(&b"id"[..])
                     .and_then(|b| b.into_bytes())
                     .and_then(|b| b.get(0..20).map(BigUint::from_bytes_be))
                     .ok_or_else(|| {
@@ -528,38 +578,27 @@ impl Response {
                         ))
                     })?;
 
-                let kind = if let Some(token) = r.remove("token").and_then(|b| b.into_bytes()) {
+                let kind = if let Some(token) = r.remove(&b"token"[..]).and_then(|b| b.into_bytes())
+                {
                     let mut values = Vec::new();
-                    if let Some(addrs) = r.remove("values").and_then(|b| b.into_list()) {
+                    if let Some(addrs) = r.remove(&b"values"[..]).and_then(|b| b.into_list()) {
                         for addr in addrs {
                             if let Some(data) = addr.into_bytes() {
-                                if data.len() == 6 {
+                                if data.len() == 6 || data.len() == 18 {
                                     values.push(bytes_to_addr(&data));
                                 }
                             }
                         }
                     }
-                    let mut nodes = Vec::new();
-                    if let Some(ns) = r.remove("nodes").and_then(|b| b.into_bytes()) {
-                        for n in ns.chunks(26) {
-                            if n.len() == 26 {
-                                nodes.push(Node::new(n));
-                            }
-                        }
-                    }
+                    let nodes = decode_nodes(&mut r);
                     ResponseKind::GetPeers {
                         id,
                         token,
                         nodes,
                         values,
                     }
-                } else if let Some(ns) = r.remove("nodes").and_then(|b| b.into_bytes()) {
-                    let mut nodes = Vec::new();
-                    for n in ns.chunks(26) {
-                        if n.len() == 26 {
-                            nodes.push(Node::new(n));
-                        }
-                    }
+                } else if r.contains_key(&b"nodes"[..]) || r.contains_key(&b"nodes6"[..]) {
+                    let nodes = decode_nodes(&mut r);
                     ResponseKind::FindNode { id, nodes }
                 } else {
                     ResponseKind::ID(id)
@@ -572,6 +611,15 @@ impl Response {
         }
     }
 
+    /// Nodes included in the response, of either address family.
+    pub fn nodes_mut(&mut self) -> Option<&mut Vec<Node>> {
+        match self.kind {
+            ResponseKind::FindNode { ref mut nodes, .. }
+            | ResponseKind::GetPeers { ref mut nodes, .. } => Some(nodes),
+            _ => None,
+        }
+    }
+
     fn is_err(&self) -> bool {
         match self.kind {
             ResponseKind::Error(_) => true,
@@ -580,6 +628,61 @@ impl Response {
     }
 }
 
+/// Encodes nodes into the compact "nodes" and "nodes6" lists. The
+/// IPv6 list is only included if non empty.
+fn encode_nodes(args: &mut BTreeMap<Vec<u8>, BEncode>, nodes: Vec<Node>) {
+    let mut nodes4 = Vec::new();
+    let mut nodes6 = Vec::new();
+    for node in nodes {
+        if node.addr.is_ipv4() {
+            nodes4.extend(node.to_bytes());
+        } else {
+            nodes6.extend(node.to_bytes());
+        }
+    }
+    args.insert(b"nodes".to_vec(), BEncode::String(nodes4));
+    if !nodes6.is_empty() {
+        args.insert(b"nodes6".to_vec(), BEncode::String(nodes6));
+    }
+}
+
+fn decode_nodes(r: &mut BTreeMap<Vec<u8>, BEncode>) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    for &(key, len) in &[("nodes", 26), ("nodes6", 38)] {
+        if let Some(ns) = r.remove(key.as_bytes()).and_then(|b| b.into_bytes()) {
+            nodes.extend(ns.chunks_exact(len).map(Node::new));
+        }
+    }
+    nodes
+}
+
+fn encode_want(args: &mut BTreeMap<Vec<u8>, BEncode>, want: &[Want]) {
+    if want.is_empty() {
+        return;
+    }
+    let want = want
+        .iter()
+        .map(|w| match w {
+            Want::N4 => BEncode::from_str("n4"),
+            Want::N6 => BEncode::from_str("n6"),
+        })
+        .collect();
+    args.insert(b"want".to_vec(), BEncode::List(want));
+}
+
+fn decode_want(a: &mut BTreeMap<Vec<u8>, BEncode>) -> Vec<Want> {
+    a.remove(&b"want"[..])
+        .and_then(|b| b.into_list())
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|w| match w.into_string().as_ref().map(String::as_str) {
+            Some("n4") => Some(Want::N4),
+            Some("n6") => Some(Want::N6),
+            _ => None,
+        })
+        .collect()
+}
+
 impl Node {
     pub fn new(data: &[u8]) -> Node {
         let id = BigUint::from_bytes_be(&data[0..20]);
@@ -591,7 +694,7 @@ impl Node {
 
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut data = self.id.to_bytes_be();
-        data.extend_from_slice(&addr_to_bytes(&self.addr)[..]);
+        data.extend(addr_to_bytes(&self.addr));
         data
     }
 }
@@ -164,19 +164,15 @@ impl RoutingTable {
                 }
                 proto::Response::id(req.transaction, self.id.clone())
             }
-            proto::RequestKind::FindNode { id, target } => {
+            proto::RequestKind::FindNode { id, target, .. } => {
                 if self.contains_id(&id) {
                     self.get_node_mut(&id).update();
                 }
-                let mut nodes = Vec::new();
-                if self.contains_id(&target) {
-                    nodes.push(self.get_node(&target).into())
+                let nodes = if self.contains_id(&target) {
+                    vec![self.get_node(&target).into()]
                 } else {
-                    let b = self.bucket_idx(&target);
-                    for node in &self.buckets[b].nodes {
-                        nodes.push(node.into());
-                    }
-                }
+                    self.closest(&target)
+                };
                 proto::Response::find_node(req.transaction, self.id.clone(), nodes)
             }
             proto::RequestKind::AnnouncePeer {
@@ -211,7 +207,7 @@ impl RoutingTable {
                 self.torrents.get_mut(&hash).unwrap().peers.push((id, addr));
                 proto::Response::id(req.transaction, self.id.clone())
             }
-            proto::RequestKind::GetPeers { id, hash } => {
+            proto::RequestKind::GetPeers { id, hash, .. } => {
                 if !self.contains_id(&id) {
                     let n = Node::new(id.clone(), addr);
                     if self.add_node(n).is_err() {
@@ -234,11 +230,7 @@ impl RoutingTable {
                         t.peers.iter().map(|p| p.1).collect(),
                     )
                 } else {
-                    let mut nodes = Vec::new();
-                    let b = self.bucket_idx(&BigUint::from_bytes_be(&hash[..]));
-                    for node in &self.buckets[b].nodes {
-                        nodes.push(node.into());
-                    }
+                    let nodes = self.closest(&BigUint::from_bytes_be(&hash[..]));
                     proto::Response::nodes(req.transaction, self.id.clone(), token, nodes)
                 }
             }
@@ -455,6 +447,12 @@ impl RoutingTable {
         reqs
     }
 
+    /// Nodes in the bucket which would hold target.
+    pub fn closest(&self, target: &ID) -> Vec<proto::Node> {
+        let b = self.bucket_idx(target);
+        self.buckets[b].nodes.iter().map(Into::into).collect()
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         bincode::serialize(self).unwrap()
     }
@@ -11,11 +11,13 @@ use url::Url;
 
 use self::reader::{ReadRes, Reader};
 use self::writer::Writer;
+use crate::bencode::{self, BEncode};
 use crate::tracker::{
-    self, dns, Announce, Error, ErrorKind, Response, Result, ResultExt, TrackerResponse,
+    self, dns, Announce, Error, ErrorKind, Response, Result, ResultExt, Scrape, ScrapeResponse,
+    TrackerResponse, WebSeed, WebSeedRange,
 };
 use crate::util::{http, UHashMap};
-use crate::{bencode, PEER_ID};
+use crate::PEER_ID;
 
 const TIMEOUT_MS: u64 = 5_000;
 
@@ -31,13 +33,32 @@ enum Event {
 }
 
 struct Tracker {
-    torrent: usize,
+    kind: Kind,
     url: Arc<Url>,
     last_updated: Instant,
     redirect: bool,
     state: TrackerState,
 }
 
+#[derive(Clone)]
+enum Kind {
+    Announce(usize),
+    Scrape(Vec<(usize, [u8; 20])>),
+    WebSeed(Fetch),
+}
+
+/// Piece data being downloaded from a web seed
+#[derive(Clone)]
+struct Fetch {
+    tid: usize,
+    seed: usize,
+    piece: u32,
+    offset: u32,
+    len: u32,
+    /// Range header value, which must be kept across redirects
+    range: Option<String>,
+}
+
 enum TrackerState {
     Error,
     ResolvingDNS {
@@ -54,13 +75,13 @@ enum TrackerState {
         reader: Reader,
     },
     Redirect(String),
-    Complete(TrackerResponse),
+    Complete(u16, Vec<u8>),
 }
 
 enum HTTPRes {
     None,
     Redirect(String),
-    Complete(TrackerResponse),
+    Complete(u16, Vec<u8>),
 }
 
 impl TrackerState {
@@ -71,7 +92,7 @@ impl TrackerState {
     fn handle(&mut self, event: Event) -> Result<HTTPRes> {
         let s = mem::replace(self, TrackerState::Error);
         match s.next(event)? {
-            TrackerState::Complete(r) => Ok(HTTPRes::Complete(r)),
+            TrackerState::Complete(c, r) => Ok(HTTPRes::Complete(c, r)),
             TrackerState::Redirect(l) => Ok(HTTPRes::Redirect(l)),
             n => {
                 *self = n;
@@ -92,12 +113,13 @@ impl TrackerState {
             ) => {
                 let addr = SocketAddr::new(r.res?, port);
                 sock.connect(addr).chain_err(|| ErrorKind::IO)?;
+                // Wait for the connection to become writable, as a fast
+                // server could otherwise complete the request before
+                // dns_resolved, which can't return a response, finishes.
                 Ok(TrackerState::Writing {
                     sock,
                     writer: Writer::new(req),
-                }
-                .next(Event::Writable)?
-                .next(Event::Readable)?)
+                })
             }
             (
                 TrackerState::Writing {
@@ -120,12 +142,7 @@ impl TrackerState {
                 },
                 _,
             ) => match reader.readable(&mut sock)? {
-                ReadRes::Done(data) => {
-                    let content = bencode::decode_buf(&data)
-                        .chain_err(|| ErrorKind::InvalidResponse("Invalid BEncoded response!"))?;
-                    let resp = TrackerResponse::from_bencode(content)?;
-                    Ok(TrackerState::Complete(resp))
-                }
+                ReadRes::Done(code, data) => Ok(TrackerState::Complete(code, data)),
                 ReadRes::Redirect(l) => Ok(TrackerState::Redirect(l)),
                 ReadRes::None => Ok(TrackerState::Reading { sock, reader }),
             },
@@ -135,6 +152,45 @@ impl TrackerState {
     }
 }
 
+impl Tracker {
+    fn response(&self, resp: Result<(u16, Vec<u8>)>) -> Response {
+        let url = self.url.clone();
+        match self.kind {
+            Kind::Announce(tid) => Response::Tracker {
+                tid,
+                url,
+                resp: resp
+                    .and_then(decode_body)
+                    .and_then(TrackerResponse::from_bencode),
+            },
+            Kind::Scrape(ref torrents) => Response::Scrape {
+                url,
+                resp: resp
+                    .and_then(decode_body)
+                    .and_then(|r| ScrapeResponse::from_bencode(r, torrents)),
+            },
+            Kind::WebSeed(ref f) => Response::WebSeed {
+                tid: f.tid,
+                seed: f.seed,
+                piece: f.piece,
+                offset: f.offset,
+                resp: resp.and_then(|(code, data)| match code {
+                    200 | 206 if data.len() == f.len as usize => Ok(data),
+                    200 | 206 => {
+                        Err(ErrorKind::InvalidResponse("Web seed sent a partial range").into())
+                    }
+                    _ => Err(ErrorKind::InvalidResponse("Web seed request failed").into()),
+                }),
+            },
+        }
+    }
+}
+
+fn decode_body((_, data): (u16, Vec<u8>)) -> Result<BEncode> {
+    bencode::decode_buf(&data)
+        .chain_err(|| ErrorKind::InvalidResponse("Invalid BEncoded response!"))
+}
+
 impl Handler {
     pub fn new(reg: &amy::Registrar) -> io::Result<Handler> {
         Ok(Handler {
@@ -162,11 +218,7 @@ impl Handler {
             trk.last_updated = Instant::now();
             match trk.state.handle(Event::DNSResolved(resp)) {
                 Ok(_) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.response(Err(e))),
             }
         } else {
             None
@@ -177,49 +229,31 @@ impl Handler {
         resp
     }
 
-    pub fn writable(&mut self, id: usize) -> Option<Response> {
-        let resp = if let Some(trk) = self.connections.get_mut(&id) {
-            trk.last_updated = Instant::now();
-            match trk.state.handle(Event::Writable) {
-                Ok(_) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
-            }
-        } else {
-            None
-        };
-        if resp.is_some() {
-            self.connections.remove(&id);
-        }
-        resp
+    pub fn writable(&mut self, id: usize, dns: &mut dns::Resolver) -> Option<Response> {
+        self.handle(id, Event::Writable, dns)
     }
 
     pub fn readable(&mut self, id: usize, dns: &mut dns::Resolver) -> Option<Response> {
+        self.handle(id, Event::Readable, dns)
+    }
+
+    /// Advances a request on a socket event. Writes read as much of the
+    /// response as is available, so either event can complete a request.
+    fn handle(&mut self, id: usize, event: Event, dns: &mut dns::Resolver) -> Option<Response> {
         let mut loc = None;
         let mut resp = if let Some(trk) = self.connections.get_mut(&id) {
             trk.last_updated = Instant::now();
-            match trk.state.handle(Event::Readable) {
-                Ok(HTTPRes::Complete(r)) => {
-                    debug!("Announce response received for {:?} succesfully", id);
-                    Some(Response::Tracker {
-                        tid: trk.torrent,
-                        url: trk.url.clone(),
-                        resp: Ok(r),
-                    })
+            match trk.state.handle(event) {
+                Ok(HTTPRes::Complete(c, r)) => {
+                    debug!("Tracker response received for {:?} succesfully", id);
+                    Some(trk.response(Ok((c, r))))
                 }
                 Ok(HTTPRes::Redirect(l)) => {
                     loc = Some((l, trk.url.clone()));
                     None
                 }
                 Ok(HTTPRes::None) => None,
-                Err(e) => Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(e),
-                }),
+                Err(e) => Some(trk.response(Err(e))),
             }
         } else {
             None
@@ -233,22 +267,13 @@ impl Handler {
             let trk = self.connections.remove(&id).unwrap();
             // Disallow 2 levels of redirection
             if trk.redirect {
-                resp = Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(ErrorKind::InvalidResponse("Too many redirects").into()),
-                });
-            }
-            if let Err(e) = self.try_redirect(&l, old, trk.torrent, dns) {
-                debug!(
-                    "Announce response received for {:?}, redirecting!",
-                    trk.torrent
+                resp = Some(
+                    trk.response(Err(ErrorKind::InvalidResponse("Too many redirects").into())),
                 );
-                resp = Some(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url,
-                    resp: Err(e),
-                });
+            }
+            if let Err(e) = self.try_redirect(&l, old, trk.kind.clone(), dns) {
+                debug!("Tracker response received for {:?}, redirecting!", id);
+                resp = Some(trk.response(Err(e)));
             }
         }
         resp
@@ -258,7 +283,7 @@ impl Handler {
         &mut self,
         url: &str,
         original_url: Arc<Url>,
-        torrent: usize,
+        kind: Kind,
         dns: &mut dns::Resolver,
     ) -> Result<()> {
         let url = match Url::parse(url) {
@@ -277,11 +302,18 @@ impl Handler {
             Error::from(ErrorKind::InvalidResponse("Malformed redirect!"))
         })?;
         let mut http_req = Vec::with_capacity(512);
-        http::RequestBuilder::new("GET", url.path(), url.query())
+        let mut builder = http::RequestBuilder::new("GET", url.path(), url.query());
+        builder
             .header("User-agent", concat!("synapse/", env!("CARGO_PKG_VERSION")))
             .header("Connection", "close")
-            .header("Host", host)
-            .encode(&mut http_req);
+            .header("Host", host);
+        if let Kind::WebSeed(Fetch {
+            range: Some(ref r), ..
+        }) = kind
+        {
+            builder.header("Range", r);
+        }
+        builder.encode(&mut http_req);
 
         let ohost = if url.scheme() == "https" {
             Some(host.to_owned())
@@ -295,13 +327,13 @@ impl Handler {
             .reg
             .register(&sock, amy::Event::Both)
             .chain_err(|| ErrorKind::IO)?;
-        let port = url.port().unwrap_or(80);
+        let port = url.port_or_known_default().unwrap_or(80);
         self.connections.insert(
             id,
             Tracker {
                 last_updated: Instant::now(),
                 redirect: true,
-                torrent,
+                kind,
                 url: original_url,
                 state: TrackerState::new(sock, http_req, port),
             },
@@ -322,12 +354,8 @@ impl Handler {
         let mut resps = Vec::new();
         self.connections.retain(|id, trk| {
             if trk.last_updated.elapsed() > Duration::from_millis(TIMEOUT_MS) {
-                debug!("Announce {:?} timed out", id);
-                resps.push(Response::Tracker {
-                    tid: trk.torrent,
-                    url: trk.url.clone(),
-                    resp: Err(ErrorKind::Timeout.into()),
-                });
+                debug!("Tracker request {:?} timed out", id);
+                resps.push(trk.response(Err(ErrorKind::Timeout.into())));
                 false
             } else {
                 true
@@ -336,6 +364,14 @@ impl Handler {
         resps
     }
 
+    /// Drops all web seed downloads in progress.
+    pub fn abort_webseeds(&mut self) {
+        self.connections.retain(|_, trk| match trk.kind {
+            Kind::WebSeed(_) => false,
+            _ => true,
+        });
+    }
+
     pub fn new_announce(&mut self, req: Announce, dns: &mut dns::Resolver) -> Result<()> {
         debug!("Received a new announce req for {:?}", req.url);
         let host = req.url.host_str().ok_or_else(|| {
@@ -367,12 +403,99 @@ impl Handler {
             .header("Host", host)
             .encode(&mut http_req);
 
-        let port =
-            req.url
-                .port()
-                .unwrap_or_else(|| if req.url.scheme() == "https" { 443 } else { 80 });
+        self.dispatch(req.url.clone(), Kind::Announce(req.id), http_req, dns)
+    }
+
+    pub fn new_scrape(&mut self, req: Scrape, dns: &mut dns::Resolver) -> Result<()> {
+        debug!("Received a new scrape req for {:?}", req.url);
+        let url = tracker::scrape_url(&req.url).ok_or_else(|| {
+            Error::from(ErrorKind::InvalidRequest(
+                "Tracker does not support scraping!".to_owned(),
+            ))
+        })?;
+        let host = url.host_str().ok_or_else(|| {
+            Error::from(ErrorKind::InvalidRequest(
+                "Tracker announce url has no host!".to_owned(),
+            ))
+        })?;
+
+        let mut http_req = Vec::with_capacity(512);
+        let mut builder = http::RequestBuilder::new("GET", url.path(), url.query());
+        for (_, hash) in &req.torrents {
+            builder.query("info_hash", hash);
+        }
+        builder
+            .header("User-agent", concat!("synapse/", env!("CARGO_PKG_VERSION")))
+            .header("Connection", "close")
+            .header("Host", host)
+            .encode(&mut http_req);
+
+        self.dispatch(req.url, Kind::Scrape(req.torrents), http_req, dns)
+    }
+
+    pub fn new_webseed(&mut self, req: WebSeed, dns: &mut dns::Resolver) -> Result<()> {
+        debug!("Received a new web seed req for {:?}", req.url);
+        let host = req.url.host_str().ok_or_else(|| {
+            Error::from(ErrorKind::InvalidRequest(
+                "Web seed url has no host!".to_owned(),
+            ))
+        })?;
+
+        let range = match req.range {
+            WebSeedRange::File(start) => Some(format!(
+                "bytes={}-{}",
+                start,
+                start + u64::from(req.len) - 1
+            )),
+            WebSeedRange::Piece(_) => None,
+        };
+        let piece = req.piece.to_string();
+        let mut http_req = Vec::with_capacity(512);
+        let mut builder = http::RequestBuilder::new("GET", req.url.path(), req.url.query());
+        if let WebSeedRange::Piece(ref hash) = req.range {
+            builder
+                .query("info_hash", hash)
+                .query("piece", piece.as_bytes());
+        }
+        builder
+            .header("User-agent", concat!("synapse/", env!("CARGO_PKG_VERSION")))
+            .header("Connection", "close")
+            .header("Host", host);
+        if let Some(ref r) = range {
+            builder.header("Range", r);
+        }
+        builder.encode(&mut http_req);
+
+        let kind = Kind::WebSeed(Fetch {
+            tid: req.tid,
+            seed: req.seed,
+            piece: req.piece,
+            offset: req.offset,
+            len: req.len,
+            range,
+        });
+        self.dispatch(Arc::new(req.url), kind, http_req, dns)
+    }
+
+    /// Sets up a connection for the request, starting a DNS query for the
+    /// tracker host. The request is sent once the query resolves.
+    fn dispatch(
+        &mut self,
+        url: Arc<Url>,
+        kind: Kind,
+        http_req: Vec<u8>,
+        dns: &mut dns::Resolver,
+    ) -> Result<()> {
+        let host = url.host_str().ok_or_else(|| {
+            Error::from(ErrorKind::InvalidRequest(
+                "Tracker announce url has no host!".to_owned(),
+            ))
+        })?;
+        let port = url
+            .port()
+            .unwrap_or_else(|| if url.scheme() == "https" { 443 } else { 80 });
 
-        let ohost = if req.url.scheme() == "https" {
+        let ohost = if url.scheme() == "https" {
             Some(host.to_owned())
         } else {
             None
@@ -387,9 +510,9 @@ impl Handler {
         self.connections.insert(
             id,
             Tracker {
-                url: req.url.clone(),
+                url: url.clone(),
                 last_updated: Instant::now(),
-                torrent: req.id,
+                kind,
                 state: TrackerState::new(sock, http_req, port),
                 redirect: false,
             },
@@ -407,3 +530,162 @@ impl Handler {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use std::thread;
+
+    const FILE_A: usize = 20_000;
+    const FILE_B: usize = 12_768;
+
+    fn file_data(path: &str) -> Option<Vec<u8>> {
+        match path {
+            "/seed/a" => Some((0..FILE_A).map(|i| (i % 251) as u8).collect()),
+            "/seed/b" => Some((0..FILE_B).map(|i| (i % 13) as u8 + 100).collect()),
+            // Answers ranged requests with half the data asked for
+            "/seed/short" => Some(vec![7; FILE_A]),
+            _ => None,
+        }
+    }
+
+    /// Runs a web seed serving byte ranges of a few files.
+    fn mock_seed() -> SocketAddr {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let mut data = Vec::new();
+                let mut buf = [0u8; 4096];
+                let req = loop {
+                    let n = conn.read(&mut buf).unwrap();
+                    data.extend_from_slice(&buf[..n]);
+                    let req = String::from_utf8_lossy(&data).into_owned();
+                    if n == 0 || req.contains("\r\n\r\n") {
+                        break req;
+                    }
+                };
+                let path = req.split(' ').nth(1).unwrap_or("").to_owned();
+                let range = req
+                    .lines()
+                    .find(|l| l.starts_with("Range: bytes="))
+                    .and_then(|l| {
+                        let mut r = l[13..].trim().split('-').map(|n| n.parse::<usize>());
+                        match (r.next(), r.next()) {
+                            (Some(Ok(s)), Some(Ok(e))) => Some((s, e)),
+                            _ => None,
+                        }
+                    });
+                let (status, body) = match (file_data(&path), range) {
+                    (Some(f), Some((s, e))) if e < f.len() => {
+                        let end = if path == "/seed/short" {
+                            s + (e + 1 - s) / 2
+                        } else {
+                            e + 1
+                        };
+                        (206, f[s..end].to_vec())
+                    }
+                    (Some(_), Some(_)) => (416, vec![]),
+                    (Some(f), None) => (200, f),
+                    (None, _) => (404, vec![]),
+                };
+                let head = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                conn.write_all(head.as_bytes()).unwrap();
+                conn.write_all(&body).unwrap();
+            }
+        });
+        addr
+    }
+
+    /// Runs the requests through a handler, returning the responses
+    /// ordered by their offset in the piece.
+    fn fetch(reqs: Vec<WebSeed>) -> Vec<(u32, Result<Vec<u8>>)> {
+        let mut poll = amy::Poller::new().unwrap();
+        let reg = poll.get_registrar();
+        let mut handler = Handler::new(&reg).unwrap();
+        let sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let mut dns = dns::Resolver {
+            id: 0,
+            res: adns::Resolver::new(&[sock.local_addr().unwrap()]),
+            sock,
+        };
+        let count = reqs.len();
+        for req in reqs {
+            handler.new_webseed(req, &mut dns).unwrap();
+        }
+
+        let mut resps = Vec::new();
+        let start = Instant::now();
+        while resps.len() < count && start.elapsed() < Duration::from_millis(TIMEOUT_MS) {
+            for event in poll.wait(50).unwrap() {
+                let resp = if event.event.readable() {
+                    handler.readable(event.id, &mut dns)
+                } else {
+                    handler.writable(event.id, &mut dns)
+                };
+                if let Some(Response::WebSeed { offset, resp, .. }) = resp {
+                    resps.push((offset, resp));
+                }
+            }
+        }
+        assert!(handler.complete());
+        resps.sort_by_key(|&(offset, _)| offset);
+        resps
+    }
+
+    fn request(addr: SocketAddr, path: &str, start: u64, offset: u32, len: u32) -> WebSeed {
+        WebSeed {
+            tid: 0,
+            seed: 0,
+            url: Url::parse(&format!("http://{}{}", addr, path)).unwrap(),
+            range: WebSeedRange::File(start),
+            piece: 1,
+            offset,
+            len,
+        }
+    }
+
+    #[test]
+    fn test_webseed_file_boundary() {
+        let addr = mock_seed();
+        // The second 16 KiB piece starts in a and ends in b
+        let tail = (FILE_A - 16_384) as u32;
+        let resps = fetch(vec![
+            request(addr, "/seed/a", 16_384, 0, tail),
+            request(addr, "/seed/b", 0, tail, FILE_B as u32),
+        ]);
+        assert_eq!(resps.len(), 2);
+
+        let mut piece = Vec::new();
+        for (_, resp) in resps {
+            piece.extend(resp.unwrap());
+        }
+        let mut expected = file_data("/seed/a").unwrap().split_off(16_384);
+        expected.extend(file_data("/seed/b").unwrap());
+        assert_eq!(piece.len(), 16_384);
+        assert!(piece == expected);
+    }
+
+    #[test]
+    fn test_webseed_bad_responses() {
+        let addr = mock_seed();
+        let resps = fetch(vec![
+            request(addr, "/seed/short", 0, 0, 8_192),
+            request(addr, "/seed/missing", 0, 8_192, 8_192),
+        ]);
+        assert_eq!(resps.len(), 2);
+        for (_, resp) in resps {
+            match resp {
+                Err(Error(ErrorKind::InvalidResponse(_), _)) => {}
+                r => panic!("Expected an invalid response, got {:?}", r),
+            }
+        }
+    }
+}
@@ -7,12 +7,14 @@ use crate::util::{aread, IOR};
 pub struct Reader {
     data: Vec<u8>,
     idx: usize,
+    code: u16,
     state: ReadState,
 }
 
 pub enum ReadRes {
     None,
-    Done(Vec<u8>),
+    /// Status code and body of the response
+    Done(u16, Vec<u8>),
     Redirect(String),
 }
 
@@ -26,6 +28,7 @@ impl Reader {
         Reader {
             data: vec![0; 75],
             idx: 0,
+            code: 0,
             state: ReadState::Header,
         }
     }
@@ -52,7 +55,7 @@ impl Reader {
                     ReadState::Body => {
                         let mut data = mem::replace(&mut self.data, Vec::with_capacity(0));
                         data.truncate(self.idx);
-                        return Ok(ReadRes::Done(data));
+                        return Ok(ReadRes::Done(self.code, data));
                     }
                     _ => return Err(ErrorKind::EOF.into()),
                 },
@@ -87,6 +90,7 @@ impl Reader {
                             }
                             return Ok(Some(ReadRes::Redirect(loc.unwrap())));
                         }
+                        self.code = resp.code.unwrap_or(0);
                         header_done = Some(i);
                     }
                     Ok(httparse::Status::Partial) => {}
@@ -0,0 +1,237 @@
+//! Local Service Discovery, as per BEP 14.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time;
+
+use net2::unix::UnixUdpBuilderExt;
+use net2::UdpBuilder;
+
+use crate::tracker;
+use crate::util;
+use crate::CONFIG;
+
+const LSD_PORT: u16 = 6771;
+const LSD_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+/// Interval between announces of each torrent
+const ANNOUNCE_INT: time::Duration = time::Duration::from_secs(5 * 60);
+/// Announces of a single torrent are never sent more often than this
+const MIN_ANNOUNCE_INT: time::Duration = time::Duration::from_secs(60);
+/// Maximum number of infohashes in one announce, which keeps it in a single packet
+const MAX_HASHES: usize = 20;
+
+pub struct Manager {
+    id: usize,
+    sock: UdpSocket,
+    /// Random cookie included in our announces, used to ignore them
+    /// when they're looped back to us
+    cookie: String,
+    torrents: HashMap<[u8; 20], Torrent>,
+    buf: Vec<u8>,
+}
+
+struct Torrent {
+    tid: usize,
+    announced: Option<time::Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Announce {
+    port: u16,
+    hashes: Vec<[u8; 20]>,
+    cookie: Option<String>,
+}
+
+impl Manager {
+    pub fn new(reg: &amy::Registrar) -> io::Result<Manager> {
+        let sock = UdpBuilder::new_v4()?
+            .reuse_address(true)?
+            .reuse_port(true)?
+            .bind(("0.0.0.0", LSD_PORT))?;
+        sock.join_multicast_v4(&LSD_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        // Other instances on this host should see our announces too
+        sock.set_multicast_loop_v4(true)?;
+        sock.set_nonblocking(true)?;
+        let id = reg.register(&sock, amy::Event::Read)?;
+        Ok(Manager {
+            id,
+            sock,
+            cookie: util::random_string(12),
+            torrents: HashMap::new(),
+            buf: vec![0u8; 1500],
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Starts announcing a torrent to the local network, sending an
+    /// announce now unless one was sent recently.
+    pub fn announce(&mut self, tid: usize, hash: [u8; 20]) {
+        let torrent = self.torrents.entry(hash).or_insert(Torrent {
+            tid,
+            announced: None,
+        });
+        torrent.tid = tid;
+        if torrent
+            .announced
+            .map(|t| t.elapsed() < MIN_ANNOUNCE_INT)
+            .unwrap_or(false)
+        {
+            return;
+        }
+        torrent.announced = Some(time::Instant::now());
+        self.send_announce(&[hash]);
+    }
+
+    pub fn remove(&mut self, hash: &[u8; 20]) {
+        self.torrents.remove(hash);
+    }
+
+    pub fn tick(&mut self) {
+        let now = time::Instant::now();
+        let due: Vec<_> = self
+            .torrents
+            .iter_mut()
+            .filter(|(_, t)| t.announced.map(|a| now - a >= ANNOUNCE_INT).unwrap_or(true))
+            .map(|(hash, t)| {
+                t.announced = Some(now);
+                *hash
+            })
+            .collect();
+        for hashes in due.chunks(MAX_HASHES) {
+            self.send_announce(hashes);
+        }
+    }
+
+    pub fn readable(&mut self) -> Vec<tracker::Response> {
+        let mut resps = Vec::new();
+        loop {
+            match self.sock.recv_from(&mut self.buf[..]) {
+                Ok((v, addr)) => {
+                    let announce = match Announce::decode(&self.buf[..v]) {
+                        Some(a) => a,
+                        None => {
+                            trace!("Received invalid LSD message from {:?}!", addr);
+                            continue;
+                        }
+                    };
+                    if announce.cookie.as_ref() == Some(&self.cookie) {
+                        continue;
+                    }
+                    let peer = SocketAddr::new(addr.ip(), announce.port);
+                    for hash in &announce.hashes {
+                        if let Some(t) = self.torrents.get(hash) {
+                            debug!("Discovered local peer {} via LSD", peer);
+                            resps.push(tracker::Response::LSD {
+                                tid: t.tid,
+                                peers: vec![peer],
+                            });
+                        }
+                    }
+                }
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        error!(
+                            "Encountered unexpected error reading from LSD socket: {:?}!",
+                            e
+                        );
+                    }
+                    break;
+                }
+            }
+        }
+        resps
+    }
+
+    fn send_announce(&mut self, hashes: &[[u8; 20]]) {
+        let msg = Announce {
+            port: CONFIG.port,
+            hashes: hashes.to_vec(),
+            cookie: Some(self.cookie.clone()),
+        }
+        .encode();
+        let addr = SocketAddrV4::new(LSD_GROUP, LSD_PORT);
+        if let Err(e) = self.sock.send_to(&msg, addr) {
+            debug!("Failed to send LSD announce: {}", e);
+        }
+    }
+}
+
+impl Announce {
+    fn encode(&self) -> Vec<u8> {
+        let mut msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}:{}\r\nPort: {}\r\n",
+            LSD_GROUP, LSD_PORT, self.port
+        );
+        for hash in &self.hashes {
+            msg.push_str(&format!("Infohash: {}\r\n", util::hash_to_id(hash)));
+        }
+        if let Some(ref cookie) = self.cookie {
+            msg.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        msg.push_str("\r\n\r\n");
+        msg.into_bytes()
+    }
+
+    fn decode(data: &[u8]) -> Option<Announce> {
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut req = httparse::Request::new(&mut headers);
+        req.parse(data).ok()?;
+        if req.method != Some("BT-SEARCH") {
+            return None;
+        }
+        let mut port = None;
+        let mut hashes = Vec::new();
+        let mut cookie = None;
+        for header in req.headers.iter() {
+            let value = std::str::from_utf8(header.value).ok()?.trim();
+            if header.name.eq_ignore_ascii_case("port") {
+                port = value.parse().ok();
+            } else if header.name.eq_ignore_ascii_case("infohash") {
+                // Infohashes are always 40 hex characters, v2 hashes are truncated
+                if value.len() == 40 {
+                    hashes.extend(util::id_to_hash(value));
+                }
+            } else if header.name.eq_ignore_ascii_case("cookie") {
+                cookie = Some(value.to_owned());
+            }
+        }
+        match port {
+            Some(port) if port != 0 && !hashes.is_empty() => Some(Announce {
+                port,
+                hashes,
+                cookie,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announce_codec() {
+        let a = Announce {
+            port: 16_384,
+            hashes: vec![[0xAB; 20], [1; 20]],
+            cookie: Some("cookie".to_owned()),
+        };
+        assert_eq!(Announce::decode(&a.encode()), Some(a));
+
+        let msg = b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: abababababababababababababababababababab\r\n\r\n\r\n";
+        let a = Announce::decode(msg).unwrap();
+        assert_eq!(a.port, 6881);
+        assert_eq!(a.hashes, vec![[0xAB; 20]]);
+        assert_eq!(a.cookie, None);
+
+        assert_eq!(
+            Announce::decode(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n"),
+            None
+        );
+    }
+}
@@ -2,6 +2,8 @@ mod dht;
 mod dns;
 mod errors;
 mod http;
+mod lsd;
+mod portmap;
 mod udp;
 
 use std::collections::VecDeque;
@@ -13,6 +15,7 @@ use byteorder::{BigEndian, ByteOrder};
 use url::Url;
 
 pub use self::errors::{Error, ErrorKind, Result, ResultExt};
+pub use self::portmap::{Method as PortMapMethod, Status as PortMapStatus};
 use crate::bencode::BEncode;
 use crate::control::cio;
 use crate::disk;
@@ -24,9 +27,11 @@ pub struct Tracker {
     poll: amy::Poller,
     ch: handle::Handle<Request, Response>,
     http: http::Handler,
-    queue: VecDeque<Announce>,
+    queue: VecDeque<Request>,
     udp: udp::Handler,
     dht: dht::Manager,
+    lsd: Option<lsd::Manager>,
+    portmap: Option<portmap::Manager>,
     dns: dns::Resolver,
     timer: usize,
     shutting_down: bool,
@@ -35,9 +40,13 @@ pub struct Tracker {
 #[derive(Debug)]
 pub enum Request {
     Announce(Announce),
+    Scrape(Scrape),
+    WebSeed(WebSeed),
     GetPeers(GetPeers),
     AddNode(SocketAddr),
     DHTAnnounce([u8; 20]),
+    LSDAnnounce(GetPeers),
+    LSDRemove([u8; 20]),
     PurgeDNS,
     Ping,
     Shutdown,
@@ -56,13 +65,42 @@ pub struct Announce {
     event: Option<Event>,
 }
 
+/// A batch of infohashes to scrape from a single tracker.
+#[derive(Debug)]
+pub struct Scrape {
+    url: Arc<Url>,
+    torrents: Vec<(usize, [u8; 20])>,
+}
+
+/// A download of part of a piece from a web seed.
+#[derive(Debug)]
+pub struct WebSeed {
+    pub tid: usize,
+    /// ID of the web seed within the torrent
+    pub seed: usize,
+    pub url: Url,
+    pub range: WebSeedRange,
+    pub piece: u32,
+    /// Offset of the data within the piece
+    pub offset: u32,
+    pub len: u32,
+}
+
+#[derive(Clone, Debug)]
+pub enum WebSeedRange {
+    /// Bytes of a file starting at the given offset, used by BEP 19 seeds
+    File(u64),
+    /// A whole piece of the torrent with the given infohash, used by BEP 17 seeds
+    Piece([u8; 20]),
+}
+
 #[derive(Debug)]
 pub struct GetPeers {
     pub id: usize,
     pub hash: [u8; 20],
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Event {
     Started,
     Stopped,
@@ -84,6 +122,22 @@ pub enum Response {
         tid: usize,
         peers: Vec<SocketAddr>,
     },
+    LSD {
+        tid: usize,
+        peers: Vec<SocketAddr>,
+    },
+    PortMap(PortMapStatus),
+    Scrape {
+        url: Arc<Url>,
+        resp: Result<Vec<ScrapeResponse>>,
+    },
+    WebSeed {
+        tid: usize,
+        seed: usize,
+        piece: u32,
+        offset: u32,
+        resp: Result<Vec<u8>>,
+    },
 }
 
 #[derive(Debug)]
@@ -94,7 +148,19 @@ pub struct TrackerResponse {
     pub seeders: u32,
 }
 
+/// Swarm statistics for a single torrent, as reported by a scrape.
+#[derive(Debug)]
+pub struct ScrapeResponse {
+    pub tid: usize,
+    pub complete: u32,
+    pub incomplete: u32,
+    pub downloaded: u32,
+}
+
 const POLL_INT_MS: usize = 1000;
+/// Maximum number of infohashes in a single scrape request, which
+/// keeps UDP scrapes within a single packet.
+const MAX_SCRAPE: usize = 50;
 
 impl Tracker {
     pub fn start(
@@ -108,6 +174,20 @@ impl Tracker {
         let udp = udp::Handler::new(&reg)?;
         let dht = dht::Manager::new(&reg, db)?;
         let http = http::Handler::new(&reg)?;
+        let lsd = if CONFIG.trk.lsd {
+            lsd::Manager::new(&reg)
+                .map_err(|e| error!("Local service discovery could not be started: {}", e))
+                .ok()
+        } else {
+            None
+        };
+        let portmap = if CONFIG.net.port_mapping {
+            portmap::Manager::new(&mut reg)
+                .map_err(|e| error!("Port mapping could not be started: {}", e))
+                .ok()
+        } else {
+            None
+        };
         let dns = dns::Resolver::new(&reg)?;
         let th = dh.run("trk", move |h| {
             Tracker {
@@ -115,6 +195,8 @@ impl Tracker {
                 ch: h,
                 udp,
                 dht,
+                lsd,
+                portmap,
                 http,
                 dns,
                 timer,
@@ -146,6 +228,12 @@ impl Tracker {
         }
 
         self.shutting_down = true;
+        // Piece downloads can be long running, and are useless once
+        // control has shut down, so don't wait on them.
+        self.http.abort_webseeds();
+        if let Some(ref mut pm) = self.portmap {
+            pm.shutdown();
+        }
 
         // Shutdown loop - wait for all requests to complete
         loop {
@@ -175,6 +263,8 @@ impl Tracker {
         while let Ok(r) = self.ch.recv() {
             match r {
                 Request::Announce(req) => self.handle_announce(req),
+                Request::Scrape(req) => self.handle_scrape(req),
+                Request::WebSeed(req) => self.handle_webseed(req),
                 Request::GetPeers(gp) => {
                     trace!("Handling dht peer find req!");
                     self.dht.get_peers(gp.id, gp.hash);
@@ -187,6 +277,17 @@ impl Tracker {
                     trace!("Handling dht announce req!");
                     self.dht.announce(hash);
                 }
+                Request::LSDAnnounce(gp) => {
+                    trace!("Handling lsd announce req!");
+                    if let Some(ref mut lsd) = self.lsd {
+                        lsd.announce(gp.id, gp.hash);
+                    }
+                }
+                Request::LSDRemove(hash) => {
+                    if let Some(ref mut lsd) = self.lsd {
+                        lsd.remove(&hash);
+                    }
+                }
                 Request::Ping => {}
                 Request::PurgeDNS => {
                     self.dns.res.purge();
@@ -203,7 +304,7 @@ impl Tracker {
         debug!("Handling announce request!");
         if self.udp.active_requests() + self.http.active_requests() > CONFIG.net.max_open_announces
         {
-            self.queue.push_back(req);
+            self.queue.push_back(Request::Announce(req));
         } else {
             let id = req.id;
             let url = req.url.clone();
@@ -224,10 +325,53 @@ impl Tracker {
         }
     }
 
+    fn handle_scrape(&mut self, req: Scrape) {
+        debug!("Handling scrape request!");
+        if self.udp.active_requests() + self.http.active_requests() > CONFIG.net.max_open_announces
+        {
+            self.queue.push_back(Request::Scrape(req));
+        } else {
+            let url = req.url.clone();
+            let response = match url.scheme() {
+                "http" | "https" => self.http.new_scrape(req, &mut self.dns),
+                "udp" => self.udp.new_scrape(req, &mut self.dns),
+                s => Err(
+                    ErrorKind::InvalidRequest(format!("Unknown tracker url scheme: {}", s)).into(),
+                ),
+            };
+            if let Err(e) = response {
+                self.send_response(Response::Scrape { url, resp: Err(e) });
+            }
+        }
+    }
+
+    /// Starts a web seed download. These bypass the announce queue, as
+    /// torrents only keep a single piece in flight per web seed.
+    fn handle_webseed(&mut self, req: WebSeed) {
+        let (tid, seed, piece, offset) = (req.tid, req.seed, req.piece, req.offset);
+        let response = match req.url.scheme() {
+            "http" | "https" => self.http.new_webseed(req, &mut self.dns),
+            s => {
+                Err(ErrorKind::InvalidRequest(format!("Unknown web seed url scheme: {}", s)).into())
+            }
+        };
+        if let Err(e) = response {
+            self.send_response(Response::WebSeed {
+                tid,
+                seed,
+                piece,
+                offset,
+                resp: Err(e),
+            });
+        }
+    }
+
     fn dequeue_req(&mut self) {
         // Attempt to dequeue next request if we can
-        if let Some(a) = self.queue.pop_front() {
-            self.handle_announce(a);
+        match self.queue.pop_front() {
+            Some(Request::Announce(a)) => self.handle_announce(a),
+            Some(Request::Scrape(s)) => self.handle_scrape(s),
+            _ => {}
         }
     }
 
@@ -268,6 +412,9 @@ impl Tracker {
         }
 
         self.dht.tick();
+        if let Some(ref mut lsd) = self.lsd {
+            lsd.tick();
+        }
         let mut dresps = vec![];
         let res = self.dns.res.tick(&mut self.dns.sock, |resp| {
             dresps.push(resp);
@@ -285,7 +432,7 @@ impl Tracker {
             let resp = if event.event.readable() {
                 self.http.readable(event.id, &mut self.dns)
             } else {
-                self.http.writable(event.id)
+                self.http.writable(event.id, &mut self.dns)
             };
             if let Some(r) = resp {
                 self.send_response(r);
@@ -294,8 +441,32 @@ impl Tracker {
             for resp in self.udp.readable() {
                 self.send_response(resp);
             }
-        } else if self.dht.id() == event.id {
-            for resp in self.dht.readable() {
+        } else if self.dht.contains(event.id) {
+            for resp in self.dht.readable(event.id) {
+                self.send_response(resp);
+            }
+        } else if self
+            .portmap
+            .as_ref()
+            .map(|p| p.id() == event.id)
+            .unwrap_or(false)
+        {
+            let resps = self
+                .portmap
+                .as_mut()
+                .map(|p| p.readable())
+                .unwrap_or_default();
+            for resp in resps {
+                self.send_response(resp);
+            }
+        } else if self
+            .lsd
+            .as_ref()
+            .map(|l| l.id() == event.id)
+            .unwrap_or(false)
+        {
+            let resps = self.lsd.as_mut().map(|l| l.readable()).unwrap_or_default();
+            for resp in resps {
                 self.send_response(resp);
             }
         } else {
@@ -316,16 +487,12 @@ impl Tracker {
 }
 
 impl Request {
-    pub fn new_announce<T: cio::CIO>(
+    pub fn announce<T: cio::CIO>(
         torrent: &Torrent<T>,
+        url: Arc<Url>,
         event: Option<Event>,
-    ) -> Option<Request> {
-        let url = if let Some(trk) = torrent.trackers().front() {
-            trk.url.clone()
-        } else {
-            return None;
-        };
-        Some(Request::Announce(Announce {
+    ) -> Request {
+        Request::Announce(Announce {
             id: torrent.id(),
             url,
             hash: torrent.info().hash,
@@ -343,32 +510,55 @@ impl Request {
             // let existing peers connect otherwise
             num_want: if torrent.complete() { None } else { Some(50) },
             event,
-        }))
+        })
     }
 
-    pub fn started<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
-        Request::new_announce(torrent, Some(Event::Started))
+    /// Creates an announce for each tracker the torrent is currently
+    /// using, one per tier when announcing to all tiers.
+    fn new_announces<T: cio::CIO>(torrent: &Torrent<T>, event: Option<Event>) -> Vec<Request> {
+        torrent
+            .announce_urls()
+            .into_iter()
+            .map(|url| Request::announce(torrent, url, event))
+            .collect()
     }
 
-    pub fn stopped<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
-        Request::new_announce(torrent, Some(Event::Stopped))
+    pub fn started<T: cio::CIO>(torrent: &Torrent<T>) -> Vec<Request> {
+        Request::new_announces(torrent, Some(Event::Started))
     }
 
-    pub fn completed<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
-        Request::new_announce(torrent, Some(Event::Completed))
+    pub fn stopped<T: cio::CIO>(torrent: &Torrent<T>) -> Vec<Request> {
+        Request::new_announces(torrent, Some(Event::Stopped))
     }
 
-    pub fn interval<T: cio::CIO>(torrent: &Torrent<T>) -> Option<Request> {
-        Request::new_announce(torrent, None)
+    pub fn completed<T: cio::CIO>(torrent: &Torrent<T>) -> Vec<Request> {
+        Request::new_announces(torrent, Some(Event::Completed))
     }
 
-    pub fn custom<T: cio::CIO>(torrent: &Torrent<T>, url: Arc<Url>) -> Option<Request> {
-        Request::new_announce(torrent, None).map(|mut r| {
-            if let Request::Announce(ref mut a) = r {
-                a.url = url
-            }
-            r
-        })
+    pub fn interval<T: cio::CIO>(torrent: &Torrent<T>) -> Vec<Request> {
+        Request::new_announces(torrent, None)
+    }
+
+    /// Creates scrape requests for the given torrents, batched so that
+    /// each request covers at most MAX_SCRAPE infohashes. Nothing is
+    /// returned if the tracker does not support scraping.
+    pub fn scrape(url: &Arc<Url>, torrents: &[(usize, [u8; 20])]) -> Vec<Request> {
+        if !scrapable(url) {
+            return Vec::new();
+        }
+        torrents
+            .chunks(MAX_SCRAPE)
+            .map(|c| {
+                Request::Scrape(Scrape {
+                    url: url.clone(),
+                    torrents: c.to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn custom<T: cio::CIO>(torrent: &Torrent<T>, url: Arc<Url>) -> Request {
+        Request::announce(torrent, url, None)
     }
 }
 
@@ -386,13 +576,13 @@ impl TrackerResponse {
         let mut d = data.into_dict().ok_or(ErrorKind::InvalidResponse(
             "Tracker response must be a dictionary type!",
         ))?;
-        if let Some(BEncode::String(data)) = d.remove("failure reason") {
+        if let Some(BEncode::String(data)) = d.remove(&b"failure reason"[..]) {
             let reason = String::from_utf8(data)
                 .chain_err(|| ErrorKind::InvalidResponse("Failure reason must be UTF8!"))?;
             return Err(ErrorKind::TrackerError(reason).into());
         }
         let mut resp = TrackerResponse::empty();
-        if let Some(BEncode::String(ref data)) = d.remove("peers") {
+        if let Some(BEncode::String(ref data)) = d.remove(&b"peers"[..]) {
             for p in data.chunks(6) {
                 if p.len() != 6 {
                     debug!("Unusual trailing bytes received for tracker!");
@@ -403,7 +593,13 @@ impl TrackerResponse {
                 resp.peers.push(SocketAddr::V4(socket));
             }
         }
-        match d.remove("interval") {
+        if let Some(BEncode::Int(i)) = d.remove(&b"complete"[..]) {
+            resp.seeders = i as u32;
+        }
+        if let Some(BEncode::Int(i)) = d.remove(&b"incomplete"[..]) {
+            resp.leechers = i as u32;
+        }
+        match d.remove(&b"interval"[..]) {
             Some(BEncode::Int(ref i)) => {
                 resp.interval = *i as u32;
             }
@@ -414,3 +610,123 @@ impl TrackerResponse {
         Ok(resp)
     }
 }
+
+impl ScrapeResponse {
+    pub fn from_bencode(
+        data: BEncode,
+        torrents: &[(usize, [u8; 20])],
+    ) -> Result<Vec<ScrapeResponse>> {
+        let mut d = data.into_dict().ok_or(ErrorKind::InvalidResponse(
+            "Scrape response must be a dictionary type!",
+        ))?;
+        if let Some(BEncode::String(data)) = d.remove(&b"failure reason"[..]) {
+            let reason = String::from_utf8(data)
+                .chain_err(|| ErrorKind::InvalidResponse("Failure reason must be UTF8!"))?;
+            return Err(ErrorKind::TrackerError(reason).into());
+        }
+        let mut files = d.remove(&b"files"[..]).and_then(BEncode::into_dict).ok_or(
+            ErrorKind::InvalidResponse("Scrape response must have files!"),
+        )?;
+        let mut resps = Vec::new();
+        for &(tid, ref hash) in torrents {
+            let file = files.remove(&hash[..]).and_then(BEncode::into_dict);
+            if let Some(mut f) = file {
+                let mut stat = |k: &str| match f.remove(k.as_bytes()) {
+                    Some(BEncode::Int(i)) => i as u32,
+                    _ => 0,
+                };
+                resps.push(ScrapeResponse {
+                    tid,
+                    complete: stat("complete"),
+                    incomplete: stat("incomplete"),
+                    downloaded: stat("downloaded"),
+                });
+            }
+        }
+        Ok(resps)
+    }
+}
+
+/// Whether a tracker supports scraping. HTTP trackers do so by
+/// convention when the last path component begins with "announce".
+pub fn scrapable(url: &Url) -> bool {
+    match url.scheme() {
+        "udp" => true,
+        "http" | "https" => scrape_url(url).is_some(),
+        _ => false,
+    }
+}
+
+/// Converts an HTTP announce URL to the corresponding scrape URL.
+pub fn scrape_url(url: &Url) -> Option<Url> {
+    let path = url.path();
+    let idx = path.rfind('/')? + 1;
+    if !path[idx..].starts_with("announce") {
+        return None;
+    }
+    let mut surl = url.clone();
+    surl.set_path(&format!("{}scrape{}", &path[..idx], &path[idx + 8..]));
+    Some(surl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_scrape_url() {
+        let conv = |u: &str| scrape_url(&Url::parse(u).unwrap()).map(|u| u.to_string());
+        assert_eq!(
+            conv("http://example.com/announce"),
+            Some("http://example.com/scrape".to_owned())
+        );
+        assert_eq!(
+            conv("http://example.com/x/announce.php?k=1"),
+            Some("http://example.com/x/scrape.php?k=1".to_owned())
+        );
+        assert_eq!(conv("http://example.com/a"), None);
+        assert_eq!(conv("http://example.com/announce/x"), None);
+    }
+
+    #[test]
+    fn test_scrape_resp() {
+        let h1 = [0xFFu8; 20];
+        let h2 = [b'a'; 20];
+        let file = |c, i, d| {
+            let mut f = BTreeMap::new();
+            f.insert(b"complete".to_vec(), BEncode::Int(c));
+            f.insert(b"incomplete".to_vec(), BEncode::Int(i));
+            f.insert(b"downloaded".to_vec(), BEncode::Int(d));
+            BEncode::Dict(f)
+        };
+        let mut files = BTreeMap::new();
+        files.insert(h1.to_vec(), file(1, 2, 3));
+        files.insert(h2.to_vec(), file(4, 5, 6));
+        let mut d = BTreeMap::new();
+        d.insert(b"files".to_vec(), BEncode::Dict(files));
+
+        let resps =
+            ScrapeResponse::from_bencode(BEncode::Dict(d), &[(1, h1), (2, h2), (3, [0; 20])])
+                .unwrap();
+        assert_eq!(resps.len(), 2);
+        assert_eq!(
+            (
+                resps[0].tid,
+                resps[0].complete,
+                resps[0].incomplete,
+                resps[0].downloaded
+            ),
+            (1, 1, 2, 3)
+        );
+        assert_eq!(
+            (
+                resps[1].tid,
+                resps[1].complete,
+                resps[1].incomplete,
+                resps[1].downloaded
+            ),
+            (2, 4, 5, 6)
+        );
+    }
+}
@@ -0,0 +1,378 @@
+//! Automatic port mapping on the gateway, so peers outside of the
+//! local network can reach us. Mapping requests block, so they're done
+//! on a separate thread which reports status back to the tracker.
+
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rand::random;
+
+use crate::tracker;
+use crate::CONFIG;
+
+mod natpmp;
+mod upnp;
+
+/// Lease requested for each mapping
+const LIFETIME_SECS: u32 = 2 * 60 * 60;
+/// Interval used to refresh permanent UPnP mappings and retry failures
+const RETRY_SECS: u64 = 5 * 60;
+
+pub struct Manager {
+    rx: amy::Receiver<Status>,
+    /// Dropped to stop the worker, so that every later check sees it
+    stop: Option<mpsc::Sender<()>>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// The method used to map ports.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+/// Result of an attempt to map every port, method is None if mapping failed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub method: Option<Method>,
+    pub external_ip: Option<IpAddr>,
+}
+
+#[derive(Debug)]
+struct Mapping {
+    external_ip: Option<IpAddr>,
+    external_port: u16,
+    lifetime: u32,
+}
+
+/// A gateway ports have been mapped on, kept for renewal and removal.
+enum Gateway {
+    Pcp(natpmp::Client),
+    NatPmp(natpmp::Client),
+    Upnp(upnp::Gateway),
+}
+
+impl Manager {
+    pub fn new(reg: &mut amy::Registrar) -> io::Result<Manager> {
+        let (tx, rx) = reg.channel()?;
+        let (stop, stop_rx) = mpsc::channel();
+        let worker = thread::Builder::new()
+            .name("portmap".to_owned())
+            .spawn(move || run(&ports(), &tx, &stop_rx))?;
+        Ok(Manager {
+            rx,
+            stop: Some(stop),
+            worker: Some(worker),
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        self.rx.get_id()
+    }
+
+    pub fn readable(&mut self) -> Vec<tracker::Response> {
+        let mut resps = Vec::new();
+        while let Ok(status) = self.rx.try_recv() {
+            resps.push(tracker::Response::PortMap(status));
+        }
+        resps
+    }
+
+    /// Stops the worker, which abandons any mapping in progress and
+    /// sends a single removal request for each mapped port.
+    pub fn shutdown(&mut self) {
+        self.stop.take();
+        if let Some(w) = self.worker.take() {
+            w.join().ok();
+        }
+    }
+}
+
+impl Protocol {
+    fn number(self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        }
+    }
+}
+
+impl Gateway {
+    fn method(&self) -> Method {
+        match *self {
+            Gateway::Pcp(_) => Method::Pcp,
+            Gateway::NatPmp(_) => Method::NatPmp,
+            Gateway::Upnp(_) => Method::Upnp,
+        }
+    }
+
+    /// Maps every port, returning the external IP and the shortest lease.
+    fn map(
+        &mut self,
+        ports: &[(Protocol, u16)],
+        lifetime: u32,
+        stop: &mpsc::Receiver<()>,
+    ) -> io::Result<(Option<IpAddr>, u32)> {
+        let mut ip = None;
+        let mut lease = lifetime;
+        for &(proto, port) in ports {
+            if stopped(stop) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let m = match *self {
+                Gateway::Pcp(ref mut c) => match c.pcp_map(proto, port, lifetime)? {
+                    Some(m) => m,
+                    None => return Err(io::ErrorKind::InvalidData.into()),
+                },
+                Gateway::NatPmp(ref mut c) => c.natpmp_map(proto, port, lifetime)?,
+                Gateway::Upnp(ref gw) => Mapping {
+                    external_ip: None,
+                    external_port: port,
+                    lifetime: gw.add_port(proto, port, lifetime)?,
+                },
+            };
+            if m.external_port != port {
+                info!(
+                    "Gateway mapped {} port {} to external port {}",
+                    proto.name(),
+                    port,
+                    m.external_port
+                );
+            }
+            ip = ip.or(m.external_ip);
+            if m.lifetime != 0 {
+                lease = lease.min(m.lifetime);
+            }
+        }
+        if ip.is_none() {
+            ip = match *self {
+                Gateway::NatPmp(ref mut c) => c.natpmp_external_ip().ok().map(IpAddr::V4),
+                Gateway::Upnp(ref gw) => gw.external_ip().ok(),
+                Gateway::Pcp(_) => None,
+            };
+        }
+        Ok((ip, lease))
+    }
+
+    fn unmap(&mut self, ports: &[(Protocol, u16)]) {
+        for &(proto, port) in ports {
+            let res = match *self {
+                Gateway::Pcp(ref mut c) => c.unmap(true, proto, port),
+                Gateway::NatPmp(ref mut c) => c.unmap(false, proto, port),
+                Gateway::Upnp(ref gw) => gw.remove_port(proto, port),
+            };
+            if let Err(e) = res {
+                debug!(
+                    "Failed to remove {} port mapping {}: {}",
+                    proto.name(),
+                    port,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Ports which peers and trackers may need to reach us on.
+fn ports() -> Vec<(Protocol, u16)> {
+    let mut ports = vec![(Protocol::Tcp, CONFIG.port)];
+    if CONFIG.peer.utp {
+        ports.push((Protocol::Udp, CONFIG.port));
+    }
+    if CONFIG.dht.bootstrap_node.is_some() {
+        ports.push((Protocol::Udp, CONFIG.dht.port));
+    }
+    ports.push((Protocol::Udp, CONFIG.trk.port));
+    ports
+}
+
+fn run(ports: &[(Protocol, u16)], tx: &amy::Sender<Status>, stop: &mpsc::Receiver<()>) {
+    let nonce = random();
+    let mut gateway: Option<Gateway> = None;
+    loop {
+        let res = match gateway {
+            Some(ref mut gw) => gw.map(ports, LIFETIME_SECS, stop),
+            None => Err(io::ErrorKind::NotFound.into()),
+        };
+        let (status, wait) = match res {
+            Ok((external_ip, lease)) => {
+                let method = gateway.as_ref().map(Gateway::method);
+                (
+                    Status {
+                        method,
+                        external_ip,
+                    },
+                    u64::from(lease / 2),
+                )
+            }
+            // Either there's no gateway yet, or the existing one stopped
+            // working, so look for a new one.
+            Err(_) if stopped(stop) => break,
+            Err(_) => match find_gateway(ports, nonce, stop) {
+                Some((gw, external_ip, lease)) => {
+                    info!(
+                        "Mapped ports with {:?}, external IP {:?}",
+                        gw.method(),
+                        external_ip
+                    );
+                    let status = Status {
+                        method: Some(gw.method()),
+                        external_ip,
+                    };
+                    gateway = Some(gw);
+                    (status, u64::from(lease / 2))
+                }
+                None if stopped(stop) => break,
+                None => {
+                    info!("Port mapping failed, no usable gateway found");
+                    gateway = None;
+                    let status = Status {
+                        method: None,
+                        external_ip: None,
+                    };
+                    (status, RETRY_SECS)
+                }
+            },
+        };
+        if tx.send(status).is_err() {
+            break;
+        }
+        let wait = if wait == 0 {
+            RETRY_SECS
+        } else {
+            wait.min(RETRY_SECS * 6)
+        };
+        match stop.recv_timeout(Duration::from_secs(wait)) {
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            _ => break,
+        }
+    }
+    if let Some(mut gw) = gateway {
+        gw.unmap(ports);
+    }
+}
+
+/// Whether the manager has been shut down.
+fn stopped(stop: &mpsc::Receiver<()>) -> bool {
+    stop.try_recv() != Err(mpsc::TryRecvError::Empty)
+}
+
+/// Tries to map ports with PCP, then NAT-PMP, then UPnP, giving up
+/// as soon as the manager is shut down.
+fn find_gateway(
+    ports: &[(Protocol, u16)],
+    nonce: [u8; 12],
+    stop: &mpsc::Receiver<()>,
+) -> Option<(Gateway, Option<IpAddr>, u32)> {
+    let mut candidates = Vec::new();
+    if let Some(addr) = gateway_addr() {
+        match natpmp::Client::new(addr, nonce) {
+            // Deleting a mapping doubles as a probe of which protocol is spoken
+            Ok(mut c) => match c.pcp_map(ports[0].0, ports[0].1, 0) {
+                Ok(Some(_)) => candidates.push(Gateway::Pcp(c)),
+                Ok(None) => candidates.push(Gateway::NatPmp(c)),
+                Err(e) => debug!("PCP/NAT-PMP gateway {} unavailable: {}", addr, e),
+            },
+            Err(e) => debug!("Could not reach gateway {}: {}", addr, e),
+        }
+    }
+    for mut gw in candidates {
+        match gw.map(ports, LIFETIME_SECS, stop) {
+            Ok((ip, lease)) => return Some((gw, ip, lease)),
+            Err(_) if stopped(stop) => {
+                gw.unmap(ports);
+                return None;
+            }
+            Err(e) => debug!("{:?} port mapping failed: {}", gw.method(), e),
+        }
+    }
+    if stopped(stop) {
+        return None;
+    }
+    match upnp::discover() {
+        Ok(gw) => {
+            let mut gw = Gateway::Upnp(gw);
+            match gw.map(ports, LIFETIME_SECS, stop) {
+                Ok((ip, lease)) => return Some((gw, ip, lease)),
+                Err(_) if stopped(stop) => gw.unmap(ports),
+                Err(e) => debug!("UPnP port mapping failed: {}", e),
+            }
+        }
+        Err(e) => debug!("UPnP discovery failed: {}", e),
+    }
+    None
+}
+
+/// Address of the NAT-PMP/PCP server, which is the configured gateway
+/// or otherwise the default route's gateway.
+fn gateway_addr() -> Option<SocketAddr> {
+    let ip = match CONFIG.net.gateway {
+        Some(ip) => Some(ip),
+        None => fs::read_to_string("/proc/net/route")
+            .ok()
+            .and_then(|r| default_gateway(&r)),
+    };
+    ip.map(|ip| SocketAddr::new(IpAddr::V4(ip), natpmp::PORT))
+}
+
+/// Finds the default gateway in the contents of /proc/net/route.
+fn default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+        // Addresses are written in host byte order
+        u32::from_str_radix(fields[2], 16)
+            .ok()
+            .map(|gw| Ipv4Addr::from(gw.to_ne_bytes()))
+            .filter(|gw| !gw.is_unspecified())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_gateway() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                      eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                      eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+        let gw = if cfg!(target_endian = "little") {
+            Ipv4Addr::new(192, 168, 1, 1)
+        } else {
+            Ipv4Addr::new(1, 1, 168, 192)
+        };
+        assert_eq!(default_gateway(routes), Some(gw));
+        assert_eq!(default_gateway("Iface\tDestination\tGateway\n"), None);
+    }
+
+    #[test]
+    fn test_stopped() {
+        let (tx, rx) = mpsc::channel();
+        assert!(!stopped(&rx));
+        drop(tx);
+        assert!(stopped(&rx));
+        assert!(stopped(&rx));
+    }
+}
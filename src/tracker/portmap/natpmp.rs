@@ -0,0 +1,256 @@
+//! NAT-PMP (RFC 6886) and its successor PCP (RFC 6887), which share
+//! a port on the gateway. PCP is tried first, falling back to NAT-PMP
+//! when the gateway does not understand it.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::{Mapping, Protocol};
+use crate::util::io_err;
+
+pub const PORT: u16 = 5351;
+
+const NATPMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const PCP_OP_MAP: u8 = 1;
+const RESP_BIT: u8 = 0x80;
+const RESULT_UNSUPP_VERSION: u16 = 1;
+/// Initial retransmission timeout, doubled on each retry. RFC 6886 allows
+/// for 9 tries, but a gateway which is that slow isn't worth waiting on.
+const INITIAL_TIMEOUT_MS: u64 = 250;
+const TRIES: u32 = 4;
+
+pub struct Client {
+    sock: UdpSocket,
+    local: Ipv4Addr,
+    nonce: [u8; 12],
+    buf: [u8; 1100],
+}
+
+impl Client {
+    pub fn new(gateway: SocketAddr, nonce: [u8; 12]) -> io::Result<Client> {
+        let sock = UdpSocket::bind(("0.0.0.0", 0))?;
+        sock.connect(gateway)?;
+        let local = match sock.local_addr()?.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => return io_err("NAT-PMP gateway must be IPv4"),
+        };
+        Ok(Client {
+            sock,
+            local,
+            nonce,
+            buf: [0; 1100],
+        })
+    }
+
+    /// Maps a port with PCP, returning None if the gateway only
+    /// supports NAT-PMP. A lifetime of 0 deletes the mapping.
+    pub fn pcp_map(
+        &mut self,
+        proto: Protocol,
+        port: u16,
+        lifetime: u32,
+    ) -> io::Result<Option<Mapping>> {
+        let req = self.pcp_request(proto, port, lifetime);
+        let nonce = self.nonce;
+        let resp = self.request(&req, |r| {
+            // NAT-PMP gateways reply to PCP with a version 0 error
+            (r.len() >= 4 && r[0] == NATPMP_VERSION)
+                || (r.len() >= 24
+                    && r[0] == PCP_VERSION
+                    && r[1] == RESP_BIT | PCP_OP_MAP
+                    && (r.len() < 60 || r[24..36] == nonce))
+        })?;
+        if resp[0] == NATPMP_VERSION {
+            return Ok(None);
+        }
+        let result = u16::from(resp[3]);
+        if result == RESULT_UNSUPP_VERSION {
+            return Ok(None);
+        } else if result != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("PCP mapping failed with result {}", result),
+            ));
+        }
+        if resp.len() < 60 {
+            return io_err("PCP response too short");
+        }
+        let mut ext = [0u8; 16];
+        ext.copy_from_slice(&resp[44..60]);
+        let ext = Ipv6Addr::from(ext);
+        Ok(Some(Mapping {
+            external_ip: Some(ext.to_ipv4().map(IpAddr::V4).unwrap_or(IpAddr::V6(ext))),
+            external_port: BigEndian::read_u16(&resp[42..44]),
+            lifetime: BigEndian::read_u32(&resp[4..8]),
+        }))
+    }
+
+    /// Deletes a mapping, sending the request once without waiting
+    /// for the gateway to respond.
+    pub fn unmap(&mut self, pcp: bool, proto: Protocol, port: u16) -> io::Result<()> {
+        if pcp {
+            let req = self.pcp_request(proto, port, 0);
+            self.sock.send(&req)?;
+        } else {
+            self.sock.send(&natpmp_request(proto, port, 0))?;
+        }
+        Ok(())
+    }
+
+    fn pcp_request(&self, proto: Protocol, port: u16, lifetime: u32) -> [u8; 60] {
+        let mut req = [0u8; 60];
+        req[0] = PCP_VERSION;
+        req[1] = PCP_OP_MAP;
+        BigEndian::write_u32(&mut req[4..8], lifetime);
+        req[8..24].copy_from_slice(&self.local.to_ipv6_mapped().octets());
+        req[24..36].copy_from_slice(&self.nonce);
+        req[36] = proto.number();
+        BigEndian::write_u16(&mut req[40..42], port);
+        BigEndian::write_u16(&mut req[42..44], port);
+        req[44..60].copy_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+        req
+    }
+
+    pub fn natpmp_external_ip(&mut self) -> io::Result<Ipv4Addr> {
+        let resp = self.request(&[NATPMP_VERSION, 0], |r| {
+            r.len() >= 12 && r[0] == NATPMP_VERSION && r[1] == RESP_BIT
+        })?;
+        natpmp_result(&resp)?;
+        Ok(Ipv4Addr::new(resp[8], resp[9], resp[10], resp[11]))
+    }
+
+    /// Maps a port with NAT-PMP. A lifetime of 0 deletes the mapping.
+    pub fn natpmp_map(&mut self, proto: Protocol, port: u16, lifetime: u32) -> io::Result<Mapping> {
+        let req = natpmp_request(proto, port, lifetime);
+        let op = req[1];
+        let resp = self.request(&req, |r| {
+            r.len() >= 16
+                && r[0] == NATPMP_VERSION
+                && r[1] == RESP_BIT | op
+                && BigEndian::read_u16(&r[8..10]) == port
+        })?;
+        natpmp_result(&resp)?;
+        Ok(Mapping {
+            external_ip: None,
+            external_port: BigEndian::read_u16(&resp[10..12]),
+            lifetime: BigEndian::read_u32(&resp[12..16]),
+        })
+    }
+
+    /// Sends a request until a response accepted by valid arrives.
+    fn request<F: Fn(&[u8]) -> bool>(&mut self, req: &[u8], valid: F) -> io::Result<Vec<u8>> {
+        let mut timeout = INITIAL_TIMEOUT_MS;
+        for _ in 0..TRIES {
+            self.sock.send(req)?;
+            self.sock
+                .set_read_timeout(Some(Duration::from_millis(timeout)))?;
+            loop {
+                match self.sock.recv(&mut self.buf) {
+                    Ok(n) if valid(&self.buf[..n]) => return Ok(self.buf[..n].to_vec()),
+                    Ok(_) => continue,
+                    Err(ref e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        break
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            timeout *= 2;
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "gateway did not respond",
+        ))
+    }
+}
+
+fn natpmp_request(proto: Protocol, port: u16, lifetime: u32) -> [u8; 12] {
+    let mut req = [0u8; 12];
+    req[0] = NATPMP_VERSION;
+    req[1] = match proto {
+        Protocol::Udp => 1,
+        Protocol::Tcp => 2,
+    };
+    BigEndian::write_u16(&mut req[4..6], port);
+    BigEndian::write_u16(&mut req[6..8], port);
+    BigEndian::write_u32(&mut req[8..12], lifetime);
+    req
+}
+
+fn natpmp_result(resp: &[u8]) -> io::Result<()> {
+    match BigEndian::read_u16(&resp[2..4]) {
+        0 => Ok(()),
+        r => Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("NAT-PMP request failed with result {}", r),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Runs a mock gateway which answers requests with respond.
+    fn mock_gateway<F>(respond: F) -> SocketAddr
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        let sock = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = sock.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1100];
+            while let Ok((n, from)) = sock.recv_from(&mut buf) {
+                sock.send_to(&respond(&buf[..n]), from).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_natpmp_fallback() {
+        let gw = mock_gateway(|req| {
+            let mut resp = vec![0u8; 16];
+            resp[1] = RESP_BIT | req[1];
+            match (req[0], req[1]) {
+                // Unsupported version
+                (PCP_VERSION, _) => resp[3] = 1,
+                (NATPMP_VERSION, 0) => resp[8..12].copy_from_slice(&[1, 2, 3, 4]),
+                _ => {
+                    resp[8..10].copy_from_slice(&req[4..6]);
+                    BigEndian::write_u16(&mut resp[10..12], 20_000);
+                    resp[12..16].copy_from_slice(&req[8..12]);
+                }
+            }
+            resp
+        });
+        let mut c = Client::new(gw, [7; 12]).unwrap();
+        assert!(c.pcp_map(Protocol::Tcp, 16_384, 3_600).unwrap().is_none());
+        let m = c.natpmp_map(Protocol::Tcp, 16_384, 3_600).unwrap();
+        assert_eq!(m.external_port, 20_000);
+        assert_eq!(m.lifetime, 3_600);
+        assert_eq!(c.natpmp_external_ip().unwrap(), Ipv4Addr::new(1, 2, 3, 4));
+    }
+
+    #[test]
+    fn test_pcp_map() {
+        let gw = mock_gateway(|req| {
+            let mut resp = req.to_vec();
+            resp[1] |= RESP_BIT;
+            resp[44..60].copy_from_slice(&Ipv4Addr::new(5, 6, 7, 8).to_ipv6_mapped().octets());
+            resp
+        });
+        let mut c = Client::new(gw, [7; 12]).unwrap();
+        let m = c.pcp_map(Protocol::Udp, 16_309, 7_200).unwrap().unwrap();
+        assert_eq!(m.external_ip, Some(IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8))));
+        assert_eq!(m.external_port, 16_309);
+        assert_eq!(m.lifetime, 7_200);
+    }
+}
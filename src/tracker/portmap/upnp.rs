@@ -0,0 +1,390 @@
+//! UPnP Internet Gateway Device port mapping, using SSDP to find the
+//! gateway and SOAP requests against its WAN connection service.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use super::Protocol;
+use crate::util::io_err;
+
+const SSDP_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::new(239, 255, 255, 250), 1900);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+const TIMEOUT: Duration = Duration::from_secs(3);
+/// Error returned by gateways which only support permanent mappings
+const ONLY_PERMANENT_LEASES: &str = "725";
+
+pub struct Gateway {
+    control: Url,
+    service: &'static str,
+    local: Ipv4Addr,
+}
+
+/// Searches the local network for a gateway with SSDP.
+pub fn discover() -> io::Result<Gateway> {
+    let sock = UdpSocket::bind(("0.0.0.0", 0))?;
+    let msg = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}:{}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+        SSDP_ADDR.0, SSDP_ADDR.1, SEARCH_TARGET
+    );
+    sock.send_to(msg.as_bytes(), SSDP_ADDR)?;
+
+    let start = Instant::now();
+    let mut buf = [0u8; 1500];
+    while start.elapsed() < TIMEOUT {
+        sock.set_read_timeout(Some(TIMEOUT - start.elapsed()))?;
+        let n = match sock.recv(&mut buf) {
+            Ok(n) => n,
+            Err(_) => break,
+        };
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut resp = httparse::Response::new(&mut headers);
+        if resp.parse(&buf[..n]).is_err() || resp.code != Some(200) {
+            continue;
+        }
+        let location = resp
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("location"))
+            .and_then(|h| str::from_utf8(h.value).ok())
+            .and_then(|l| Url::parse(l.trim()).ok());
+        if let Some(url) = location {
+            match Gateway::from_location(&url) {
+                Ok(gw) => return Ok(gw),
+                Err(e) => debug!("UPnP device at {} is unusable: {}", url, e),
+            }
+        }
+    }
+    io_err("no UPnP gateway found")
+}
+
+impl Gateway {
+    /// Loads the description of a device, finding its WAN connection service.
+    pub fn from_location(url: &Url) -> io::Result<Gateway> {
+        let (code, body, local) = http(url, "GET", &[], "")?;
+        if code != 200 {
+            return io_err("failed to fetch UPnP device description");
+        }
+        let base = tag(&body, "URLBase")
+            .and_then(|b| Url::parse(b.trim()).ok())
+            .unwrap_or_else(|| url.clone());
+        for desc in body.split("<service>").skip(1) {
+            let kind = match tag(desc, "serviceType") {
+                Some(k) => k.trim(),
+                None => continue,
+            };
+            let service = match SERVICES.iter().find(|&&s| s == kind) {
+                Some(&s) => s,
+                None => continue,
+            };
+            let control = tag(desc, "controlURL").and_then(|c| base.join(c.trim()).ok());
+            if let Some(control) = control {
+                let local = match local {
+                    IpAddr::V4(ip) => ip,
+                    IpAddr::V6(_) => return io_err("UPnP gateway must be IPv4"),
+                };
+                return Ok(Gateway {
+                    control,
+                    service,
+                    local,
+                });
+            }
+        }
+        io_err("UPnP device has no WAN connection service")
+    }
+
+    pub fn external_ip(&self) -> io::Result<IpAddr> {
+        let body = self.soap("GetExternalIPAddress", &[])?;
+        tag(&body, "NewExternalIPAddress")
+            .and_then(|ip| ip.trim().parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "invalid UPnP external IP"))
+    }
+
+    /// Maps a port to the same port on this host, returning the lease
+    /// duration granted, 0 meaning the mapping is permanent.
+    pub fn add_port(&self, proto: Protocol, port: u16, lease: u32) -> io::Result<u32> {
+        let port_s = port.to_string();
+        let local = self.local.to_string();
+        let mut lease_s = lease.to_string();
+        loop {
+            let args = [
+                ("NewRemoteHost", ""),
+                ("NewExternalPort", &port_s),
+                ("NewProtocol", proto.name()),
+                ("NewInternalPort", &port_s),
+                ("NewInternalClient", &local),
+                ("NewEnabled", "1"),
+                ("NewPortMappingDescription", "synapse"),
+                ("NewLeaseDuration", &lease_s),
+            ];
+            match self.soap("AddPortMapping", &args) {
+                Ok(_) => return Ok(lease_s.parse().unwrap_or(0)),
+                Err(ref e) if lease_s != "0" && e.to_string() == ONLY_PERMANENT_LEASES => {
+                    lease_s = "0".to_owned();
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn remove_port(&self, proto: Protocol, port: u16) -> io::Result<()> {
+        let port = port.to_string();
+        let args = [
+            ("NewRemoteHost", ""),
+            ("NewExternalPort", &port[..]),
+            ("NewProtocol", proto.name()),
+        ];
+        self.soap("DeletePortMapping", &args).map(|_| ())
+    }
+
+    /// Performs a SOAP action, returning the response body. Failed actions
+    /// produce an error holding the UPnP error code.
+    fn soap(&self, action: &str, args: &[(&str, &str)]) -> io::Result<String> {
+        let mut body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{} xmlns:u=\"{}\">",
+            action, self.service
+        );
+        for (name, value) in args {
+            body.push_str(&format!("<{0}>{1}</{0}>", name, value));
+        }
+        body.push_str(&format!("</u:{}></s:Body></s:Envelope>\r\n", action));
+
+        let soap_action = format!("\"{}#{}\"", self.service, action);
+        let headers = [
+            ("Content-Type", "text/xml; charset=\"utf-8\""),
+            ("SOAPAction", &soap_action[..]),
+        ];
+        let (code, resp, _) = http(&self.control, "POST", &headers, &body)?;
+        if code == 200 {
+            Ok(resp)
+        } else {
+            let err = tag(&resp, "errorCode").unwrap_or("unknown").trim();
+            Err(io::Error::new(io::ErrorKind::Other, err.to_owned()))
+        }
+    }
+}
+
+/// Performs a blocking HTTP request, returning the status code, body
+/// and the local address used to reach the server.
+fn http(
+    url: &Url,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> io::Result<(u16, String, IpAddr)> {
+    let host = match url.host_str() {
+        Some(h) => h,
+        None => return io_err("URL has no host"),
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addr: SocketAddr = match (host, port).to_socket_addrs()?.next() {
+        Some(a) => a,
+        None => return io_err("could not resolve host"),
+    };
+    let mut conn = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    conn.set_read_timeout(Some(TIMEOUT))?;
+    conn.set_write_timeout(Some(TIMEOUT))?;
+    let local = conn.local_addr()?.ip();
+
+    let mut path = url.path().to_owned();
+    if let Some(q) = url.query() {
+        path.push('?');
+        path.push_str(q);
+    }
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        host,
+        port,
+        body.len()
+    );
+    for (name, value) in headers {
+        req.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    req.push_str("\r\n");
+    req.push_str(body);
+    conn.write_all(req.as_bytes())?;
+
+    let mut data = Vec::new();
+    conn.read_to_end(&mut data)?;
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut resp = httparse::Response::new(&mut headers);
+    let len = match resp.parse(&data) {
+        Ok(httparse::Status::Complete(len)) => len,
+        _ => return io_err("invalid HTTP response"),
+    };
+    let chunked = resp.headers.iter().any(|h| {
+        h.name.eq_ignore_ascii_case("transfer-encoding")
+            && str::from_utf8(h.value)
+                .map(|v| v.trim().eq_ignore_ascii_case("chunked"))
+                .unwrap_or(false)
+    });
+    let body = if chunked {
+        dechunk(&data[len..])?
+    } else {
+        data[len..].to_vec()
+    };
+    let body = String::from_utf8(body)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "response is not UTF8"))?;
+    Ok((resp.code.unwrap_or(0), body, local))
+}
+
+fn dechunk(mut data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = match data.windows(2).position(|w| w == b"\r\n") {
+            Some(l) => l,
+            None => return io_err("invalid chunked body"),
+        };
+        let size = str::from_utf8(&data[..line])
+            .ok()
+            .and_then(|s| usize::from_str_radix(s.split(';').next()?.trim(), 16).ok());
+        let size = match size {
+            Some(0) => return Ok(body),
+            Some(s) if data.len() >= line + 2 + s => s,
+            _ => return io_err("invalid chunked body"),
+        };
+        body.extend_from_slice(&data[line + 2..line + 2 + size]);
+        data = &data[(line + 4 + size).min(data.len())..];
+    }
+}
+
+/// Finds the contents of the first element with the given name. Namespace
+/// prefixes are ignored, since gateways use a variety of them.
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    loop {
+        let open = rest.find('<')?;
+        rest = &rest[open + 1..];
+        let end = rest.find('>')?;
+        let elem = &rest[..end];
+        let elem_name = elem.split_whitespace().next().unwrap_or("");
+        let local_name = elem_name.rsplit(':').next().unwrap_or("");
+        if local_name == name && !elem.ends_with('/') {
+            let content = &rest[end + 1..];
+            let close = content.find(&format!("</{}>", elem_name)[..])?;
+            return Some(&content[..close]);
+        }
+        rest = &rest[end + 1..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const DESCRIPTION: &str = "<root><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+        <controlURL>/l3f</controlURL></service>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <controlURL>/ctl/IPConn</controlURL></service>\
+        </serviceList></device></root>";
+
+    /// Runs a mock gateway which serves its description and answers
+    /// SOAP actions, only accepting permanent leases.
+    fn mock_gateway() -> Url {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let mut data = Vec::new();
+                let mut buf = [0u8; 4096];
+                let req = loop {
+                    let n = conn.read(&mut buf).unwrap();
+                    data.extend_from_slice(&buf[..n]);
+                    let req = String::from_utf8_lossy(&data).into_owned();
+                    if let Some(i) = req.find("\r\n\r\n") {
+                        let len: usize = req
+                            .lines()
+                            .find(|l| l.starts_with("Content-Length"))
+                            .and_then(|l| l[15..].trim().parse().ok())
+                            .unwrap_or(0);
+                        if req.len() >= i + 4 + len {
+                            break req;
+                        }
+                    }
+                };
+                let (status, body) = if req.starts_with("GET /desc.xml") {
+                    // Exercise chunked transfer encoding
+                    let body = format!("{:x}\r\n{}\r\n0\r\n\r\n", DESCRIPTION.len(), DESCRIPTION);
+                    conn.write_all(
+                        format!(
+                            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+                            body
+                        )
+                        .as_bytes(),
+                    )
+                    .unwrap();
+                    continue;
+                } else if !req.starts_with("POST /ctl/IPConn") {
+                    (404, String::new())
+                } else if req.contains("#AddPortMapping") {
+                    if req.contains("<NewLeaseDuration>0<") {
+                        (200, String::new())
+                    } else {
+                        (
+                            500,
+                            "<UPnPError><errorCode>725</errorCode></UPnPError>".to_owned(),
+                        )
+                    }
+                } else if req.contains("#GetExternalIPAddress") {
+                    (
+                        200,
+                        "<u:GetExternalIPAddressResponse><NewExternalIPAddress>\
+                         9.8.7.6</NewExternalIPAddress></u:GetExternalIPAddressResponse>"
+                            .to_owned(),
+                    )
+                } else {
+                    (401, String::new())
+                };
+                let resp = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                conn.write_all(resp.as_bytes()).unwrap();
+            }
+        });
+        Url::parse(&format!("http://{}/desc.xml", addr)).unwrap()
+    }
+
+    #[test]
+    fn test_gateway() {
+        let gw = Gateway::from_location(&mock_gateway()).unwrap();
+        assert_eq!(gw.service, SERVICES[1]);
+        assert_eq!(gw.control.path(), "/ctl/IPConn");
+        assert_eq!(gw.add_port(Protocol::Tcp, 16_384, 3_600).unwrap(), 0);
+        assert_eq!(
+            gw.external_ip().unwrap(),
+            "9.8.7.6".parse::<IpAddr>().unwrap()
+        );
+        assert!(gw.remove_port(Protocol::Tcp, 16_384).is_err());
+    }
+
+    #[test]
+    fn test_tag() {
+        let xml = "<s:Body><u:Resp xmlns:u=\"x\"><NewExternalIPAddress>1.2.3.4</NewExternalIPAddress></u:Resp></s:Body>";
+        assert_eq!(tag(xml, "NewExternalIPAddress"), Some("1.2.3.4"));
+        assert_eq!(
+            tag(xml, "Resp"),
+            Some("<NewExternalIPAddress>1.2.3.4</NewExternalIPAddress>")
+        );
+        assert_eq!(tag(xml, "errorCode"), None);
+    }
+}
@@ -6,7 +6,8 @@ use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use rand::random;
 
 use crate::tracker::{
-    dns, Announce, Error, ErrorKind, Event, Response, Result, ResultExt, TrackerResponse,
+    dns, Announce, Error, ErrorKind, Event, Response, Result, ResultExt, Scrape, ScrapeResponse,
+    TrackerResponse,
 };
 use crate::util::{bytes_to_addr, FHashMap, UHashMap};
 use crate::{CONFIG, PEER_ID};
@@ -27,17 +28,38 @@ pub struct Handler {
 }
 
 struct Connection {
-    torrent: usize,
     last_updated: time::Instant,
     last_retrans: time::Instant,
     state: State,
-    announce: Announce,
+    req: Req,
+}
+
+enum Req {
+    Announce(Announce),
+    Scrape(Scrape),
 }
 
 enum State {
     ResolvingDNS { port: u16 },
     Connecting { addr: SocketAddr, data: [u8; 16] },
     Announcing { addr: SocketAddr, data: [u8; 98] },
+    Scraping { addr: SocketAddr, data: Vec<u8> },
+}
+
+impl Connection {
+    fn error(&self, e: Error) -> Response {
+        match self.req {
+            Req::Announce(ref a) => Response::Tracker {
+                tid: a.id,
+                url: a.url.clone(),
+                resp: Err(e),
+            },
+            Req::Scrape(ref s) => Response::Scrape {
+                url: s.url.clone(),
+                resp: Err(e),
+            },
+        }
+    }
 }
 
 impl Handler {
@@ -52,7 +74,7 @@ impl Handler {
             connections: UHashMap::default(),
             transactions: FHashMap::default(),
             conn_count: 0,
-            buf: vec![0u8; 350],
+            buf: vec![0u8; 1500],
         })
     }
 
@@ -73,8 +95,20 @@ impl Handler {
     }
 
     pub fn new_announce(&mut self, req: Announce, dns: &mut dns::Resolver) -> Result<()> {
-        let url = req.url.clone();
-        debug!("Received a new announce req for {:?}", url);
+        debug!("Received a new announce req for {:?}", req.url);
+        self.new_req(Req::Announce(req), dns)
+    }
+
+    pub fn new_scrape(&mut self, req: Scrape, dns: &mut dns::Resolver) -> Result<()> {
+        debug!("Received a new scrape req for {:?}", req.url);
+        self.new_req(Req::Scrape(req), dns)
+    }
+
+    fn new_req(&mut self, req: Req, dns: &mut dns::Resolver) -> Result<()> {
+        let url = match req {
+            Req::Announce(ref a) => a.url.clone(),
+            Req::Scrape(ref s) => s.url.clone(),
+        };
         let host = url.host_str().ok_or_else(|| {
             Error::from(ErrorKind::InvalidRequest(
                 "Tracker announce url has no host!".to_owned(),
@@ -90,11 +124,10 @@ impl Handler {
         self.connections.insert(
             id,
             Connection {
-                torrent: req.id,
                 last_updated: time::Instant::now(),
                 last_retrans: time::Instant::now(),
                 state: State::ResolvingDNS { port },
-                announce: req,
+                req,
             },
         );
         debug!("Dispatching DNS req for {:?}, url: {:?}", id, host);
@@ -134,11 +167,7 @@ impl Handler {
                             self.transactions.insert(tid, id);
                             None
                         }
-                        Err(e) => Some(Response::Tracker {
-                            tid: conn.torrent,
-                            url: conn.announce.url.clone(),
-                            resp: Err(e),
-                        }),
+                        Err(e) => Some(conn.error(e)),
                     }
                 }
                 _ => None,
@@ -171,6 +200,11 @@ impl Handler {
                         resps.push(r);
                     }
                 }
+                2 if v >= 8 => {
+                    if let Some(r) = self.process_scrape(v) {
+                        resps.push(r);
+                    }
+                }
                 3 if v >= 8 => {
                     if let Some(r) = self.process_error(v) {
                         resps.push(r);
@@ -190,12 +224,8 @@ impl Handler {
         {
             self.connections.retain(|id, conn| {
                 if conn.last_updated.elapsed() > time::Duration::from_millis(TIMEOUT_MS) {
-                    resps.push(Response::Tracker {
-                        tid: conn.torrent,
-                        url: conn.announce.url.clone(),
-                        resp: Err(ErrorKind::Timeout.into()),
-                    });
-                    debug!("Announce {:?} timed out", id);
+                    resps.push(conn.error(ErrorKind::Timeout.into()));
+                    debug!("Tracker request {:?} timed out", id);
                     false
                 } else {
                     if conn.last_retrans.elapsed() > time::Duration::from_millis(RETRANS_MS) {
@@ -241,6 +271,24 @@ impl Handler {
                 State::Connecting { addr, .. } => addr,
                 _ => return None,
             };
+            let announce = match conn.req {
+                Req::Announce(ref a) => a,
+                Req::Scrape(ref req) => {
+                    let mut data = Vec::with_capacity(16 + 20 * req.torrents.len());
+                    data.write_u64::<BigEndian>(connection_id).unwrap();
+                    // scrape action
+                    data.write_u32::<BigEndian>(2).unwrap();
+                    let tid = random::<u32>();
+                    data.write_u32::<BigEndian>(tid).unwrap();
+                    self.transactions.insert(tid, id);
+                    for (_, hash) in &req.torrents {
+                        data.extend_from_slice(hash);
+                    }
+                    conn.state = State::Scraping { addr, data };
+                    conn.last_updated = time::Instant::now();
+                    return self.send_data(id);
+                }
+            };
 
             {
                 let mut announce_req = Cursor::new(&mut data[..]);
@@ -252,18 +300,18 @@ impl Handler {
                 announce_req.write_u32::<BigEndian>(tid).unwrap();
                 self.transactions.insert(tid, id);
 
-                announce_req.write_all(&conn.announce.hash).unwrap();
+                announce_req.write_all(&announce.hash).unwrap();
                 announce_req.write_all(&PEER_ID[..]).unwrap();
                 announce_req
-                    .write_u64::<BigEndian>(conn.announce.downloaded as u64)
+                    .write_u64::<BigEndian>(announce.downloaded as u64)
                     .unwrap();
                 announce_req
-                    .write_u64::<BigEndian>(conn.announce.left as u64)
+                    .write_u64::<BigEndian>(announce.left as u64)
                     .unwrap();
                 announce_req
-                    .write_u64::<BigEndian>(conn.announce.uploaded as u64)
+                    .write_u64::<BigEndian>(announce.uploaded as u64)
                     .unwrap();
-                match conn.announce.event {
+                match announce.event {
                     Some(Event::Started) => {
                         announce_req.write_u32::<BigEndian>(2).unwrap();
                     }
@@ -283,12 +331,10 @@ impl Handler {
                 // Key - TODO: randomly generate this
                 announce_req.write_u32::<BigEndian>(0xFFFF_00BA).unwrap();
                 // Num want
-                let nw = conn.announce.num_want.map(i32::from).unwrap_or(-1);
+                let nw = announce.num_want.map(i32::from).unwrap_or(-1);
                 announce_req.write_i32::<BigEndian>(nw).unwrap();
                 // port
-                announce_req
-                    .write_u16::<BigEndian>(conn.announce.port)
-                    .unwrap();
+                announce_req.write_u16::<BigEndian>(announce.port).unwrap();
             }
             conn.state = State::Announcing { addr, data };
             conn.last_updated = time::Instant::now();
@@ -306,9 +352,12 @@ impl Handler {
             None => return None,
         };
 
-        let conn = match self.connections.remove(&id) {
-            Some(c) => c,
-            None => return None,
+        let announce = match self.connections.remove(&id) {
+            Some(Connection {
+                req: Req::Announce(a),
+                ..
+            }) => a,
+            _ => return None,
         };
 
         resp.interval = announce_resp.read_u32::<BigEndian>().unwrap();
@@ -321,12 +370,41 @@ impl Handler {
             }
         }
         Some(Response::Tracker {
-            tid: conn.torrent,
-            url: conn.announce.url,
+            tid: announce.id,
+            url: announce.url,
             resp: Ok(resp),
         })
     }
 
+    fn process_scrape(&mut self, len: usize) -> Option<Response> {
+        let transaction_id = BigEndian::read_u32(&self.buf[4..8]);
+        let id = self.transactions.remove(&transaction_id)?;
+        let req = match self.connections.remove(&id) {
+            Some(Connection {
+                req: Req::Scrape(s),
+                ..
+            }) => s,
+            _ => return None,
+        };
+
+        // Stats are returned in the order the hashes were requested
+        let resps = req
+            .torrents
+            .iter()
+            .zip(self.buf[8..len].chunks_exact(12))
+            .map(|(&(tid, _), d)| ScrapeResponse {
+                tid,
+                complete: BigEndian::read_u32(&d[0..4]),
+                downloaded: BigEndian::read_u32(&d[4..8]),
+                incomplete: BigEndian::read_u32(&d[8..12]),
+            })
+            .collect();
+        Some(Response::Scrape {
+            url: req.url,
+            resp: Ok(resps),
+        })
+    }
+
     fn process_error(&mut self, len: usize) -> Option<Response> {
         let mut s = String::new();
         let mut connect_resp = Cursor::new(&self.buf[4..len]);
@@ -337,25 +415,14 @@ impl Handler {
             None => return None,
         };
 
-        let conn = match self.connections.remove(&id) {
-            Some(c) => c,
-            None => return None,
-        };
+        let conn = self.connections.remove(&id)?;
 
         if connect_resp.read_to_string(&mut s).is_err() {
-            let resp =
-                Err(ErrorKind::InvalidResponse("Tracker error response was invalid UTF8").into());
-            Some(Response::Tracker {
-                tid: conn.torrent,
-                url: conn.announce.url,
-                resp,
-            })
+            Some(conn.error(
+                ErrorKind::InvalidResponse("Tracker error response was invalid UTF8").into(),
+            ))
         } else {
-            Some(Response::Tracker {
-                tid: conn.torrent,
-                url: conn.announce.url,
-                resp: Err(ErrorKind::TrackerError(s).into()),
-            })
+            Some(conn.error(ErrorKind::TrackerError(s).into()))
         }
     }
 
@@ -366,10 +433,8 @@ impl Handler {
     }
 
     fn send_data(&mut self, id: usize) -> Option<Response> {
-        let tid;
         let res = {
             let conn = self.connections.get_mut(&id).unwrap();
-            tid = conn.torrent;
             // If this actually blocks, something is really fucked(prob with the NIC)
             // and i dont think we need to care
             match conn.state {
@@ -381,19 +446,16 @@ impl Handler {
                     conn.last_retrans = time::Instant::now();
                     self.sock.send_to(data, addr).chain_err(|| ErrorKind::IO)
                 }
+                State::Scraping { ref addr, ref data } => {
+                    conn.last_retrans = time::Instant::now();
+                    self.sock.send_to(data, addr).chain_err(|| ErrorKind::IO)
+                }
                 _ => Ok(0),
             }
         };
 
         match res {
-            Err(e) => {
-                let url = self.connections.remove(&id).unwrap().announce.url;
-                Some(Response::Tracker {
-                    tid,
-                    url,
-                    resp: Err(e),
-                })
-            }
+            Err(e) => Some(self.connections.remove(&id).unwrap().error(e)),
             Ok(_) => None,
         }
     }
@@ -0,0 +1,295 @@
+//! Sets of IP ranges, used for the peer blocklist and LAN subnets. Lists
+//! are loaded from eMule DAT, PeerGuardian P2P or plain CIDR files, with
+//! the format detected per line, and stored as sorted disjoint ranges.
+
+use std::io::{self, BufRead, BufReader};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::{cmp, fs};
+
+use crate::util::io_err;
+
+/// eMule DAT entries with an access level below this are blocked
+const DAT_ALLOW_LEVEL: u32 = 128;
+
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    pub fn new() -> IpFilter {
+        IpFilter::default()
+    }
+
+    /// Loads a list, skipping comments and lines which can't be parsed.
+    pub fn load(path: &str) -> io::Result<IpFilter> {
+        let file = BufReader::new(fs::File::open(path)?);
+        let mut filter = IpFilter::new();
+        let mut invalid = 0;
+        for line in file.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some((start, end))) => filter.add(start, end),
+                Some(None) => {}
+                None => invalid += 1,
+            }
+        }
+        if invalid != 0 {
+            info!("Skipped {} invalid lines of blocklist {}", invalid, path);
+        }
+        if filter.is_empty() && invalid != 0 {
+            return io_err("Blocklist contains no valid ranges");
+        }
+        filter.v4 = merge(filter.v4);
+        filter.v6 = merge(filter.v6);
+        Ok(filter)
+    }
+
+    /// Builds a filter from addresses and CIDR ranges, skipping any
+    /// which are invalid.
+    pub fn from_nets<S: AsRef<str>>(nets: &[S]) -> IpFilter {
+        let mut filter = IpFilter::new();
+        for (start, end) in nets.iter().filter_map(|n| parse_net(n.as_ref())) {
+            filter.add(start, end);
+        }
+        filter.v4 = merge(filter.v4);
+        filter.v6 = merge(filter.v6);
+        filter
+    }
+
+    /// Number of disjoint ranges in the filter.
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => match ipv4_mapped(ip) {
+                Some(ip) => contains(&self.v4, u32::from(ip)),
+                None => contains(&self.v6, u128::from(ip)),
+            },
+        }
+    }
+
+    fn add(&mut self, start: IpAddr, end: IpAddr) {
+        match (start, end) {
+            (IpAddr::V4(s), IpAddr::V4(e)) => {
+                let (s, e) = (u32::from(s), u32::from(e));
+                self.v4.push((cmp::min(s, e), cmp::max(s, e)));
+            }
+            (IpAddr::V6(s), IpAddr::V6(e)) => {
+                let (s, e) = (u128::from(s), u128::from(e));
+                self.v6.push((cmp::min(s, e), cmp::max(s, e)));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sorts ranges and merges any which overlap or are adjacent.
+fn merge<T: Ord + Copy + From<u8> + std::ops::Add<Output = T>>(
+    mut ranges: Vec<(T, T)>,
+) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        if let Some(last) = merged.last_mut() {
+            if last.1 >= start || last.1 + T::from(1) == start {
+                last.1 = cmp::max(last.1, end);
+                continue;
+            }
+        }
+        merged.push((start, end));
+    }
+    merged.shrink_to_fit();
+    merged
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    match ranges.binary_search_by_key(&ip, |&(start, _)| start) {
+        Ok(_) => true,
+        Err(0) => false,
+        Err(i) => ranges[i - 1].1 >= ip,
+    }
+}
+
+/// Parses a line in any supported format, returning None if it's invalid,
+/// or Some(None) if it's valid but doesn't block anything.
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    if let Some(entry) = parse_dat(line) {
+        return Some(entry);
+    }
+    // PeerGuardian P2P: "description:start-end"
+    if let Some(i) = line.rfind(':') {
+        if let Some(range) = parse_range(&line[i + 1..]) {
+            return Some(Some(range));
+        }
+    }
+    // CIDR: "addr/len"
+    if line.contains('/') {
+        return parse_net(line).map(Some);
+    }
+    // A plain range or single address
+    parse_range(line)
+        .or_else(|| parse_addr(line).map(|a| (a, a)))
+        .map(Some)
+}
+
+/// Parses a single address or CIDR range.
+pub fn parse_net(s: &str) -> Option<(IpAddr, IpAddr)> {
+    match s.find('/') {
+        Some(i) => {
+            let len = s[i + 1..].trim().parse::<u32>().ok()?;
+            parse_cidr(parse_addr(&s[..i])?, len)
+        }
+        None => parse_addr(s).map(|a| (a, a)),
+    }
+}
+
+/// Parses an eMule DAT line, "start - end , level , description".
+fn parse_dat(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    let mut parts = line.splitn(3, ',');
+    let range = parse_range(parts.next()?)?;
+    let level = parts.next()?.trim().parse::<u32>().ok()?;
+    Some(if level < DAT_ALLOW_LEVEL {
+        Some(range)
+    } else {
+        None
+    })
+}
+
+fn parse_range(s: &str) -> Option<(IpAddr, IpAddr)> {
+    let i = s.find('-')?;
+    Some((parse_addr(&s[..i])?, parse_addr(&s[i + 1..])?))
+}
+
+/// Parses an address, allowing the zero padded octets used by DAT lists.
+fn parse_addr(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    let octets: Vec<_> = s.split('.').collect();
+    if octets.len() == 4 {
+        let mut ip = [0u8; 4];
+        for (o, s) in ip.iter_mut().zip(octets) {
+            *o = s.parse().ok()?;
+        }
+        return Some(IpAddr::V4(Ipv4Addr::from(ip)));
+    }
+    s.parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+}
+
+/// Extracts the address of an IPv4-mapped IPv6 address, ::ffff:a.b.c.d.
+pub fn ipv4_mapped(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, hi, lo] => Some(Ipv4Addr::new(
+            (hi >> 8) as u8,
+            hi as u8,
+            (lo >> 8) as u8,
+            lo as u8,
+        )),
+        _ => None,
+    }
+}
+
+fn parse_cidr(addr: IpAddr, len: u32) -> Option<(IpAddr, IpAddr)> {
+    match addr {
+        IpAddr::V4(ip) if len <= 32 => {
+            let mask = std::u32::MAX.checked_shl(32 - len).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+            Some((
+                IpAddr::V4(Ipv4Addr::from(start)),
+                IpAddr::V4(Ipv4Addr::from(start | !mask)),
+            ))
+        }
+        IpAddr::V6(ip) if len <= 128 => {
+            let mask = std::u128::MAX.checked_shl(128 - len).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+            Some((
+                IpAddr::V6(Ipv6Addr::from(start)),
+                IpAddr::V6(Ipv6Addr::from(start | !mask)),
+            ))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ipv4_mapped, parse_line, IpFilter};
+
+    fn filter(lines: &[&str]) -> IpFilter {
+        let mut f = IpFilter::new();
+        for line in lines {
+            if let Some(Some((s, e))) = parse_line(line) {
+                f.add(s, e);
+            }
+        }
+        f.v4 = super::merge(f.v4);
+        f.v6 = super::merge(f.v6);
+        f
+    }
+
+    #[test]
+    fn test_formats() {
+        let f = filter(&[
+            "001.002.003.000 - 001.002.003.255 , 000 , Some DAT range",
+            "005.000.000.000 - 005.255.255.255 , 200 , Allowed DAT range",
+            "Some P2P range, w/ a comma:10.0.0.0-10.0.0.9",
+            "192.168.0.0/16",
+            "2001:db8::/32",
+            "8.8.8.8",
+        ]);
+        assert!(f.contains("1.2.3.4".parse().unwrap()));
+        assert!(!f.contains("1.2.4.0".parse().unwrap()));
+        assert!(!f.contains("5.1.1.1".parse().unwrap()));
+        assert!(f.contains("10.0.0.9".parse().unwrap()));
+        assert!(!f.contains("10.0.0.10".parse().unwrap()));
+        assert!(f.contains("192.168.255.255".parse().unwrap()));
+        assert!(!f.contains("192.169.0.0".parse().unwrap()));
+        assert!(f.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!f.contains("2001:db9::1".parse().unwrap()));
+        assert!(f.contains("8.8.8.8".parse().unwrap()));
+        assert!(f.contains("::ffff:8.8.8.8".parse().unwrap()));
+        assert!(!f.contains("8.8.4.4".parse().unwrap()));
+        assert!(parse_line("not an address").is_none());
+    }
+
+    #[test]
+    fn test_merge() {
+        let f = filter(&[
+            "10.0.0.0-10.0.0.9",
+            "10.0.0.10-10.0.0.20",
+            "10.0.0.5-10.0.0.7",
+        ]);
+        assert_eq!(f.len(), 1);
+        assert!(f.contains("10.0.0.20".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_from_nets() {
+        let f = IpFilter::from_nets(&["10.0.0.0/8", "fe80::/10", "bogus"]);
+        assert_eq!(f.len(), 2);
+        assert!(f.contains("10.1.2.3".parse().unwrap()));
+        assert!(f.contains("fe80::1".parse().unwrap()));
+        assert!(!f.contains("11.0.0.0".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ipv4_mapped() {
+        assert_eq!(
+            ipv4_mapped("::ffff:10.1.2.3".parse().unwrap()),
+            Some("10.1.2.3".parse().unwrap())
+        );
+        assert_eq!(ipv4_mapped("::10.1.2.3".parse().unwrap()), None);
+        assert_eq!(ipv4_mapped("2001:db8::ffff:a01:203".parse().unwrap()), None);
+    }
+}
@@ -1,3 +1,4 @@
+pub mod filter;
 pub mod http;
 mod io;
 pub mod native;
@@ -5,13 +6,14 @@ pub mod native;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Write as FWrite;
 use std::hash::BuildHasherDefault;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use metrohash::MetroHash;
 use rand::distributions::Alphanumeric;
 use rand::{self, Rng};
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 
 pub type FHashMap<K, V> = fnv::FnvHashMap<K, V>;
 pub type FHashSet<T> = fnv::FnvHashSet<T>;
@@ -54,6 +56,12 @@ pub fn sha1_hash(data: &[u8]) -> [u8; 20] {
     ctx.finalize().into()
 }
 
+pub fn sha256_hash(data: &[u8]) -> [u8; 32] {
+    let mut ctx = Sha256::new();
+    ctx.update(data);
+    ctx.finalize().into()
+}
+
 pub fn peer_rpc_id(torrent: &[u8; 20], peer: u64) -> String {
     const PEER_ID: &[u8] = b"PEER";
     let mut idx = [0u8; 8];
@@ -84,6 +92,14 @@ pub fn trk_rpc_id(torrent: &[u8; 20], url: &str) -> String {
     hash_to_id(&ctx.finalize())
 }
 
+pub fn group_rpc_id(name: &str) -> String {
+    const GROUP_ID: &[u8] = b"GROUP";
+    let mut ctx = Sha1::new();
+    ctx.update(GROUP_ID);
+    ctx.update(name.as_bytes());
+    hash_to_id(&ctx.finalize())
+}
+
 pub fn hash_to_id(hash: &[u8]) -> String {
     let mut hash_str = String::new();
     for i in hash {
@@ -92,20 +108,38 @@ pub fn hash_to_id(hash: &[u8]) -> String {
     hash_str
 }
 
+/// Converts a hex encoded ID into the 20 byte hash used to identify
+/// a torrent's swarm. Full v2 infohashes are truncated, as per BEP 52.
 pub fn id_to_hash(s: &str) -> Option<[u8; 20]> {
     let mut data = [0u8; 20];
-    if s.len() != 40 {
+    match s.len() {
+        40 => decode_hex(s, &mut data).map(|_| data),
+        64 => id_to_hash_v2(s).map(|h| {
+            data.copy_from_slice(&h[..20]);
+            data
+        }),
+        _ => None,
+    }
+}
+
+pub fn id_to_hash_v2(s: &str) -> Option<[u8; 32]> {
+    let mut data = [0u8; 32];
+    if s.len() != 64 {
         return None;
     }
+    decode_hex(s, &mut data).map(|_| data)
+}
+
+fn decode_hex(s: &str, data: &mut [u8]) -> Option<()> {
     let mut c = s.chars();
-    for i in &mut data {
-        if let (Some(a), Some(b)) = (hex_to_bit(c.next().unwrap()), hex_to_bit(c.next().unwrap())) {
+    for i in data {
+        if let (Some(a), Some(b)) = (hex_to_bit(c.next()?), hex_to_bit(c.next()?)) {
             *i = a << 4 | b
         } else {
             return None;
         }
     }
-    Some(data)
+    Some(())
 }
 
 fn hex_to_bit(c: char) -> Option<u8> {
@@ -131,24 +165,26 @@ fn hex_to_bit(c: char) -> Option<u8> {
     Some(r)
 }
 
+/// Parses a compact address and port, which is 18 bytes long for IPv6
+/// and 6 bytes long for IPv4.
 pub fn bytes_to_addr(p: &[u8]) -> SocketAddr {
-    let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
-    SocketAddr::V4(SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..])))
-}
-
-pub fn addr_to_bytes(addr: &SocketAddr) -> [u8; 6] {
-    let mut data = [0u8; 6];
-    match *addr {
-        SocketAddr::V4(s) => {
-            let oct = s.ip().octets();
-            data[0] = oct[0];
-            data[1] = oct[1];
-            data[2] = oct[2];
-            data[3] = oct[3];
-            BigEndian::write_u16(&mut data[4..], s.port());
-        }
-        _ => panic!("IPv6 DHT not supported"),
+    if p.len() == 18 {
+        let mut oct = [0u8; 16];
+        oct.copy_from_slice(&p[..16]);
+        let ip = Ipv6Addr::from(oct);
+        SocketAddr::V6(SocketAddrV6::new(ip, BigEndian::read_u16(&p[16..]), 0, 0))
+    } else {
+        let ip = Ipv4Addr::new(p[0], p[1], p[2], p[3]);
+        SocketAddr::V4(SocketAddrV4::new(ip, BigEndian::read_u16(&p[4..])))
     }
+}
+
+pub fn addr_to_bytes(addr: &SocketAddr) -> Vec<u8> {
+    let mut data = match *addr {
+        SocketAddr::V4(s) => s.ip().octets().to_vec(),
+        SocketAddr::V6(s) => s.ip().octets().to_vec(),
+    };
+    data.write_u16::<BigEndian>(addr.port()).unwrap();
     data
 }
 
@@ -174,5 +210,22 @@ mod test {
         let hash = [8u8; 20];
         let s = hash_to_id(&hash);
         assert_eq!(id_to_hash(&s).unwrap(), hash);
+
+        let v2 = [9u8; 32];
+        assert_eq!(id_to_hash(&hash_to_id(&v2)).unwrap(), [9u8; 20]);
+    }
+
+    #[test]
+    fn test_addr_enc() {
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let data = addr_to_bytes(&addr);
+        assert_eq!(data, vec![10, 0, 0, 1, 0x1a, 0xe1]);
+        assert_eq!(bytes_to_addr(&data), addr);
+
+        let addr: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let data = addr_to_bytes(&addr);
+        assert_eq!(data.len(), 18);
+        assert_eq!(&data[16..], &[0x1a, 0xe1]);
+        assert_eq!(bytes_to_addr(&data), addr);
     }
 }
@@ -244,6 +244,7 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
         "piece" => ResourceKind::Piece,
         "file" => ResourceKind::File,
         "server" => ResourceKind::Server,
+        "throttle_group" => ResourceKind::ThrottleGroup,
         _ => bail!("Unexpected resource kind {}", kind),
     };
     let results = search(&mut c, k, crit)?;
@@ -269,6 +270,9 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
             ResourceKind::Server => {
                 table.set_titles(row!["DL RT", "UL RT"]);
             }
+            ResourceKind::ThrottleGroup => {
+                table.set_titles(row!["Name", "DL Limit", "UL Limit"]);
+            }
         }
 
         #[cfg_attr(rustfmt, rustfmt_skip)]
@@ -320,6 +324,14 @@ pub fn list(mut c: Client, kind: &str, crit: Vec<Criterion>, output: &str) -> Re
                     let ru = fmt_bytes(s.rate_up as f64) + "/s";
                     table.add_row(row![rd, ru]);
                 }
+                ResourceKind::ThrottleGroup => {
+                    let g = res.as_throttle_group();
+                    let limit = |l: Option<i64>| match l {
+                        Some(l) if l >= 0 => fmt_bytes(l as f64) + "/s",
+                        _ => "unlimited".to_owned(),
+                    };
+                    table.add_row(row![g.name, limit(g.throttle_down), limit(g.throttle_up)]);
+                }
             }
         }
         table.printstd();
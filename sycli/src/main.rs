@@ -164,7 +164,15 @@ fn main() {
                 .arg(
                     Arg::with_name("kind")
                         .help("The kind of resource to list.")
-                        .possible_values(&["torrent", "peer", "file", "server", "tracker", "piece"])
+                        .possible_values(&[
+                            "torrent",
+                            "peer",
+                            "file",
+                            "server",
+                            "tracker",
+                            "piece",
+                            "throttle_group",
+                        ])
                         .default_value("torrent")
                         .short("k")
                         .long("kind"),